@@ -0,0 +1,238 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use tokio::runtime::Runtime;
+
+use zerofs_nfsserve::nfs::*;
+use zerofs_nfsserve::vfs::{AuthContext, NFSFileSystem};
+
+use nfs_mirror::config::{MountConfig, ServerConfig};
+use nfs_mirror::filesystem::MirrorFS;
+use nfs_mirror::fsmap::FSMap;
+use nfs_mirror::instrument;
+
+/// Directories in the synthetic tree, each holding `FILES` files
+const DIRS: usize = 20;
+const FILES: usize = 500;
+
+/// Clients issuing GETATTRs at once in the concurrent benchmark
+const CLIENTS: usize = 8;
+
+/// Build the synthetic tree once per run, under the system temp directory
+fn synthetic_tree() -> PathBuf {
+    let root = std::env::temp_dir().join(format!("nfs_mirror_bench_{}", std::process::id()));
+    for d in 0..DIRS {
+        let dir = root.join(format!("dir{:03}", d));
+        std::fs::create_dir_all(&dir).unwrap();
+        for f in 0..FILES {
+            std::fs::write(dir.join(format!("file{:04}", f)), b"").unwrap();
+        }
+    }
+    root
+}
+
+fn auth() -> AuthContext {
+    AuthContext {
+        uid: 0,
+        gid: 0,
+        gids: vec![],
+    }
+}
+
+fn mirror(source: &Path) -> MirrorFS {
+    MirrorFS::new_with_mounts(
+        source.to_path_buf(),
+        &ServerConfig::default(),
+        vec![MountConfig::new(source, "/bench")],
+    )
+}
+
+/// Look up `bench/dir000/file0000` from the root
+async fn lookup_file(fs: &MirrorFS) -> fileid3 {
+    let mut id = fs.root_dir();
+    for name in ["bench", "dir000", "file0000"] {
+        id = fs
+            .lookup(&auth(), id, &name.as_bytes().into())
+            .await
+            .unwrap();
+    }
+    id
+}
+
+/// Print the backing store calls one run of `op` makes
+fn report_calls(rt: &Runtime, name: &str, op: impl AsyncFnOnce()) {
+    instrument::take();
+    rt.block_on(op());
+    let calls = instrument::take();
+    eprintln!(
+        "{}: {} read_dir, {} stat (zero without --features instrument)",
+        name, calls.read_dir, calls.stat
+    );
+}
+
+fn bench_lookup(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tree = synthetic_tree();
+
+    report_calls(&rt, "cold lookup", async || {
+        lookup_file(&mirror(&tree)).await;
+    });
+    c.bench_function("lookup/cold", |b| {
+        b.iter_batched(
+            || mirror(&tree),
+            |fs| rt.block_on(lookup_file(&fs)),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let fs = mirror(&tree);
+    rt.block_on(lookup_file(&fs));
+    report_calls(&rt, "warm lookup", async || {
+        lookup_file(&fs).await;
+    });
+    c.bench_function("lookup/warm", |b| b.iter(|| rt.block_on(lookup_file(&fs))));
+
+    let _ = std::fs::remove_dir_all(&tree);
+}
+
+fn bench_readdir(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tree = synthetic_tree();
+    let fs = mirror(&tree);
+    let dir = rt.block_on(async {
+        let mount = fs.lookup(&auth(), 0, &b"bench"[..].into()).await.unwrap();
+        fs.lookup(&auth(), mount, &b"dir000"[..].into())
+            .await
+            .unwrap()
+    });
+
+    // Every page of a directory of FILES entries, 32 at a time
+    let paginate = async || {
+        let mut cookie = 0;
+        loop {
+            let page = fs.readdir(&auth(), dir, cookie, 32).await.unwrap();
+            if let Some(last) = page.entries.last() {
+                cookie = last.fileid;
+            }
+            if page.end {
+                break;
+            }
+        }
+    };
+    report_calls(&rt, "readdir pagination", &paginate);
+    c.bench_function("readdir/paginate", |b| b.iter(|| rt.block_on(paginate())));
+
+    let _ = std::fs::remove_dir_all(&tree);
+}
+
+fn bench_relist(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tree = synthetic_tree();
+    let mut fsmap = FSMap::new_with_mounts(tree.clone(), vec![MountConfig::new(&tree, "/bench")]);
+    let mount = rt.block_on(fsmap.find_child(0, b"bench")).unwrap();
+    rt.block_on(fsmap.refresh_dir_list(mount)).unwrap();
+    let dir = rt.block_on(fsmap.find_child(mount, b"dir000")).unwrap();
+
+    // Dropping the listing forces the next refresh to read the directory again
+    let mut relist = async || {
+        fsmap.find_entry_mut(dir).unwrap().children = None;
+        fsmap.refresh_dir_list(dir).await.unwrap();
+    };
+    report_calls(&rt, "full relist", &mut relist);
+    c.bench_function("refresh_dir_list/relist", |b| {
+        b.iter(|| rt.block_on(relist()))
+    });
+
+    let _ = std::fs::remove_dir_all(&tree);
+}
+
+fn bench_repeated_read(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tree = synthetic_tree();
+    for (name, cache_real_paths) in [
+        ("read/repeated", false),
+        ("read/repeated_cached_path", true),
+    ] {
+        let server = ServerConfig {
+            cache_real_paths,
+            ..ServerConfig::default()
+        };
+        let fs = MirrorFS::new_with_mounts(
+            tree.clone(),
+            &server,
+            vec![MountConfig::new(&tree, "/bench")],
+        );
+        let id = rt.block_on(lookup_file(&fs));
+        c.bench_function(name, |b| {
+            b.iter(|| rt.block_on(fs.read(&auth(), id, 0, 4096)).unwrap())
+        });
+    }
+
+    let _ = std::fs::remove_dir_all(&tree);
+}
+
+fn bench_concurrent_getattr(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let tree = synthetic_tree();
+    let server = ServerConfig {
+        snapshot: true,
+        ..ServerConfig::default()
+    };
+    let snapshot = |lock_free: bool| {
+        let fs = Arc::new(MirrorFS::new_with_mounts(
+            tree.clone(),
+            &server,
+            vec![MountConfig::new(&tree, "/bench")],
+        ));
+        rt.block_on(async {
+            if lock_free {
+                fs.take_snapshot().await;
+            } else {
+                // Frozen like a snapshot, but served through the locked map
+                fs.fsmap.lock().await.freeze().await;
+            }
+        });
+        let id = rt.block_on(lookup_file(&fs));
+        (fs, id)
+    };
+
+    // Every client stats the same file 100 times
+    let getattrs = |fs: &Arc<MirrorFS>, id: fileid3| {
+        rt.block_on(async {
+            let clients: Vec<_> = (0..CLIENTS)
+                .map(|_| {
+                    let fs = Arc::clone(fs);
+                    tokio::spawn(async move {
+                        for _ in 0..100 {
+                            fs.getattr(&auth(), id).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for client in clients {
+                client.await.unwrap();
+            }
+        })
+    };
+    let (fs, id) = snapshot(false);
+    c.bench_function("getattr/concurrent_locked", |b| {
+        b.iter(|| getattrs(&fs, id))
+    });
+    let (fs, id) = snapshot(true);
+    c.bench_function("getattr/concurrent_snapshot", |b| {
+        b.iter(|| getattrs(&fs, id))
+    });
+
+    let _ = std::fs::remove_dir_all(&tree);
+}
+
+criterion_group!(
+    benches,
+    bench_lookup,
+    bench_readdir,
+    bench_relist,
+    bench_repeated_read,
+    bench_concurrent_getattr
+);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::fsmap::FSMap;
+
+/// Runtime read-only override, independent of the configured read-only settings
+///
+/// While enabled, every mutating operation fails with `NFS3ERR_ROFS`.
+#[derive(Debug, Default)]
+pub struct ReadOnlySwitch {
+    enabled: AtomicBool,
+}
+
+impl ReadOnlySwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check whether the override is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Enable or disable the override, logging the transition
+    pub fn set(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::SeqCst) != enabled {
+            if enabled {
+                warn!("Server switched to read-only");
+            } else {
+                info!("Server switched back to read-write");
+            }
+        }
+    }
+
+    /// Flip the override, returning the new state
+    pub fn toggle(&self) -> bool {
+        let enabled = !self.enabled.load(Ordering::SeqCst);
+        self.set(enabled);
+        enabled
+    }
+}
+
+/// Spawn a task that toggles the read-only override on every SIGUSR1
+pub fn spawn_sigusr1_toggle(switch: Arc<ReadOnlySwitch>) -> std::io::Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            switch.toggle();
+        }
+    });
+    Ok(())
+}
+
+/// Wait for SIGTERM or SIGINT, whichever arrives first
+///
+/// The handlers are installed before this returns, so a signal sent while the
+/// server is still starting is not lost.
+pub fn termination() -> std::io::Result<impl Future<Output = ()>> {
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = term.recv() => info!("Received SIGTERM, shutting down"),
+            _ = int.recv() => info!("Received SIGINT, shutting down"),
+        }
+    })
+}
+
+/// Spawn a task that writes a JSON dump of the file map to `path` on every SIGUSR2
+///
+/// The file map is only locked while the dump is collected, not while it is
+/// serialized and written.
+pub fn spawn_sigusr2_dump(fsmap: Arc<Mutex<FSMap>>, path: PathBuf) -> std::io::Result<()> {
+    let mut signals = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let dump = fsmap.lock().await.dump().await;
+            let written = match serde_json::to_vec_pretty(&dump) {
+                Ok(json) => tokio::fs::write(&path, json).await,
+                Err(e) => Err(e.into()),
+            };
+            match written {
+                Ok(()) => info!(
+                    "File map dumped to {} ({} entries)",
+                    path.display(),
+                    dump.entries.len()
+                ),
+                Err(e) => warn!("Failed to dump the file map to {}: {}", path.display(), e),
+            }
+        }
+    });
+    Ok(())
+}
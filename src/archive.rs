@@ -0,0 +1,399 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+use tracing::debug;
+
+use zerofs_nfsserve::nfs::*;
+
+/// Size of a tar header and of the blocks member data is padded to
+const BLOCK: u64 = 512;
+
+/// What a tar member is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberKind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+}
+
+/// One member of a tar archive, with where its data is stored
+#[derive(Debug, Clone)]
+pub struct Member {
+    /// Path inside the archive, relative and without `.` or `..` components
+    pub path: PathBuf,
+    pub kind: MemberKind,
+    /// Offset of the member's data in the archive file
+    pub offset: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u64,
+}
+
+impl Member {
+    /// NFS attributes of the member, filling in what tar does not store from `base`
+    pub fn attr(&self, base: fattr3) -> fattr3 {
+        let mut attr = base;
+        attr.ftype = match self.kind {
+            MemberKind::File => ftype3::NF3REG,
+            MemberKind::Dir => ftype3::NF3DIR,
+            MemberKind::Symlink(_) => ftype3::NF3LNK,
+        };
+        attr.mode = self.mode & 0o7777;
+        attr.nlink = if self.kind == MemberKind::Dir { 2 } else { 1 };
+        attr.uid = self.uid;
+        attr.gid = self.gid;
+        attr.size = match self.kind {
+            MemberKind::Symlink(ref target) => target.as_os_str().len() as u64,
+            _ => self.size,
+        };
+        attr.used = attr.size;
+        attr.mtime = nfstime3 {
+            seconds: self.mtime as u32,
+            nseconds: 0,
+        };
+        attr.ctime = attr.mtime;
+        attr
+    }
+}
+
+/// Read the member list of the uncompressed tar archive at `path`
+///
+/// Understands ustar, GNU long names and pax path records. Hard links,
+/// devices and other special members are skipped, as are members whose
+/// path would leave the archive. A later member replaces an earlier one
+/// with the same path, as when extracting.
+pub fn read_index(path: &Path) -> std::io::Result<Vec<Member>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut members = Vec::new();
+    let mut header = [0u8; BLOCK as usize];
+    let mut offset = 0;
+    let mut long_name = None;
+    let mut long_link = None;
+
+    while offset + BLOCK <= len {
+        file.read_exact_at(&mut header, offset)?;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if checksum(&header) != number(&header[148..156])? {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("bad tar header checksum at offset {}", offset),
+            ));
+        }
+        let size = number(&header[124..136])?;
+        let data = offset + BLOCK;
+        if data.checked_add(size).is_none_or(|end| end > len) {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("tar member at offset {} is truncated", offset),
+            ));
+        }
+        offset = data + size.div_ceil(BLOCK) * BLOCK;
+
+        let typeflag = header[156];
+        match typeflag {
+            // GNU long name or link target of the next member
+            b'L' | b'K' => {
+                let mut value = vec![0; size as usize];
+                file.read_exact_at(&mut value, data)?;
+                let value = field(&value).to_vec();
+                if typeflag == b'L' {
+                    long_name = Some(value);
+                } else {
+                    long_link = Some(value);
+                }
+                continue;
+            }
+            // pax extended header of the next member
+            b'x' => {
+                let mut records = vec![0; size as usize];
+                file.read_exact_at(&mut records, data)?;
+                for (key, value) in pax_records(&records) {
+                    match key {
+                        b"path" => long_name = Some(value.to_vec()),
+                        b"linkpath" => long_link = Some(value.to_vec()),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let name = long_name.take().unwrap_or_else(|| header_name(&header));
+        let link = long_link
+            .take()
+            .unwrap_or_else(|| field(&header[157..257]).to_vec());
+        let kind = match typeflag {
+            b'0' | 0 | b'7' if name.ends_with(b"/") => MemberKind::Dir,
+            b'0' | 0 | b'7' => MemberKind::File,
+            b'5' => MemberKind::Dir,
+            b'2' => MemberKind::Symlink(PathBuf::from(OsStr::from_bytes(&link))),
+            _ => {
+                debug!(
+                    "Skipping tar member {:?} of type {:?}",
+                    OsStr::from_bytes(&name),
+                    typeflag as char
+                );
+                continue;
+            }
+        };
+        let Some(path) = member_path(&name) else {
+            debug!("Skipping tar member {:?}", OsStr::from_bytes(&name));
+            continue;
+        };
+        members.push(Member {
+            path,
+            size: if kind == MemberKind::File { size } else { 0 },
+            kind,
+            offset: data,
+            mode: number(&header[100..108])? as u32,
+            uid: number(&header[108..116])? as u32,
+            gid: number(&header[116..124])? as u32,
+            mtime: number(&header[136..148])?,
+        });
+    }
+    Ok(members)
+}
+
+/// Read up to `count` bytes at `offset` from `member` of the archive at `archive`
+///
+/// Returns the data and whether EOF was reached.
+pub fn read_member(
+    archive: &Path,
+    member: &Member,
+    offset: u64,
+    count: u32,
+) -> std::io::Result<(Vec<u8>, bool)> {
+    let file = File::open(archive)?;
+    let start = offset.min(member.size);
+    let end = offset.saturating_add(count as u64).min(member.size);
+    let mut buf = vec![0; (end - start) as usize];
+    file.read_exact_at(&mut buf, member.offset + start)?;
+    Ok((buf, offset.saturating_add(count as u64) >= member.size))
+}
+
+/// Bytes of a NUL-terminated header field
+fn field(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// Member name from the header, joined with the ustar prefix if there is one
+fn header_name(header: &[u8; BLOCK as usize]) -> Vec<u8> {
+    let name = field(&header[0..100]);
+    let prefix = field(&header[345..500]);
+    if &header[257..262] != b"ustar" || prefix.is_empty() {
+        return name.to_vec();
+    }
+    [prefix, b"/", name].concat()
+}
+
+/// A numeric header field, octal or GNU base-256 for large values
+fn number(bytes: &[u8]) -> std::io::Result<u64> {
+    if bytes[0] & 0x80 != 0 {
+        let value = bytes[1..]
+            .iter()
+            .fold(u64::from(bytes[0] & 0x7f), |n, &b| (n << 8) | u64::from(b));
+        return Ok(value);
+    }
+    let digits = std::str::from_utf8(field(bytes))
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "non-ASCII tar header number"))?
+        .trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid tar header number"))
+}
+
+/// Header checksum, counting the checksum field itself as spaces
+fn checksum(header: &[u8; BLOCK as usize]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+        .map(u64::from)
+        .sum()
+}
+
+/// Key-value records of a pax extended header, each `<len> <key>=<value>\n`
+fn pax_records(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut records = Vec::new();
+    while let Some(space) = data.iter().position(|&b| b == b' ') {
+        let len = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok());
+        let Some(len) = len.filter(|&len| len > space && len <= data.len()) else {
+            break;
+        };
+        let record = &data[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(eq) = record.iter().position(|&b| b == b'=') {
+            records.push((&record[..eq], &record[eq + 1..]));
+        }
+        data = &data[len..];
+    }
+    records
+}
+
+/// Relative path of a member, or None if it would leave the archive
+fn member_path(name: &[u8]) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(OsStr::from_bytes(name)).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Build an uncompressed tar of `(name, typeflag, data)` members; a symlink's data is its target
+#[cfg(test)]
+pub fn build_tar(members: &[(&str, u8, &[u8])]) -> Vec<u8> {
+    fn header(name: &[u8], typeflag: u8, size: usize, link: &[u8]) -> [u8; BLOCK as usize] {
+        let mut header = [0u8; BLOCK as usize];
+        header[..name.len()].copy_from_slice(name);
+        let mode: &[u8] = if typeflag == b'5' {
+            b"0000755\0"
+        } else {
+            b"0000644\0"
+        };
+        header[100..108].copy_from_slice(mode);
+        header[108..116].copy_from_slice(b"0001750\0");
+        header[116..124].copy_from_slice(b"0001750\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        header[136..148].copy_from_slice(b"14000000000\0");
+        header[156] = typeflag;
+        header[157..157 + link.len()].copy_from_slice(link);
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let sum = checksum(&header);
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        header
+    }
+
+    let mut tar = Vec::new();
+    for &(name, typeflag, data) in members {
+        let (name, link, data) = match typeflag {
+            b'2' => (name.as_bytes(), data, &[][..]),
+            _ => (name.as_bytes(), &[][..], data),
+        };
+        if name.len() > 100 {
+            tar.extend(header(b"././@LongLink", b'L', name.len() + 1, b""));
+            tar.extend(name);
+            tar.push(0);
+            tar.resize(tar.len().next_multiple_of(BLOCK as usize), 0);
+        }
+        tar.extend(header(
+            &name[..name.len().min(100)],
+            typeflag,
+            data.len(),
+            link,
+        ));
+        tar.extend(data);
+        tar.resize(tar.len().next_multiple_of(BLOCK as usize), 0);
+    }
+    tar.resize(tar.len() + 2 * BLOCK as usize, 0);
+    tar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_index() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_tar_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let long = format!("deep/{}", "n".repeat(120));
+        let tar = build_tar(&[
+            ("./docs/", b'5', b""),
+            ("docs/readme.txt", b'0', b"hello archive"),
+            ("docs/link", b'2', b"readme.txt"),
+            (&long, b'0', b"long"),
+            ("../escape", b'0', b"no"),
+            ("fifo", b'6', b""),
+        ]);
+        let path = dir.join("data.tar");
+        std::fs::write(&path, &tar).unwrap();
+
+        let members = read_index(&path).unwrap();
+        let paths: Vec<_> = members.iter().map(|m| m.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("docs"),
+                PathBuf::from("docs/readme.txt"),
+                PathBuf::from("docs/link"),
+                PathBuf::from(&long),
+            ]
+        );
+        assert_eq!(members[0].kind, MemberKind::Dir);
+        assert_eq!(members[0].mode, 0o755);
+        assert_eq!(members[1].size, 13);
+        assert_eq!((members[1].uid, members[1].mtime), (1000, 0o14000000000));
+        assert_eq!(
+            members[2].kind,
+            MemberKind::Symlink(PathBuf::from("readme.txt"))
+        );
+
+        assert_eq!(
+            read_member(&path, &members[1], 6, 100).unwrap(),
+            (b"archive".to_vec(), true)
+        );
+        assert_eq!(
+            read_member(&path, &members[3], 0, 2).unwrap(),
+            (b"lo".to_vec(), false)
+        );
+        assert_eq!(
+            read_member(&path, &members[1], u64::MAX - 1, 100).unwrap(),
+            (Vec::new(), true)
+        );
+
+        let mut corrupt = tar.clone();
+        corrupt[0] = b'X';
+        std::fs::write(&path, &corrupt).unwrap();
+        assert_eq!(
+            read_index(&path).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+
+        // A base-256 size that wraps the member's end around must not pass
+        // the truncation check
+        let mut huge = build_tar(&[("file", b'0', b"data")]);
+        huge[124..136].fill(0xff);
+        let header: &mut [u8; BLOCK as usize] = (&mut huge[..BLOCK as usize]).try_into().unwrap();
+        let sum = format!("{:06o}\0 ", checksum(header));
+        header[148..156].copy_from_slice(sum.as_bytes());
+        std::fs::write(&path, &huge).unwrap();
+        assert_eq!(
+            read_index(&path).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_pax_records() {
+        let records = pax_records(b"20 path=a/long/name\n11 uid=100\n");
+        assert_eq!(
+            records,
+            [
+                (&b"path"[..], &b"a/long/name"[..]),
+                (&b"uid"[..], &b"100"[..])
+            ]
+        );
+    }
+}
@@ -1,9 +1,12 @@
-use clap::Parser;
+use clap::{ArgAction, Parser};
+use serde::Serialize;
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::path::PathBuf;
-use tracing::info;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
 use crate::config::{Config, MountConfig, ServerConfig};
+use crate::fsmap::utc_date;
 
 /// NFS Mirror - Mirror local directories into an NFS shared service
 #[derive(Parser)]
@@ -50,6 +53,13 @@ pub struct Cli {
     )]
     pub port: u16,
 
+    /// MOUNT protocol port (defaults to the NFS port)
+    #[arg(
+        long = "mount-port",
+        help = "MOUNT protocol port advertised to clients (defaults to --port)"
+    )]
+    pub mount_port: Option<u16>,
+
     /// Log level (trace, debug, info, warn, error)
     #[arg(
         short = 'l',
@@ -60,9 +70,14 @@ pub struct Cli {
     )]
     pub log_level: String,
 
-    /// Enable verbose output
-    #[arg(short = 'v', long = "verbose", help = "Enable verbose output")]
-    pub verbose: bool,
+    /// Enable verbose output (repeat for more detail: -v is DEBUG, -vv is TRACE)
+    #[arg(
+        short = 'v',
+        long = "verbose",
+        action = ArgAction::Count,
+        help = "Enable verbose output (-v for debug, -vv for trace)"
+    )]
+    pub verbose: u8,
 
     /// Daemon mode (run in background)
     #[arg(short = 'd', long = "daemon", help = "Run in daemon mode")]
@@ -104,6 +119,20 @@ pub struct Cli {
     #[arg(long = "read-only", help = "Enable read-only mode")]
     pub read_only: bool,
 
+    /// Serve a read-only snapshot of the tree taken at startup
+    #[arg(
+        long = "snapshot",
+        help = "Serve a read-only snapshot of the directory listing and metadata taken at startup"
+    )]
+    pub snapshot: bool,
+
+    /// Exit when any task panics, for a supervisor to restart the server
+    #[arg(
+        long = "exit-on-panic",
+        help = "Log a panic in any request and exit so a supervisor can restart the server"
+    )]
+    pub exit_on_panic: bool,
+
     /// Comma-separated list of allowed client IP addresses
     #[arg(
         long = "allow-ips",
@@ -115,12 +144,33 @@ pub struct Cli {
     #[arg(long = "no-color", help = "Disable log colors")]
     pub no_color: bool,
 
+    /// Run an end-to-end self-test against a local mount and exit
+    #[arg(
+        long = "self-test",
+        help = "Start the server on an ephemeral port, mount it locally, run basic checks and exit"
+    )]
+    pub self_test: bool,
+
+    /// Check the consistency of the file map built from the configured mounts and exit
+    #[arg(
+        long = "fsck",
+        help = "List every configured mount, check the internal file map for inconsistencies and exit"
+    )]
+    pub fsck: bool,
+
     /// Generate a sample configuration file
     #[arg(
         long = "generate-config",
         help = "Generate a sample configuration file and exit"
     )]
     pub generate_config: Option<PathBuf>,
+
+    /// Validate and canonicalize a configuration file in place
+    #[arg(
+        long = "normalize-config",
+        help = "Validate a configuration file, rewrite it in canonical form and exit"
+    )]
+    pub normalize_config: Option<PathBuf>,
 }
 
 impl Cli {
@@ -136,19 +186,23 @@ impl Cli {
         }
     }
 
-    /// Get the effective log level based on verbose flag and log-level setting
+    /// Get the effective log level based on verbose count and log-level setting
+    ///
+    /// Each `-v` raises the level on top of `--log-level`: `-v` is at least DEBUG
+    /// and `-vv` is TRACE.
     pub fn get_log_level(&self) -> tracing::Level {
-        if self.verbose {
-            tracing::Level::DEBUG
-        } else {
-            match self.log_level.as_str() {
-                "trace" => tracing::Level::TRACE,
-                "debug" => tracing::Level::DEBUG,
-                "info" => tracing::Level::INFO,
-                "warn" => tracing::Level::WARN,
-                "error" => tracing::Level::ERROR,
-                _ => tracing::Level::ERROR,
-            }
+        let level = match self.log_level.as_str() {
+            "trace" => tracing::Level::TRACE,
+            "debug" => tracing::Level::DEBUG,
+            "info" => tracing::Level::INFO,
+            "warn" => tracing::Level::WARN,
+            "error" => tracing::Level::ERROR,
+            _ => tracing::Level::ERROR,
+        };
+        match self.verbose {
+            0 => level,
+            1 => level.max(tracing::Level::DEBUG),
+            _ => tracing::Level::TRACE,
         }
     }
 
@@ -170,14 +224,16 @@ impl Cli {
                 target: target.clone(),
                 read_only: self.read_only,
                 description: Some(format!("Mount from {} to {}", directory.display(), target)),
+                ..Default::default()
             };
 
             Ok(Config {
                 server: ServerConfig {
                     ip: self.ip,
                     port: self.port,
+                    mount_port: self.mount_port,
                     log_level: self.log_level.clone(),
-                    verbose: self.verbose,
+                    verbose: self.verbose > 0,
                     daemon: self.daemon,
                     pid_file: self.pid_file.clone(),
                     work_dir: self.work_dir.clone(),
@@ -187,6 +243,9 @@ impl Cli {
                     read_only: self.read_only,
                     allow_ips: self.allow_ips.clone(),
                     no_color: self.no_color,
+                    snapshot: self.snapshot,
+                    exit_on_panic: self.exit_on_panic,
+                    ..Default::default()
                 },
                 mounts: vec![mount],
             })
@@ -215,27 +274,38 @@ impl Cli {
             std::process::exit(0);
         }
 
+        if let Some(ref config_path) = self.normalize_config {
+            Self::normalize_config_file(config_path)?;
+            info!("Configuration file normalized: {}", config_path.display());
+            std::process::exit(0);
+        }
+
         // Load from config file if specified
         if let Some(ref config_path) = self.config {
-            let mut config = Config::from_file(config_path).map_err(|e| {
-                format!(
-                    "Failed to load configuration from '{}': {}",
-                    config_path.display(),
-                    e
-                )
-            })?;
+            let mut config = Config::from_file(config_path)
+                .map_err(|e| format!("Failed to load configuration: {}", e))?;
 
             // Override config file settings with CLI arguments
             self.override_config(&mut config);
 
             // Validate the configuration
+            config.expand_targets(&target_variables()?)?;
+            config.resolve_sources()?;
+            if config.server.skip_bad_mounts {
+                config.skip_bad_mounts()?;
+            }
             config.validate()?;
             return Ok(config);
         }
 
         // Check if we're in single directory mode
         if self.directory.is_some() {
-            let config = self.to_config()?;
+            let mut config = self.to_config()?;
+            config.expand_targets(&target_variables()?)?;
+            config.resolve_sources()?;
+            if config.server.skip_bad_mounts {
+                config.skip_bad_mounts()?;
+            }
             config.validate()?;
             return Ok(config);
         }
@@ -252,11 +322,14 @@ impl Cli {
         if self.port != 11451 {
             config.server.port = self.port;
         }
+        if self.mount_port.is_some() {
+            config.server.mount_port = self.mount_port;
+        }
         if self.log_level != "error" {
             config.server.log_level = self.log_level.clone();
         }
-        if self.verbose {
-            config.server.verbose = self.verbose;
+        if self.verbose > 0 {
+            config.server.verbose = true;
         }
         if self.daemon {
             config.server.daemon = self.daemon;
@@ -285,6 +358,29 @@ impl Cli {
         if self.no_color {
             config.server.no_color = self.no_color;
         }
+        if self.snapshot {
+            config.server.snapshot = self.snapshot;
+        }
+        if self.exit_on_panic {
+            config.server.exit_on_panic = self.exit_on_panic;
+        }
+    }
+
+    /// Rewrite a configuration file with resolved sources, normalized targets and every default filled in
+    ///
+    /// The file is left untouched if it does not validate.
+    fn normalize_config_file(config_path: &Path) -> Result<(), String> {
+        let mut config = Config::from_file(config_path)
+            .map_err(|e| format!("Failed to load configuration: {}", e))?;
+        config.normalize()?;
+        config.validate()?;
+        config.to_file(config_path).map_err(|e| {
+            format!(
+                "Failed to write configuration to '{}': {}",
+                config_path.display(),
+                e
+            )
+        })
     }
 
     /// Create a sample configuration
@@ -296,17 +392,63 @@ impl Cli {
                 target: "/bbbb".to_string(),
                 read_only: false,
                 description: Some("Example mount: maps /Users/aaaa to /bbbb".to_string()),
+                ..Default::default()
             },
             MountConfig {
                 source: PathBuf::from("/tmp/shared"),
                 target: "/shared".to_string(),
                 read_only: true,
                 description: Some("Read-only shared directory".to_string()),
+                ..Default::default()
             },
         ];
         config
     }
 
+    /// Resolved configuration as a single JSON object, for the startup event
+    pub fn startup_event_json(
+        config: &Config,
+        allowed_ips: &[IpAddr],
+    ) -> Result<String, serde_json::Error> {
+        #[derive(Serialize)]
+        struct StartupEvent<'a> {
+            version: &'static str,
+            server: &'a ServerConfig,
+            mounts: &'a [MountConfig],
+            allowed_ips: &'a [IpAddr],
+        }
+
+        serde_json::to_string(&StartupEvent {
+            version: env!("CARGO_PKG_VERSION"),
+            server: &config.server,
+            mounts: &config.mounts,
+            allowed_ips,
+        })
+    }
+
+    /// Emit the resolved configuration once, under the `startup` target
+    ///
+    /// Unlike the human-readable lines of `print_startup_info`, this event is
+    /// meant for tooling that records exactly what the process is running.
+    pub fn log_startup_event(config: &Config, allowed_ips: &[IpAddr]) {
+        match Self::startup_event_json(config, allowed_ips) {
+            Ok(json) => info!(target: "startup", config = %json, "Resolved configuration"),
+            Err(e) => warn!("Failed to serialize the startup configuration: {}", e),
+        }
+    }
+
+    /// Log the paths sources were resolved against, once the root is known
+    ///
+    /// Relative mount sources depend on the working directory, and inside a
+    /// chroot both paths are relative to the jail.
+    pub fn print_resolved_paths(root_dir: &Path) {
+        info!("Root directory: {}", root_dir.display());
+        match std::env::current_dir() {
+            Ok(cwd) => info!("Working directory: {}", cwd.display()),
+            Err(e) => warn!("Working directory: unknown ({})", e),
+        }
+    }
+
     /// Print startup information using log system
     pub fn print_startup_info(config: &Config, allowed_ips: &[IpAddr]) {
         info!("NFS Mirror service starting...");
@@ -314,6 +456,9 @@ impl Cli {
             "Listen address: {}:{}",
             config.server.ip, config.server.port
         );
+        if config.server.effective_mount_port() != config.server.port {
+            info!("Mount port: {}", config.server.effective_mount_port());
+        }
         info!("Log level: {}", config.server.log_level);
         info!("Max connections: {}", config.server.max_connections);
         info!("Read timeout: {} seconds", config.server.read_timeout);
@@ -331,6 +476,10 @@ impl Cli {
             info!("Daemon mode: Enabled");
         }
 
+        if config.server.snapshot {
+            info!("Snapshot mode: Enabled (serving the tree as of startup, read-only)");
+        }
+
         info!("Configured mount points:");
         for (i, mount) in config.mounts.iter().enumerate() {
             info!(
@@ -357,7 +506,7 @@ impl Cli {
             info!(
                 "mount -t nfs -o nolocks,vers=3,tcp,port={},mountport={},soft {}:{} /mnt{}",
                 config.server.port,
-                config.server.port,
+                config.server.effective_mount_port(),
                 config.server.ip,
                 mount.target,
                 mount.target
@@ -365,3 +514,108 @@ impl Cli {
         }
     }
 }
+
+/// Variables that mount targets may use: `hostname`, and `date` (UTC, `YYYY-MM-DD`)
+fn target_variables() -> Result<HashMap<&'static str, String>, String> {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is valid for its length; the name is NUL-terminated
+    // unless truncated, which the last byte of the buffer guards against
+    let rc = unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len() - 1) };
+    if rc != 0 {
+        return Err(format!(
+            "Failed to get the hostname: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    let hostname = String::from_utf8_lossy(&name[..len]).into_owned();
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| format!("System clock is before 1970: {}", e))?;
+    let date = utc_date(now.as_secs() as i64);
+    Ok(HashMap::from([("hostname", hostname), ("date", date)]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verbose_count_log_level() {
+        let level = |args: &[&str]| {
+            Cli::parse_from(std::iter::once("nfs_mirror").chain(args.iter().copied()))
+                .get_log_level()
+        };
+        assert_eq!(level(&[]), tracing::Level::ERROR);
+        assert_eq!(level(&["-v"]), tracing::Level::DEBUG);
+        assert_eq!(level(&["-vv"]), tracing::Level::TRACE);
+        assert_eq!(level(&["-l", "trace", "-v"]), tracing::Level::TRACE);
+        assert_eq!(level(&["-l", "info", "-v", "-v"]), tracing::Level::TRACE);
+    }
+
+    #[test]
+    fn test_startup_event_json() {
+        let cli = Cli::parse_from([
+            "nfs_mirror",
+            "/srv/share",
+            "--target",
+            "/share",
+            "--read-only",
+        ]);
+        let config = cli.to_config().unwrap();
+        let allowed_ips = ["192.168.1.10".parse().unwrap()];
+        let json = Cli::startup_event_json(&config, &allowed_ips).unwrap();
+
+        let event: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(event["server"]["read_only"], true);
+        assert_eq!(event["mounts"][0]["source"], "/srv/share");
+        assert_eq!(event["allowed_ips"][0], "192.168.1.10");
+    }
+
+    #[test]
+    fn test_normalize_config_file() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_normalize_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                "[server]\nwork_dir = {:?}\n\n[[mounts]]\nsource = \"data\"\ntarget = \"/data/\"\n",
+                dir
+            ),
+        )
+        .unwrap();
+
+        Cli::normalize_config_file(&path).unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.mounts[0].target, "/data");
+        assert_eq!(
+            config.mounts[0].source,
+            dir.join("data").canonicalize().unwrap()
+        );
+        // Defaults are written out explicitly
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.contains("max_connections"));
+
+        // An invalid file is not rewritten
+        let invalid = "[server]\nport = 0\n\n[[mounts]]\nsource = \"/\"\ntarget = \"/root/\"\n";
+        std::fs::write(&path, invalid).unwrap();
+        assert!(Cli::normalize_config_file(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), invalid);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_target_variables() {
+        let vars = target_variables().unwrap();
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        assert_eq!(vars["hostname"], hostname.trim());
+        let date = &vars["date"];
+        assert_eq!(date.len(), 10);
+        assert!(date.starts_with("20"));
+        assert_eq!(utc_date(0), "1970-01-01");
+        assert_eq!(utc_date(951_782_400), "2000-02-29");
+    }
+}
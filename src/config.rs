@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::IpAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tracing::warn;
 
 /// NFS Mirror configuration structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,6 +22,8 @@ pub struct ServerConfig {
     /// Listen port
     #[serde(default = "default_port")]
     pub port: u16,
+    /// MOUNT protocol port advertised to clients (defaults to the NFS port)
+    pub mount_port: Option<u16>,
     /// Log level (trace, debug, info, warn, error)
     #[serde(default = "default_log_level")]
     pub log_level: String,
@@ -50,10 +54,166 @@ pub struct ServerConfig {
     /// Disable log colors
     #[serde(default)]
     pub no_color: bool,
+    /// Stat timeout in milliseconds for the backing store (0 waits forever)
+    #[serde(default = "default_stat_timeout_ms")]
+    pub stat_timeout_ms: u64,
+    /// Owner uid reported to clients for every file
+    pub force_uid: Option<u32>,
+    /// Owner gid reported to clients for every file
+    pub force_gid: Option<u32>,
+    /// Serve a read-only snapshot of the tree taken at startup
+    #[serde(default)]
+    pub snapshot: bool,
+    /// Interval in seconds between metrics log lines (0 disables)
+    #[serde(default)]
+    pub metrics_interval_secs: u64,
+    /// Number of retries for transient backing store errors on reads and stats
+    #[serde(default)]
+    pub io_retries: u32,
+    /// Delay in milliseconds before the first retry, doubled for each further retry
+    #[serde(default = "default_io_retry_backoff_ms")]
+    pub io_retry_backoff_ms: u64,
+    /// Exit after this many seconds without any mounted client
+    pub exit_when_idle: Option<u64>,
+    /// Walk the mount sources in the background at startup to warm the file map
+    #[serde(default)]
+    pub preload: bool,
+    /// Maximum directory depth walked by `preload` (unlimited if not set)
+    pub preload_depth: Option<usize>,
+    /// Order of directory entries returned to clients
+    #[serde(default)]
+    pub readdir_order: ReaddirOrder,
+    /// Serve attributes refreshed within this many milliseconds without a new stat (0 disables)
+    #[serde(default)]
+    pub attr_cache_ms: u64,
+    /// Keep the backing path of each file once resolved instead of resolving it on every request
+    #[serde(default)]
+    pub cache_real_paths: bool,
+    /// Stat every entry returned by readdir instead of returning the attributes cached at listing
+    #[serde(default)]
+    pub readdir_refresh_attrs: bool,
+    /// Start every directory listing with `.` and `..` entries
+    #[serde(default)]
+    pub readdir_dot_entries: bool,
+    /// Directory to chroot into before serving; mount sources are paths inside it
+    pub chroot: Option<PathBuf>,
+    /// File the in-memory file map is dumped to as JSON on SIGUSR2 (disabled if not set)
+    pub fsmap_dump_path: Option<PathBuf>,
+    /// Return zeros for holes in sparse files without reading them
+    #[serde(default)]
+    pub sparse_reads: bool,
+    /// Open files for reading with `O_NOATIME`, so serving them leaves their access time alone
+    #[serde(default)]
+    pub no_atime: bool,
+    /// Make reads wait for writes to the same file in progress, so they never see part of one
+    #[serde(default)]
+    pub ordered_file_io: bool,
+    /// Whether clients see one combined root or independent per-mount exports
+    #[serde(default)]
+    pub export_root: ExportRoot,
+    /// Most entries listed from a single directory (unlimited if not set)
+    pub max_dir_entries: Option<usize>,
+    /// Most directory levels below a moved or removed directory whose cached entries are updated, or below a source scanned for its date view (unlimited if not set)
+    #[serde(default)]
+    pub max_path_depth: Option<usize>,
+    /// Handling of directory entries whose attributes cannot be read
+    #[serde(default)]
+    pub unreadable_entries: UnreadableEntries,
+    /// Kilobytes read beyond each client read and kept for sequential reads (0 disables)
+    #[serde(default)]
+    pub read_ahead_kb: u64,
+    /// Keep serving files deleted on disk if read or written within this many seconds (0 disables)
+    #[serde(default)]
+    pub keep_unlinked_open_secs: u64,
+    /// On REMOVE of a file in use, rename it to a hidden name and delete it once idle
+    #[serde(default)]
+    pub silly_rename: bool,
+    /// Most backing store reads and writes in flight at once (unlimited if not set)
+    pub max_concurrent_io: Option<usize>,
+    /// Number of files and directories the mounts are expected to hold, to pre-size the file map
+    pub expected_entries: Option<usize>,
+    /// JSON file keeping the fileid of each source path across restarts (disabled if not set)
+    pub fileid_store: Option<PathBuf>,
+    /// What to do when mounts share a source or one source lies inside another
+    #[serde(default)]
+    pub shared_sources: SharedSources,
+    /// Raise the soft open file limit to the hard limit at startup
+    #[serde(default)]
+    pub raise_fd_limit: bool,
+    /// Serve the usable mounts when others fail validation, instead of refusing to start
+    #[serde(default)]
+    pub skip_bad_mounts: bool,
+    /// Exit the process when any task panics, so a supervisor restarts it with a fresh state
+    #[serde(default)]
+    pub exit_on_panic: bool,
+    /// Refuse relative mount sources instead of resolving them against `work_dir` or the current directory
+    #[serde(default)]
+    pub require_absolute_sources: bool,
+    /// Fsync the parent directory after creating, removing, renaming or linking a file
+    #[serde(default)]
+    pub dir_sync: bool,
+    /// Seal files created through the server once unwritten for this many seconds (0 disables)
+    #[serde(default)]
+    pub write_once_secs: u64,
+    /// Interval in seconds between checks that drop file names no longer in use from memory (0 disables)
+    #[serde(default)]
+    pub intern_compact_secs: u64,
+    /// Hash files in the background at up to this many MB per second to report duplicated content (0 disables)
+    #[serde(default)]
+    pub dedup_scan_mb_per_sec: u64,
+    /// File the dedup scan keeps its hashes in, so a restart does not read every file again
+    #[serde(default)]
+    pub dedup_state_path: Option<PathBuf>,
+    /// Block size advertised in FSINFO as the preferred multiple of reads and writes (built-in sizes if not set)
+    #[serde(default)]
+    pub block_size: Option<u32>,
+    /// Answer every mutating request with a retry-later error for this many seconds after startup (0 disables)
+    #[serde(default)]
+    pub grace_period_secs: u64,
+    /// Replay repeats of non-idempotent requests seen within this many seconds (0 disables)
+    #[serde(default)]
+    pub duplicate_request_secs: u64,
+}
+
+/// Handling of mounts whose sources overlap
+///
+/// Every mount caches its files under its own paths, so a change made
+/// through one mount is only noticed by another one serving the same files
+/// when that one next stats them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedSources {
+    /// Log a warning and serve the mounts
+    #[default]
+    Warn,
+    /// Refuse the configuration
+    Reject,
+}
+
+/// How the mounts are presented at the export root
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportRoot {
+    /// One root listing every mount as a directory named by its target
+    #[default]
+    Combined,
+    /// Each mount is only reachable by mounting its target; the root lists nothing
+    PerMount,
+}
+
+/// Order of directory entries returned by readdir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReaddirOrder {
+    /// Order in which entries were first seen (cheapest)
+    #[default]
+    Fileid,
+    /// Sorted by file name bytes
+    Name,
 }
 
 /// Mount point configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct MountConfig {
     /// Local directory path to mirror
     pub source: PathBuf,
@@ -64,6 +224,182 @@ pub struct MountConfig {
     pub read_only: bool,
     /// Description for this mount point
     pub description: Option<String>,
+    /// Match client file names against the source in this Unicode normalization form
+    pub unicode_normalize: Option<UnicodeForm>,
+    /// Fall back to a case-insensitive match when a name is not found exactly
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// How file names that are not valid UTF-8 are presented to clients
+    #[serde(default)]
+    pub non_utf8_names: NonUtf8Names,
+    /// List characters Windows does not allow in names as private-use code points, and find them by that name
+    #[serde(default)]
+    pub windows_names: bool,
+    /// Let clients find long names by a stable 8.3 short alias, e.g. `LONGF~PD.MAR`
+    #[serde(default)]
+    pub short_names: bool,
+    /// How absolute symlink targets sent by clients are stored
+    #[serde(default)]
+    pub symlink_targets: SymlinkTargets,
+    /// AUTH_SYS uids allowed to modify this mount (with `write_gids`; both empty allows everyone)
+    #[serde(default)]
+    pub write_uids: Vec<u32>,
+    /// AUTH_SYS gids whose members may modify this mount
+    #[serde(default)]
+    pub write_gids: Vec<u32>,
+    /// Permission bits reported for the mount root instead of the source's
+    pub display_mode: Option<u32>,
+    /// Owner reported for the mount root instead of the source's
+    pub display_uid: Option<u32>,
+    /// Group reported for the mount root instead of the source's
+    pub display_gid: Option<u32>,
+    /// Ranges of client uids stored as other uids in the source, as `container:host:count`
+    #[serde(default)]
+    pub uid_map: Vec<IdMap>,
+    /// Ranges of client gids stored as other gids in the source, as `container:host:count`
+    #[serde(default)]
+    pub gid_map: Vec<IdMap>,
+    /// Report the display_* overrides for every file in the mount, not just its root
+    #[serde(default)]
+    pub display_recursive: bool,
+    /// Name of a read-only virtual directory at the mount root grouping its files by modification date
+    pub date_view: Option<String>,
+    /// Name of a read-only virtual file at the mount root reporting the mount's statistics as JSON
+    pub status_file: Option<String>,
+    /// Serve reads from memory mappings of the files instead of read calls
+    #[serde(default)]
+    pub use_mmap: bool,
+    /// Serve the members of the uncompressed tar archive at `source` as a read-only tree
+    #[serde(default)]
+    pub archive: bool,
+    /// Only let clients create files and append to them, never overwrite, truncate, remove or rename
+    #[serde(default)]
+    pub append_only: bool,
+    /// Largest size clients may grow a file in this mount to, in bytes (unlimited if None)
+    pub max_file_size: Option<u64>,
+}
+
+/// Handling of absolute targets in client-created symlinks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkTargets {
+    /// Store targets exactly as sent by the client
+    #[default]
+    Verbatim,
+    /// Rewrite absolute targets inside the mount source relative to the link
+    Relative,
+    /// Refuse to create symlinks with absolute targets
+    RejectAbsolute,
+}
+
+/// Ids seen by clients as `container..container + count`, stored in the source as `host..host + count`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IdMap {
+    pub container: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+/// Id reported for source owners no range of an id map covers (`nobody`)
+pub const OVERFLOW_ID: u32 = 65534;
+
+impl IdMap {
+    /// Id clients see for the source id `id`, `OVERFLOW_ID` if no range of `maps` covers it
+    ///
+    /// An empty map leaves every id as it is.
+    pub fn to_client(maps: &[IdMap], id: u32) -> u32 {
+        if maps.is_empty() {
+            return id;
+        }
+        maps.iter()
+            .find(|m| id.wrapping_sub(m.host) < m.count)
+            .map_or(OVERFLOW_ID, |m| m.container + (id - m.host))
+    }
+
+    /// Source id for the client id `id`, if a range of `maps` covers it
+    pub fn to_host(maps: &[IdMap], id: u32) -> Option<u32> {
+        if maps.is_empty() {
+            return Some(id);
+        }
+        maps.iter()
+            .find(|m| id.wrapping_sub(m.container) < m.count)
+            .map(|m| m.host + (id - m.container))
+    }
+
+    fn overlaps(&self, other: &IdMap) -> bool {
+        let within = |a: u32, b: u32, count: u32| a.wrapping_sub(b) < count;
+        within(self.container, other.container, other.count)
+            || within(other.container, self.container, self.count)
+            || within(self.host, other.host, other.count)
+            || within(other.host, self.host, self.count)
+    }
+}
+
+impl TryFrom<String> for IdMap {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, String> {
+        let ids: Vec<u32> = spec
+            .split(':')
+            .map(|id| id.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("id map '{}' is not container:host:count", spec))?;
+        let [container, host, count] = ids[..] else {
+            return Err(format!("id map '{}' is not container:host:count", spec));
+        };
+        if count == 0 {
+            return Err(format!("id map '{}' has an empty range", spec));
+        }
+        if container.checked_add(count - 1).is_none() || host.checked_add(count - 1).is_none() {
+            return Err(format!("id map '{}' runs past the largest id", spec));
+        }
+        Ok(Self {
+            container,
+            host,
+            count,
+        })
+    }
+}
+
+impl From<IdMap> for String {
+    fn from(map: IdMap) -> String {
+        format!("{}:{}:{}", map.container, map.host, map.count)
+    }
+}
+
+/// Presentation of file names that are not valid UTF-8
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NonUtf8Names {
+    /// List them with their raw bytes
+    #[default]
+    Raw,
+    /// Leave them out of listings
+    Hide,
+    /// List them decoded as Latin-1, and find them by that name
+    Latin1,
+}
+
+/// Handling of directory entries that can be listed but not stat'ed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnreadableEntries {
+    /// Leave them out of listings
+    #[default]
+    Skip,
+    /// List them with their type and no permissions, attributes taken from their directory
+    Placeholder,
+}
+
+/// Unicode normalization form used to compare file names
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnicodeForm {
+    /// Canonical composition (e.g. stored by Linux and Windows tools)
+    Nfc,
+    /// Canonical decomposition (e.g. sent by macOS clients)
+    Nfd,
 }
 
 impl Default for ServerConfig {
@@ -71,6 +407,7 @@ impl Default for ServerConfig {
         Self {
             ip: default_ip(),
             port: default_port(),
+            mount_port: None,
             log_level: default_log_level(),
             verbose: false,
             daemon: false,
@@ -82,6 +419,49 @@ impl Default for ServerConfig {
             read_only: false,
             allow_ips: None,
             no_color: false,
+            stat_timeout_ms: default_stat_timeout_ms(),
+            force_uid: None,
+            force_gid: None,
+            snapshot: false,
+            metrics_interval_secs: 0,
+            io_retries: 0,
+            io_retry_backoff_ms: default_io_retry_backoff_ms(),
+            exit_when_idle: None,
+            preload: false,
+            preload_depth: None,
+            readdir_order: ReaddirOrder::default(),
+            attr_cache_ms: 0,
+            cache_real_paths: false,
+            readdir_refresh_attrs: false,
+            readdir_dot_entries: false,
+            chroot: None,
+            fsmap_dump_path: None,
+            sparse_reads: false,
+            no_atime: false,
+            ordered_file_io: false,
+            export_root: ExportRoot::default(),
+            max_dir_entries: None,
+            max_path_depth: None,
+            unreadable_entries: UnreadableEntries::default(),
+            read_ahead_kb: 0,
+            keep_unlinked_open_secs: 0,
+            silly_rename: false,
+            max_concurrent_io: None,
+            expected_entries: None,
+            fileid_store: None,
+            shared_sources: SharedSources::default(),
+            raise_fd_limit: false,
+            skip_bad_mounts: false,
+            exit_on_panic: false,
+            require_absolute_sources: false,
+            dir_sync: false,
+            write_once_secs: 0,
+            intern_compact_secs: 0,
+            dedup_scan_mb_per_sec: 0,
+            dedup_state_path: None,
+            block_size: None,
+            grace_period_secs: 0,
+            duplicate_request_secs: 0,
         }
     }
 }
@@ -111,14 +491,140 @@ fn default_write_timeout() -> u64 {
     30
 }
 
+fn default_stat_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_io_retry_backoff_ms() -> u64 {
+    10
+}
+
+/// Replace every `${name}` in `text` with its value in `vars`
+fn expand_variables(text: &str, vars: &HashMap<&str, String>) -> Result<String, String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated variable '{}'", &rest[start..]))?;
+        let name = &after[..end];
+        let value = vars
+            .get(name)
+            .ok_or_else(|| format!("undefined variable '${{{}}}'", name))?;
+        expanded.push_str(value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Describe a TOML error as `path:line: message`, naming the key whose value is at fault
+fn describe_toml_error(path: &Path, content: &str, error: &toml::de::Error) -> String {
+    let Some(offset) = error.span().map(|span| span.start.min(content.len())) else {
+        return format!("{}: {}", path.display(), error.message());
+    };
+    let line = content[..offset].matches('\n').count() + 1;
+    match key_of_value_at(content, offset) {
+        Some(key) => format!(
+            "{}:{}: invalid value for {}: {}",
+            path.display(),
+            line,
+            key,
+            error.message()
+        ),
+        None => format!("{}:{}: {}", path.display(), line, error.message()),
+    }
+}
+
+/// Dotted name of the key whose value `offset` falls in, e.g. `server.port`
+///
+/// Only simple `key = value` lines are recognized, under the last table
+/// header before them.
+fn key_of_value_at(content: &str, offset: usize) -> Option<String> {
+    let line_start = content[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = content[line_start..].lines().next()?;
+    let equals = line.find('=')?;
+    if offset <= line_start + equals {
+        return None;
+    }
+    let key = line[..equals].trim();
+    let table = content[..line_start]
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| line.starts_with('['))
+        .map(|header| header.trim_matches(|c| c == '[' || c == ']').trim());
+    Some(match table {
+        Some(table) => format!("{}.{}", table, key),
+        None => key.to_string(),
+    })
+}
+
+impl MountConfig {
+    /// Create a writable mount of `source` at `target` with default options
+    pub fn new(source: impl Into<PathBuf>, target: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            target: target.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Check whether AUTH_SYS credentials may modify files in this mount
+    pub fn allows_write(&self, uid: u32, gid: u32, gids: &[u32]) -> bool {
+        if self.write_uids.is_empty() && self.write_gids.is_empty() {
+            return true;
+        }
+        self.write_uids.contains(&uid)
+            || std::iter::once(&gid)
+                .chain(gids)
+                .any(|g| self.write_gids.contains(g))
+    }
+
+    /// Target path without the leading '/', as it appears in the root listing
+    pub fn target_name(&self) -> &str {
+        self.target.trim_start_matches('/')
+    }
+}
+
+impl ServerConfig {
+    /// MOUNT protocol port, falling back to the NFS port when not set
+    pub fn effective_mount_port(&self) -> u16 {
+        self.mount_port.unwrap_or(self.port)
+    }
+
+    /// Host path of `path`, which is interpreted inside `chroot` when one is set
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        match self.chroot {
+            Some(ref jail) => jail.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+impl Default for Config {
+    /// Create a default configuration file
+    fn default() -> Self {
+        Self {
+            server: ServerConfig::default(),
+            mounts: vec![],
+        }
+    }
+}
+
 #[allow(unused)]
 impl Config {
     /// Load configuration from a TOML file
     pub fn from_file<P: AsRef<std::path::Path>>(
         path: P,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let content = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
+        let path = path.as_ref();
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let config: Config =
+            toml::from_str(&content).map_err(|e| describe_toml_error(path, &content, &e))?;
         Ok(config)
     }
 
@@ -132,48 +638,232 @@ impl Config {
         Ok(())
     }
 
-    /// Create a default configuration file
-    pub fn default() -> Self {
-        Self {
-            server: ServerConfig::default(),
-            mounts: vec![],
+    /// Make every mount source absolute and canonical
+    ///
+    /// Relative sources are resolved against `work_dir` if set, otherwise
+    /// against the current directory, so they do not depend on where the
+    /// process was started. Under a `chroot`, sources are paths inside it and
+    /// relative ones are taken from its root. Sources that do not exist, and
+    /// relative ones with `require_absolute_sources`, are left for `validate`
+    /// to report.
+    pub fn resolve_sources(&mut self) -> Result<(), String> {
+        let require_absolute = self.server.require_absolute_sources;
+        let mounts = self
+            .mounts
+            .iter_mut()
+            .filter(|mount| !require_absolute || mount.source.is_absolute());
+
+        if self.server.chroot.is_some() {
+            for mount in mounts {
+                mount.source = Path::new("/").join(&mount.source);
+            }
+            return Ok(());
         }
+
+        let base = match self.server.work_dir {
+            Some(ref work_dir) => work_dir.clone(),
+            None => std::env::current_dir()
+                .map_err(|e| format!("Failed to get the current directory: {}", e))?,
+        };
+        for mount in mounts {
+            let source = base.join(&mount.source);
+            mount.source = source.canonicalize().unwrap_or(source);
+        }
+        Ok(())
     }
 
-    /// Validate the configuration
-    pub fn validate(&self) -> Result<(), String> {
-        // Validate mounts
-        if self.mounts.is_empty() {
-            return Err("At least one mount point must be configured".to_string());
+    /// Expand `${name}` variables in mount targets from `vars`
+    ///
+    /// Lets one template configuration adapt to each machine, e.g. a target
+    /// of `/data/${hostname}`. A variable that is not in `vars` is an error.
+    pub fn expand_targets(&mut self, vars: &HashMap<&str, String>) -> Result<(), String> {
+        for (i, mount) in self.mounts.iter_mut().enumerate() {
+            mount.target = expand_variables(&mount.target, vars)
+                .map_err(|e| format!("Mount point {}: target '{}': {}", i, mount.target, e))?;
         }
+        Ok(())
+    }
 
-        for (i, mount) in self.mounts.iter().enumerate() {
-            if !mount.source.exists() {
+    /// Bring a hand-edited configuration into canonical form
+    ///
+    /// Resolves sources like `resolve_sources` and drops trailing slashes
+    /// from targets, so equivalent configurations serialize identically.
+    pub fn normalize(&mut self) -> Result<(), String> {
+        self.resolve_sources()?;
+        for mount in &mut self.mounts {
+            let trimmed = mount.target.trim_end_matches('/');
+            if !trimmed.is_empty() {
+                mount.target = trimmed.to_string();
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the source and target of the mount at index `i`
+    fn validate_mount(&self, i: usize, mount: &MountConfig) -> Result<(), String> {
+        if self.server.require_absolute_sources && mount.source.is_relative() {
+            return Err(format!(
+                "Mount point {}: source '{}' is relative; give an absolute path, or unset require_absolute_sources to resolve it against work_dir or the current directory",
+                i,
+                mount.source.display()
+            ));
+        }
+
+        let source = self.server.host_path(&mount.source);
+        if !source.exists() {
+            return Err(format!(
+                "Mount point {}: source '{}' does not exist",
+                i,
+                mount.source.display()
+            ));
+        }
+
+        if !source.is_dir() && !source.is_file() {
+            return Err(format!(
+                "Mount point {}: source '{}' is neither a directory nor a regular file",
+                i,
+                mount.source.display()
+            ));
+        }
+
+        // Existing is not enough: the server must be able to list or read it
+        let readable = if source.is_dir() {
+            std::fs::read_dir(&source).map(drop)
+        } else {
+            std::fs::File::open(&source).map(drop)
+        };
+        if let Err(e) = readable {
+            return Err(format!(
+                "Mount point {}: source '{}' is not readable: {}",
+                i,
+                mount.source.display(),
+                e
+            ));
+        }
+
+        if mount.archive && !source.is_file() {
+            return Err(format!(
+                "Mount point {}: archive source '{}' is not a file",
+                i,
+                mount.source.display()
+            ));
+        }
+
+        if mount.target.is_empty() {
+            return Err(format!("Mount point {}: target path cannot be empty", i));
+        }
+
+        // Truncating a mapped file under a read raises SIGBUS, so only mounts
+        // this server cannot change the files of may map them
+        if mount.use_mmap && !(mount.read_only || self.server.read_only) {
+            return Err(format!(
+                "Mount point {}: use_mmap requires a read-only mount",
+                i
+            ));
+        }
+
+        for (name, maps) in [("uid_map", &mount.uid_map), ("gid_map", &mount.gid_map)] {
+            for (j, map) in maps.iter().enumerate() {
+                if let Some(other) = maps[..j].iter().find(|other| map.overlaps(other)) {
+                    return Err(format!(
+                        "Mount point {}: {} ranges '{}' and '{}' overlap",
+                        i,
+                        name,
+                        String::from(*other),
+                        String::from(*map)
+                    ));
+                }
+            }
+        }
+
+        if let Some(ref view) = mount.date_view {
+            if view.is_empty() || view == "." || view == ".." || view.contains('/') {
                 return Err(format!(
-                    "Mount point {}: source directory '{}' does not exist",
-                    i,
-                    mount.source.display()
+                    "Mount point {}: invalid date_view name '{}'",
+                    i, view
                 ));
             }
+        }
 
-            if !mount.source.is_dir() {
+        if let Some(ref status) = mount.status_file {
+            if status.is_empty() || status == "." || status == ".." || status.contains('/') {
                 return Err(format!(
-                    "Mount point {}: source '{}' is not a directory",
-                    i,
-                    mount.source.display()
+                    "Mount point {}: invalid status_file name '{}'",
+                    i, status
                 ));
             }
+            if mount.date_view.as_ref() == Some(status) {
+                return Err(format!(
+                    "Mount point {}: status_file and date_view are both named '{}'",
+                    i, status
+                ));
+            }
+        }
+
+        if mount.max_file_size == Some(0) {
+            return Err(format!("Mount point {}: max_file_size must be positive", i));
+        }
+
+        // Target path should start with /
+        if !mount.target.starts_with('/') {
+            return Err(format!(
+                "Mount point {}: target path '{}' must start with '/'",
+                i, mount.target
+            ));
+        }
+
+        Ok(())
+    }
 
-            if mount.target.is_empty() {
-                return Err(format!("Mount point {}: target path cannot be empty", i));
+    /// Drop mounts that fail validation, logging why, so the others can still be served
+    ///
+    /// Fails if no mount is left.
+    pub fn skip_bad_mounts(&mut self) -> Result<(), String> {
+        let mounts = std::mem::take(&mut self.mounts);
+        let configured = mounts.len();
+        for (i, mount) in mounts.into_iter().enumerate() {
+            match self.validate_mount(i, &mount) {
+                Ok(()) => self.mounts.push(mount),
+                Err(e) => warn!("Skipping mount {}: {}", mount.target, e),
             }
+        }
+        if self.mounts.is_empty() && configured > 0 {
+            return Err(format!(
+                "None of the {} configured mount points is usable",
+                configured
+            ));
+        }
+        Ok(())
+    }
 
-            // Target path should start with /
-            if !mount.target.starts_with('/') {
-                return Err(format!(
-                    "Mount point {}: target path '{}' must start with '/'",
-                    i, mount.target
-                ));
+    /// Validate the configuration
+    pub fn validate(&self) -> Result<(), String> {
+        // Validate mounts
+        if self.mounts.is_empty() {
+            return Err("At least one mount point must be configured".to_string());
+        }
+
+        if let Some(ref jail) = self.server.chroot {
+            if !jail.is_dir() {
+                return Err(format!("chroot '{}' is not a directory", jail.display()));
+            }
+        }
+
+        for (i, mount) in self.mounts.iter().enumerate() {
+            self.validate_mount(i, mount)?;
+        }
+
+        for (i, j) in self.overlapping_sources() {
+            let message = format!(
+                "Mount points {} and {}: sources '{}' and '{}' overlap; changes through one are only seen by the other once its cache expires",
+                i,
+                j,
+                self.mounts[i].source.display(),
+                self.mounts[j].source.display()
+            );
+            match self.server.shared_sources {
+                SharedSources::Warn => warn!("{}", message),
+                SharedSources::Reject => return Err(message),
             }
         }
 
@@ -193,9 +883,62 @@ impl Config {
             return Err("Server port cannot be 0".to_string());
         }
 
+        if self.server.exit_when_idle == Some(0) {
+            return Err("exit_when_idle must be at least 1 second".to_string());
+        }
+
+        if let Some(size) = self.server.block_size {
+            if !size.is_power_of_two() || !(512..=1024 * 1024).contains(&size) {
+                return Err(format!(
+                    "block_size {} must be a power of two between 512 and 1048576",
+                    size
+                ));
+            }
+        }
+
+        if self.server.silly_rename && self.server.keep_unlinked_open_secs == 0 {
+            return Err("silly_rename requires keep_unlinked_open_secs to be set".to_string());
+        }
+
+        if self.server.max_concurrent_io == Some(0) {
+            return Err("max_concurrent_io must be at least 1".to_string());
+        }
+
+        if self.server.max_dir_entries == Some(0) {
+            return Err("max_dir_entries must be at least 1".to_string());
+        }
+        if self.server.max_path_depth == Some(0) {
+            return Err("max_path_depth must be at least 1".to_string());
+        }
+
+        if self.server.mount_port == Some(0) {
+            return Err("Mount port cannot be 0".to_string());
+        }
+
         Ok(())
     }
 
+    /// Pairs of mounts whose sources are the same or nested inside each other
+    pub fn overlapping_sources(&self) -> Vec<(usize, usize)> {
+        let sources: Vec<PathBuf> = self
+            .mounts
+            .iter()
+            .map(|mount| {
+                let source = self.server.host_path(&mount.source);
+                source.canonicalize().unwrap_or(source)
+            })
+            .collect();
+        let mut overlaps = Vec::new();
+        for (i, a) in sources.iter().enumerate() {
+            for (j, b) in sources.iter().enumerate().skip(i + 1) {
+                if a.starts_with(b) || b.starts_with(a) {
+                    overlaps.push((i, j));
+                }
+            }
+        }
+        overlaps
+    }
+
     /// Get mount by target path
     pub fn get_mount_by_target(&self, target: &str) -> Option<&MountConfig> {
         self.mounts.iter().find(|m| m.target == target)
@@ -229,6 +972,7 @@ mod tests {
                 target: "/test".to_string(),
                 read_only: false,
                 description: Some("Test mount".to_string()),
+                ..Default::default()
             }],
         };
 
@@ -237,4 +981,334 @@ mod tests {
         assert_eq!(config.server.port, parsed.server.port);
         assert_eq!(config.mounts.len(), parsed.mounts.len());
     }
+
+    #[test]
+    fn test_mount_port_defaults_to_port() {
+        let mut server = ServerConfig {
+            port: 2049,
+            ..Default::default()
+        };
+        assert_eq!(server.effective_mount_port(), 2049);
+        server.mount_port = Some(20048);
+        assert_eq!(server.effective_mount_port(), 20048);
+    }
+
+    #[test]
+    fn test_mount_write_credentials() {
+        let open = MountConfig::new("/tmp/test", "/test");
+        assert!(open.allows_write(1000, 1000, &[]));
+
+        let restricted = MountConfig {
+            write_uids: vec![1000],
+            write_gids: vec![50],
+            ..open
+        };
+        assert!(restricted.allows_write(1000, 100, &[]));
+        assert!(restricted.allows_write(1001, 50, &[]));
+        assert!(restricted.allows_write(1001, 100, &[20, 50]));
+        assert!(!restricted.allows_write(1001, 100, &[20]));
+        assert!(!restricted.allows_write(0, 0, &[]));
+    }
+
+    #[test]
+    fn test_file_mount_source_is_valid() {
+        let dir =
+            std::env::temp_dir().join(format!("nfs_mirror_file_mount_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("disk.img");
+        std::fs::write(&image, b"").unwrap();
+
+        let config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![MountConfig::new(&image, "/disk.img")],
+        };
+        assert!(config.validate().is_ok());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sources_resolve_inside_chroot() {
+        let jail = std::env::temp_dir().join(format!("nfs_mirror_chroot_{}", std::process::id()));
+        std::fs::create_dir_all(jail.join("srv/share")).unwrap();
+
+        let mut config = Config {
+            server: ServerConfig {
+                chroot: Some(jail.clone()),
+                ..ServerConfig::default()
+            },
+            mounts: vec![MountConfig::new("/srv/share", "/share")],
+        };
+        assert_eq!(
+            config.server.host_path(Path::new("/srv/share")),
+            jail.join("srv/share")
+        );
+        assert!(config.validate().is_ok());
+
+        config.mounts[0].source = PathBuf::from("/srv/missing");
+        assert!(config.validate().is_err());
+
+        let _ = std::fs::remove_dir_all(&jail);
+    }
+
+    #[test]
+    fn test_unreadable_source_is_rejected() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Root can read anything, so there is nothing to test
+        // SAFETY: geteuid has no preconditions
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let dir =
+            std::env::temp_dir().join(format!("nfs_mirror_unreadable_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![MountConfig::new(&dir, "/test")],
+        };
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("is not readable"), "{}", err);
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(config.validate().is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_relative_sources_resolve_against_work_dir() {
+        let work_dir =
+            std::env::temp_dir().join(format!("nfs_mirror_work_dir_{}", std::process::id()));
+        std::fs::create_dir_all(work_dir.join("data")).unwrap();
+
+        let mut config = Config {
+            server: ServerConfig {
+                work_dir: Some(work_dir.clone()),
+                ..ServerConfig::default()
+            },
+            mounts: vec![
+                MountConfig::new("data", "/data"),
+                MountConfig::new("./data/../data", "/again"),
+                MountConfig::new("missing", "/missing"),
+            ],
+        };
+        config.resolve_sources().unwrap();
+
+        let data = work_dir.join("data").canonicalize().unwrap();
+        assert_eq!(config.mounts[0].source, data);
+        assert_eq!(config.mounts[1].source, data);
+        assert_eq!(config.mounts[2].source, work_dir.join("missing"));
+
+        let _ = std::fs::remove_dir_all(&work_dir);
+    }
+
+    #[test]
+    fn test_require_absolute_sources() {
+        let work_dir =
+            std::env::temp_dir().join(format!("nfs_mirror_absolute_{}", std::process::id()));
+        std::fs::create_dir_all(work_dir.join("data")).unwrap();
+
+        let mut config = Config {
+            server: ServerConfig {
+                work_dir: Some(work_dir.clone()),
+                require_absolute_sources: true,
+                ..ServerConfig::default()
+            },
+            mounts: vec![MountConfig::new("data", "/data")],
+        };
+        config.resolve_sources().unwrap();
+        assert_eq!(config.mounts[0].source, Path::new("data"));
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("is relative"), "{}", err);
+
+        config.mounts[0].source = work_dir.join("data");
+        config.resolve_sources().unwrap();
+        assert!(config.validate().is_ok());
+
+        let _ = std::fs::remove_dir_all(&work_dir);
+    }
+
+    #[test]
+    fn test_shared_sources_detected() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_shared_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let other = std::env::temp_dir().join(format!("nfs_mirror_other_{}", std::process::id()));
+        std::fs::create_dir_all(&other).unwrap();
+
+        let mut config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![
+                MountConfig::new(&dir, "/rw"),
+                MountConfig {
+                    read_only: true,
+                    ..MountConfig::new(&dir, "/ro")
+                },
+                MountConfig::new(dir.join("sub"), "/sub"),
+                MountConfig::new(&other, "/other"),
+            ],
+        };
+        assert_eq!(config.overlapping_sources(), vec![(0, 1), (0, 2), (1, 2)]);
+        // Only a warning by default
+        assert!(config.validate().is_ok());
+
+        config.server.shared_sources = SharedSources::Reject;
+        let err = config.validate().unwrap_err();
+        assert!(err.contains("overlap"), "{}", err);
+
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&other);
+    }
+
+    #[test]
+    fn test_skip_bad_mounts_keeps_usable_ones() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_skip_bad_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![
+                MountConfig::new(dir.join("missing"), "/missing"),
+                MountConfig::new(&dir, "/good"),
+            ],
+        };
+        assert!(config.validate().is_err());
+        config.skip_bad_mounts().unwrap();
+        assert_eq!(config.mounts.len(), 1);
+        assert_eq!(config.mounts[0].target, "/good");
+        assert!(config.validate().is_ok());
+
+        config.mounts = vec![MountConfig::new(dir.join("missing"), "/missing")];
+        assert!(config.skip_bad_mounts().is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_expand_target_variables() {
+        let vars = HashMap::from([
+            ("hostname", "web1".to_string()),
+            ("date", "2026-10-16".to_string()),
+        ]);
+        let mut config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![
+                MountConfig::new("/srv", "/data/${hostname}/${date}"),
+                MountConfig::new("/srv", "/plain"),
+            ],
+        };
+        config.expand_targets(&vars).unwrap();
+        assert_eq!(config.mounts[0].target, "/data/web1/2026-10-16");
+        assert_eq!(config.mounts[1].target, "/plain");
+
+        config.mounts[1].target = "/data/${user}".to_string();
+        let err = config.expand_targets(&vars).unwrap_err();
+        assert!(err.contains("undefined variable '${user}'"), "{}", err);
+        config.mounts[1].target = "/data/${hostname".to_string();
+        let err = config.expand_targets(&vars).unwrap_err();
+        assert!(err.contains("unterminated"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_errors_name_line_and_key() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_bad_toml_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let error = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            Config::from_file(&path).unwrap_err().to_string()
+        };
+        let prefix = format!("{}:", path.display());
+
+        let err = error("[server]\nip = \"127.0.0.1\"\nport = \"nfs\"\n\n[[mounts]]\n");
+        assert!(err.starts_with(&prefix), "{}", err);
+        assert!(err.contains(":3: invalid value for server.port"), "{}", err);
+
+        let err = error("[server]\nport = 2049\n\n[[mounts]]\nsource = \"/srv\"\ntarget = /srv\n");
+        assert!(
+            err.contains(":6: invalid value for mounts.target"),
+            "{}",
+            err
+        );
+
+        let err = error("[server]\nport = 2049\n[server\n");
+        assert!(err.contains(":3: "), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+        let err = Config::from_file(&path).unwrap_err().to_string();
+        assert!(err.starts_with(&prefix), "{}", err);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_block_size_must_be_power_of_two() {
+        let mut config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![MountConfig::new(std::env::temp_dir(), "/tmp")],
+        };
+        for (size, valid) in [
+            (4096, true),
+            (512, true),
+            (1000, false),
+            (256, false),
+            (1 << 21, false),
+        ] {
+            config.server.block_size = Some(size);
+            assert_eq!(config.validate().is_ok(), valid, "block_size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_id_maps() {
+        let mount: MountConfig = toml::from_str(
+            "source = '/tmp'\ntarget = '/tmp'\nuid_map = ['0:100000:65536', '70000:1000:1']",
+        )
+        .unwrap();
+        assert_eq!(IdMap::to_client(&mount.uid_map, 100000), 0);
+        assert_eq!(IdMap::to_client(&mount.uid_map, 165535), 65535);
+        assert_eq!(IdMap::to_client(&mount.uid_map, 1000), 70000);
+        assert_eq!(IdMap::to_client(&mount.uid_map, 0), OVERFLOW_ID);
+        assert_eq!(IdMap::to_host(&mount.uid_map, 42), Some(100042));
+        assert_eq!(IdMap::to_host(&mount.uid_map, 70001), None);
+        assert_eq!(IdMap::to_client(&mount.gid_map, 7), 7);
+        assert_eq!(IdMap::to_host(&mount.gid_map, 7), Some(7));
+
+        // Written back in the form it was read
+        let toml_str = toml::to_string(&mount).unwrap();
+        assert!(toml_str.contains("\"0:100000:65536\""), "{}", toml_str);
+
+        for spec in ["1:2", "a:1:1", "0:0:0", "4294967295:0:2"] {
+            assert!(IdMap::try_from(spec.to_string()).is_err(), "{}", spec);
+        }
+
+        let mut config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![mount],
+        };
+        config.mounts[0].source = std::env::temp_dir();
+        assert!(config.validate().is_ok());
+        config.mounts[0]
+            .uid_map
+            .push(IdMap::try_from("1:500:1".to_string()).unwrap());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_use_mmap_requires_read_only() {
+        let mut config = Config {
+            server: ServerConfig::default(),
+            mounts: vec![MountConfig {
+                use_mmap: true,
+                ..MountConfig::new(std::env::temp_dir(), "/tmp")
+            }],
+        };
+        assert!(config.validate().unwrap_err().contains("use_mmap"));
+        config.mounts[0].read_only = true;
+        assert!(config.validate().is_ok());
+        config.mounts[0].read_only = false;
+        config.server.read_only = true;
+        assert!(config.validate().is_ok());
+    }
 }
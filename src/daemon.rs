@@ -1,7 +1,14 @@
 use crate::cli::Cli;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+/// Descriptors one connection may hold at once: its socket and the files it is reading or writing
+const FDS_PER_CONNECTION: u64 = 4;
+
+/// Descriptors used outside of connections, e.g. listeners, logs and kept-open files
+const RESERVED_FDS: u64 = 256;
 
 /// Handle daemon mode initialization
 pub fn handle_daemon_mode(cli: &Cli) -> Result<(), String> {
@@ -89,6 +96,111 @@ fn write_pid_file(pid_file: &PathBuf) -> Result<(), String> {
     Ok(())
 }
 
+/// Confine the process to `dir`, which becomes its root directory
+///
+/// Paths opened afterwards, including the mount sources, are resolved inside
+/// `dir`. Requires root.
+#[cfg(unix)]
+pub fn enter_chroot(dir: &Path) -> Result<(), String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    // SAFETY: geteuid has no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(format!(
+            "Cannot chroot into '{}': chroot requires running as root",
+            dir.display()
+        ));
+    }
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| format!("Invalid chroot path '{}'", dir.display()))?;
+    // SAFETY: path is a valid NUL-terminated string
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(format!(
+            "Failed to chroot into '{}': {}",
+            dir.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    std::env::set_current_dir("/")
+        .map_err(|e| format!("Failed to change directory inside chroot: {}", e))
+}
+
+/// Check the open file limit against the expected number of descriptors
+///
+/// Running out of descriptors makes every open fail with `EMFILE`, which
+/// clients only see as IO errors. If `raise` is set, the soft limit is first
+/// raised to the hard limit. Logs the effective limit, and a warning if it is
+/// low for `max_connections`.
+#[cfg(unix)]
+pub fn check_fd_limit(max_connections: usize, raise: bool) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is a valid rlimit for getrlimit to fill in
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        warn!(
+            "Failed to query the open file limit: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+
+    if raise && limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: limit.rlim_max,
+            rlim_max: limit.rlim_max,
+        };
+        // SAFETY: raised is a valid rlimit within the hard limit
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit = raised;
+        } else {
+            warn!(
+                "Failed to raise the open file limit to {}: {}",
+                limit.rlim_max,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    let soft = limit.rlim_cur;
+    info!("Open file limit: {} (hard limit {})", soft, limit.rlim_max);
+    let wanted = max_connections as u64 * FDS_PER_CONNECTION + RESERVED_FDS;
+    if soft < wanted {
+        warn!(
+            "Open file limit {} is low for {} connections (about {} descriptors); raise it with ulimit -n or set raise_fd_limit",
+            soft, max_connections, wanted
+        );
+    }
+}
+
+/// Log panics through the log system, and exit on them if `exit` is set
+///
+/// A panic in a request only ends the task serving that connection; the
+/// file map lock is released as the task unwinds, so other clients are
+/// still served. The map may however be left half-updated, so with `exit`
+/// the process ends with status 101 for a supervisor to restart it.
+pub fn install_panic_hook(exit: bool) {
+    std::panic::set_hook(Box::new(move |info| {
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+        error!("Panic at {}: {}", location, message);
+        if exit {
+            error!("Exiting after a panic so the server can be restarted");
+            std::process::exit(101);
+        }
+    }));
+}
+
 /// Change working directory if specified
 pub fn change_working_directory(work_dir: &Option<PathBuf>) -> Result<(), String> {
     if let Some(dir) = work_dir {
@@ -0,0 +1,334 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use zerofs_nfsserve::nfs::*;
+
+use crate::fnv::{self, Fnv1a};
+use crate::fsmap::FSMap;
+use crate::metrics::{DedupStats, MountAccess};
+
+/// Time between the end of one scan and the start of the next
+const RESCAN_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How often the state of a running scan is written out
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Size of the reads a file is hashed in
+const CHUNK: usize = 1 << 20;
+
+/// Hash of a file's content, as of the size and mtime it was hashed at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Hashed {
+    size: u64,
+    mtime: (u32, u32),
+    hash: u64,
+}
+
+/// Hashes kept across scans and restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ScanState {
+    /// Hash of a fixed string, to notice hashes made by a different hasher
+    hasher: u64,
+    files: HashMap<PathBuf, Hashed>,
+}
+
+/// A regular file found by the walk
+struct ScanFile {
+    path: PathBuf,
+    mount: usize,
+    size: u64,
+    mtime: (u32, u32),
+}
+
+/// Background scan estimating how much of each mount's content is duplicated
+///
+/// Files are hashed at most `rate` bytes per second and compared by size
+/// and a 64-bit hash, so the result is an estimate; nothing is changed on
+/// disk. Files whose size and mtime are unchanged since they were last
+/// hashed are not read again, and with a `state_path` that also holds
+/// across restarts.
+pub struct DedupScan {
+    fsmap: Arc<Mutex<FSMap>>,
+    mount_access: Arc<MountAccess>,
+    rate: u64,
+    state_path: Option<PathBuf>,
+    /// Hashes known so far, None until loaded; shared so shutdown can save them
+    state: std::sync::Mutex<Option<ScanState>>,
+}
+
+impl DedupScan {
+    pub fn new(
+        fsmap: Arc<Mutex<FSMap>>,
+        mount_access: Arc<MountAccess>,
+        rate: u64,
+        state_path: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            fsmap,
+            mount_access,
+            rate,
+            state_path,
+            state: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Scan every mount now and then every `RESCAN_INTERVAL`
+    pub fn spawn(self: &Arc<Self>) {
+        let scan = Arc::clone(self);
+        tokio::spawn(async move {
+            let state = scan.load_state().await;
+            *scan.state.lock().unwrap() = Some(state);
+            loop {
+                scan.scan().await;
+                tokio::time::sleep(RESCAN_INTERVAL).await;
+            }
+        });
+    }
+
+    async fn load_state(&self) -> ScanState {
+        let empty = ScanState {
+            hasher: hasher_id(),
+            files: HashMap::new(),
+        };
+        let Some(ref path) = self.state_path else {
+            return empty;
+        };
+        let state: ScanState = match tokio::fs::read(path).await {
+            Ok(json) => match serde_json::from_slice(&json) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Ignoring unreadable dedup state {}: {}", path.display(), e);
+                    return empty;
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return empty,
+            Err(e) => {
+                warn!("Failed to read dedup state {}: {}", path.display(), e);
+                return empty;
+            }
+        };
+        if state.hasher != empty.hasher {
+            info!("Dedup state was written by a different build, rehashing every file");
+            return empty;
+        }
+        state
+    }
+
+    /// Write the hashes known so far to `state_path`, once they were loaded
+    pub async fn save_state(&self) {
+        let Some(ref path) = self.state_path else {
+            return;
+        };
+        let json = match self.state.lock().unwrap().as_ref() {
+            Some(state) => serde_json::to_vec(state),
+            None => return,
+        };
+        let written = match json {
+            Ok(json) => tokio::fs::write(path, json).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = written {
+            warn!("Failed to write dedup state {}: {}", path.display(), e);
+        }
+    }
+
+    /// Walk every mount like the preload, hashing the regular files found
+    async fn scan(&self) {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(self.rate);
+        let mut last_save = Instant::now();
+        let mut seen = HashSet::new();
+        let mounts = self.fsmap.lock().await.mounts.len();
+        let mut content: Vec<HashSet<(u64, u64)>> = vec![HashSet::new(); mounts];
+        let mut stats = vec![DedupStats::default(); mounts];
+        let mut queue = vec![0];
+        while let Some(id) = queue.pop() {
+            let (subdirs, files) = match self.list(id).await {
+                Ok(listed) => listed,
+                Err(e) => {
+                    debug!("Dedup scan failed to list {:?}: {:?}", id, e);
+                    continue;
+                }
+            };
+            queue.extend(subdirs);
+            for file in files {
+                let known = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .and_then(|state| state.files.get(&file.path).copied());
+                let hashed = match known {
+                    Some(h) if (h.size, h.mtime) == (file.size, file.mtime) => h,
+                    _ => match hash_file(&file.path, &mut throttle).await {
+                        Ok(hash) => Hashed {
+                            size: file.size,
+                            mtime: file.mtime,
+                            hash,
+                        },
+                        Err(e) => {
+                            debug!("Dedup scan failed to read {:?}: {}", file.path, e);
+                            continue;
+                        }
+                    },
+                };
+                let mount = &mut stats[file.mount];
+                mount.files += 1;
+                mount.bytes += hashed.size;
+                if content[file.mount].insert((hashed.size, hashed.hash)) {
+                    mount.unique_bytes += hashed.size;
+                }
+                seen.insert(file.path.clone());
+                if let Some(state) = self.state.lock().unwrap().as_mut() {
+                    state.files.insert(file.path, hashed);
+                }
+            }
+            if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+                self.save_state().await;
+                last_save = Instant::now();
+            }
+        }
+        // Forget files that are gone, so the state does not grow forever
+        if let Some(state) = self.state.lock().unwrap().as_mut() {
+            state.files.retain(|path, _| seen.contains(path));
+        }
+        self.save_state().await;
+
+        for (index, mount) in stats.into_iter().enumerate() {
+            self.mount_access.set_dedup(index, mount);
+        }
+        info!(
+            "Dedup scan finished: {} files in {:.1?}",
+            seen.len(),
+            start.elapsed()
+        );
+    }
+
+    /// Subdirectories to walk and regular files to hash in directory `id`
+    ///
+    /// Date views and archives are skipped: the former only repeat files of
+    /// their mount, and the latter are not plain files on disk.
+    async fn list(&self, id: fileid3) -> Result<(Vec<fileid3>, Vec<ScanFile>), nfsstat3> {
+        let mut fsmap = self.fsmap.lock().await;
+        let entry = fsmap.find_entry(id)?;
+        if fsmap.in_date_view(&entry.name) || fsmap.in_archive(&entry.name) {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let subdirs = fsmap.list_subdirs(id).await?;
+        let children = fsmap.find_entry(id)?.children.unwrap_or_default();
+        let mut files = Vec::new();
+        for child in children {
+            let Some(entry) = fsmap.id_to_path.get(&child) else {
+                continue;
+            };
+            if !matches!(entry.fsmeta.ftype, ftype3::NF3REG) || fsmap.in_date_view(&entry.name) {
+                continue;
+            }
+            let (Some(mount), Some((path, _))) = (
+                fsmap.mount_index(&entry.name),
+                fsmap.sym_to_real_path(&entry.name).await,
+            ) else {
+                continue;
+            };
+            files.push(ScanFile {
+                path,
+                mount,
+                size: entry.fsmeta.size,
+                mtime: (entry.fsmeta.mtime.seconds, entry.fsmeta.mtime.nseconds),
+            });
+        }
+        Ok((subdirs, files))
+    }
+}
+
+/// Limits how fast files are read for hashing
+struct Throttle {
+    rate: u64,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account for `bytes` just read, sleeping until they fit the rate
+    async fn consume(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.rate as f64);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+async fn hash_file(path: &Path, throttle: &mut Throttle) -> std::io::Result<u64> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Fnv1a::default();
+    let mut buf = vec![0; CHUNK];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buf[..read]);
+        throttle.consume(read).await;
+    }
+}
+
+/// Identifies the hasher, so state written with a different one is rehashed
+fn hasher_id() -> u64 {
+    fnv::hash(b"nfs_mirror dedup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MountConfig;
+
+    #[tokio::test]
+    async fn test_scan_counts_duplicates_and_resumes() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_dedup_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), b"same content").unwrap();
+        std::fs::write(dir.join("sub/b"), b"same content").unwrap();
+        std::fs::write(dir.join("c"), b"other").unwrap();
+        let state_path = dir.with_extension("state");
+
+        let fsmap = FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(&dir, "/test")]);
+        let mount_access = Arc::clone(&fsmap.mount_access);
+        let scan = DedupScan::new(
+            Arc::new(Mutex::new(fsmap)),
+            Arc::clone(&mount_access),
+            1 << 30,
+            Some(state_path.clone()),
+        );
+        *scan.state.lock().unwrap() = Some(scan.load_state().await);
+        scan.scan().await;
+        let stats = mount_access.snapshot()[0].dedup.unwrap();
+        assert_eq!((stats.files, stats.bytes, stats.unique_bytes), (3, 29, 17));
+
+        // A restart picks up the saved hashes instead of reading files again
+        let saved = scan.load_state().await;
+        assert_eq!(saved.files.len(), 3);
+        let state = scan.state.lock().unwrap().take().unwrap();
+        assert_eq!(saved.files, state.files);
+
+        let _ = std::fs::remove_file(&state_path);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
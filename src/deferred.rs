@@ -0,0 +1,196 @@
+use std::sync::{Arc, OnceLock};
+
+use async_trait::async_trait;
+
+use zerofs_nfsserve::nfs::*;
+use zerofs_nfsserve::vfs::{AuthContext, NFSFileSystem, ReadDirResult, VFSCapabilities};
+
+use crate::filesystem::MirrorFS;
+
+/// File system handed to the listener before it is built
+///
+/// Lets `main` bind the socket, enter the chroot and only then read the mount
+/// sources. Requests are only read once the listener starts serving, after the
+/// file system is set; any earlier one would get `NFS3ERR_JUKEBOX` and retry.
+#[derive(Clone, Default)]
+pub struct DeferredFS {
+    fs: Arc<OnceLock<MirrorFS>>,
+}
+
+impl DeferredFS {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `fs` from now on; only the first call has an effect
+    pub fn set(&self, fs: MirrorFS) {
+        let _ = self.fs.set(fs);
+    }
+
+    fn get(&self) -> Result<&MirrorFS, nfsstat3> {
+        self.fs.get().ok_or(nfsstat3::NFS3ERR_JUKEBOX)
+    }
+}
+
+#[async_trait]
+impl NFSFileSystem for DeferredFS {
+    fn root_dir(&self) -> fileid3 {
+        self.fs.get().map_or(0, MirrorFS::root_dir)
+    }
+
+    fn capabilities(&self) -> VFSCapabilities {
+        self.fs
+            .get()
+            .map_or(VFSCapabilities::ReadOnly, MirrorFS::capabilities)
+    }
+
+    async fn lookup(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.get()?.lookup(auth, dirid, filename).await
+    }
+
+    async fn getattr(&self, auth: &AuthContext, id: fileid3) -> Result<fattr3, nfsstat3> {
+        self.get()?.getattr(auth, id).await
+    }
+
+    async fn read(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.get()?.read(auth, id, offset, count).await
+    }
+
+    async fn readdir(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        self.get()?
+            .readdir(auth, dirid, start_after, max_entries)
+            .await
+    }
+
+    async fn setattr(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        setattr: sattr3,
+    ) -> Result<fattr3, nfsstat3> {
+        self.get()?.setattr(auth, id, setattr).await
+    }
+
+    async fn write(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<fattr3, nfsstat3> {
+        self.get()?.write(auth, id, offset, data).await
+    }
+
+    async fn create(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+        setattr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.get()?.create(auth, dirid, filename, setattr).await
+    }
+
+    async fn create_exclusive(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.get()?.create_exclusive(auth, dirid, filename).await
+    }
+
+    async fn remove(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.get()?.remove(auth, dirid, filename).await
+    }
+
+    async fn rename(
+        &self,
+        auth: &AuthContext,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.get()?
+            .rename(auth, from_dirid, from_filename, to_dirid, to_filename)
+            .await
+    }
+
+    async fn mkdir(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        dirname: &filename3,
+        attrs: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.get()?.mkdir(auth, dirid, dirname, attrs).await
+    }
+
+    async fn symlink(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.get()?
+            .symlink(auth, dirid, linkname, symlink, attr)
+            .await
+    }
+
+    async fn readlink(&self, auth: &AuthContext, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        self.get()?.readlink(auth, id).await
+    }
+
+    async fn mknod(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: Option<&specdata3>,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.get()?
+            .mknod(auth, dirid, filename, ftype, attr, spec)
+            .await
+    }
+
+    async fn link(
+        &self,
+        auth: &AuthContext,
+        fileid: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.get()?.link(auth, fileid, linkdirid, linkname).await
+    }
+
+    async fn fsinfo(&self, auth: &AuthContext, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        self.get()?.fsinfo(auth, root_fileid).await
+    }
+}
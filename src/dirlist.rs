@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use zerofs_nfsserve::nfs::*;
+
+/// A directory entry as returned by readdir
+#[derive(Debug, Clone)]
+pub struct ListedEntry {
+    pub fileid: fileid3,
+    pub name: OsString,
+    pub attr: fattr3,
+}
+
+/// Directory contents captured at the start of a paginated readdir
+#[derive(Debug)]
+struct DirSnapshot {
+    dirid: fileid3,
+    uid: u32,
+    entries: Arc<Vec<ListedEntry>>,
+    /// Number of entries handed out so far
+    served: usize,
+    taken: Instant,
+}
+
+impl DirSnapshot {
+    /// Position a continuation after `cookie` resumes at, if this listing handed it out
+    ///
+    /// `dots` are the cookies of `.` and `..`, which precede every entry.
+    fn resume_at(&self, cookie: fileid3, dots: &[fileid3]) -> Option<usize> {
+        let resume = if dots.contains(&cookie) {
+            0
+        } else {
+            self.entries.iter().position(|e| e.fileid == cookie)? + 1
+        };
+        (resume <= self.served).then_some(resume)
+    }
+}
+
+/// Listings kept for in-progress readdir enumerations, one per enumeration
+///
+/// A listing that does not fit in one reply is kept here so that later pages
+/// continue over the same ordered set, even if entries are created or removed
+/// in between. Each listing is kept under its own verifier, so concurrent
+/// enumerations of one directory, even by the same user, never replace each
+/// other. The readdir cookie is the fileid of the last entry returned, and the
+/// NFS layer does not tell which client a request came from, so a
+/// continuation is matched to the listing of the caller's uid that handed out
+/// its cookie, preferring the one whose last page ended there. Listings expire
+/// after `ttl` in case the client never asks for the last page, and at most
+/// `capacity` are kept; the oldest is dropped first, and its enumeration
+/// restarts from a fresh listing.
+#[derive(Debug)]
+pub struct DirSnapshots {
+    ttl: Duration,
+    capacity: usize,
+    snapshots: Mutex<HashMap<u64, DirSnapshot>>,
+    next_verifier: AtomicU64,
+}
+
+impl DirSnapshots {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            snapshots: Mutex::new(HashMap::new()),
+            next_verifier: AtomicU64::new(1),
+        }
+    }
+
+    /// Keep a listing of `dirid` by `uid` with its first `served` entries
+    /// handed out, returning the verifier it is kept under
+    pub fn insert(
+        &self,
+        dirid: fileid3,
+        uid: u32,
+        entries: Arc<Vec<ListedEntry>>,
+        served: usize,
+    ) -> u64 {
+        let verifier = self.next_verifier.fetch_add(1, Ordering::Relaxed);
+        let mut snapshots = self.snapshots.lock().unwrap();
+        snapshots.retain(|_, snapshot| snapshot.taken.elapsed() < self.ttl);
+        if snapshots.len() >= self.capacity {
+            let oldest = snapshots
+                .iter()
+                .min_by_key(|(_, snapshot)| snapshot.taken)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                snapshots.remove(&oldest);
+            }
+        }
+        snapshots.insert(
+            verifier,
+            DirSnapshot {
+                dirid,
+                uid,
+                entries,
+                served,
+                taken: Instant::now(),
+            },
+        );
+        verifier
+    }
+
+    /// Find the kept listing of `dirid` that a continuation by `uid` after `cookie` belongs to
+    ///
+    /// `dots` are the cookies of `.` and `..`, if the listing started with them.
+    pub fn get(
+        &self,
+        dirid: fileid3,
+        uid: u32,
+        cookie: fileid3,
+        dots: &[fileid3],
+    ) -> Option<(u64, Arc<Vec<ListedEntry>>)> {
+        // Cookie 0 starts a fresh enumeration
+        if cookie == 0 {
+            return None;
+        }
+        let snapshots = self.snapshots.lock().unwrap();
+        snapshots
+            .iter()
+            .filter(|(_, s)| s.dirid == dirid && s.uid == uid && s.taken.elapsed() < self.ttl)
+            .filter_map(|(&verifier, s)| Some((verifier, s, s.resume_at(cookie, dots)?)))
+            // The listing whose last page ended on the cookie, the oldest on a tie
+            .min_by_key(|&(verifier, s, resume)| (s.served - resume, verifier))
+            .map(|(verifier, s, _)| (verifier, Arc::clone(&s.entries)))
+    }
+
+    /// Record that the listing kept under `verifier` has handed out `served` entries
+    pub fn advance(&self, verifier: u64, served: usize) {
+        if let Some(snapshot) = self.snapshots.lock().unwrap().get_mut(&verifier) {
+            snapshot.served = snapshot.served.max(served);
+        }
+    }
+
+    /// Forget the listing kept under `verifier` once its enumeration is complete
+    pub fn remove(&self, verifier: u64) {
+        self.snapshots.lock().unwrap().remove(&verifier);
+    }
+}
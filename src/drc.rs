@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use zerofs_nfsserve::nfs::*;
+
+/// A non-idempotent request, identified by its arguments
+///
+/// The RPC transaction id is not visible at the file system layer, so a
+/// retransmit is recognised by carrying the same operation and arguments.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Request {
+    Create {
+        dir: fileid3,
+        name: Vec<u8>,
+    },
+    Mkdir {
+        dir: fileid3,
+        name: Vec<u8>,
+    },
+    Symlink {
+        dir: fileid3,
+        name: Vec<u8>,
+        target: Vec<u8>,
+    },
+    Remove {
+        dir: fileid3,
+        name: Vec<u8>,
+    },
+    Rename {
+        from_dir: fileid3,
+        from_name: Vec<u8>,
+        to_dir: fileid3,
+        to_name: Vec<u8>,
+    },
+    Link {
+        file: fileid3,
+        dir: fileid3,
+        name: Vec<u8>,
+    },
+}
+
+impl Request {
+    /// Directory entries the request changes
+    fn names(&self) -> Vec<(fileid3, &[u8])> {
+        match self {
+            Request::Create { dir, name }
+            | Request::Mkdir { dir, name }
+            | Request::Symlink { dir, name, .. }
+            | Request::Remove { dir, name }
+            | Request::Link { dir, name, .. } => vec![(*dir, name.as_slice())],
+            Request::Rename {
+                from_dir,
+                from_name,
+                to_dir,
+                to_name,
+            } => vec![
+                (*from_dir, from_name.as_slice()),
+                (*to_dir, to_name.as_slice()),
+            ],
+        }
+    }
+}
+
+/// Outcome of a successful non-idempotent request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    /// An object was created with this fileid
+    Created(fileid3),
+    /// The request succeeded with no result beyond its status
+    Done,
+}
+
+/// Duplicate request cache for non-idempotent operations
+///
+/// Keeps the replies of recent successful requests so that a retransmit,
+/// sent because the original reply was lost, gets the same answer instead of
+/// failing with `NFS3ERR_EXIST` or `NFS3ERR_NOENT` or repeating a create that
+/// would truncate a file written in the meantime. Replies expire after
+/// `window`, and at most `capacity` are kept.
+///
+/// Recording a request forgets the replies of earlier requests on the same
+/// directory entries, so that a legitimate repeat of an operation after an
+/// intervening change through this server is executed rather than replayed.
+/// A zero `window` disables the cache.
+#[derive(Debug)]
+pub struct DuplicateRequestCache {
+    window: Duration,
+    capacity: usize,
+    replies: Mutex<HashMap<Request, (Instant, Reply)>>,
+}
+
+impl DuplicateRequestCache {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            replies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Find the reply to a recent request with the same arguments
+    pub fn get(&self, request: &Request) -> Option<Reply> {
+        let replies = self.replies.lock().unwrap();
+        let (at, reply) = replies.get(request)?;
+        (at.elapsed() < self.window).then_some(*reply)
+    }
+
+    /// Record the reply to a request that just succeeded
+    pub fn insert(&self, request: Request, reply: Reply) {
+        if self.window.is_zero() {
+            return;
+        }
+        let mut replies = self.replies.lock().unwrap();
+        replies.retain(|cached, (at, _)| {
+            at.elapsed() < self.window
+                && !cached
+                    .names()
+                    .iter()
+                    .any(|name| request.names().contains(name))
+        });
+        while replies.len() >= self.capacity {
+            let Some(oldest) = replies
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(cached, _)| cached.clone())
+            else {
+                break;
+            };
+            replies.remove(&oldest);
+        }
+        replies.insert(request, (Instant::now(), reply));
+    }
+
+    /// Forget a reply that no longer describes the backing store
+    pub fn remove(&self, request: &Request) {
+        self.replies.lock().unwrap().remove(request);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(name: &str) -> Request {
+        Request::Create {
+            dir: 1,
+            name: name.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_later_change_to_same_name_forgets_reply() {
+        let cache = DuplicateRequestCache::new(Duration::from_secs(60), 16);
+        cache.insert(create("a"), Reply::Created(5));
+        cache.insert(create("b"), Reply::Created(6));
+        assert_eq!(cache.get(&create("a")), Some(Reply::Created(5)));
+
+        cache.insert(
+            Request::Rename {
+                from_dir: 1,
+                from_name: b"a".to_vec(),
+                to_dir: 2,
+                to_name: b"c".to_vec(),
+            },
+            Reply::Done,
+        );
+        assert_eq!(cache.get(&create("a")), None);
+        assert_eq!(cache.get(&create("b")), Some(Reply::Created(6)));
+    }
+
+    #[test]
+    fn test_zero_window_disables_cache() {
+        let cache = DuplicateRequestCache::new(Duration::ZERO, 16);
+        cache.insert(create("a"), Reply::Created(5));
+        assert_eq!(cache.get(&create("a")), None);
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let cache = DuplicateRequestCache::new(Duration::from_secs(60), 2);
+        for name in ["a", "b", "c"] {
+            cache.insert(create(name), Reply::Done);
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(cache.get(&create("a")), None);
+        assert_eq!(cache.get(&create("c")), Some(Reply::Done));
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use zerofs_nfsserve::nfs::*;
+
+use crate::fsmap::FSMap;
+
+/// Fileids of source paths, kept in a JSON file so handles survive restarts
+///
+/// Paths are the backing paths of files and directories, so a fileid stays
+/// bound to a path even if its inode changes, e.g. after a restore from
+/// backup. Paths that are not valid UTF-8 are not persisted.
+#[derive(Debug)]
+pub struct FileIdStore {
+    path: PathBuf,
+    ids: BTreeMap<String, fileid3>,
+    /// Changed since it was last written
+    dirty: bool,
+}
+
+impl FileIdStore {
+    /// Load the store at `path`, starting empty if the file does not exist yet
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let ids = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("Invalid fileid store '{}': {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(format!(
+                    "Failed to read fileid store '{}': {}",
+                    path.display(),
+                    e
+                ));
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            ids,
+            dirty: false,
+        })
+    }
+
+    /// Fileid stored for a source path
+    pub fn get(&self, source: &Path) -> Option<fileid3> {
+        self.ids.get(source.to_str()?).copied()
+    }
+
+    /// Record the fileid of a source path
+    pub fn insert(&mut self, source: &Path, id: fileid3) {
+        match source.to_str() {
+            Some(source) => {
+                if self.ids.insert(source.to_string(), id) != Some(id) {
+                    self.dirty = true;
+                }
+            }
+            None => debug!("Not persisting the fileid of non-UTF-8 {:?}", source),
+        }
+    }
+
+    /// Forget the fileid of a source path that no longer exists
+    pub fn remove(&mut self, source: &Path) {
+        if let Some(source) = source.to_str() {
+            self.dirty |= self.ids.remove(source).is_some();
+        }
+    }
+
+    /// Highest fileid in the store
+    pub fn max_id(&self) -> Option<fileid3> {
+        self.ids.values().copied().max()
+    }
+
+    /// Serialized contents to write, if the store changed since it was last taken
+    fn take_pending(&mut self) -> Option<(PathBuf, Vec<u8>)> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        match serde_json::to_vec(&self.ids) {
+            Ok(data) => Some((self.path.clone(), data)),
+            Err(e) => {
+                warn!("Failed to serialize the fileid store: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Write the fileid store of `fsmap` if it changed
+///
+/// The map is only locked while the store is serialized. The file is
+/// replaced atomically so a crash never leaves a truncated store behind.
+pub async fn save(fsmap: &Mutex<FSMap>) -> std::io::Result<()> {
+    let pending = fsmap
+        .lock()
+        .await
+        .fileid_store
+        .as_mut()
+        .and_then(FileIdStore::take_pending);
+    let Some((path, data)) = pending else {
+        return Ok(());
+    };
+    let tmp = path.with_extension("tmp");
+    let written = match tokio::fs::write(&tmp, data).await {
+        Ok(()) => tokio::fs::rename(&tmp, &path).await,
+        Err(e) => Err(e),
+    };
+    if written.is_err() {
+        // Try again on the next save
+        if let Some(store) = fsmap.lock().await.fileid_store.as_mut() {
+            store.dirty = true;
+        }
+    }
+    written
+}
+
+/// Spawn a task that writes the fileid store every `interval` while it changes
+pub fn spawn_saver(fsmap: Arc<Mutex<FSMap>>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = save(&fsmap).await {
+                warn!("Failed to write the fileid store: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+
+use zerofs_nfsserve::nfs::*;
+
+/// Per-file ordering of reads against changes made through the server
+///
+/// A read holding a file's shared guard sees the file as it was before a
+/// write or as it is after it, never part of one. A file only has a lock
+/// while some request holds it, so idle files cost nothing.
+#[derive(Debug, Default)]
+pub struct FileOrder {
+    files: Mutex<HashMap<fileid3, Weak<RwLock<()>>>>,
+}
+
+impl FileOrder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until no change to `id` is in progress, holding changes off until the guard drops
+    pub async fn read(&self, id: fileid3) -> OwnedRwLockReadGuard<()> {
+        self.lock_of(id).read_owned().await
+    }
+
+    /// Wait until no read or change of `id` is in progress, holding them off until the guard drops
+    pub async fn write(&self, id: fileid3) -> OwnedRwLockWriteGuard<()> {
+        self.lock_of(id).write_owned().await
+    }
+
+    fn lock_of(&self, id: fileid3) -> Arc<RwLock<()>> {
+        let mut files = self.files.lock().unwrap();
+        if let Some(lock) = files.get(&id).and_then(Weak::upgrade) {
+            return lock;
+        }
+        // Locks no request holds any more are dropped as new ones are made
+        files.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(RwLock::new(()));
+        files.insert(id, Arc::downgrade(&lock));
+        lock
+    }
+}
@@ -1,27 +1,180 @@
-use std::ffi::OsStr;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
 use std::io::SeekFrom;
-use std::ops::Bound;
-use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use intaglio::Symbol;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tracing::debug;
+use tokio::sync::{OwnedRwLockReadGuard, OwnedRwLockWriteGuard, Semaphore, SemaphorePermit};
+use tracing::{debug, info, warn};
 
 use zerofs_nfsserve::fs_util::*;
 use zerofs_nfsserve::nfs::*;
 use zerofs_nfsserve::vfs::{AuthContext, DirEntry, NFSFileSystem, ReadDirResult, VFSCapabilities};
 
-use crate::fsmap::{FSMap, RefreshResult};
+use crate::admin::ReadOnlySwitch;
+use crate::archive::{self, MemberKind};
+use crate::config::{ExportRoot, IdMap, MountConfig, ReaddirOrder, ServerConfig, SymlinkTargets};
+use crate::dedup::DedupScan;
+use crate::dirlist::{DirSnapshots, ListedEntry};
+use crate::drc::{DuplicateRequestCache, Reply, Request};
+use crate::fileids::{self, FileIdStore};
+use crate::fileorder::FileOrder;
+use crate::frozen::{FrozenEntry, FrozenSource, FrozenView};
+use crate::fsmap::{
+    FSMap, RefreshResult, fattr3_from_metadata, refresh_dir_list_unlocked, refresh_unlocked,
+    stat_unlocked,
+};
+use crate::metrics::Metrics;
+use crate::mmap::MmapCache;
+use crate::names;
+use crate::openfiles::{OpenFiles, read_open_file};
+use crate::readahead::{FileStamp, ReadAhead};
+use crate::retry::RetryPolicy;
+use crate::writeonce::WriteOnce;
+
+/// How long a paginated readdir may take before its listing is rebuilt
+const DIR_SNAPSHOT_TTL: Duration = Duration::from_secs(60);
+
+/// Most paginated readdir listings kept at once
+const DIR_SNAPSHOT_CAPACITY: usize = 64;
+
+/// Most replies kept for retransmitted non-idempotent requests
+const DUPLICATE_REQUEST_CAPACITY: usize = 1024;
+
+/// Most files with a read-ahead buffer at once
+const READ_AHEAD_FILES: usize = 256;
+
+/// Most descriptors kept open for files deleted while in use
+const OPEN_FILES_CAPACITY: usize = 128;
+
+/// Most files mapped at once for mounts with `use_mmap`
+const MMAP_FILES: usize = 256;
+
+/// How often changes to the fileid store are written out
+const FILEID_STORE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Size of the writes that zero a range where holes cannot be punched
+const ZERO_FILL_CHUNK: usize = 1 << 20;
+
+/// Open flag that keeps reads from updating the access time (none where unsupported)
+#[cfg(target_os = "linux")]
+const O_NOATIME: i32 = libc::O_NOATIME;
+#[cfg(not(target_os = "linux"))]
+const O_NOATIME: i32 = 0;
 
 /// Mirror file system implementation
 #[derive(Debug)]
 pub struct MirrorFS {
     /// File system mapping protected by mutex
-    pub fsmap: tokio::sync::Mutex<FSMap>,
+    pub fsmap: Arc<tokio::sync::Mutex<FSMap>>,
     /// Read-only mode flag
     pub read_only: bool,
+    /// Runtime read-only override, toggled without a restart
+    pub read_only_switch: Arc<ReadOnlySwitch>,
+    /// Retry policy for transient backing store errors on reads
+    pub retry: RetryPolicy,
+    /// Operation counters
+    pub metrics: Arc<Metrics>,
+    /// Interval between metrics log lines (disabled if None)
+    pub metrics_interval: Option<Duration>,
+    /// Owner uid reported for every file (on-disk owner if None)
+    pub force_uid: Option<u32>,
+    /// Owner gid reported for every file (on-disk group if None)
+    pub force_gid: Option<u32>,
+    /// Order of entries returned by readdir
+    pub readdir_order: ReaddirOrder,
+    /// Refresh the attributes of each entry returned by readdir
+    pub readdir_refresh_attrs: bool,
+    /// Start directory listings with `.` and `..`
+    pub readdir_dot_entries: bool,
+    /// Listings of in-progress paginated readdirs
+    pub dir_snapshots: DirSnapshots,
+    /// Copy of the snapshot served without locking the map, once taken
+    pub frozen_view: OnceLock<FrozenView>,
+    /// Replies to recent non-idempotent requests, for retransmits
+    duplicate_requests: DuplicateRequestCache,
+    /// Walk the mounts in the background at startup
+    pub preload: bool,
+    /// Maximum directory depth walked by the preload (unlimited if None)
+    pub preload_depth: Option<usize>,
+    /// Skip reading holes of sparse files
+    pub sparse_reads: bool,
+    /// Leave the access time of files read alone where the backing store allows
+    pub no_atime: bool,
+    /// Orders reads of each file against writes to it (unordered if None)
+    pub file_order: Option<FileOrder>,
+    /// How the mounts are presented at the root
+    pub export_root: ExportRoot,
+    /// Buffers of data read ahead for sequential readers (disabled if None)
+    pub read_ahead: Option<ReadAhead>,
+    /// Descriptors of files in use, served after deletion on disk (disabled if None)
+    pub open_files: Option<Arc<OpenFiles>>,
+    /// Rename files in use on REMOVE instead of deleting them right away
+    pub silly_rename: bool,
+    /// Limit on concurrent backing store reads and writes (unlimited if None)
+    pub io_limit: Option<Semaphore>,
+    /// Mappings of files read from mounts with `use_mmap` (None if no mount uses it)
+    pub mmaps: Option<MmapCache>,
+    /// Write the file map's fileid store in the background
+    pub persist_fileids: bool,
+    /// Fsync the parent directory after changes to the namespace
+    pub dir_sync: bool,
+    /// Files created through the server that are not sealed yet (write-once disabled if None)
+    pub write_once: Option<WriteOnce>,
+    /// Interval between intern table compactions (disabled if None)
+    pub intern_compact_interval: Option<Duration>,
+    /// Bytes per second the dedup scan may hash (disabled if None)
+    pub dedup_rate: Option<u64>,
+    /// File the dedup scan keeps its hashes in between restarts
+    pub dedup_state_path: Option<PathBuf>,
+    /// Dedup scan started by `spawn_background_tasks`, kept to save its state on shutdown
+    dedup_scan: OnceLock<Arc<DedupScan>>,
+    /// Block size advertised in FSINFO (built-in transfer sizes if None)
+    pub block_size: Option<u32>,
+    /// End of the startup grace period during which writes are deferred (None if disabled)
+    pub grace_until: Option<Instant>,
+}
+
+/// Persistent state of a `MirrorFS`, written out on shutdown
+pub struct Shutdown {
+    fsmap: Arc<tokio::sync::Mutex<FSMap>>,
+    persist_fileids: bool,
+    dedup_scan: Option<Arc<DedupScan>>,
+}
+
+impl Shutdown {
+    /// Save the fileid store and dedup state
+    ///
+    /// Failures are logged and do not stop the remaining steps.
+    pub async fn run(&self) {
+        if self.persist_fileids
+            && let Err(e) = fileids::save(&self.fsmap).await
+        {
+            warn!("Failed to write the fileid store: {}", e);
+        }
+        if let Some(ref scan) = self.dedup_scan {
+            scan.save_state().await;
+        }
+    }
+}
+
+/// Slot for one backing store read or write, counted as in flight while held
+struct IoSlot<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+    metrics: &'a Metrics,
+}
+
+impl Drop for IoSlot<'_> {
+    fn drop(&mut self) {
+        self.metrics.io_in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 /// Enumeration for the create_fs_object method
@@ -34,6 +187,8 @@ pub enum CreateFSObject {
     Exclusive,
     /// Creates a symlink with a set of attributes to a target location
     Symlink((sattr3, nfspath3)),
+    /// Creates a named pipe with a set of attributes
+    Fifo(sattr3),
 }
 
 #[allow(dead_code)]
@@ -41,711 +196,4581 @@ impl MirrorFS {
     /// Create a new mirror file system with root directory only
     pub fn new(root_dir: PathBuf, read_only: bool) -> MirrorFS {
         MirrorFS {
-            fsmap: tokio::sync::Mutex::new(FSMap::new_with_root(root_dir)),
+            fsmap: Arc::new(tokio::sync::Mutex::new(FSMap::new_with_root(root_dir))),
             read_only,
+            read_only_switch: Arc::new(ReadOnlySwitch::new()),
+            retry: RetryPolicy::default(),
+            metrics: Arc::new(Metrics::new()),
+            metrics_interval: None,
+            force_uid: None,
+            force_gid: None,
+            readdir_order: ReaddirOrder::default(),
+            readdir_refresh_attrs: false,
+            readdir_dot_entries: false,
+            dir_snapshots: DirSnapshots::new(DIR_SNAPSHOT_TTL, DIR_SNAPSHOT_CAPACITY),
+            frozen_view: OnceLock::new(),
+            duplicate_requests: DuplicateRequestCache::new(
+                Duration::ZERO,
+                DUPLICATE_REQUEST_CAPACITY,
+            ),
+            preload: false,
+            preload_depth: None,
+            sparse_reads: false,
+            no_atime: false,
+            file_order: None,
+            export_root: ExportRoot::default(),
+            read_ahead: None,
+            open_files: None,
+            silly_rename: false,
+            io_limit: None,
+            mmaps: None,
+            persist_fileids: false,
+            dir_sync: false,
+            write_once: None,
+            intern_compact_interval: None,
+            dedup_rate: None,
+            dedup_state_path: None,
+            dedup_scan: OnceLock::new(),
+            block_size: None,
+            grace_until: None,
         }
     }
 
     /// Create a new mirror file system with mount points
     pub fn new_with_mounts(
         root_dir: PathBuf,
-        read_only: bool,
-        mounts: Vec<crate::config::MountConfig>,
+        server: &ServerConfig,
+        mut mounts: Vec<MountConfig>,
     ) -> MirrorFS {
-        // Convert MountConfig to (String, PathBuf, bool) format
-        let mount_tuples: Vec<(String, PathBuf, bool)> = mounts
-            .into_iter()
-            .map(|m| (m.target, m.source, m.read_only))
-            .collect();
+        // Archives are only ever served read-only
+        for mount in mounts.iter_mut().filter(|m| m.archive) {
+            mount.read_only = true;
+        }
+        let retry = RetryPolicy::new(
+            server.io_retries,
+            Duration::from_millis(server.io_retry_backoff_ms),
+        );
+        // Capabilities are global, so only a config where every mount is
+        // read-only can be advertised as a read-only export
+        let all_mounts_read_only = !mounts.is_empty() && mounts.iter().all(|m| m.read_only);
+        let any_mount_mmap = mounts.iter().any(|m| m.use_mmap);
+        let mut fsmap = FSMap::new_with_mounts(root_dir, mounts);
+        fsmap.stat_timeout =
+            (server.stat_timeout_ms > 0).then(|| Duration::from_millis(server.stat_timeout_ms));
+        fsmap.retry = retry;
+        fsmap.attr_cache =
+            (server.attr_cache_ms > 0).then(|| Duration::from_millis(server.attr_cache_ms));
+        fsmap.cache_real_paths = server.cache_real_paths;
+        fsmap.max_dir_entries = server.max_dir_entries;
+        fsmap.max_path_depth = server.max_path_depth;
+        fsmap.unreadable_entries = server.unreadable_entries;
+        if let Some(entries) = server.expected_entries {
+            fsmap.reserve(entries);
+        }
+        if let Some(ref path) = server.fileid_store {
+            match FileIdStore::load(path) {
+                Ok(store) => fsmap.attach_fileid_store(store),
+                Err(e) => warn!("{}; fileids will not persist across restarts", e),
+            }
+        }
+        let persist_fileids = fsmap.fileid_store.is_some();
+        let mount_access = Arc::clone(&fsmap.mount_access);
 
         MirrorFS {
-            fsmap: tokio::sync::Mutex::new(FSMap::new_with_mounts(root_dir, mount_tuples)),
-            read_only,
+            fsmap: Arc::new(tokio::sync::Mutex::new(fsmap)),
+            read_only: server.read_only || server.snapshot || all_mounts_read_only,
+            read_only_switch: Arc::new(ReadOnlySwitch::new()),
+            retry,
+            metrics: Arc::new(Metrics::with_mount_access(mount_access)),
+            metrics_interval: (server.metrics_interval_secs > 0)
+                .then(|| Duration::from_secs(server.metrics_interval_secs)),
+            force_uid: server.force_uid,
+            force_gid: server.force_gid,
+            readdir_order: server.readdir_order,
+            readdir_refresh_attrs: server.readdir_refresh_attrs,
+            readdir_dot_entries: server.readdir_dot_entries,
+            dir_snapshots: DirSnapshots::new(DIR_SNAPSHOT_TTL, DIR_SNAPSHOT_CAPACITY),
+            frozen_view: OnceLock::new(),
+            duplicate_requests: DuplicateRequestCache::new(
+                Duration::from_secs(server.duplicate_request_secs),
+                DUPLICATE_REQUEST_CAPACITY,
+            ),
+            preload: server.preload,
+            preload_depth: server.preload_depth,
+            sparse_reads: server.sparse_reads,
+            no_atime: server.no_atime,
+            file_order: server.ordered_file_io.then(FileOrder::new),
+            export_root: server.export_root,
+            read_ahead: (server.read_ahead_kb > 0)
+                .then(|| ReadAhead::new(server.read_ahead_kb * 1024, READ_AHEAD_FILES)),
+            open_files: (server.keep_unlinked_open_secs > 0).then(|| {
+                Arc::new(OpenFiles::new(
+                    Duration::from_secs(server.keep_unlinked_open_secs),
+                    OPEN_FILES_CAPACITY,
+                ))
+            }),
+            silly_rename: server.silly_rename,
+            io_limit: server.max_concurrent_io.map(Semaphore::new),
+            mmaps: any_mount_mmap.then(|| MmapCache::new(MMAP_FILES)),
+            persist_fileids,
+            dir_sync: server.dir_sync,
+            write_once: (server.write_once_secs > 0)
+                .then(|| WriteOnce::new(Duration::from_secs(server.write_once_secs))),
+            intern_compact_interval: (server.intern_compact_secs > 0)
+                .then(|| Duration::from_secs(server.intern_compact_secs)),
+            dedup_rate: (server.dedup_scan_mb_per_sec > 0)
+                .then(|| server.dedup_scan_mb_per_sec * 1024 * 1024),
+            dedup_state_path: server.dedup_state_path.clone(),
+            dedup_scan: OnceLock::new(),
+            block_size: server.block_size,
+            grace_until: (server.grace_period_secs > 0)
+                .then(|| Instant::now() + Duration::from_secs(server.grace_period_secs)),
         }
     }
 
-    /// creates a FS object in a given directory and of a given type
-    pub async fn create_fs_object(
-        &self,
-        dirid: fileid3,
-        objectname: &filename3,
-        object: &CreateFSObject,
-    ) -> Result<(fileid3, fattr3), nfsstat3> {
-        if self.read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
+    /// Check whether mutations are refused, by configuration or the runtime override
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.read_only_switch.is_enabled()
+    }
+
+    /// Apply presentation overrides to attributes before they are sent to a client
+    ///
+    /// Owners are translated through the mount's id maps first; per-mount
+    /// display overrides take precedence over the global ones.
+    pub fn present_attr(&self, fsmap: &FSMap, attr: fattr3) -> fattr3 {
+        let mut attr = fsmap.map_ids(attr);
+        if let Some(uid) = self.force_uid {
+            attr.uid = uid;
         }
+        if let Some(gid) = self.force_gid {
+            attr.gid = gid;
+        }
+        fsmap.display_attr(attr)
+    }
 
+    /// Pin the current tree as a read-only snapshot served from memory
+    pub async fn take_snapshot(&self) {
         let mut fsmap = self.fsmap.lock().await;
-        let ent = fsmap.find_entry(dirid)?;
-
-        // Get the real file system path for the directory
-        let (dir_path, dir_read_only) = match fsmap.sym_to_real_path(&ent.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point, cannot create objects here
-                return Err(nfsstat3::NFS3ERR_ACCES);
-            }
-        };
+        let listed = fsmap.freeze().await;
+        info!(
+            "Snapshot taken: {} directories, {} entries",
+            listed,
+            fsmap.id_to_path.len()
+        );
+        let view = self.frozen_view_of(&fsmap).await;
+        debug!("Serving {} snapshot entries without locking", view.len());
+        let _ = self.frozen_view.set(view);
+    }
 
-        if dir_read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
+    /// Copy a frozen map into a view that is read without locking it
+    async fn frozen_view_of(&self, fsmap: &FSMap) -> FrozenView {
+        let mut view = FrozenView::default();
+        for (&id, entry) in &fsmap.id_to_path {
+            let listing = match entry.fsmeta.ftype {
+                ftype3::NF3DIR => self.list_dir(fsmap, id).await.ok().map(|mut listing| {
+                    for listed in &mut listing {
+                        listed.attr = self.present_attr(fsmap, listed.attr);
+                    }
+                    Arc::new(listing)
+                }),
+                _ => None,
+            };
+            let source = match entry.fsmeta.ftype {
+                ftype3::NF3REG if !fsmap.in_archive(&entry.name) => fsmap
+                    .sym_to_real_path(&entry.name)
+                    .await
+                    .map(|(path, _)| FrozenSource {
+                        path,
+                        use_mmap: fsmap.mount_for(&entry.name).is_some_and(|m| m.use_mmap),
+                    }),
+                _ => None,
+            };
+            view.insert(
+                id,
+                FrozenEntry {
+                    attr: self.present_attr(fsmap, entry.fsmeta),
+                    source,
+                    mount: fsmap.mount_index(&entry.name),
+                    parent: entry.parent,
+                    listing,
+                },
+            );
         }
+        view
+    }
 
-        let mut path = dir_path;
-        let objectname_osstr = OsStr::from_bytes(objectname).to_os_string();
-        path.push(&objectname_osstr);
+    /// Entries of the cached directory `dirid`, in readdir order
+    async fn list_dir(&self, fsmap: &FSMap, dirid: fileid3) -> Result<Vec<ListedEntry>, nfsstat3> {
+        let entry = fsmap.find_entry(dirid)?;
+        if !matches!(entry.fsmeta.ftype, ftype3::NF3DIR) {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        // we must have children here
+        let children = entry.children.ok_or(nfsstat3::NFS3ERR_IO)?;
 
-        match object {
-            CreateFSObject::Directory => {
-                debug!("mkdir {:?}", path);
-                if exists_no_traverse(&path) {
-                    return Err(nfsstat3::NFS3ERR_EXIST);
-                }
-                tokio::fs::create_dir(&path)
-                    .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-            }
-            CreateFSObject::File(setattr) => {
-                debug!("create {:?}", path);
-                let file = std::fs::File::create(&path).map_err(|_| nfsstat3::NFS3ERR_IO)?;
-                let _ = file_setattr(&file, setattr).await;
-            }
-            CreateFSObject::Exclusive => {
-                debug!("create exclusive {:?}", path);
-                let _ = std::fs::File::options()
-                    .write(true)
-                    .create_new(true)
-                    .open(&path)
-                    .map_err(|_| nfsstat3::NFS3ERR_EXIST)?;
-            }
-            CreateFSObject::Symlink((_, target)) => {
-                debug!("symlink {:?} {:?}", path, target);
-                if exists_no_traverse(&path) {
-                    return Err(nfsstat3::NFS3ERR_EXIST);
+        let mut listing = Vec::with_capacity(children.len());
+        for fileid in children {
+            let fileent = fsmap.find_entry(fileid)?;
+            let mut name = fsmap.sym_to_fname(&fileent.name).await;
+            if let Some(mount) = fsmap.mount_for(&fileent.name) {
+                if let Cow::Owned(shown) = names::display_name(mount, name.as_bytes()) {
+                    name = OsString::from_vec(shown);
                 }
-                tokio::fs::symlink(OsStr::from_bytes(target), &path)
-                    .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-                // we do not set attributes on symlinks
             }
+            listing.push(ListedEntry {
+                fileid,
+                name,
+                attr: fileent.fsmeta,
+            });
         }
-
-        let _ = fsmap.refresh_entry(dirid).await;
-
-        let sym = fsmap.intern.intern(objectname_osstr).unwrap();
-        let mut name = ent.name.clone();
-        name.push(sym);
-        let meta = path.symlink_metadata().map_err(|_| nfsstat3::NFS3ERR_IO)?;
-        let fileid = fsmap.create_entry(&name, meta.clone()).await;
-
-        // update the children list
-        if let Some(ref mut children) = fsmap
-            .id_to_path
-            .get_mut(&dirid)
-            .ok_or(nfsstat3::NFS3ERR_NOENT)?
-            .children
-        {
-            children.insert(fileid);
+        if self.readdir_order == ReaddirOrder::Name {
+            listing.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
         }
-        Ok((fileid, metadata_to_fattr3(fileid, &meta)))
-    }
-}
-
-#[async_trait]
-impl NFSFileSystem for MirrorFS {
-    fn root_dir(&self) -> fileid3 {
-        0
+        Ok(listing)
     }
 
-    fn capabilities(&self) -> VFSCapabilities {
-        if self.read_only {
-            VFSCapabilities::ReadOnly
+    /// Position in `listing` a readdir of `dirid` resumes at after the cookie `start_after`
+    fn resume_at(
+        &self,
+        listing: &[ListedEntry],
+        dirid: fileid3,
+        parent: fileid3,
+        start_after: fileid3,
+    ) -> Result<usize, nfsstat3> {
+        if start_after == 0 {
+            Ok(0)
+        } else if self.readdir_dot_entries && (start_after == dirid || start_after == parent) {
+            // The last page ended on `.` or `..`
+            Ok(0)
+        } else if let Some(i) = listing.iter().position(|e| e.fileid == start_after) {
+            Ok(i + 1)
+        } else if self.readdir_order == ReaddirOrder::Fileid {
+            // The cookie entry is gone; fileid order still tells us where it was
+            Ok(listing.partition_point(|e| e.fileid <= start_after))
         } else {
-            VFSCapabilities::ReadWrite
+            Err(nfsstat3::NFS3ERR_BAD_COOKIE)
         }
     }
 
-    async fn lookup(
+    /// `.` and `..` to start a readdir page of `dirid` with, if enabled
+    ///
+    /// Cookies are fileids, and `..` of the root is the root itself, whose
+    /// fileid is also the cookie that starts a listing. So the entries only
+    /// start a first page with room for more, and a page ending on them is
+    /// continued from the first real entry.
+    fn dot_entries(
         &self,
-        _auth: &AuthContext,
         dirid: fileid3,
-        filename: &filename3,
-    ) -> Result<fileid3, nfsstat3> {
-        let mut fsmap = self.fsmap.lock().await;
-        if let Ok(id) = fsmap.find_child(dirid, filename).await {
-            if fsmap.id_to_path.contains_key(&id) {
-                return Ok(id);
-            }
+        parent: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
+        attr: impl Fn(fileid3) -> Option<fattr3>,
+    ) -> Vec<DirEntry> {
+        if !self.readdir_dot_entries || start_after != 0 || max_entries <= 2 {
+            return Vec::new();
         }
-        // Optimize for negative lookups.
-        // See if the file actually exists on the filesystem
-        let dirent = fsmap.find_entry(dirid)?;
-
-        // Get the real file system path for the directory
-        let (dir_path, _dir_read_only) = match fsmap.sym_to_real_path(&dirent.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point, check if it's the mount point itself
-                if dirent.name.len() == 1 {
-                    let mount_name = fsmap
-                        .intern
-                        .get(dirent.name[0])
-                        .ok_or(nfsstat3::NFS3ERR_NOENT)?;
-                    for (target_path, _source_path, _) in &fsmap.mounts {
-                        if mount_name == OsStr::new(target_path.trim_start_matches('/')) {
-                            // Check if the filename matches this mount point
-                            let filename_str = OsStr::from_bytes(filename);
-                            if filename_str == mount_name {
-                                // This is a lookup for the mount point itself
-                                return Ok(dirid);
-                            }
-                        }
-                    }
-                }
-                return Err(nfsstat3::NFS3ERR_NOENT);
-            }
+        let (Some(dir_attr), Some(parent_attr)) = (attr(dirid), attr(parent)) else {
+            return Vec::new();
         };
+        vec![
+            DirEntry {
+                fileid: dirid,
+                name: b"."[..].into(),
+                attr: dir_attr,
+            },
+            DirEntry {
+                fileid: parent,
+                name: b".."[..].into(),
+                attr: parent_attr,
+            },
+        ]
+    }
 
-        let mut path = dir_path;
-        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
-        path.push(&objectname_osstr);
-        if !exists_no_traverse(&path) {
-            return Err(nfsstat3::NFS3ERR_NOENT);
+    /// Spawn background tasks needed by the enabled features
+    pub fn spawn_background_tasks(&self) {
+        if let Some(interval) = self.metrics_interval {
+            self.metrics.spawn_logger(interval);
         }
-        // ok the file actually exists.
-        // that means something changed under me probably.
-        // refresh.
-
-        if let RefreshResult::Delete = fsmap.refresh_entry(dirid).await? {
-            return Err(nfsstat3::NFS3ERR_NOENT);
+        if self.preload {
+            tokio::spawn(preload(Arc::clone(&self.fsmap), self.preload_depth));
         }
-        let _ = fsmap.refresh_dir_list(dirid).await;
+        if self.persist_fileids {
+            fileids::spawn_saver(Arc::clone(&self.fsmap), FILEID_STORE_SAVE_INTERVAL);
+        }
+        if let Some(interval) = self.intern_compact_interval {
+            tokio::spawn(compact_intern(Arc::clone(&self.fsmap), interval));
+        }
+        if let Some(rate) = self.dedup_rate {
+            let scan = Arc::new(DedupScan::new(
+                Arc::clone(&self.fsmap),
+                Arc::clone(&self.metrics.mount_access),
+                rate,
+                self.dedup_state_path.clone(),
+            ));
+            scan.spawn();
+            let _ = self.dedup_scan.set(scan);
+        }
+    }
 
-        fsmap.find_child(dirid, filename).await
+    /// Handle to the state that must reach the disk before the process exits
+    ///
+    /// Taken before the listener takes ownership of the file system.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        Shutdown {
+            fsmap: Arc::clone(&self.fsmap),
+            persist_fileids: self.persist_fileids,
+            dedup_scan: self.dedup_scan.get().cloned(),
+        }
     }
 
-    async fn getattr(&self, _auth: &AuthContext, id: fileid3) -> Result<fattr3, nfsstat3> {
-        //debug!("Stat query {:?}", id);
-        let mut fsmap = self.fsmap.lock().await;
-        if let RefreshResult::Delete = fsmap.refresh_entry(id).await? {
-            return Err(nfsstat3::NFS3ERR_NOENT);
+    /// Wait for a free IO slot, so excess reads and writes queue instead of thrashing the disk
+    async fn io_slot(&self) -> IoSlot<'_> {
+        Metrics::incr(&self.metrics.io_in_flight);
+        let permit = match self.io_limit {
+            // The semaphore is never closed
+            Some(ref limit) => limit.acquire().await.ok(),
+            None => None,
+        };
+        IoSlot {
+            _permit: permit,
+            metrics: &self.metrics,
         }
-        let ent = fsmap.find_entry(id)?;
-        let path = fsmap.sym_to_path(&ent.name).await;
-        debug!("Stat {:?}: {:?}", path, ent);
-        Ok(ent.fsmeta)
     }
 
-    async fn read(
+    /// Read from the backing file, retrying transient errors
+    async fn read_backing(
+        &self,
+        path: &Path,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        self.retry
+            .run(|| read_range(path, offset, count, self.sparse_reads, self.no_atime))
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
+                _ => nfsstat3::NFS3ERR_IO,
+            })
+    }
+
+    /// Read from the backing file of `id`, through whichever cache serves it
+    async fn read_file(
         &self,
-        _auth: &AuthContext,
         id: fileid3,
+        path: &Path,
+        use_mmap: bool,
         offset: u64,
         count: u32,
     ) -> Result<(Vec<u8>, bool), nfsstat3> {
-        let fsmap = self.fsmap.lock().await;
-        let ent = fsmap.find_entry(id)?;
+        let _order = self.order_read(id).await;
+        let _io = self.io_slot().await;
+        if let Some(file) = self.unlinked_open_file(id, path) {
+            debug!("Reading deleted {:?} through its kept descriptor", path);
+            return read_open_file(&file, offset, count).map_err(|_| nfsstat3::NFS3ERR_IO);
+        }
+        self.remember_open(id, path);
+        if let (true, Some(mmaps)) = (use_mmap, &self.mmaps) {
+            return mmaps
+                .read(id, path, offset, count)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
+                    _ => nfsstat3::NFS3ERR_IO,
+                });
+        }
+        let Some(ref read_ahead) = self.read_ahead else {
+            return self.read_backing(path, offset, count).await;
+        };
 
-        // Get the real file system path
-        let (path, _read_only) = match fsmap.sym_to_real_path(&ent.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point or root, cannot read
-                return Err(nfsstat3::NFS3ERR_ISDIR);
-            }
+        // A stat is cheaper than reopening and reading the file, and tells
+        // whether the buffer still matches it
+        let meta = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
+                _ => nfsstat3::NFS3ERR_IO,
+            })?;
+        let stamp = FileStamp {
+            mtime: meta.modified().ok(),
+            size: meta.len(),
         };
+        if let Some(reply) = read_ahead.get(id, offset, count, stamp) {
+            Metrics::incr(&self.metrics.read_ahead_hits);
+            return Ok(reply);
+        }
+        let window = (count as u64 + read_ahead.window).min(u32::MAX as u64) as u32;
+        let (data, _) = self.read_backing(path, offset, window).await?;
+        let reply = (
+            data[..data.len().min(count as usize)].to_vec(),
+            offset.saturating_add(count as u64) >= stamp.size,
+        );
+        read_ahead.insert(id, offset, data, stamp);
+        Ok(reply)
+    }
 
-        drop(fsmap);
-        let mut f = File::open(&path).await.or(Err(nfsstat3::NFS3ERR_NOENT))?;
-        let len = f.metadata().await.or(Err(nfsstat3::NFS3ERR_NOENT))?.len();
-        let mut start = offset;
-        let mut end = offset + count as u64;
-        let eof = end >= len;
-        if start >= len {
-            start = len;
+    /// Hold off changes to `id` through the server while the guard is held, with `ordered_file_io`
+    async fn order_read(&self, id: fileid3) -> Option<OwnedRwLockReadGuard<()>> {
+        match self.file_order {
+            Some(ref order) => Some(order.read(id).await),
+            None => None,
         }
-        if end > len {
-            end = len;
+    }
+
+    /// Hold off reads and other changes of `id` while the guard is held, with `ordered_file_io`
+    async fn order_write(&self, id: fileid3) -> Option<OwnedRwLockWriteGuard<()>> {
+        match self.file_order {
+            Some(ref order) => Some(order.write(id).await),
+            None => None,
         }
-        f.seek(SeekFrom::Start(start))
-            .await
-            .or(Err(nfsstat3::NFS3ERR_IO))?;
-        let mut buf = vec![0; (end - start) as usize];
-        f.read_exact(&mut buf).await.or(Err(nfsstat3::NFS3ERR_IO))?;
-        Ok((buf, eof))
     }
 
-    async fn readdir(
-        &self,
-        _auth: &AuthContext,
-        dirid: fileid3,
-        start_after: fileid3,
-        max_entries: usize,
-    ) -> Result<ReadDirResult, nfsstat3> {
-        let mut fsmap = self.fsmap.lock().await;
-        fsmap.refresh_entry(dirid).await?;
-        fsmap.refresh_dir_list(dirid).await?;
+    /// Drop data read ahead from a file that is about to change
+    fn invalidate_read_ahead(&self, id: fileid3) {
+        if let Some(ref read_ahead) = self.read_ahead {
+            read_ahead.invalidate(id);
+        }
+    }
 
-        let entry = fsmap.find_entry(dirid)?;
-        if !matches!(entry.fsmeta.ftype, ftype3::NF3DIR) {
-            return Err(nfsstat3::NFS3ERR_NOTDIR);
+    /// Refuse a change to a file sealed by write-once mode
+    fn check_unsealed(&self, id: fileid3) -> Result<(), nfsstat3> {
+        match self.write_once {
+            Some(ref write_once) if !write_once.touch(id) => {
+                debug!("Denying change to sealed file {:?}", id);
+                Err(nfsstat3::NFS3ERR_ACCES)
+            }
+            _ => Ok(()),
         }
-        debug!("readdir({:?}, {:?})", entry, start_after);
-        // we must have children here
-        let children = entry.children.ok_or(nfsstat3::NFS3ERR_IO)?;
+    }
 
-        let mut ret = ReadDirResult {
-            entries: Vec::new(),
-            end: false,
+    /// Whether the file at `path`, cached as `id` if at all, is sealed by write-once mode
+    ///
+    /// Directories are never sealed, and a path that does not exist has
+    /// nothing to seal.
+    fn is_sealed(&self, path: &Path, id: Option<fileid3>) -> bool {
+        let Some(ref write_once) = self.write_once else {
+            return false;
         };
+        match path.symlink_metadata() {
+            Ok(meta) if !meta.is_dir() => !id.is_some_and(|id| write_once.touch(id)),
+            _ => false,
+        }
+    }
 
-        let range_start = if start_after > 0 {
-            Bound::Excluded(start_after)
-        } else {
-            Bound::Unbounded
+    /// Fsync the directory holding `path`, if `dir_sync` is set
+    ///
+    /// Makes a created, removed or renamed name durable, not just the data
+    /// of the files involved.
+    async fn sync_parent(&self, path: &Path) -> Result<(), nfsstat3> {
+        let Some(dir) = path.parent().filter(|_| self.dir_sync) else {
+            return Ok(());
         };
+        Metrics::incr(&self.metrics.dir_syncs);
+        let dir = tokio::fs::File::open(dir)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        dir.sync_all().await.map_err(|_| nfsstat3::NFS3ERR_IO)
+    }
 
-        let remaining_length = children.range((range_start, Bound::Unbounded)).count();
-        let path = fsmap.sym_to_path(&entry.name).await;
-        debug!("path: {:?}", path);
-        debug!("children len: {:?}", children.len());
-        debug!("remaining_len : {:?}", remaining_length);
-        for i in children.range((range_start, Bound::Unbounded)) {
-            let fileid = *i;
-            let fileent = fsmap.find_entry(fileid)?;
-            let name = fsmap.sym_to_fname(&fileent.name).await;
-            debug!("\t --- {:?} {:?}", fileid, name);
-            ret.entries.push(DirEntry {
-                fileid,
-                name: name.as_bytes().into(),
-                attr: fileent.fsmeta,
-            });
-            if ret.entries.len() >= max_entries {
-                break;
-            }
+    /// Drop data and descriptors cached for a file that is gone
+    fn drop_cached(&self, id: fileid3) {
+        self.invalidate_read_ahead(id);
+        if let Some(ref open_files) = self.open_files {
+            open_files.forget(id);
         }
-        if ret.entries.len() == remaining_length {
-            ret.end = true;
+        if let Some(ref mmaps) = self.mmaps {
+            mmaps.invalidate(id);
         }
-        debug!("readdir_result:{:?}", ret);
+    }
 
-        Ok(ret)
+    /// Keep a descriptor of a file a client is using, if deleted files are served
+    fn remember_open(&self, id: fileid3, path: &Path) {
+        if let Some(ref open_files) = self.open_files {
+            open_files.remember(id, path);
+        }
     }
 
-    async fn setattr(
-        &self,
-        _auth: &AuthContext,
-        id: fileid3,
-        setattr: sattr3,
-    ) -> Result<fattr3, nfsstat3> {
-        let mut fsmap = self.fsmap.lock().await;
-        let entry = fsmap.find_entry(id)?;
-        let path = fsmap.sym_to_path(&entry.name).await;
-        path_setattr(&path, &setattr).await?;
+    /// Kept descriptor of a file in use that was deleted on disk
+    fn unlinked_open_file(&self, id: fileid3, path: &Path) -> Option<Arc<std::fs::File>> {
+        let file = self.open_files.as_ref()?.get(id)?;
+        (!exists_no_traverse(path)).then_some(file)
+    }
 
-        // I have to lookup a second time to update
-        let metadata = path.symlink_metadata().or(Err(nfsstat3::NFS3ERR_IO))?;
-        if let Ok(entry) = fsmap.find_entry_mut(id) {
-            entry.fsmeta = metadata_to_fattr3(id, &metadata);
-        }
-        Ok(metadata_to_fattr3(id, &metadata))
+    /// Attributes of a file in use that was deleted on disk, from its kept descriptor
+    async fn unlinked_open_attr(&self, fsmap: &FSMap, id: fileid3) -> Option<fattr3> {
+        self.open_files.as_ref()?.get(id)?;
+        let entry = fsmap.id_to_path.get(&id)?;
+        let (path, _) = fsmap.sym_to_real_path(&entry.name).await?;
+        let meta = self.unlinked_open_file(id, &path)?.metadata().ok()?;
+        Some(fattr3_from_metadata(id, &meta))
     }
 
-    async fn write(
-        &self,
-        _auth: &AuthContext,
-        id: fileid3,
-        offset: u64,
-        data: &[u8],
-    ) -> Result<fattr3, nfsstat3> {
-        if self.read_only {
+    /// Check that the server is writable and the caller may modify objects under the mount of `id`
+    ///
+    /// The caller is judged by its AUTH_SYS credentials. Every mutating
+    /// operation starts here, so a read-only server refuses all of them with
+    /// `NFS3ERR_ROFS` before anything else is looked at, replayed
+    /// retransmits included. During the startup grace period they are
+    /// deferred with `NFS3ERR_JUKEBOX` instead, so clients retry them later.
+    pub async fn authorize_write(&self, auth: &AuthContext, id: fileid3) -> Result<(), nfsstat3> {
+        if self.is_read_only() {
             return Err(nfsstat3::NFS3ERR_ROFS);
         }
+        if self.grace_until.is_some_and(|end| Instant::now() < end) {
+            debug!("Deferring a change to {:?} during the grace period", id);
+            return Err(nfsstat3::NFS3ERR_JUKEBOX);
+        }
         let fsmap = self.fsmap.lock().await;
-        let ent = fsmap.find_entry(id)?;
+        let entry = fsmap.find_entry(id)?;
+        if fsmap.status_files.contains(&entry.name) {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+        match fsmap.mount_for(&entry.name) {
+            Some(mount) if !mount.allows_write(auth.uid, auth.gid, &auth.gids) => {
+                debug!("Denying write by uid {} to {:?}", auth.uid, mount.target);
+                Err(nfsstat3::NFS3ERR_ACCES)
+            }
+            _ => Ok(()),
+        }
+    }
 
-        // Get the real file system path
-        let (path, read_only) = match fsmap.sym_to_real_path(&ent.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point or root, cannot write
-                return Err(nfsstat3::NFS3ERR_ISDIR);
+    /// Drop the cached entry of a removed file along with its cached data
+    async fn forget_removed(&self, fsmap: &mut FSMap, dirid: fileid3, sympath: &[Symbol]) {
+        // update the fileid -> path
+        // and the path -> fileid mappings for the deleted file
+        if let Some((fileid, last_link)) = fsmap.remove_path(sympath) {
+            if last_link {
+                self.drop_cached(fileid);
+            } else {
+                // The file lives on under its other links, with one link less
+                fsmap.invalidate(fileid);
             }
-        };
+            // we need to update the children listing for the directories,
+            // unless another link to the file is in the same one
+            let still_listed = fsmap
+                .id_to_path
+                .get(&dirid)
+                .is_some_and(|dir| fsmap.has_link_in(fileid, &dir.name));
+            if !still_listed {
+                if let Ok(dirent_mut) = fsmap.find_entry_mut(dirid) {
+                    if let Some(ref mut fromch) = dirent_mut.children {
+                        fromch.remove(&fileid);
+                    }
+                }
+            }
+        }
+
+        fsmap.invalidate(dirid);
+        let _ = fsmap.refresh_entry(dirid).await;
+    }
+
+    /// Create an object, unless this is a retransmit of a create that succeeded
+    ///
+    /// A replayed create returns the object made by the original request, as
+    /// long as it still exists.
+    async fn create_once(
+        &self,
+        request: Request,
+        dirid: fileid3,
+        objectname: &filename3,
+        object: &CreateFSObject,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        if let Some(Reply::Created(id)) = self.duplicate_requests.get(&request) {
+            if let Ok(attr) = self.getattr(id).await {
+                debug!("Replaying {:?}", request);
+                return Ok((id, attr));
+            }
+            self.duplicate_requests.remove(&request);
+        }
+        let created = self.create_fs_object(dirid, objectname, object).await?;
+        self.duplicate_requests
+            .insert(request, Reply::Created(created.0));
+        Ok(created)
+    }
+
+    /// Check whether a REMOVE of `fileid` should rename it rather than delete it
+    fn wants_silly_rename(&self, fileid: fileid3) -> bool {
+        self.silly_rename
+            && self
+                .open_files
+                .as_ref()
+                .is_some_and(|open_files| open_files.get(fileid).is_some())
+    }
+
+    /// Hide a removed file that is still in use under a `.nfs` name
+    ///
+    /// The file keeps its fileid, so clients holding its handle can go on
+    /// reading it, and is deleted once it has not been used for the open-file
+    /// idle time, like knfsd's silly rename.
+    async fn silly_rename(
+        &self,
+        fsmap: &mut FSMap,
+        dirid: fileid3,
+        fileid: fileid3,
+        path: &Path,
+        sympath: &[Symbol],
+    ) -> Result<(), nfsstat3> {
+        let silly_name = OsString::from(format!(".nfs{:016x}", fileid));
+        let silly_path = path.with_file_name(&silly_name);
+        let mut silly_sympath = sympath.to_vec();
+        if let Some(last) = silly_sympath.last_mut() {
+            *last = fsmap.intern_name(silly_name)?;
+        }
+        tokio::fs::rename(path, &silly_path)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        debug!("Silly-renamed {:?} to {:?}", path, silly_path);
+        self.sync_parent(&silly_path).await?;
+
+        fsmap.move_entry(fileid, silly_sympath);
+        fsmap.invalidate(dirid);
+
+        if let Some(open_files) = self.open_files.clone() {
+            tokio::spawn(async move {
+                while open_files.get(fileid).is_some() {
+                    tokio::time::sleep(open_files.idle()).await;
+                }
+                open_files.forget(fileid);
+                if let Err(e) = tokio::fs::remove_file(&silly_path).await {
+                    debug!("Failed to delete {:?}: {:?}", silly_path, e);
+                }
+            });
+        }
+        Ok(())
+    }
 
+    /// Apply attribute changes to `id`
+    pub async fn apply_setattr(&self, id: fileid3, setattr: sattr3) -> Result<fattr3, nfsstat3> {
+        if self.is_read_only() {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+        self.invalidate_read_ahead(id);
+        let _order = match setattr.size {
+            set_size3::size(_) => self.order_write(id).await,
+            set_size3::Void => None,
+        };
+        let mut fsmap = self.fsmap.lock().await;
+        let entry = fsmap.find_entry(id)?;
+        if !matches!(entry.fsmeta.ftype, ftype3::NF3DIR) {
+            self.check_unsealed(id)?;
+        }
+        let (path, read_only) = fsmap
+            .sym_to_real_path(&entry.name)
+            .await
+            .ok_or(nfsstat3::NFS3ERR_ACCES)?;
         if read_only {
             return Err(nfsstat3::NFS3ERR_ROFS);
         }
+        if let set_size3::size(size) = setattr.size {
+            // Resizing opens the file for writing, which blocks on a pipe
+            // with the file map locked
+            let current = fsmap.stat(&path).await?;
+            if current.is_dir() {
+                return Err(nfsstat3::NFS3ERR_ISDIR);
+            }
+            if !current.is_file() {
+                return Err(nfsstat3::NFS3ERR_INVAL);
+            }
+            if is_append_only(&fsmap, &entry.name) && size < current.len() {
+                debug!("Denying truncation of {:?}", path);
+                return Err(nfsstat3::NFS3ERR_ACCES);
+            }
+            if max_file_size(&fsmap, &entry.name).is_some_and(|max| size > max) {
+                debug!("Denying resize of {:?} to {} bytes", path, size);
+                return Err(nfsstat3::NFS3ERR_FBIG);
+            }
+        }
+        let setattr = host_owner(&fsmap, &entry.name, setattr)?;
+        path_setattr(&path, &setattr).await?;
 
-        drop(fsmap);
-        debug!("write to init {:?}", path);
-        let mut f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(&path)
-            .await
-            .map_err(|e| {
-                debug!("Unable to open {:?}", e);
-                nfsstat3::NFS3ERR_IO
-            })?;
-        f.seek(SeekFrom::Start(offset)).await.map_err(|e| {
-            debug!("Unable to seek {:?}", e);
-            nfsstat3::NFS3ERR_IO
-        })?;
-        f.write_all(data).await.map_err(|e| {
-            debug!("Unable to write {:?}", e);
-            nfsstat3::NFS3ERR_IO
-        })?;
-        debug!("write to {:?} {:?} {:?}", path, offset, data.len());
-        let _ = f.flush().await;
-        let _ = f.sync_all().await;
-        let meta = f.metadata().await.or(Err(nfsstat3::NFS3ERR_IO))?;
-        Ok(metadata_to_fattr3(id, &meta))
+        // I have to lookup a second time to update
+        let metadata = path.symlink_metadata().or(Err(nfsstat3::NFS3ERR_IO))?;
+        if let Ok(entry) = fsmap.find_entry_mut(id) {
+            entry.fsmeta = fattr3_from_metadata(id, &metadata);
+            entry.refreshed = Some(Instant::now());
+        }
+        Ok(self.present_attr(&fsmap, fattr3_from_metadata(id, &metadata)))
     }
 
-    async fn create(
+    /// Reserve backing store space for `len` bytes of `id` from `offset`
+    ///
+    /// Grows the file if the range ends past it, like NFSv4.2 ALLOCATE, so
+    /// database-like clients can lay out large files without fragmenting
+    /// them. Where the backing file system cannot reserve space the file is
+    /// only grown, and later writes into the range may still run out of space.
+    pub async fn allocate(
         &self,
-        _auth: &AuthContext,
-        dirid: fileid3,
-        filename: &filename3,
-        setattr: sattr3,
-    ) -> Result<(fileid3, fattr3), nfsstat3> {
-        self.create_fs_object(dirid, filename, &CreateFSObject::File(setattr))
+        auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        len: u64,
+    ) -> Result<fattr3, nfsstat3> {
+        let path = self.space_target(auth, id, offset, len, false).await?;
+        self.change_space(id, path, offset, len, false).await
+    }
+
+    /// Free the backing store space of `len` bytes of `id` from `offset`
+    ///
+    /// The range reads back as zeros and the size is unchanged, like NFSv4.2
+    /// DEALLOCATE. Where holes cannot be punched the range is overwritten
+    /// with zeros instead.
+    pub async fn deallocate(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        len: u64,
+    ) -> Result<fattr3, nfsstat3> {
+        let path = self.space_target(auth, id, offset, len, true).await?;
+        self.change_space(id, path, offset, len, true).await
+    }
+
+    /// Check that the space of a range of `id` may be changed, returning its backing path
+    async fn space_target(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        len: u64,
+        overwrites: bool,
+    ) -> Result<PathBuf, nfsstat3> {
+        if len == 0
+            || offset
+                .checked_add(len)
+                .is_none_or(|end| end > i64::MAX as u64)
+        {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+        self.authorize_write(auth, id).await?;
+        self.check_unsealed(id)?;
+        self.invalidate_read_ahead(id);
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+        match ent.fsmeta.ftype {
+            ftype3::NF3REG => {}
+            ftype3::NF3DIR => return Err(nfsstat3::NFS3ERR_ISDIR),
+            _ => return Err(nfsstat3::NFS3ERR_INVAL),
+        }
+        let (path, read_only) = fsmap
+            .sym_to_real_path(&ent.name)
             .await
+            .ok_or(nfsstat3::NFS3ERR_ISDIR)?;
+        if read_only {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+        if overwrites && is_append_only(&fsmap, &ent.name) {
+            debug!("Denying deallocation in {:?}", path);
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+        // Allocating past the end grows the file
+        let end = offset.saturating_add(len);
+        if !overwrites && max_file_size(&fsmap, &ent.name).is_some_and(|max| end > max) {
+            debug!("Denying allocation in {:?} up to {}", path, end);
+            return Err(nfsstat3::NFS3ERR_FBIG);
+        }
+        Ok(path)
     }
 
-    async fn create_exclusive(
+    /// Allocate or, with `punch`, deallocate a range of the file at `path`
+    async fn change_space(
         &self,
-        _auth: &AuthContext,
-        dirid: fileid3,
-        filename: &filename3,
-    ) -> Result<fileid3, nfsstat3> {
-        Ok(self
-            .create_fs_object(dirid, filename, &CreateFSObject::Exclusive)
-            .await?
-            .0)
+        id: fileid3,
+        path: PathBuf,
+        offset: u64,
+        len: u64,
+        punch: bool,
+    ) -> Result<fattr3, nfsstat3> {
+        let _order = self.order_write(id).await;
+        let _io = self.io_slot().await;
+        let meta = tokio::task::spawn_blocking(move || space_of(&path, offset, len, punch))
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?
+            .map_err(|e| {
+                debug!("Unable to change space of {:?}: {:?}", id, e);
+                match e.kind() {
+                    std::io::ErrorKind::StorageFull => nfsstat3::NFS3ERR_NOSPC,
+                    std::io::ErrorKind::FileTooLarge => nfsstat3::NFS3ERR_FBIG,
+                    std::io::ErrorKind::PermissionDenied => nfsstat3::NFS3ERR_ACCES,
+                    std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
+                    _ => nfsstat3::NFS3ERR_IO,
+                }
+            })?;
+        let mut fsmap = self.fsmap.lock().await;
+        fsmap.invalidate(id);
+        Ok(self.present_attr(&fsmap, fattr3_from_metadata(id, &meta)))
     }
 
-    async fn remove(
+    /// creates a FS object in a given directory and of a given type
+    pub async fn create_fs_object(
         &self,
-        _auth: &AuthContext,
         dirid: fileid3,
-        filename: &filename3,
-    ) -> Result<(), nfsstat3> {
-        if self.read_only {
+        objectname: &filename3,
+        object: &CreateFSObject,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        if self.is_read_only() {
             return Err(nfsstat3::NFS3ERR_ROFS);
         }
 
         let mut fsmap = self.fsmap.lock().await;
         let ent = fsmap.find_entry(dirid)?;
 
-        // Get the real file system path for the directory
-        let (dir_path, dir_read_only) = match fsmap.sym_to_real_path(&ent.name).await {
-            Some(path) => path,
+        // Get the real file system path for the directory. A mount root
+        // resolves to its source and carries the mount's read-only flag; only
+        // the synthetic root has no real path and refuses with ACCES.
+        let (dir_path, dir_read_only) = fsmap.real_dir_path(&ent.name).await?;
+
+        if dir_read_only {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+
+        let mut path = dir_path;
+        let objectname_osstr = OsStr::from_bytes(objectname).to_os_string();
+        path.push(&objectname_osstr);
+
+        match object {
+            CreateFSObject::Directory => {
+                debug!("mkdir {:?}", path);
+                if exists_no_traverse(&path) {
+                    return Err(nfsstat3::NFS3ERR_EXIST);
+                }
+                tokio::fs::create_dir(&path)
+                    .await
+                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            }
+            CreateFSObject::File(setattr) => {
+                debug!("create {:?}", path);
+                // Creating over an existing file would truncate it
+                if is_append_only(&fsmap, &ent.name) && exists_no_traverse(&path) {
+                    return Err(nfsstat3::NFS3ERR_ACCES);
+                }
+                if self.is_sealed(&path, fsmap.find_child(dirid, objectname).await.ok()) {
+                    return Err(nfsstat3::NFS3ERR_ACCES);
+                }
+                // Only a regular file can be created over: opening a pipe
+                // blocks, and a symlink would be followed
+                if path.symlink_metadata().is_ok_and(|meta| !meta.is_file()) {
+                    return Err(nfsstat3::NFS3ERR_EXIST);
+                }
+                let setattr = host_owner(&fsmap, &ent.name, *setattr)?;
+                let file = std::fs::File::create(&path).map_err(|_| nfsstat3::NFS3ERR_IO)?;
+                let _ = file_setattr(&file, &setattr).await;
+            }
+            CreateFSObject::Exclusive => {
+                debug!("create exclusive {:?}", path);
+                let _ = std::fs::File::options()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .map_err(|_| nfsstat3::NFS3ERR_EXIST)?;
+            }
+            CreateFSObject::Symlink((_, target)) => {
+                debug!("symlink {:?} {:?}", path, target);
+                if exists_no_traverse(&path) {
+                    return Err(nfsstat3::NFS3ERR_EXIST);
+                }
+                let mount = fsmap.mount_for(&ent.name).ok_or(nfsstat3::NFS3ERR_ACCES)?;
+                let target = symlink_target(
+                    mount.symlink_targets,
+                    &mount.source,
+                    path.parent().unwrap_or(&path),
+                    Path::new(OsStr::from_bytes(target)),
+                )?;
+                tokio::fs::symlink(target, &path)
+                    .await
+                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+                // we do not set attributes on symlinks
+            }
+            CreateFSObject::Fifo(setattr) => {
+                debug!("mkfifo {:?}", path);
+                if exists_no_traverse(&path) {
+                    return Err(nfsstat3::NFS3ERR_EXIST);
+                }
+                let setattr = host_owner(&fsmap, &ent.name, *setattr)?;
+                let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+                    .map_err(|_| nfsstat3::NFS3ERR_INVAL)?;
+                // SAFETY: c_path is a NUL-terminated path that outlives the call
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o666) } != 0 {
+                    return Err(nfsstat3::NFS3ERR_IO);
+                }
+                // Setting a size would open the pipe, which blocks until the other end does
+                let setattr = sattr3 {
+                    size: set_size3::Void,
+                    ..setattr
+                };
+                let _ = path_setattr(&path, &setattr).await;
+            }
+        }
+
+        fsmap.invalidate(dirid);
+        let _ = fsmap.refresh_entry(dirid).await;
+
+        let sym = fsmap.intern_name(objectname_osstr)?;
+        let mut name = ent.name.clone();
+        name.push(sym);
+        let meta = path.symlink_metadata().map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        let fileid = fsmap.create_entry(&name, meta.clone()).await;
+
+        // update the children list
+        if let Some(ref mut children) = fsmap
+            .id_to_path
+            .get_mut(&dirid)
+            .ok_or(nfsstat3::NFS3ERR_NOENT)?
+            .children
+        {
+            children.insert(fileid);
+        }
+        if let (Some(write_once), CreateFSObject::File(_) | CreateFSObject::Exclusive) =
+            (&self.write_once, object)
+        {
+            write_once.created(fileid);
+        }
+        self.sync_parent(&path).await?;
+        let attr = fsmap.find_entry(fileid)?.fsmeta;
+        Ok((fileid, self.present_attr(&fsmap, attr)))
+    }
+
+    /// Largest file size clients may create in the export containing `id`
+    ///
+    /// The smaller of the `max_file_size` and backing file system limits of
+    /// every mount the export spans.
+    pub async fn max_file_size_for(&self, id: fileid3) -> Result<u64, nfsstat3> {
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+        let mounts: Vec<_> = match fsmap.mount_for(&ent.name) {
+            Some(mount) => vec![(mount.source.clone(), mount.max_file_size)],
+            // The synthetic root spans every mount
+            None => fsmap
+                .mounts
+                .iter()
+                .map(|m| (m.source.clone(), m.max_file_size))
+                .collect(),
+        };
+        drop(fsmap);
+
+        Ok(mounts
+            .iter()
+            .map(|(source, limit)| backing_max_file_size(source).min(limit.unwrap_or(u64::MAX)))
+            .min()
+            .unwrap_or(u64::MAX))
+    }
+
+    /// Compute the FSINFO property flags for the export containing `id`
+    pub async fn fsinfo_properties_for(&self, id: fileid3) -> Result<u32, nfsstat3> {
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+
+        let (sources, read_only) = match fsmap.sym_to_real_path(&ent.name).await {
+            Some((path, read_only)) => (vec![path], read_only),
             None => {
-                // This is a mount point, cannot remove objects here
-                return Err(nfsstat3::NFS3ERR_ACCES);
+                // The synthetic root spans every mount
+                let sources = fsmap.mounts.iter().map(|m| m.source.clone()).collect();
+                let all_read_only = fsmap.mounts.iter().all(|m| m.read_only);
+                (sources, all_read_only)
             }
         };
+        drop(fsmap);
+
+        let (links, symlinks) = sources
+            .iter()
+            .map(|source| backing_link_support(source))
+            .fold((true, true), |(l, s), (bl, bs)| (l && bl, s && bs));
+
+        Ok(fsinfo_properties(
+            self.is_read_only() || read_only,
+            links,
+            symlinks,
+            sources.len() == 1,
+        ))
+    }
+}
+
+/// `max_file_size` of the mount the entry at `sympath` is in
+fn max_file_size(fsmap: &FSMap, sympath: &[Symbol]) -> Option<u64> {
+    fsmap.mount_for(sympath)?.max_file_size
+}
+
+/// Largest file the file system backing `path` can hold
+fn backing_max_file_size(path: &Path) -> u64 {
+    // The limit the server advertised before it asked the backing store
+    const FALLBACK: u64 = 128 * 1024 * 1024 * 1024;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return FALLBACK;
+    };
+    // Bits of a signed integer needed to hold the largest file size
+    match unsafe { libc::pathconf(c_path.as_ptr(), libc::_PC_FILESIZEBITS) } {
+        bits if bits >= 64 => i64::MAX as u64,
+        bits if bits > 1 => (1u64 << (bits - 1)) - 1,
+        _ => FALLBACK,
+    }
+}
+
+/// Whether the entry at `sympath` is in an `append_only` mount
+fn is_append_only(fsmap: &FSMap, sympath: &[Symbol]) -> bool {
+    fsmap
+        .mount_for(sympath)
+        .is_some_and(|mount| mount.append_only)
+}
+
+/// Size of the file at `path`, 0 if it does not exist
+async fn current_size(path: &Path) -> Result<u64, nfsstat3> {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) => Ok(meta.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(_) => Err(nfsstat3::NFS3ERR_IO),
+    }
+}
+
+/// `setattr` with the owner it sets translated to source ids through the id
+/// maps of the mount `sympath` is in
+///
+/// Ids no range covers are refused with INVAL, as they cannot be stored.
+fn host_owner(fsmap: &FSMap, sympath: &[Symbol], mut setattr: sattr3) -> Result<sattr3, nfsstat3> {
+    let Some(mount) = fsmap.mount_for(sympath) else {
+        return Ok(setattr);
+    };
+    if let set_uid3::uid(uid) = setattr.uid {
+        let host = IdMap::to_host(&mount.uid_map, uid).ok_or(nfsstat3::NFS3ERR_INVAL)?;
+        setattr.uid = set_uid3::uid(host);
+    }
+    if let set_gid3::gid(gid) = setattr.gid {
+        let host = IdMap::to_host(&mount.gid_map, gid).ok_or(nfsstat3::NFS3ERR_INVAL)?;
+        setattr.gid = set_gid3::gid(host);
+    }
+    Ok(setattr)
+}
+
+/// Populate the file map by listing directories up to `max_depth` levels deep
+///
+/// The map is only locked while a single directory is listed, so requests are
+/// served normally while the preload runs.
+pub async fn preload(fsmap: Arc<tokio::sync::Mutex<FSMap>>, max_depth: Option<usize>) -> usize {
+    let start = Instant::now();
+    info!("Preloading mounts (max depth: {:?})", max_depth);
+    let mut listed = 0;
+    let mut queue = vec![(0, 0)];
+    while let Some((id, depth)) = queue.pop() {
+        let subdirs = match fsmap.lock().await.list_subdirs(id).await {
+            Ok(subdirs) => subdirs,
+            Err(e) => {
+                debug!("Preload failed to list {:?}: {:?}", id, e);
+                continue;
+            }
+        };
+        listed += 1;
+        if listed % 1000 == 0 {
+            info!("Preloaded {} directories", listed);
+        }
+        if max_depth.is_some_and(|max| depth >= max) {
+            continue;
+        }
+        queue.extend(subdirs.into_iter().map(|child| (child, depth + 1)));
+    }
+    info!(
+        "Preload finished: {} directories, {} entries in {:.1?}",
+        listed,
+        fsmap.lock().await.id_to_path.len(),
+        start.elapsed()
+    );
+    listed
+}
+
+/// Drop file names no longer in use from the intern table every `interval`
+///
+/// The map stays locked while the table is rebuilt, which only happens once
+/// most of the names it holds are dead.
+async fn compact_intern(fsmap: Arc<tokio::sync::Mutex<FSMap>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let mut fsmap = fsmap.lock().await;
+        let start = Instant::now();
+        match fsmap.compact_intern() {
+            Ok(0) => {}
+            Ok(dropped) => info!(
+                "Dropped {} unused file names, {} left, in {:.1?}",
+                dropped,
+                fsmap.intern.len(),
+                start.elapsed()
+            ),
+            Err(e) => warn!("Failed to compact the file name table: {:?}", e),
+        }
+    }
+}
+
+/// Open a backing file for reading, with `O_NOATIME` if `no_atime` is set
+///
+/// Only the owner of a file (or root) may open it with `O_NOATIME`; anyone
+/// else gets EPERM, and the file is opened normally instead.
+async fn open_for_read(path: &Path, no_atime: bool) -> std::io::Result<File> {
+    if no_atime && O_NOATIME != 0 {
+        let opened = OpenOptions::new()
+            .read(true)
+            .custom_flags(O_NOATIME)
+            .open(path)
+            .await;
+        match opened {
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                debug!("Not allowed to read {:?} without updating its atime", path);
+            }
+            opened => return opened,
+        }
+    }
+    File::open(path).await
+}
+
+/// Read up to `count` bytes at `offset`, returning the data and whether EOF was reached
+///
+/// With `skip_holes`, only the data regions reported by the file system are
+/// read; holes are left as the zeros the buffer starts with.
+async fn read_range(
+    path: &Path,
+    offset: u64,
+    count: u32,
+    skip_holes: bool,
+    no_atime: bool,
+) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut f = open_for_read(path, no_atime).await?;
+    let len = f.metadata().await?.len();
+    let mut start = offset;
+    let mut end = offset.saturating_add(count as u64);
+    let eof = end >= len;
+    if start >= len {
+        start = len;
+    }
+    if end > len {
+        end = len;
+    }
+    let mut buf = vec![0; (end - start) as usize];
+    let ranges = if skip_holes {
+        data_ranges(&f, start, end)
+    } else {
+        vec![(start, end)]
+    };
+    for (from, to) in ranges {
+        f.seek(SeekFrom::Start(from)).await?;
+        f.read_exact(&mut buf[(from - start) as usize..(to - start) as usize])
+            .await?;
+    }
+    Ok((buf, eof))
+}
+
+/// Allocate or, with `punch`, deallocate `len` bytes of `path` from `offset`
+///
+/// Falls back to growing the file, or to writing zeros over the range, where
+/// the file system does not support `fallocate`.
+fn space_of(path: &Path, offset: u64, len: u64, punch: bool) -> std::io::Result<std::fs::Metadata> {
+    use std::os::unix::fs::FileExt;
+
+    let file = std::fs::OpenOptions::new().write(true).open(path)?;
+    match fallocate(&file, offset, len, punch) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+            debug!("{:?} does not support fallocate, emulating it", path);
+            let size = file.metadata()?.len();
+            let end = offset.saturating_add(len);
+            if !punch && size < end {
+                file.set_len(end)?;
+            } else if punch && offset < size {
+                let stop = end.min(size);
+                let zeros = vec![0; ZERO_FILL_CHUNK.min((stop - offset) as usize)];
+                let mut pos = offset;
+                while pos < stop {
+                    let count = zeros.len().min((stop - pos) as usize);
+                    file.write_all_at(&zeros[..count], pos)?;
+                    pos += count as u64;
+                }
+            }
+        }
+        Err(e) => return Err(e),
+    }
+    file.metadata()
+}
+
+/// Reserve, or with `punch` free, a range of `file` on the backing file system
+#[cfg(target_os = "linux")]
+fn fallocate(file: &std::fs::File, offset: u64, len: u64, punch: bool) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mode = match punch {
+        true => libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+        false => 0,
+    };
+    // SAFETY: fallocate on an open descriptor, passing no memory
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            mode,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// `fallocate` is Linux only; elsewhere space changes are always emulated
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_file: &std::fs::File, _offset: u64, _len: u64, _punch: bool) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Regions of `start..end` in `file` that hold data, leaving out holes
+///
+/// Falls back to the whole range if the file system cannot report holes.
+fn data_ranges(file: &File, start: u64, end: u64) -> Vec<(u64, u64)> {
+    use std::os::fd::AsRawFd;
+
+    let seek = |offset: u64, whence| {
+        // SAFETY: lseek on an open descriptor; the position is reset before reading
+        let pos = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+        if pos >= 0 {
+            Ok(Some(pos as u64))
+        } else {
+            let err = std::io::Error::last_os_error();
+            // ENXIO: no more data after offset
+            match err.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(err),
+            }
+        }
+    };
+
+    let mut ranges = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let data = match seek(pos, libc::SEEK_DATA) {
+            Ok(Some(data)) if data < end => data,
+            Ok(_) => break,
+            Err(_) => return vec![(start, end)],
+        };
+        let hole = match seek(data, libc::SEEK_HOLE) {
+            Ok(Some(hole)) => hole.min(end),
+            _ => end,
+        };
+        ranges.push((data, hole));
+        pos = hole;
+    }
+    ranges
+}
+
+/// Resolve the target to store for a new symlink in `link_dir` under a mount's policy
+///
+/// With `SymlinkTargets::Relative`, an absolute target inside `source` is
+/// rewritten relative to `link_dir`; targets outside it are stored as sent.
+pub fn symlink_target(
+    mode: SymlinkTargets,
+    source: &Path,
+    link_dir: &Path,
+    target: &Path,
+) -> Result<PathBuf, nfsstat3> {
+    if !target.is_absolute() {
+        return Ok(target.to_path_buf());
+    }
+    match mode {
+        SymlinkTargets::Verbatim => Ok(target.to_path_buf()),
+        SymlinkTargets::RejectAbsolute => Err(nfsstat3::NFS3ERR_INVAL),
+        SymlinkTargets::Relative => {
+            let lexical = target
+                .components()
+                .all(|c| !matches!(c, Component::ParentDir));
+            if !lexical || !target.starts_with(source) || !link_dir.starts_with(source) {
+                return Ok(target.to_path_buf());
+            }
+            let mut link_parts = link_dir.components().peekable();
+            let mut target_parts = target.components().peekable();
+            while let (Some(l), Some(t)) = (link_parts.peek(), target_parts.peek()) {
+                if l != t {
+                    break;
+                }
+                link_parts.next();
+                target_parts.next();
+            }
+            let mut relative: PathBuf = link_parts.map(|_| Component::ParentDir).collect();
+            relative.extend(target_parts);
+            if relative.as_os_str().is_empty() {
+                relative.push(".");
+            }
+            Ok(relative)
+        }
+    }
+}
+
+/// Build FSINFO property flags from a mount's read-only status and backing capabilities
+///
+/// Read-only exports clear the flags that imply writes so clients don't attempt
+/// doomed link, symlink or set-time requests.
+pub fn fsinfo_properties(read_only: bool, links: bool, symlinks: bool, homogeneous: bool) -> u32 {
+    let mut properties = 0;
+    if !read_only {
+        if links {
+            properties |= FSF_LINK;
+        }
+        if symlinks {
+            properties |= FSF_SYMLINK;
+        }
+        properties |= FSF_CANSETTIME;
+    }
+    if homogeneous {
+        properties |= FSF_HOMOGENEOUS;
+    }
+    properties
+}
+
+/// Detect whether the file system backing `path` supports hard links and symlinks
+#[cfg(target_os = "linux")]
+fn backing_link_support(path: &std::path::Path) -> (bool, bool) {
+    // FAT-family file systems support neither hard links nor symlinks
+    const MSDOS_SUPER_MAGIC: i64 = 0x4d44;
+    const EXFAT_SUPER_MAGIC: i64 = 0x2011_bab0;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return (true, true);
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return (true, true);
+    }
+    match stat.f_type as i64 {
+        MSDOS_SUPER_MAGIC | EXFAT_SUPER_MAGIC => (false, false),
+        _ => (true, true),
+    }
+}
+
+/// Detect whether the file system backing `path` supports hard links and symlinks
+#[cfg(not(target_os = "linux"))]
+fn backing_link_support(_path: &std::path::Path) -> (bool, bool) {
+    (true, true)
+}
+
+#[async_trait]
+impl NFSFileSystem for MirrorFS {
+    fn root_dir(&self) -> fileid3 {
+        0
+    }
+
+    fn capabilities(&self) -> VFSCapabilities {
+        if self.is_read_only() {
+            VFSCapabilities::ReadOnly
+        } else {
+            VFSCapabilities::ReadWrite
+        }
+    }
+
+    async fn lookup(
+        &self,
+        _auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        let mut fsmap = self.fsmap.lock().await;
+        if let Ok(id) = fsmap.find_child(dirid, filename).await {
+            if fsmap.id_to_path.contains_key(&id) {
+                Metrics::incr(&self.metrics.lookup_hits);
+                return Ok(id);
+            }
+        }
+        // A snapshot never picks up files created after it was taken
+        if fsmap.frozen {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        // Optimize for negative lookups.
+        // See if the file actually exists on the filesystem
+        let dirent = fsmap.find_entry(dirid)?;
+        if !matches!(dirent.fsmeta.ftype, ftype3::NF3DIR) {
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+
+        // The date view and archives only exist in the map, so a miss means
+        // they need listing
+        if fsmap.in_date_view(&dirent.name)
+            || fsmap.is_date_view(&dirent.name, filename)
+            || fsmap.is_status_name(&dirent.name, filename)
+            || fsmap.in_archive(&dirent.name)
+        {
+            drop(fsmap);
+            let (mut fsmap, listed) = refresh_dir_list_unlocked(&self.fsmap, dirid).await;
+            listed?;
+            return fsmap.find_child(dirid, filename).await;
+        }
+
+        // Get the real file system path for the directory. Mount points resolve
+        // to their source like any other directory; only the synthetic root has
+        // no real path, and its children (the mounts) are always cached.
+        let (dir_path, _dir_read_only) = match fsmap.sym_to_real_path(&dirent.name).await {
+            Some(path) => path,
+            None => return Err(nfsstat3::NFS3ERR_NOENT),
+        };
+
+        let mut path = dir_path;
+        let objectname_osstr = OsStr::from_bytes(filename).to_os_string();
+        path.push(&objectname_osstr);
+        // Symbols may be renumbered while the lock is released, so none is kept
+        let loose_matching = fsmap
+            .mount_for(&dirent.name)
+            .is_some_and(names::has_loose_matching);
+        // A hung backing store only stalls this lookup, not every client
+        drop(fsmap);
+        let stat = stat_unlocked(&self.fsmap, &path).await;
+        let mut fsmap = self.fsmap.lock().await;
+        if let Err(e) = stat {
+            if let nfsstat3::NFS3ERR_NOENT = e {
+                // The name may match a differently spelled entry on disk
+                if loose_matching {
+                    let _ = fsmap.refresh_dir_list(dirid).await;
+                    if let Ok(id) = fsmap.find_child(dirid, filename).await {
+                        return Ok(id);
+                    }
+                }
+                Metrics::incr(&self.metrics.lookup_negative);
+            }
+            return Err(e);
+        }
+        Metrics::incr(&self.metrics.lookup_misses);
+        // ok the file actually exists.
+        // that means something changed under me probably.
+        // refresh.
+        drop(fsmap);
+        let (mut fsmap, refreshed) = refresh_unlocked(&self.fsmap, dirid).await;
+        if let RefreshResult::Delete = refreshed? {
+            self.drop_cached(dirid);
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        let _ = fsmap.refresh_dir_list(dirid).await;
+
+        fsmap.find_child(dirid, filename).await
+    }
+
+    async fn getattr(&self, _auth: &AuthContext, id: fileid3) -> Result<fattr3, nfsstat3> {
+        //debug!("Stat query {:?}", id);
+        if let Some(frozen) = self.frozen_view.get().and_then(|view| view.get(id)) {
+            return Ok(frozen.attr);
+        }
+        {
+            let fsmap = self.fsmap.lock().await;
+            // A file deleted while in use stays visible until it goes idle,
+            // without dropping its entry
+            if let Some(attr) = self.unlinked_open_attr(&fsmap, id).await {
+                return Ok(self.present_attr(&fsmap, attr));
+            }
+        }
+        let (fsmap, refreshed) = refresh_unlocked(&self.fsmap, id).await;
+        if let RefreshResult::Delete = refreshed? {
+            self.drop_cached(id);
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        let ent = fsmap.find_entry(id)?;
+        let path = fsmap.sym_to_path(&ent.name).await;
+        debug!("Stat {:?}: {:?}", path, ent);
+        Ok(self.present_attr(&fsmap, ent.fsmeta))
+    }
+
+    async fn read(
+        &self,
+        _auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+    ) -> Result<(Vec<u8>, bool), nfsstat3> {
+        if let Some(frozen) = self.frozen_view.get().and_then(|view| view.get(id)) {
+            if let Some(ref source) = frozen.source {
+                let reply = self
+                    .read_file(id, &source.path, source.use_mmap, offset, count)
+                    .await?;
+                if let Some(mount) = frozen.mount {
+                    self.metrics
+                        .mount_access
+                        .add_read(mount, reply.0.len() as u64);
+                }
+                return Ok(reply);
+            }
+        }
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+
+        // Members of an archive are read out of the archive file
+        if fsmap.in_archive(&ent.name) {
+            let (source, member) = fsmap
+                .archive_member(&ent.name)
+                .ok_or(nfsstat3::NFS3ERR_ISDIR)?;
+            let mount = fsmap.mount_index(&ent.name);
+            drop(fsmap);
+            let reply = tokio::task::spawn_blocking(move || {
+                archive::read_member(&source, &member, offset, count)
+            })
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_SERVERFAULT)?
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            if let Some(mount) = mount {
+                self.metrics
+                    .mount_access
+                    .add_read(mount, reply.0.len() as u64);
+            }
+            return Ok(reply);
+        }
+
+        // Opening a pipe blocks until a writer shows up on the server
+        if matches!(ent.fsmeta.ftype, ftype3::NF3FIFO) {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+
+        // Status files are generated anew for every read
+        if let Some(content) = fsmap.status_content(&ent.name) {
+            let start = content.len().min(offset as usize);
+            let end = content.len().min(start + count as usize);
+            return Ok((content[start..end].to_vec(), end == content.len()));
+        }
+
+        // Get the real file system path
+        let (path, _read_only) = match fsmap.real_path_of(id).await {
+            Some(path) => path,
+            None => {
+                // This is the synthetic root, cannot read
+                return Err(nfsstat3::NFS3ERR_ISDIR);
+            }
+        };
+        let use_mmap = fsmap.mount_for(&ent.name).is_some_and(|m| m.use_mmap);
+
+        let mount = fsmap.mount_index(&ent.name);
+
+        drop(fsmap);
+        let reply = self.read_file(id, &path, use_mmap, offset, count).await?;
+        if let Some(mount) = mount {
+            self.metrics
+                .mount_access
+                .add_read(mount, reply.0.len() as u64);
+        }
+        Ok(reply)
+    }
+
+    async fn readdir(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        start_after: fileid3,
+        max_entries: usize,
+    ) -> Result<ReadDirResult, nfsstat3> {
+        // Independent exports are reached by looking up their targets from
+        // the root, as MOUNT does, but are not listed there
+        if dirid == self.root_dir() && self.export_root == ExportRoot::PerMount {
+            return Ok(ReadDirResult {
+                entries: Vec::new(),
+                end: true,
+            });
+        }
+
+        // A snapshot's listings never change, so every page comes from the same one
+        if let Some(view) = self.frozen_view.get() {
+            if let Some(frozen) = view.get(dirid) {
+                if let Some(ref listing) = frozen.listing {
+                    let start = self.resume_at(listing, dirid, frozen.parent, start_after)?;
+                    let mut entries =
+                        self.dot_entries(dirid, frozen.parent, start_after, max_entries, |id| {
+                            view.get(id).map(|entry| entry.attr)
+                        });
+                    let room = max_entries - entries.len();
+                    let page = &listing[start..listing.len().min(start + room)];
+                    entries.extend(page.iter().map(|listed| DirEntry {
+                        fileid: listed.fileid,
+                        name: listed.name.as_bytes().into(),
+                        attr: listed.attr,
+                    }));
+                    return Ok(ReadDirResult {
+                        entries,
+                        end: start + page.len() == listing.len(),
+                    });
+                }
+            }
+        }
+
+        let mut fsmap = self.fsmap.lock().await;
+        let parent = match self.readdir_dot_entries {
+            true => fsmap.parent_of(dirid).unwrap_or(dirid),
+            false => dirid,
+        };
+
+        // Continue a paginated enumeration over the listing it started with,
+        // without relisting the directory, also after a page that ended on
+        // `.` or `..`. A fresh enumeration (cookie 0) never matches a kept listing.
+        let dots = match self.readdir_dot_entries {
+            true => vec![dirid, parent],
+            false => Vec::new(),
+        };
+        let kept = self.dir_snapshots.get(dirid, auth.uid, start_after, &dots);
+        let (verifier, listing) = match kept {
+            Some((verifier, listing)) => (Some(verifier), listing),
+            None => {
+                drop(fsmap);
+                let refreshed;
+                (fsmap, refreshed) = refresh_unlocked(&self.fsmap, dirid).await;
+                refreshed?;
+                drop(fsmap);
+                let listed;
+                (fsmap, listed) = refresh_dir_list_unlocked(&self.fsmap, dirid).await;
+                listed?;
+                debug!("readdir({:?}, {:?})", dirid, start_after);
+                (None, Arc::new(self.list_dir(&fsmap, dirid).await?))
+            }
+        };
+
+        // Resume after the cookie's position in the chosen order
+        let start = self.resume_at(&listing, dirid, parent, start_after)?;
+
+        debug!("children len: {:?}", listing.len());
+        debug!("remaining_len : {:?}", listing.len() - start);
+        let mut ret = ReadDirResult {
+            entries: self.dot_entries(dirid, parent, start_after, max_entries, |id| {
+                let entry = fsmap.id_to_path.get(&id)?;
+                Some(self.present_attr(&fsmap, entry.fsmeta))
+            }),
+            end: false,
+        };
+        let room = max_entries - ret.entries.len();
+        let page = &listing[start..listing.len().min(start + room)];
+        for listed in page {
+            debug!("\t --- {:?} {:?}", listed.fileid, listed.name);
+            let mut attr = listed.attr;
+            // A listing only changes with its directory, not with the files in it
+            if self.readdir_refresh_attrs {
+                match fsmap.refresh_entry(listed.fileid).await {
+                    Ok(RefreshResult::Delete) => {
+                        self.drop_cached(listed.fileid);
+                        continue;
+                    }
+                    Err(nfsstat3::NFS3ERR_NOENT) => continue,
+                    Ok(_) => attr = fsmap.find_entry(listed.fileid).map_or(attr, |e| e.fsmeta),
+                    Err(_) => {}
+                }
+            }
+            ret.entries.push(DirEntry {
+                fileid: listed.fileid,
+                name: listed.name.as_bytes().into(),
+                attr: self.present_attr(&fsmap, attr),
+            });
+        }
+        let served = start + page.len();
+        ret.end = served == listing.len();
+        match verifier {
+            Some(verifier) if ret.end => self.dir_snapshots.remove(verifier),
+            Some(verifier) => self.dir_snapshots.advance(verifier, served),
+            None if ret.end => {}
+            None => {
+                self.dir_snapshots.insert(dirid, auth.uid, listing, served);
+            }
+        }
+        debug!("readdir_result:{:?}", ret);
+
+        Ok(ret)
+    }
+
+    async fn setattr(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        setattr: sattr3,
+    ) -> Result<fattr3, nfsstat3> {
+        self.authorize_write(auth, id).await?;
+        self.apply_setattr(id, setattr).await
+    }
+
+    async fn write(
+        &self,
+        auth: &AuthContext,
+        id: fileid3,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<fattr3, nfsstat3> {
+        self.authorize_write(auth, id).await?;
+        self.check_unsealed(id)?;
+        self.invalidate_read_ahead(id);
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+        // Opening a pipe blocks until a reader shows up on the server
+        if matches!(ent.fsmeta.ftype, ftype3::NF3FIFO) {
+            return Err(nfsstat3::NFS3ERR_INVAL);
+        }
+
+        // Get the real file system path
+        let (path, read_only) = match fsmap.real_path_of(id).await {
+            Some(path) => path,
+            None => {
+                // This is the synthetic root, cannot write
+                return Err(nfsstat3::NFS3ERR_ISDIR);
+            }
+        };
+
+        if read_only {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+        let mount = fsmap.mount_index(&ent.name);
+        let append_only = is_append_only(&fsmap, &ent.name);
+        let max_size = max_file_size(&fsmap, &ent.name);
+
+        drop(fsmap);
+        let _order = self.order_write(id).await;
+        if append_only && offset < current_size(&path).await? {
+            debug!("Denying overwrite of {:?} at {}", path, offset);
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+        if let Some(max) = max_size {
+            let end = offset.saturating_add(data.len() as u64);
+            if end > max && end > current_size(&path).await? {
+                debug!("Denying growth of {:?} to {} bytes", path, end);
+                return Err(nfsstat3::NFS3ERR_FBIG);
+            }
+        }
+        self.remember_open(id, &path);
+        let _io = self.io_slot().await;
+        debug!("write to init {:?}", path);
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                debug!("Unable to open {:?}", e);
+                nfsstat3::NFS3ERR_IO
+            })?;
+        f.seek(SeekFrom::Start(offset)).await.map_err(|e| {
+            debug!("Unable to seek {:?}", e);
+            nfsstat3::NFS3ERR_IO
+        })?;
+        f.write_all(data).await.map_err(|e| {
+            debug!("Unable to write {:?}", e);
+            nfsstat3::NFS3ERR_IO
+        })?;
+        debug!("write to {:?} {:?} {:?}", path, offset, data.len());
+        if let Some(mount) = mount {
+            self.metrics
+                .mount_access
+                .add_written(mount, data.len() as u64);
+        }
+        let _ = f.flush().await;
+        f.sync_all().await.or(Err(nfsstat3::NFS3ERR_IO))?;
+        let meta = f.metadata().await.or(Err(nfsstat3::NFS3ERR_IO))?;
+        let mut fsmap = self.fsmap.lock().await;
+        fsmap.invalidate(id);
+        Ok(self.present_attr(&fsmap, fattr3_from_metadata(id, &meta)))
+    }
+
+    async fn create(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+        setattr: sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.authorize_write(auth, dirid).await?;
+        let request = Request::Create {
+            dir: dirid,
+            name: filename.to_vec(),
+        };
+        self.create_once(request, dirid, filename, &CreateFSObject::File(setattr))
+            .await
+    }
+
+    async fn create_exclusive(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<fileid3, nfsstat3> {
+        self.authorize_write(auth, dirid).await?;
+        let request = Request::Create {
+            dir: dirid,
+            name: filename.to_vec(),
+        };
+        Ok(self
+            .create_once(request, dirid, filename, &CreateFSObject::Exclusive)
+            .await?
+            .0)
+    }
+
+    async fn remove(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.authorize_write(auth, dirid).await?;
+        let request = Request::Remove {
+            dir: dirid,
+            name: filename.to_vec(),
+        };
+
+        let mut fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(dirid)?;
+
+        // Get the real file system path for the directory
+        let (dir_path, dir_read_only) = fsmap.real_dir_path(&ent.name).await?;
+
+        if dir_read_only {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+
+        let mut path = dir_path;
+        path.push(OsStr::from_bytes(filename));
+
+        let filesym = fsmap.intern_name(OsStr::from_bytes(filename).to_os_string())?;
+        let mut sympath = ent.name.clone();
+        sympath.push(filesym);
+
+        let Ok(meta) = path.symlink_metadata() else {
+            // A retransmitted REMOVE finds the file already gone. Succeed if we
+            // knew the file (stale cache entry) or just removed it ourselves.
+            let cached = fsmap.path_to_id.contains_key(&sympath);
+            if cached || self.duplicate_requests.get(&request).is_some() {
+                debug!("remove {:?}: already gone", path);
+                self.forget_removed(&mut fsmap, dirid, &sympath).await;
+                return Ok(());
+            }
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        };
+
+        if is_append_only(&fsmap, &ent.name)
+            || self.is_sealed(&path, fsmap.path_to_id.get(&sympath).copied())
+        {
+            debug!("Denying removal of {:?}", path);
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+
+        if let Some(fileid) = fsmap.path_to_id.get(&sympath).copied() {
+            // Another hard link keeps an open file reachable anyway
+            let linked = fsmap.hard_links.contains_key(&fileid);
+            if !meta.is_dir() && !linked && self.wants_silly_rename(fileid) {
+                self.silly_rename(&mut fsmap, dirid, fileid, &path, &sympath)
+                    .await?;
+                self.duplicate_requests.insert(request, Reply::Done);
+                return Ok(());
+            }
+        }
+
+        if meta.is_dir() {
+            tokio::fs::remove_dir(&path)
+                .await
+                .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        } else {
+            tokio::fs::remove_file(&path)
+                .await
+                .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+        }
+        self.duplicate_requests.insert(request, Reply::Done);
+        self.forget_removed(&mut fsmap, dirid, &sympath).await;
+        self.sync_parent(&path).await?;
+
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        auth: &AuthContext,
+        from_dirid: fileid3,
+        from_filename: &filename3,
+        to_dirid: fileid3,
+        to_filename: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.authorize_write(auth, from_dirid).await?;
+        self.authorize_write(auth, to_dirid).await?;
+        let request = Request::Rename {
+            from_dir: from_dirid,
+            from_name: from_filename.to_vec(),
+            to_dir: to_dirid,
+            to_name: to_filename.to_vec(),
+        };
+        if self.duplicate_requests.get(&request).is_some() {
+            debug!("Replaying {:?}", request);
+            return Ok(());
+        }
+
+        let mut fsmap = self.fsmap.lock().await;
+
+        let from_dirent = fsmap.find_entry(from_dirid)?;
+        let (from_dir_path, from_read_only) = fsmap.real_dir_path(&from_dirent.name).await?;
+
+        let to_dirent = fsmap.find_entry(to_dirid)?;
+        let (to_dir_path, to_read_only) = fsmap.real_dir_path(&to_dirent.name).await?;
+
+        if from_read_only || to_read_only {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+
+        let mut from_path = from_dir_path;
+        from_path.push(OsStr::from_bytes(from_filename));
+
+        let mut to_path = to_dir_path;
+        // to folder must exist
+        if !exists_no_traverse(&to_path) {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        to_path.push(OsStr::from_bytes(to_filename));
+
+        // src path must exist
+        if !exists_no_traverse(&from_path) {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        // Nothing is moved out of an append-only mount, nor replaced in one,
+        // and sealed files are neither moved nor replaced
+        let from_id = fsmap.find_child(from_dirid, from_filename).await.ok();
+        let to_id = fsmap.find_child(to_dirid, to_filename).await.ok();
+        if is_append_only(&fsmap, &from_dirent.name)
+            || (is_append_only(&fsmap, &to_dirent.name) && exists_no_traverse(&to_path))
+            || self.is_sealed(&from_path, from_id)
+            || self.is_sealed(&to_path, to_id)
+        {
+            debug!("Denying rename of {:?} to {:?}", from_path, to_path);
+            return Err(nfsstat3::NFS3ERR_ACCES);
+        }
+        // Interned before renaming, so a full symbol table cannot leave the
+        // file map behind the backing store
+        let oldsym = fsmap.intern_name(OsStr::from_bytes(from_filename).to_os_string())?;
+        let newsym = fsmap.intern_name(OsStr::from_bytes(to_filename).to_os_string())?;
+        debug!("Rename {:?} to {:?}", from_path, to_path);
+        tokio::fs::rename(&from_path, &to_path)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+
+        let mut from_sympath = from_dirent.name.clone();
+        from_sympath.push(oldsym);
+        let mut to_sympath = to_dirent.name.clone();
+        to_sympath.push(newsym);
+        let moved_id = fsmap.path_to_id.get(&from_sympath).copied();
+        // Renaming a hard link over another link of the same file changes nothing
+        let same_file =
+            moved_id.is_some() && fsmap.path_to_id.get(&to_sympath) == moved_id.as_ref();
+        if let Some(fileid) = moved_id.filter(|_| !same_file) {
+            // update the fileid -> path
+            // and the path -> fileid mappings for the new file. Another hard
+            // link only moves that link, the entry keeps its name.
+            if !fsmap.move_link(fileid, &from_sympath, to_sympath.clone()) {
+                fsmap.move_entry(fileid, to_sympath);
+            }
+            if to_dirid != from_dirid {
+                // moving across directories.
+                // we need to update the children listing for the directories
+                let still_linked = fsmap.has_link_in(fileid, &from_dirent.name);
+                if let Ok(from_dirent_mut) = fsmap.find_entry_mut(from_dirid) {
+                    if let Some(ref mut fromch) = from_dirent_mut.children {
+                        if !still_linked {
+                            fromch.remove(&fileid);
+                        }
+                    }
+                }
+                if let Ok(to_dirent_mut) = fsmap.find_entry_mut(to_dirid) {
+                    if let Some(ref mut toch) = to_dirent_mut.children {
+                        toch.insert(fileid);
+                    }
+                }
+            }
+        }
+        fsmap.invalidate(from_dirid);
+        fsmap.invalidate(to_dirid);
+        let _ = fsmap.refresh_entry(from_dirid).await;
+        if to_dirid != from_dirid {
+            let _ = fsmap.refresh_entry(to_dirid).await;
+        }
+        self.duplicate_requests.insert(request, Reply::Done);
+
+        self.sync_parent(&to_path).await?;
+        if to_dirid != from_dirid {
+            self.sync_parent(&from_path).await?;
+        }
+        Ok(())
+    }
+
+    async fn mkdir(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        dirname: &filename3,
+        _attrs: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.authorize_write(auth, dirid).await?;
+        let request = Request::Mkdir {
+            dir: dirid,
+            name: dirname.to_vec(),
+        };
+        self.create_once(request, dirid, dirname, &CreateFSObject::Directory)
+            .await
+    }
+
+    async fn symlink(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        linkname: &filename3,
+        symlink: &nfspath3,
+        attr: &sattr3,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.authorize_write(auth, dirid).await?;
+        let request = Request::Symlink {
+            dir: dirid,
+            name: linkname.to_vec(),
+            target: symlink.to_vec(),
+        };
+        self.create_once(
+            request,
+            dirid,
+            linkname,
+            &CreateFSObject::Symlink((*attr, symlink.clone())),
+        )
+        .await
+    }
+
+    async fn readlink(&self, _auth: &AuthContext, id: fileid3) -> Result<nfspath3, nfsstat3> {
+        let fsmap = self.fsmap.lock().await;
+        let ent = fsmap.find_entry(id)?;
+
+        if fsmap.in_archive(&ent.name) {
+            return match fsmap
+                .archive_member(&ent.name)
+                .map(|(_, member)| member.kind)
+            {
+                Some(MemberKind::Symlink(target)) => Ok(target.as_os_str().as_bytes().into()),
+                _ => Err(nfsstat3::NFS3ERR_BADTYPE),
+            };
+        }
+
+        // Get the real file system path
+        let (path, _read_only) = match fsmap.sym_to_real_path(&ent.name).await {
+            Some(path) => path,
+            None => {
+                // This is the synthetic root, cannot readlink
+                return Err(nfsstat3::NFS3ERR_BADTYPE);
+            }
+        };
+
+        drop(fsmap);
+        if path.is_symlink() {
+            if let Ok(target) = path.read_link() {
+                Ok(target.as_os_str().as_bytes().into())
+            } else {
+                Err(nfsstat3::NFS3ERR_IO)
+            }
+        } else {
+            Err(nfsstat3::NFS3ERR_BADTYPE)
+        }
+    }
+
+    async fn mknod(
+        &self,
+        auth: &AuthContext,
+        dirid: fileid3,
+        filename: &filename3,
+        ftype: ftype3,
+        attr: &sattr3,
+        spec: Option<&specdata3>,
+    ) -> Result<(fileid3, fattr3), nfsstat3> {
+        self.authorize_write(auth, dirid).await?;
+        // Device files need elevated privileges and sockets only exist while
+        // a process listens on them, so those are mirrored as regular files
+        match ftype {
+            ftype3::NF3CHR | ftype3::NF3BLK => {
+                // Create a regular file to represent the device
+                // In a real implementation, you would use spec.specdata1 (major) and spec.specdata2 (minor)
+                if let Some(_device_spec) = spec {
+                    // Could log or store device major/minor info here
+                }
+                self.create_fs_object(dirid, filename, &CreateFSObject::File(*attr))
+                    .await
+            }
+            ftype3::NF3SOCK => {
+                self.create_fs_object(dirid, filename, &CreateFSObject::File(*attr))
+                    .await
+            }
+            ftype3::NF3FIFO => {
+                self.create_fs_object(dirid, filename, &CreateFSObject::Fifo(*attr))
+                    .await
+            }
+            _ => Err(nfsstat3::NFS3ERR_BADTYPE),
+        }
+    }
+
+    async fn link(
+        &self,
+        auth: &AuthContext,
+        fileid: fileid3,
+        linkdirid: fileid3,
+        linkname: &filename3,
+    ) -> Result<(), nfsstat3> {
+        self.authorize_write(auth, linkdirid).await?;
+        let request = Request::Link {
+            file: fileid,
+            dir: linkdirid,
+            name: linkname.to_vec(),
+        };
+        if self.duplicate_requests.get(&request).is_some() {
+            debug!("Replaying {:?}", request);
+            return Ok(());
+        }
+
+        let mut fsmap = self.fsmap.lock().await;
+
+        // Get the file path
+        let file_entry = fsmap.find_entry(fileid)?;
+        let (file_path, _file_read_only) = match fsmap.sym_to_real_path(&file_entry.name).await {
+            Some(path) => path,
+            None => {
+                // This is the synthetic root, cannot link
+                return Err(nfsstat3::NFS3ERR_ACCES);
+            }
+        };
+
+        // Get the link directory path
+        let linkdir_entry = fsmap.find_entry(linkdirid)?;
+        let (link_dir_path, link_read_only) = fsmap.real_dir_path(&linkdir_entry.name).await?;
+
+        if link_read_only {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+
+        let mut link_path = link_dir_path;
+        link_path.push(OsStr::from_bytes(linkname));
+        let link_sym = fsmap.intern_name(OsStr::from_bytes(linkname).to_os_string())?;
+
+        // Create the hard link
+        tokio::fs::hard_link(&file_path, &link_path)
+            .await
+            .map_err(|e| {
+                debug!("Failed to create hard link: {:?}", e);
+                match e.kind() {
+                    std::io::ErrorKind::PermissionDenied => nfsstat3::NFS3ERR_ACCES,
+                    std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
+                    std::io::ErrorKind::AlreadyExists => nfsstat3::NFS3ERR_EXIST,
+                    _ => nfsstat3::NFS3ERR_IO,
+                }
+            })?;
+
+        // Update the fsmap with the new link
+        let mut link_sympath = linkdir_entry.name.clone();
+        link_sympath.push(link_sym);
+
+        // The link points to the same fileid as the original file
+        fsmap.add_link(fileid, link_sympath);
+
+        // Update the directory's children if needed
+        if let Ok(linkdir_entry_mut) = fsmap.find_entry_mut(linkdirid) {
+            if let Some(ref mut children) = linkdir_entry_mut.children {
+                children.insert(fileid);
+            }
+        }
+        // The link count and the directory both changed
+        fsmap.invalidate(fileid);
+        fsmap.invalidate(linkdirid);
+        self.duplicate_requests.insert(request, Reply::Done);
+
+        self.sync_parent(&link_path).await
+    }
+
+    async fn fsinfo(&self, auth: &AuthContext, root_fileid: fileid3) -> Result<fsinfo3, nfsstat3> {
+        let obj_attributes = match self.getattr(auth, root_fileid).await {
+            Ok(attr) => post_op_attr::attributes(attr),
+            Err(_) => post_op_attr::Void,
+        };
+        let properties = self.fsinfo_properties_for(root_fileid).await?;
+        debug!("fsinfo {:?} properties {:#x}", root_fileid, properties);
+
+        let mut info = fsinfo3 {
+            obj_attributes,
+            rtmax: 1024 * 1024,
+            rtpref: 1024 * 124,
+            rtmult: 1024 * 1024,
+            wtmax: 1024 * 1024,
+            wtpref: 1024 * 1024,
+            wtmult: 1024 * 1024,
+            dtpref: 1024 * 1024,
+            maxfilesize: self.max_file_size_for(root_fileid).await?,
+            time_delta: nfstime3 {
+                seconds: 0,
+                nseconds: 1000000,
+            },
+            properties,
+        };
+        // Every mount advertises the same block size, whatever its backing
+        // store uses; `used` in the attributes stays the real allocation
+        if let Some(block) = self.block_size {
+            info.rtmult = block;
+            info.wtmult = block;
+            info.rtpref = (info.rtpref / block).max(1) * block;
+            info.wtpref = (info.wtpref / block).max(1) * block;
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{NonUtf8Names, OVERFLOW_ID, UnicodeForm};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    /// Create an empty scratch directory for a test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nfs_mirror_{}_{}_{}",
+            name,
+            std::process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn auth() -> AuthContext {
+        AuthContext {
+            uid: 0,
+            gid: 0,
+            gids: vec![],
+        }
+    }
+
+    fn fname(name: &str) -> filename3 {
+        name.as_bytes().into()
+    }
+
+    /// Build a MirrorFS exposing `source` at `/test`, returning it with the mount's fileid
+    async fn mirror(source: &std::path::Path, server: ServerConfig) -> (MirrorFS, fileid3) {
+        mirror_mount(MountConfig::new(source, "/test"), server).await
+    }
+
+    /// Build a MirrorFS exposing a single mount at `/test`
+    async fn mirror_mount(mount: MountConfig, server: ServerConfig) -> (MirrorFS, fileid3) {
+        let fs = MirrorFS::new_with_mounts(mount.source.clone(), &server, vec![mount]);
+        let mount_id = fs.lookup(&auth(), 0, &fname("test")).await.unwrap();
+        (fs, mount_id)
+    }
+
+    #[test]
+    fn test_fsinfo_properties_read_only_mount() {
+        let rw = fsinfo_properties(false, true, true, true);
+        assert_eq!(
+            rw,
+            FSF_LINK | FSF_SYMLINK | FSF_CANSETTIME | FSF_HOMOGENEOUS
+        );
+
+        let ro = fsinfo_properties(true, true, true, true);
+        assert_eq!(ro & (FSF_LINK | FSF_SYMLINK | FSF_CANSETTIME), 0);
+        assert_ne!(ro & FSF_HOMOGENEOUS, 0);
+
+        let no_links = fsinfo_properties(false, false, false, false);
+        assert_eq!(no_links, FSF_CANSETTIME);
+    }
+
+    #[test]
+    fn test_symlink_target_modes() {
+        let source = Path::new("/srv/src");
+        let link_dir = Path::new("/srv/src/a/b");
+        let inside = Path::new("/srv/src/a/c/file");
+        let outside = Path::new("/etc/passwd");
+        let relative = Path::new("../c/file");
+
+        for target in [inside, outside, relative] {
+            assert_eq!(
+                symlink_target(SymlinkTargets::Verbatim, source, link_dir, target).unwrap(),
+                target
+            );
+        }
+
+        let rewrite = |target| symlink_target(SymlinkTargets::Relative, source, link_dir, target);
+        assert_eq!(rewrite(inside).unwrap(), Path::new("../c/file"));
+        assert_eq!(rewrite(Path::new("/srv/src")).unwrap(), Path::new("../.."));
+        assert_eq!(rewrite(Path::new("/srv/src/a/b")).unwrap(), Path::new("."));
+        assert_eq!(rewrite(outside).unwrap(), outside);
+        assert_eq!(rewrite(relative).unwrap(), relative);
+
+        let reject =
+            |target| symlink_target(SymlinkTargets::RejectAbsolute, source, link_dir, target);
+        assert!(matches!(reject(inside), Err(nfsstat3::NFS3ERR_INVAL)));
+        assert!(matches!(reject(outside), Err(nfsstat3::NFS3ERR_INVAL)));
+        assert_eq!(reject(relative).unwrap(), relative);
+    }
+
+    #[tokio::test]
+    async fn test_fsinfo_per_mount() {
+        let rw_dir = scratch_dir("fsinfo_rw");
+        let ro_dir = scratch_dir("fsinfo_ro");
+        let mounts = vec![
+            MountConfig::new(rw_dir.clone(), "/rw"),
+            MountConfig {
+                read_only: true,
+                ..MountConfig::new(ro_dir.clone(), "/ro")
+            },
+        ];
+        let fs = MirrorFS::new_with_mounts(rw_dir.clone(), &ServerConfig::default(), mounts);
+        let rw_id = fs.lookup(&auth(), 0, &fname("rw")).await.unwrap();
+        let ro_id = fs.lookup(&auth(), 0, &fname("ro")).await.unwrap();
+
+        let rw = fs.fsinfo(&auth(), rw_id).await.unwrap().properties;
+        let ro = fs.fsinfo(&auth(), ro_id).await.unwrap().properties;
+        assert_ne!(rw & FSF_CANSETTIME, 0);
+        assert_eq!(ro & (FSF_LINK | FSF_SYMLINK | FSF_CANSETTIME), 0);
+
+        let _ = std::fs::remove_dir_all(&rw_dir);
+        let _ = std::fs::remove_dir_all(&ro_dir);
+    }
+
+    #[tokio::test]
+    async fn test_getattr_reports_forced_owner() {
+        let dir = scratch_dir("force_owner");
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        let server = ServerConfig {
+            force_uid: Some(4242),
+            force_gid: Some(4343),
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        let attr = fs.getattr(&auth(), id).await.unwrap();
+        assert_eq!((attr.uid, attr.gid), (4242, 4343));
+        let mount_attr = fs.getattr(&auth(), mount_id).await.unwrap();
+        assert_eq!((mount_attr.uid, mount_attr.gid), (4242, 4343));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_ignores_later_changes() {
+        let dir = scratch_dir("snapshot");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"data").unwrap();
+        let server = ServerConfig {
+            snapshot: true,
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        fs.take_snapshot().await;
+
+        std::fs::write(dir.join("late"), b"late").unwrap();
+        std::fs::write(dir.join("sub/file"), b"changed!").unwrap();
+
+        assert!(fs.lookup(&auth(), mount_id, &fname("late")).await.is_err());
+        let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+        let file = fs.lookup(&auth(), sub, &fname("file")).await.unwrap();
+        assert_eq!(fs.getattr(&auth(), file).await.unwrap().size, 4);
+        assert!(matches!(
+            fs.write(&auth(), file, 0, b"x").await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_served_without_locking() {
+        let dir = scratch_dir("frozen_view");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"data").unwrap();
+        let server = ServerConfig {
+            snapshot: true,
+            force_uid: Some(1234),
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        fs.take_snapshot().await;
+        let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+        let file = fs.lookup(&auth(), sub, &fname("file")).await.unwrap();
+
+        // Answered while something else holds the map
+        let _fsmap = fs.fsmap.lock().await;
+        let served = async {
+            let attr = fs.getattr(&auth(), file).await.unwrap();
+            assert_eq!((attr.size, attr.uid), (4, 1234));
+            assert_eq!(fs.read(&auth(), file, 0, 16).await.unwrap().0, b"data");
+            let listing = fs.readdir(&auth(), sub, 0, 16).await.unwrap();
+            assert!(listing.end);
+            assert_eq!(listing.entries.len(), 1);
+            assert_eq!(listing.entries[0].fileid, file);
+            assert_eq!(listing.entries[0].attr.uid, 1234);
+        };
+        tokio::time::timeout(Duration::from_secs(5), served)
+            .await
+            .expect("snapshot request waited for the file map");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_hit_miss_counters() {
+        let dir = scratch_dir("lookup_metrics");
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let hits = |fs: &MirrorFS| {
+            use std::sync::atomic::Ordering::Relaxed;
+            (
+                fs.metrics.lookup_hits.load(Relaxed),
+                fs.metrics.lookup_misses.load(Relaxed),
+                fs.metrics.lookup_negative.load(Relaxed),
+            )
+        };
+        // The mount itself was a cache hit
+        assert_eq!(hits(&fs), (1, 0, 0));
+
+        fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        assert_eq!(hits(&fs), (1, 1, 0));
+        fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        assert_eq!(hits(&fs), (2, 1, 0));
+        assert!(
+            fs.lookup(&auth(), mount_id, &fname("missing"))
+                .await
+                .is_err()
+        );
+        assert_eq!(hits(&fs), (2, 1, 1));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_mount_name_inside_mount() {
+        let dir = scratch_dir("mount_self_lookup");
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+
+        // A name equal to the mount's own name is not the mount itself
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &fname("test")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+
+        std::fs::create_dir(dir.join("test")).unwrap();
+        let child = fs.lookup(&auth(), mount_id, &fname("test")).await.unwrap();
+        assert_ne!(child, mount_id);
+        assert_eq!(
+            fs.lookup(&auth(), 0, &fname("test")).await.unwrap(),
+            mount_id
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_create_remove_rename_in_mount_root() {
+        let dir = scratch_dir("mount_root_ops");
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+
+        let id = fs
+            .create_exclusive(&auth(), mount_id, &fname("new"))
+            .await
+            .unwrap();
+        assert!(dir.join("new").exists());
+        assert_eq!(
+            fs.lookup(&auth(), mount_id, &fname("new")).await.unwrap(),
+            id
+        );
+
+        fs.rename(
+            &auth(),
+            mount_id,
+            &fname("new"),
+            mount_id,
+            &fname("renamed"),
+        )
+        .await
+        .unwrap();
+        assert!(dir.join("renamed").exists());
+
+        fs.remove(&auth(), mount_id, &fname("renamed"))
+            .await
+            .unwrap();
+        assert!(!dir.join("renamed").exists());
+
+        // The synthetic root only holds mount points
+        assert!(matches!(
+            fs.create_exclusive(&auth(), 0, &fname("new")).await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_fresh_mount_lists_source_on_first_readdir() {
+        let dir = scratch_dir("mount_readdir");
+        std::fs::write(dir.join("a"), b"a").unwrap();
+        std::fs::write(dir.join("b"), b"b").unwrap();
+        std::fs::create_dir(dir.join("c")).unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+
+        let attr = fs.getattr(&auth(), mount_id).await.unwrap();
+        assert_eq!(attr.fileid, mount_id);
+
+        let result = fs.readdir(&auth(), mount_id, 0, 100).await.unwrap();
+        let mut names: Vec<Vec<u8>> = result.entries.iter().map(|e| e.name.0.clone()).collect();
+        names.sort();
+        assert_eq!(names, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert!(result.end);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_fileid_store() {
+        let dir = scratch_dir("shutdown");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let store = dir.with_extension("ids");
+        let server = ServerConfig {
+            fileid_store: Some(store.clone()),
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let shutdown = fs.shutdown_handle();
+
+        // The handle outlives the file system, as it does once the listener owns it
+        drop(fs);
+        shutdown.run().await;
+        let saved = FileIdStore::load(&store).unwrap();
+        assert_eq!(saved.get(&dir.join("file")), Some(id));
+
+        let _ = std::fs::remove_file(&store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_unicode_normalized_lookup() {
+        let dir = scratch_dir("unicode");
+        let nfc = "caf\u{e9}";
+        let nfd = "cafe\u{301}";
+        std::fs::write(dir.join(nfc), b"").unwrap();
+
+        let (plain, plain_mount) = mirror(&dir, ServerConfig::default()).await;
+        assert!(
+            plain
+                .lookup(&auth(), plain_mount, &fname(nfd))
+                .await
+                .is_err()
+        );
+
+        let mount = MountConfig {
+            unicode_normalize: Some(UnicodeForm::Nfc),
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname(nfc)).await.unwrap();
+        assert_eq!(fs.lookup(&auth(), mount_id, &fname(nfd)).await.unwrap(), id);
+        // IO goes to the on-disk name
+        fs.write(&auth(), id, 0, b"x").await.unwrap();
+        assert_eq!(std::fs::read(dir.join(nfc)).unwrap(), b"x");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_case_insensitive_lookup() {
+        let dir = scratch_dir("case");
+        std::fs::write(dir.join("README.TXT"), b"").unwrap();
+        std::fs::write(dir.join("Makefile"), b"").unwrap();
+        std::fs::write(dir.join("makefile"), b"").unwrap();
+
+        let mount = MountConfig {
+            case_insensitive: true,
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+
+        let id = fs
+            .lookup(&auth(), mount_id, &fname("README.TXT"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs.lookup(&auth(), mount_id, &fname("readme.txt"))
+                .await
+                .unwrap(),
+            id
+        );
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &fname("missing.txt")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        // Exact names still resolve; a folded name matching both is ambiguous
+        assert!(
+            fs.lookup(&auth(), mount_id, &fname("makefile"))
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &fname("MAKEFILE")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_write_uids_deny_other_users() {
+        let dir = scratch_dir("write_uids");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let mount = MountConfig {
+            write_uids: vec![1000],
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let owner = AuthContext {
+            uid: 1000,
+            gid: 1000,
+            gids: vec![],
+        };
+
+        assert!(matches!(
+            fs.write(&auth(), id, 0, b"denied").await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        assert!(matches!(
+            fs.remove(&auth(), mount_id, &fname("file")).await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        // Reads are not restricted
+        assert!(fs.read(&auth(), id, 0, 16).await.is_ok());
+
+        fs.write(&owner, id, 0, b"ok").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"ok");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_preload_respects_depth() {
+        let dir = scratch_dir("preload");
+        std::fs::create_dir_all(dir.join("a/b/c")).unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+
+        // Root, mount and `a` are listed; `b` is known but not listed
+        assert_eq!(preload(Arc::clone(&fs.fsmap), Some(2)).await, 3);
+        let fsmap = fs.fsmap.lock().await;
+        let a = fsmap.find_child(mount_id, b"a").await.unwrap();
+        let b = fsmap.find_child(a, b"b").await.unwrap();
+        assert!(fsmap.find_entry(b).unwrap().children.is_none());
+        drop(fsmap);
+
+        assert_eq!(preload(Arc::clone(&fs.fsmap), None).await, 5);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_read_only_switch_mid_session() {
+        let dir = scratch_dir("ro_switch");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        fs.write(&auth(), id, 0, b"a").await.unwrap();
+        fs.read_only_switch.set(true);
+        assert!(matches!(fs.capabilities(), VFSCapabilities::ReadOnly));
+        assert!(matches!(
+            fs.write(&auth(), id, 1, b"b").await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(matches!(
+            fs.remove(&auth(), mount_id, &fname("file")).await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        // Reads keep working while frozen
+        assert_eq!(fs.read(&auth(), id, 0, 16).await.unwrap().0, b"a");
+
+        fs.read_only_switch.set(false);
+        fs.write(&auth(), id, 1, b"b").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"ab");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_readdir_sorted_by_name() {
+        let dir = scratch_dir("readdir_sorted");
+        for name in ["c", "a", "d", "b"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let server = ServerConfig {
+            readdir_order: ReaddirOrder::Name,
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+
+        let mut names = Vec::new();
+        let mut cookie = 0;
+        loop {
+            let page = fs.readdir(&auth(), mount_id, cookie, 3).await.unwrap();
+            for entry in &page.entries {
+                names.push(String::from_utf8(entry.name.to_vec()).unwrap());
+                cookie = entry.fileid;
+            }
+            if page.end {
+                break;
+            }
+        }
+        assert_eq!(names, ["a", "b", "c", "d"]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_readdir_pagination_sees_consistent_set() {
+        let dir = scratch_dir("readdir_consistent");
+        for i in 0..20 {
+            std::fs::write(dir.join(format!("file{:02}", i)), b"").unwrap();
+        }
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let expected: Vec<String> = (0..20).map(|i| format!("file{:02}", i)).collect();
+
+        let first = fs.readdir(&auth(), mount_id, 0, 4).await.unwrap();
+        assert!(!first.end);
+
+        // Churn the directory while the enumeration is in progress
+        let churn_dir = dir.clone();
+        let churn = tokio::task::spawn_blocking(move || {
+            for i in 0..20 {
+                std::fs::write(churn_dir.join(format!("new{:02}", i)), b"").unwrap();
+                let _ = std::fs::remove_file(churn_dir.join(format!("file{:02}", 19 - i)));
+            }
+        });
+
+        let mut names: Vec<String> = first
+            .entries
+            .iter()
+            .map(|e| String::from_utf8(e.name.to_vec()).unwrap())
+            .collect();
+        let mut cookie = first.entries.last().unwrap().fileid;
+        loop {
+            let page = fs.readdir(&auth(), mount_id, cookie, 4).await.unwrap();
+            for entry in &page.entries {
+                names.push(String::from_utf8(entry.name.to_vec()).unwrap());
+                cookie = entry.fileid;
+            }
+            if page.end {
+                break;
+            }
+        }
+        churn.await.unwrap();
+
+        names.sort();
+        assert_eq!(names, expected);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_with_read_only_mounts() {
+        let rw_dir = scratch_dir("caps_rw");
+        let ro_dir = scratch_dir("caps_ro");
+        let read_only = |dir: &PathBuf, target| MountConfig {
+            read_only: true,
+            ..MountConfig::new(dir, target)
+        };
+
+        let mixed = MirrorFS::new_with_mounts(
+            rw_dir.clone(),
+            &ServerConfig::default(),
+            vec![MountConfig::new(&rw_dir, "/rw"), read_only(&ro_dir, "/ro")],
+        );
+        assert!(matches!(mixed.capabilities(), VFSCapabilities::ReadWrite));
+        let ro_id = mixed.lookup(&auth(), 0, &fname("ro")).await.unwrap();
+        let ro = mixed.fsinfo(&auth(), ro_id).await.unwrap().properties;
+        assert_eq!(ro & FSF_CANSETTIME, 0);
+
+        let all_ro = MirrorFS::new_with_mounts(
+            rw_dir.clone(),
+            &ServerConfig::default(),
+            vec![read_only(&rw_dir, "/a"), read_only(&ro_dir, "/b")],
+        );
+        assert!(matches!(all_ro.capabilities(), VFSCapabilities::ReadOnly));
+
+        let _ = std::fs::remove_dir_all(&rw_dir);
+        let _ = std::fs::remove_dir_all(&ro_dir);
+    }
+
+    #[tokio::test]
+    async fn test_attr_cache_skips_restat() {
+        let dir = scratch_dir("attr_cache");
+        std::fs::write(dir.join("file"), b"abc").unwrap();
+        let server = ServerConfig {
+            attr_cache_ms: 60_000,
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        assert_eq!(fs.getattr(&auth(), id).await.unwrap().size, 3);
+        // Not seen: the second getattr is served from the cache without a stat
+        std::fs::write(dir.join("file"), b"abcdef").unwrap();
+        assert_eq!(fs.getattr(&auth(), id).await.unwrap().size, 3);
+
+        // Changes made through the server are never hidden
+        fs.write(&auth(), id, 6, b"g").await.unwrap();
+        assert_eq!(fs.getattr(&auth(), id).await.unwrap().size, 7);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_single_file_mount() {
+        let dir = scratch_dir("single_file");
+        let image = dir.join("disk.img");
+        std::fs::write(&image, b"0123456789").unwrap();
+        let fs = MirrorFS::new_with_mounts(
+            dir.clone(),
+            &ServerConfig::default(),
+            vec![MountConfig::new(&image, "/disk.img")],
+        );
+
+        let id = fs.lookup(&auth(), 0, &fname("disk.img")).await.unwrap();
+        let attr = fs.getattr(&auth(), id).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3REG));
+        assert_eq!(attr.size, 10);
+        let root = fs.readdir(&auth(), 0, 0, 16).await.unwrap();
+        assert!(matches!(root.entries[0].attr.ftype, ftype3::NF3REG));
+
+        assert_eq!(fs.read(&auth(), id, 2, 3).await.unwrap().0, b"234");
+        fs.write(&auth(), id, 0, b"ab").await.unwrap();
+        assert_eq!(std::fs::read(&image).unwrap(), b"ab23456789");
+
+        assert!(matches!(
+            fs.lookup(&auth(), id, &fname("child")).await,
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        ));
+        assert!(matches!(
+            fs.remove(&auth(), id, &fname("child")).await,
+            Err(nfsstat3::NFS3ERR_NOTDIR)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_remove_is_idempotent_for_retransmits() {
+        let dir = scratch_dir("remove_twice");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        std::fs::write(dir.join("stale"), b"").unwrap();
+        let server = ServerConfig {
+            duplicate_request_secs: 120,
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        fs.remove(&auth(), mount_id, &fname("file")).await.unwrap();
+        fs.remove(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        // Removed behind our back while still cached
+        std::fs::remove_file(dir.join("stale")).unwrap();
+        fs.remove(&auth(), mount_id, &fname("stale")).await.unwrap();
+
+        assert!(matches!(
+            fs.remove(&auth(), mount_id, &fname("never")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_retransmitted_mkdir_and_rename_replay() {
+        let dir = scratch_dir("drc");
+        let server = ServerConfig {
+            duplicate_request_secs: 120,
+            ..Default::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let attrs = sattr3 {
+            mode: set_mode3::Void,
+            uid: set_uid3::Void,
+            gid: set_gid3::Void,
+            size: set_size3::Void,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
+
+        let (id, _) = fs
+            .mkdir(&auth(), mount_id, &fname("sub"), &attrs)
+            .await
+            .unwrap();
+        let (again, _) = fs
+            .mkdir(&auth(), mount_id, &fname("sub"), &attrs)
+            .await
+            .unwrap();
+        assert_eq!(id, again);
+
+        fs.rename(&auth(), mount_id, &fname("sub"), mount_id, &fname("moved"))
+            .await
+            .unwrap();
+        fs.rename(&auth(), mount_id, &fname("sub"), mount_id, &fname("moved"))
+            .await
+            .unwrap();
+        assert!(dir.join("moved").is_dir());
+
+        // The rename forgot the mkdir reply, so a new mkdir really runs
+        fs.mkdir(&auth(), mount_id, &fname("sub"), &attrs)
+            .await
+            .unwrap();
+        assert!(dir.join("sub").is_dir());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_rename_runs_without_duplicate_cache() {
+        let dir = scratch_dir("no_drc");
+        std::fs::write(dir.join("a"), b"first").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+
+        fs.rename(&auth(), mount_id, &fname("a"), mount_id, &fname("b"))
+            .await
+            .unwrap();
+        // Recreated by a program on the server, then renamed again
+        std::fs::write(dir.join("a"), b"second").unwrap();
+        fs.rename(&auth(), mount_id, &fname("a"), mount_id, &fname("b"))
+            .await
+            .unwrap();
+        assert!(!dir.join("a").exists());
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"second");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_mount_display_overrides() {
+        let dir = scratch_dir("display");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let mount = MountConfig {
+            display_mode: Some(0o700),
+            display_uid: Some(4242),
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount.clone(), ServerConfig::default()).await;
+
+        let attr = fs.getattr(&auth(), mount_id).await.unwrap();
+        assert_eq!(attr.mode, 0o700);
+        assert_eq!(attr.uid, 4242);
+        let root = fs.readdir(&auth(), 0, 0, 16).await.unwrap();
+        assert_eq!(root.entries[0].attr.mode, 0o700);
+
+        let file_id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        assert_ne!(fs.getattr(&auth(), file_id).await.unwrap().uid, 4242);
+
+        let recursive = MountConfig {
+            display_recursive: true,
+            ..mount
+        };
+        let (fs, mount_id) = mirror_mount(recursive, ServerConfig::default()).await;
+        let file_id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let attr = fs.getattr(&auth(), file_id).await.unwrap();
+        assert_eq!((attr.mode, attr.uid), (0o700, 4242));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_mknod_fifo_creates_named_pipe() {
+        use std::os::unix::fs::FileTypeExt;
+
+        let dir = scratch_dir("fifo");
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let attrs = sattr3 {
+            mode: set_mode3::mode(0o640),
+            uid: set_uid3::Void,
+            gid: set_gid3::Void,
+            size: set_size3::Void,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
+        let (_, attr) = fs
+            .mknod(
+                &auth(),
+                mount_id,
+                &fname("pipe"),
+                ftype3::NF3FIFO,
+                &attrs,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3FIFO));
+        assert_eq!(attr.mode & 0o777, 0o640);
+
+        let meta = std::fs::symlink_metadata(dir.join("pipe")).unwrap();
+        assert!(meta.file_type().is_fifo());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+
+        // A fresh server reports the type from the backing store
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let listing = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        let entry = listing
+            .entries
+            .iter()
+            .find(|e| e.name.0 == b"pipe")
+            .unwrap();
+        assert!(matches!(entry.attr.ftype, ftype3::NF3FIFO));
+        let id = fs.lookup(&auth(), mount_id, &fname("pipe")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(&auth(), id).await.unwrap().ftype,
+            ftype3::NF3FIFO
+        ));
+        assert!(matches!(
+            fs.read(&auth(), id, 0, 16).await,
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+
+        // Neither resizing nor creating over the pipe may open it
+        let resize = sattr3 {
+            mode: set_mode3::Void,
+            size: set_size3::size(0),
+            ..attrs
+        };
+        assert!(matches!(
+            fs.setattr(&auth(), id, resize).await,
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+        assert!(matches!(
+            fs.create(&auth(), mount_id, &fname("pipe"), attrs).await,
+            Err(nfsstat3::NFS3ERR_EXIST)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_status_file() {
+        let dir = scratch_dir("status");
+        std::fs::write(dir.join("file"), b"hello").unwrap();
+        let mount = MountConfig {
+            status_file: Some(".status".to_string()),
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount.clone(), ServerConfig::default()).await;
+        let file_id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        fs.read(&auth(), file_id, 0, 16).await.unwrap();
+
+        let status_id = fs
+            .lookup(&auth(), mount_id, &fname(".status"))
+            .await
+            .unwrap();
+        let attr = fs.getattr(&auth(), status_id).await.unwrap();
+        let (content, eof) = fs.read(&auth(), status_id, 0, 4096).await.unwrap();
+        assert!(eof);
+        assert_eq!(attr.size, content.len() as u64);
+        let status: serde_json::Value = serde_json::from_slice(&content).unwrap();
+        assert_eq!(status["target"], "/test");
+        assert_eq!(status["bytes_read"], 5);
+        assert_eq!(status["entries"], 2);
+        assert!(status["last_access"].is_u64());
+        let listing = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        assert!(listing.entries.iter().any(|e| e.fileid == status_id));
+
+        assert!(matches!(
+            fs.write(&auth(), status_id, 0, b"x").await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(!dir.join(".status").exists());
+
+        // A real file of the same name is served instead
+        std::fs::write(dir.join(".status"), b"real").unwrap();
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let real_id = fs
+            .lookup(&auth(), mount_id, &fname(".status"))
+            .await
+            .unwrap();
+        assert_eq!(fs.read(&auth(), real_id, 0, 16).await.unwrap().0, b"real");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_status_file_survives_intern_compaction() {
+        let dir = scratch_dir("status_compact");
+        let mount = MountConfig {
+            status_file: Some(".status".to_string()),
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let status_id = fs
+            .lookup(&auth(), mount_id, &fname(".status"))
+            .await
+            .unwrap();
+
+        // List and drop enough names that compaction renumbers every symbol
+        {
+            let mut fsmap = fs.fsmap.lock().await;
+            let names: Vec<_> = (0..50).map(|i| format!("churn{}", i)).collect();
+            for name in &names {
+                std::fs::write(dir.join(name), b"").unwrap();
+            }
+            fsmap.find_entry_mut(mount_id).unwrap().children_meta.size += 1;
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+            for name in &names {
+                std::fs::remove_file(dir.join(name)).unwrap();
+            }
+            fsmap.find_entry_mut(mount_id).unwrap().children_meta.size += 1;
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+            assert!(fsmap.compact_intern().unwrap() >= 50);
+        }
+
+        assert!(matches!(
+            fs.write(&auth(), status_id, 0, b"x").await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(!dir.join(".status").exists());
+        let (content, _) = fs.read(&auth(), status_id, 0, 4096).await.unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&content).unwrap();
+        assert_eq!(status["target"], "/test");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_id_maps_round_trip_owner() {
+        let dir = scratch_dir("idmap");
+        std::fs::write(dir.join("file"), b"").unwrap();
+        // SAFETY: geteuid and getegid have no preconditions
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let idmap = |container: u32, host: u32| IdMap {
+            container,
+            host,
+            count: 1,
+        };
+        let mount = MountConfig {
+            uid_map: vec![idmap(1000, uid)],
+            gid_map: vec![idmap(2000, gid)],
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let file_id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let attr = fs.getattr(&auth(), file_id).await.unwrap();
+        assert_eq!((attr.uid, attr.gid), (1000, 2000));
+
+        // Handing the file to the mapped owner stores our own ids, which needs no privilege
+        let chown = |uid, gid| sattr3 {
+            mode: set_mode3::Void,
+            uid: set_uid3::uid(uid),
+            gid: set_gid3::gid(gid),
+            size: set_size3::Void,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
+        let attr = fs
+            .setattr(&auth(), file_id, chown(1000, 2000))
+            .await
+            .unwrap();
+        assert_eq!((attr.uid, attr.gid), (1000, 2000));
+        let meta = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+        assert!(matches!(
+            fs.setattr(&auth(), file_id, chown(1001, 2000)).await,
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
+
+        // Owners outside every range show up as nobody
+        let mount = MountConfig {
+            uid_map: vec![idmap(0, uid.wrapping_add(1))],
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let file_id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        assert_eq!(fs.getattr(&auth(), file_id).await.unwrap().uid, OVERFLOW_ID);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_sparse_read_across_hole() {
+        let dir = scratch_dir("sparse");
+        let path = dir.join("image");
+        {
+            use std::io::{Seek, Write};
+            let mut f = std::fs::File::create(&path).unwrap();
+            f.write_all(b"head").unwrap();
+            f.seek(SeekFrom::Start(1 << 20)).unwrap();
+            f.write_all(b"tail").unwrap();
+        }
+        let server = ServerConfig {
+            sparse_reads: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("image")).await.unwrap();
+
+        let (data, eof) = fs.read(&auth(), id, 0, (1 << 20) + 8).await.unwrap();
+        assert!(eof);
+        assert_eq!(data.len(), (1 << 20) + 4);
+        assert_eq!(&data[..4], b"head");
+        assert!(data[4..1 << 20].iter().all(|&b| b == 0));
+        assert_eq!(&data[1 << 20..], b"tail");
+
+        let (data, _) = fs.read(&auth(), id, 2, 4).await.unwrap();
+        assert_eq!(data, b"ad\0\0");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_export_root_modes() {
+        let dir = scratch_dir("export_root");
+        std::fs::write(dir.join("file"), b"").unwrap();
+
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let root = fs.readdir(&auth(), 0, 0, 16).await.unwrap();
+        assert_eq!(root.entries.len(), 1);
+        assert_eq!(root.entries[0].fileid, mount_id);
+
+        let server = ServerConfig {
+            export_root: ExportRoot::PerMount,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let root = fs.readdir(&auth(), 0, 0, 16).await.unwrap();
+        assert!(root.entries.is_empty() && root.end);
+        // The mount itself is still reachable and listable
+        let listed = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        assert_eq!(listed.entries.len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_create_at_mount_roots() {
+        let rw_dir = scratch_dir("create_root_rw");
+        let ro_dir = scratch_dir("create_root_ro");
+        let fs = MirrorFS::new_with_mounts(
+            rw_dir.clone(),
+            &ServerConfig::default(),
+            vec![
+                MountConfig::new(&rw_dir, "/rw"),
+                MountConfig {
+                    read_only: true,
+                    ..MountConfig::new(&ro_dir, "/ro")
+                },
+            ],
+        );
+        let rw_id = fs.lookup(&auth(), 0, &fname("rw")).await.unwrap();
+        let ro_id = fs.lookup(&auth(), 0, &fname("ro")).await.unwrap();
+
+        fs.create_exclusive(&auth(), rw_id, &fname("new"))
+            .await
+            .unwrap();
+        assert!(rw_dir.join("new").is_file());
+        assert!(matches!(
+            fs.create_exclusive(&auth(), ro_id, &fname("new")).await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(matches!(
+            fs.create_exclusive(&auth(), 0, &fname("new")).await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+
+        let _ = std::fs::remove_dir_all(&rw_dir);
+        let _ = std::fs::remove_dir_all(&ro_dir);
+    }
+
+    #[tokio::test]
+    async fn test_sequential_reads_hit_read_ahead() {
+        let dir = scratch_dir("read_ahead");
+        std::fs::write(dir.join("file"), b"0123456789abcdefghij").unwrap();
+        let server = ServerConfig {
+            read_ahead_kb: 4,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let hits = || fs.metrics.read_ahead_hits.load(Ordering::Relaxed);
+
+        assert_eq!(
+            fs.read(&auth(), id, 0, 4).await.unwrap(),
+            (b"0123".to_vec(), false)
+        );
+        assert_eq!(hits(), 0);
+        assert_eq!(
+            fs.read(&auth(), id, 4, 4).await.unwrap(),
+            (b"4567".to_vec(), false)
+        );
+        assert_eq!(
+            fs.read(&auth(), id, 16, 8).await.unwrap(),
+            (b"ghij".to_vec(), true)
+        );
+        assert_eq!(hits(), 2);
+
+        // A write drops the buffer, so the next read sees the new data
+        fs.write(&auth(), id, 8, b"AB").await.unwrap();
+        assert_eq!(
+            fs.read(&auth(), id, 8, 4).await.unwrap(),
+            (b"ABab".to_vec(), false)
+        );
+        assert_eq!(hits(), 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_read_deleted_but_open_file() {
+        let dir = scratch_dir("unlinked_open");
+        std::fs::write(dir.join("file"), b"still here").unwrap();
+        let server = ServerConfig {
+            keep_unlinked_open_secs: 60,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        fs.read(&auth(), id, 0, 5).await.unwrap();
+
+        std::fs::remove_file(dir.join("file")).unwrap();
+        let attr = fs.getattr(&auth(), id).await.unwrap();
+        assert_eq!((attr.size, attr.nlink), (10, 0));
+        assert_eq!(
+            fs.read(&auth(), id, 6, 10).await.unwrap(),
+            (b"here".to_vec(), true)
+        );
+
+        // Without the option the handle goes away with the file
+        std::fs::write(dir.join("other"), b"").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("other")).await.unwrap();
+        fs.read(&auth(), id, 0, 5).await.unwrap();
+        std::fs::remove_file(dir.join("other")).unwrap();
+        assert!(matches!(
+            fs.getattr(&auth(), id).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_silly_rename_of_open_file() {
+        let dir = scratch_dir("silly_rename");
+        std::fs::write(dir.join("file"), b"open data").unwrap();
+        let server = ServerConfig {
+            keep_unlinked_open_secs: 1,
+            silly_rename: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        fs.read(&auth(), id, 0, 4).await.unwrap();
+
+        fs.remove(&auth(), mount_id, &fname("file")).await.unwrap();
+        let silly = dir.join(format!(".nfs{:016x}", id));
+        assert!(!dir.join("file").exists());
+        assert!(silly.exists());
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &fname("file")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert_eq!(fs.read(&auth(), id, 5, 4).await.unwrap().0, b"data");
+
+        // Deleted once nobody has used it for the idle time
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert!(!silly.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_io_bounds_reads() {
+        let dir = scratch_dir("max_io");
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        let server = ServerConfig {
+            max_concurrent_io: Some(2),
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let fs = Arc::new(fs);
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        let first = fs.io_slot().await;
+        let _second = fs.io_slot().await;
+        assert_eq!(fs.metrics.io_in_flight.load(Ordering::Relaxed), 2);
+
+        let reader = Arc::clone(&fs);
+        let mut read = tokio::spawn(async move { reader.read(&auth(), id, 0, 4).await });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), &mut read)
+                .await
+                .is_err()
+        );
+        assert_eq!(fs.metrics.io_in_flight.load(Ordering::Relaxed), 3);
+
+        drop(first);
+        assert_eq!(read.await.unwrap().unwrap().0, b"data");
+        assert_eq!(fs.metrics.io_in_flight.load(Ordering::Relaxed), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_mmap_read_of_large_file() {
+        let dir = scratch_dir("mmap");
+        let data: Vec<u8> = (0..8u32 << 20).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("large"), &data).unwrap();
+        let mount = MountConfig {
+            use_mmap: true,
+            read_only: true,
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("large")).await.unwrap();
+
+        // An offset near the end of the range must not wrap around
+        let (read, eof) = fs.read(&auth(), id, u64::MAX - 1, 65536).await.unwrap();
+        assert!(read.is_empty());
+        assert!(eof);
+
+        let (read, eof) = fs.read(&auth(), id, 3 << 20, 65536).await.unwrap();
+        assert_eq!(read, &data[3 << 20..(3 << 20) + 65536]);
+        assert!(!eof);
+        let (read, eof) = fs.read(&auth(), id, (8 << 20) - 10, 65536).await.unwrap();
+        assert_eq!(read, &data[(8 << 20) - 10..]);
+        assert!(eof);
+
+        // A file that shrinks is remapped rather than read past its end
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.join("large"))
+            .unwrap()
+            .set_len(1 << 20)
+            .unwrap();
+        let (read, eof) = fs.read(&auth(), id, (1 << 20) - 4, 65536).await.unwrap();
+        assert_eq!(read, &data[(1 << 20) - 4..1 << 20]);
+        assert!(eof);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_symlink_size_is_target_length() {
+        let dir = scratch_dir("symlink_size");
+        let target = "some/where/else";
+        std::os::unix::fs::symlink(target, dir.join("existing")).unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+
+        let id = fs
+            .lookup(&auth(), mount_id, &fname("existing"))
+            .await
+            .unwrap();
+        let attr = fs.getattr(&auth(), id).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3LNK));
+        assert_eq!(attr.size, target.len() as u64);
+
+        let (id, attr) = fs
+            .symlink(
+                &auth(),
+                mount_id,
+                &fname("created"),
+                &"a/b".as_bytes().into(),
+                &sattr3 {
+                    mode: set_mode3::Void,
+                    uid: set_uid3::Void,
+                    gid: set_gid3::Void,
+                    size: set_size3::Void,
+                    atime: set_atime::DONT_CHANGE,
+                    mtime: set_mtime::DONT_CHANGE,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(attr.size, 3);
+        assert_eq!(fs.getattr(&auth(), id).await.unwrap().size, 3);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_bytes_counted_per_mount() {
+        let dir = scratch_dir("mount_bytes");
+        std::fs::write(dir.join("file"), b"0123456789").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        fs.read(&auth(), id, 2, 4).await.unwrap();
+        fs.read(&auth(), id, 8, 100).await.unwrap();
+        fs.write(&auth(), id, 10, b"abc").await.unwrap();
+
+        let stats = fs.metrics.mount_access.snapshot();
+        assert_eq!(stats[0].bytes_read, 6);
+        assert_eq!(stats[0].bytes_written, 3);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_panic_while_holding_fsmap_does_not_wedge() {
+        let dir = scratch_dir("panic");
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+
+        let fsmap = Arc::clone(&fs.fsmap);
+        let panicked = tokio::spawn(async move {
+            let _fsmap = fsmap.lock().await;
+            panic!("operation failed while holding the file map");
+        })
+        .await;
+        assert!(panicked.unwrap_err().is_panic());
+
+        let attr = tokio::time::timeout(Duration::from_secs(5), fs.getattr(&auth(), id))
+            .await
+            .expect("file map lock still held after a panic");
+        assert_eq!(attr.unwrap().size, 4);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_archive_mount() {
+        let dir = scratch_dir("archive");
+        let tar = dir.join("data.tar");
+        std::fs::write(
+            &tar,
+            archive::build_tar(&[
+                ("docs/readme.txt", b'0', b"hello archive"),
+                ("docs/link", b'2', b"readme.txt"),
+                ("top", b'0', b""),
+            ]),
+        )
+        .unwrap();
+        let mut mount = MountConfig::new(&tar, "/test");
+        mount.archive = true;
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+
+        let root = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        let mut names: Vec<_> = root.entries.iter().map(|e| e.name.0.clone()).collect();
+        names.sort();
+        assert_eq!(names, [b"docs".to_vec(), b"top".to_vec()]);
+
+        let docs = fs.lookup(&auth(), mount_id, &fname("docs")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(&auth(), docs).await.unwrap().ftype,
+            ftype3::NF3DIR
+        ));
+        let file = fs
+            .lookup(&auth(), docs, &fname("readme.txt"))
+            .await
+            .unwrap();
+        let attr = fs.getattr(&auth(), file).await.unwrap();
+        assert_eq!((attr.size, attr.mode, attr.uid), (13, 0o644, 1000));
+        assert_eq!(
+            fs.read(&auth(), file, 6, 100).await.unwrap(),
+            (b"archive".to_vec(), true)
+        );
+        let link = fs.lookup(&auth(), docs, &fname("link")).await.unwrap();
+        assert_eq!(fs.readlink(&auth(), link).await.unwrap().0, b"readme.txt");
+        assert!(matches!(
+            fs.lookup(&auth(), docs, &fname("missing")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert!(matches!(
+            fs.read(&auth(), docs, 0, 10).await,
+            Err(nfsstat3::NFS3ERR_ISDIR)
+        ));
+
+        assert!(matches!(
+            fs.write(&auth(), file, 0, b"x").await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(matches!(
+            fs.remove(&auth(), docs, &fname("readme.txt")).await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_names() {
+        let dir = scratch_dir("latin1");
+        std::fs::write(dir.join(OsStr::from_bytes(b"caf\xe9")), b"latin").unwrap();
+        std::fs::write(dir.join("plain"), b"").unwrap();
+
+        let listed = |fs: MirrorFS, mount_id| async move {
+            let result = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+            let mut names: Vec<Vec<u8>> = result.entries.iter().map(|e| e.name.0.clone()).collect();
+            names.sort();
+            (fs, names)
+        };
+
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let (_, names) = listed(fs, mount_id).await;
+        assert_eq!(names, [b"caf\xe9".to_vec(), b"plain".to_vec()]);
+
+        let mut mount = MountConfig::new(&dir, "/test");
+        mount.non_utf8_names = NonUtf8Names::Latin1;
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let (fs, names) = listed(fs, mount_id).await;
+        assert_eq!(names, ["café".as_bytes().to_vec(), b"plain".to_vec()]);
+        let id = fs.lookup(&auth(), mount_id, &fname("café")).await.unwrap();
+        assert_eq!(fs.read(&auth(), id, 0, 16).await.unwrap().0, b"latin");
+
+        let mut mount = MountConfig::new(&dir, "/test");
+        mount.non_utf8_names = NonUtf8Names::Hide;
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let (fs, names) = listed(fs, mount_id).await;
+        assert_eq!(names, [b"plain".to_vec()]);
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &b"caf\xe9"[..].into()).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_windows_names() {
+        let dir = scratch_dir("windows_names");
+        std::fs::write(dir.join("a:b"), b"colon").unwrap();
+        std::fs::write(dir.join("plain"), b"").unwrap();
+
+        let mut mount = MountConfig::new(&dir, "/test");
+        mount.windows_names = true;
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let result = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        let mut names: Vec<Vec<u8>> = result.entries.iter().map(|e| e.name.0.clone()).collect();
+        names.sort();
+        assert_eq!(names, ["a\u{F03A}b".as_bytes().to_vec(), b"plain".to_vec()]);
+
+        // Both the listed and the on-disk name find the file, which keeps its name
+        for name in ["a\u{F03A}b", "a:b"] {
+            let id = fs.lookup(&auth(), mount_id, &fname(name)).await.unwrap();
+            assert_eq!(fs.read(&auth(), id, 0, 16).await.unwrap().0, b"colon");
+        }
+        assert!(dir.join("a:b").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_short_name_lookup() {
+        let dir = scratch_dir("short_names");
+        std::fs::write(dir.join("Long File Name.markdown"), b"long").unwrap();
+        std::fs::write(dir.join("SHORT.TXT"), b"short").unwrap();
+
+        let mut mount = MountConfig::new(&dir, "/test");
+        mount.short_names = true;
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let long = fs
+            .lookup(&auth(), mount_id, &fname("Long File Name.markdown"))
+            .await
+            .unwrap();
+        for alias in ["LONGF~PD.MAR", "longf~pd.mar"] {
+            let id = fs.lookup(&auth(), mount_id, &fname(alias)).await.unwrap();
+            assert_eq!(id, long);
+        }
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &fname("LONGF~PE.MAR")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_readdir_pagination_of_large_changing_directory() {
+        let dir = scratch_dir("readdir_large");
+        let count = 50_000;
+        for i in 0..count {
+            std::fs::write(dir.join(format!("f{:05}", i)), b"").unwrap();
+        }
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let other = AuthContext {
+            uid: 1001,
+            ..auth()
+        };
+
+        let mut seen = std::collections::BTreeSet::new();
+        let mut cookie = 0;
+        let mut pages = 0;
+        loop {
+            let page = fs.readdir(&auth(), mount_id, cookie, 997).await.unwrap();
+            for entry in &page.entries {
+                assert!(seen.insert(entry.name.to_vec()), "entry listed twice");
+                cookie = entry.fileid;
+            }
+            if page.end {
+                break;
+            }
+            // Change the directory between pages, and now and then start
+            // another enumeration of it by someone else
+            pages += 1;
+            std::fs::remove_file(dir.join(format!("f{:05}", pages * 7))).unwrap();
+            std::fs::write(dir.join(format!("new{:05}", pages)), b"").unwrap();
+            if pages % 10 == 0 {
+                let first = fs.readdir(&other, mount_id, 0, 10).await.unwrap();
+                assert_eq!(first.entries.len(), 10);
+            }
+        }
+        assert!(pages >= 50);
+        let expected: std::collections::BTreeSet<Vec<u8>> = (0..count)
+            .map(|i| format!("f{:05}", i).into_bytes())
+            .collect();
+        assert_eq!(seen, expected);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_readdirs_by_one_user_keep_their_listings() {
+        let dir = scratch_dir("readdir_concurrent");
+        for i in 0..30 {
+            std::fs::write(dir.join(format!("a{:02}", i)), b"").unwrap();
+        }
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let names = |dir: &std::path::Path| -> std::collections::BTreeSet<Vec<u8>> {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_vec())
+                .collect()
+        };
+
+        // Two enumerations by the same user with different page sizes, each
+        // started right after a change to the directory
+        let mut enumerations = Vec::new();
+        for (i, page_size) in [3, 5].into_iter().enumerate() {
+            std::fs::write(dir.join(format!("b{}", i)), b"").unwrap();
+            let expected = names(&dir);
+            let page = fs.readdir(&auth(), mount_id, 0, page_size).await.unwrap();
+            let seen: std::collections::BTreeSet<Vec<u8>> =
+                page.entries.iter().map(|e| e.name.to_vec()).collect();
+            enumerations.push((expected, page_size, page, seen));
+        }
+
+        // Continue them page by page, interleaved, changing the directory in between
+        let mut round = 0;
+        while enumerations.iter().any(|(_, _, page, _)| !page.end) {
+            round += 1;
+            std::fs::write(dir.join(format!("c{:02}", round)), b"").unwrap();
+            for (_, page_size, page, seen) in enumerations.iter_mut().filter(|e| !e.2.end) {
+                let cookie = page.entries.last().unwrap().fileid;
+                *page = fs
+                    .readdir(&auth(), mount_id, cookie, *page_size)
+                    .await
+                    .unwrap();
+                seen.extend(page.entries.iter().map(|e| e.name.to_vec()));
+            }
+        }
+        for (expected, _, _, seen) in &enumerations {
+            assert_eq!(seen, expected);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_file_replaced_by_directory() {
+        let dir = scratch_dir("type_change");
+        std::fs::write(dir.join("x"), b"file").unwrap();
+        std::fs::write(dir.join("y"), b"file").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let old_x = fs.lookup(&auth(), mount_id, &fname("x")).await.unwrap();
+        let old_y = fs.lookup(&auth(), mount_id, &fname("y")).await.unwrap();
+
+        std::fs::remove_file(dir.join("x")).unwrap();
+        std::fs::create_dir(dir.join("x")).unwrap();
+        assert!(matches!(
+            fs.getattr(&auth(), old_x).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        {
+            let fsmap = fs.fsmap.lock().await;
+            let parent = fsmap.find_entry(mount_id).unwrap();
+            assert!(!parent.children.iter().flatten().any(|&id| id == old_x));
+        }
+        let new_x = fs.lookup(&auth(), mount_id, &fname("x")).await.unwrap();
+        assert_ne!(new_x, old_x);
+        let attr = fs.getattr(&auth(), new_x).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3DIR));
+
+        // Found by relisting the parent rather than through the old fileid
+        std::fs::remove_file(dir.join("y")).unwrap();
+        std::fs::create_dir(dir.join("y")).unwrap();
+        let listing = fs.readdir(&auth(), mount_id, 0, 10).await.unwrap();
+        let new_y = listing
+            .entries
+            .iter()
+            .find(|entry| entry.name.0 == b"y")
+            .unwrap()
+            .fileid;
+        assert_ne!(new_y, old_y);
+        assert!(fs.getattr(&auth(), old_y).await.is_err());
+        let attr = fs.getattr(&auth(), new_y).await.unwrap();
+        assert!(matches!(attr.ftype, ftype3::NF3DIR));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dir_sync_after_namespace_changes() {
+        let dir = scratch_dir("dir_sync");
+        let server = ServerConfig {
+            dir_sync: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let syncs = || fs.metrics.dir_syncs.load(Ordering::Relaxed);
+        let attrs = sattr3 {
+            mode: set_mode3::Void,
+            uid: set_uid3::Void,
+            gid: set_gid3::Void,
+            size: set_size3::Void,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
+
+        fs.create_exclusive(&auth(), mount_id, &fname("a"))
+            .await
+            .unwrap();
+        assert_eq!(syncs(), 1);
+        let (sub, _) = fs
+            .mkdir(&auth(), mount_id, &fname("sub"), &attrs)
+            .await
+            .unwrap();
+        assert_eq!(syncs(), 2);
+        fs.rename(&auth(), mount_id, &fname("a"), mount_id, &fname("b"))
+            .await
+            .unwrap();
+        assert_eq!(syncs(), 3);
+        // Both directories change when moving across them
+        fs.rename(&auth(), mount_id, &fname("b"), sub, &fname("b"))
+            .await
+            .unwrap();
+        assert_eq!(syncs(), 5);
+        fs.remove(&auth(), sub, &fname("b")).await.unwrap();
+        assert_eq!(syncs(), 6);
+
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        fs.create_exclusive(&auth(), mount_id, &fname("c"))
+            .await
+            .unwrap();
+        assert_eq!(fs.metrics.dir_syncs.load(Ordering::Relaxed), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_append_only_mount() {
+        let dir = scratch_dir("append_only");
+        std::fs::write(dir.join("log"), b"first\n").unwrap();
+        let mut mount = MountConfig::new(&dir, "/test");
+        mount.append_only = true;
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let log = fs.lookup(&auth(), mount_id, &fname("log")).await.unwrap();
+
+        // Appending and creating new files is allowed
+        fs.write(&auth(), log, 6, b"second\n").await.unwrap();
+        let new = fs
+            .create_exclusive(&auth(), mount_id, &fname("new"))
+            .await
+            .unwrap();
+        fs.write(&auth(), new, 0, b"data").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("log")).unwrap(), b"first\nsecond\n");
+
+        // Existing bytes cannot be overwritten
+        assert!(matches!(
+            fs.write(&auth(), log, 0, b"FIRST").await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        assert!(matches!(
+            fs.write(&auth(), new, 2, b"TA").await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        assert_eq!(std::fs::read(dir.join("log")).unwrap(), b"first\nsecond\n");
+
+        // Nor can files be deleted or renamed
+        assert!(matches!(
+            fs.remove(&auth(), mount_id, &fname("log")).await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        assert!(matches!(
+            fs.rename(&auth(), mount_id, &fname("log"), mount_id, &fname("old"))
+                .await,
+            Err(nfsstat3::NFS3ERR_ACCES)
+        ));
+        assert!(dir.join("log").exists());
+        assert!(!dir.join("old").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_cached_real_paths_follow_renames() {
+        let dir = scratch_dir("real_paths");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"content").unwrap();
+        let server = ServerConfig {
+            cache_real_paths: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+        let file = fs.lookup(&auth(), sub, &fname("file")).await.unwrap();
+        assert_eq!(fs.read(&auth(), file, 0, 16).await.unwrap().0, b"content");
+
+        // Moving the file, or the directory above it, drops the cached path
+        fs.rename(&auth(), sub, &fname("file"), sub, &fname("moved"))
+            .await
+            .unwrap();
+        assert_eq!(fs.read(&auth(), file, 0, 16).await.unwrap().0, b"content");
+        fs.rename(&auth(), mount_id, &fname("sub"), mount_id, &fname("dir"))
+            .await
+            .unwrap();
+        fs.write(&auth(), file, 7, b"!").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("dir/moved")).unwrap(), b"content!");
+        assert_eq!(fs.getattr(&auth(), file).await.unwrap().size, 8);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_max_file_size() {
+        let dir = scratch_dir("max_file_size");
+        std::fs::write(dir.join("big"), [0; 16]).unwrap();
+        let mount = MountConfig {
+            max_file_size: Some(8),
+            ..MountConfig::new(&dir, "/test")
+        };
+        let (fs, mount_id) = mirror_mount(mount, ServerConfig::default()).await;
+        let resize = |size| sattr3 {
+            mode: set_mode3::Void,
+            uid: set_uid3::Void,
+            gid: set_gid3::Void,
+            size,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
+        let (file, _) = fs
+            .create(&auth(), mount_id, &fname("log"), resize(set_size3::Void))
+            .await
+            .unwrap();
+
+        fs.write(&auth(), file, 0, b"12345678").await.unwrap();
+        assert!(matches!(
+            fs.write(&auth(), file, 8, b"9").await,
+            Err(nfsstat3::NFS3ERR_FBIG)
+        ));
+        assert!(matches!(
+            fs.write(&auth(), file, 4, b"56789").await,
+            Err(nfsstat3::NFS3ERR_FBIG)
+        ));
+        assert_eq!(std::fs::read(dir.join("log")).unwrap(), b"12345678");
+        assert!(matches!(
+            fs.setattr(&auth(), file, resize(set_size3::size(9))).await,
+            Err(nfsstat3::NFS3ERR_FBIG)
+        ));
+        fs.setattr(&auth(), file, resize(set_size3::size(4)))
+            .await
+            .unwrap();
+
+        // A file that is already larger can be rewritten in place
+        let big = fs.lookup(&auth(), mount_id, &fname("big")).await.unwrap();
+        fs.write(&auth(), big, 10, b"x").await.unwrap();
+
+        let info = fs.fsinfo(&auth(), mount_id).await.unwrap();
+        assert_eq!(info.maxfilesize, 8);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_write_once_seals_files() {
+        let dir = scratch_dir("write_once");
+        std::fs::write(dir.join("old"), b"before").unwrap();
+        let server = ServerConfig {
+            write_once_secs: 1,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let denied = |result: Result<_, nfsstat3>| matches!(result, Err(nfsstat3::NFS3ERR_ACCES));
+        let chmod = sattr3 {
+            mode: set_mode3::mode(0o600),
+            uid: set_uid3::Void,
+            gid: set_gid3::Void,
+            size: set_size3::Void,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
 
-        if dir_read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
-        }
+        // Files the server did not create are sealed from the start
+        let old = fs.lookup(&auth(), mount_id, &fname("old")).await.unwrap();
+        assert!(denied(fs.write(&auth(), old, 6, b"!").await.map(drop)));
+        assert!(denied(fs.setattr(&auth(), old, chmod).await.map(drop)));
+        assert!(denied(fs.remove(&auth(), mount_id, &fname("old")).await));
 
-        let mut path = dir_path;
-        path.push(OsStr::from_bytes(filename));
+        // A new file can be written until it goes unwritten for write_once_secs
+        let id = fs
+            .create_exclusive(&auth(), mount_id, &fname("report"))
+            .await
+            .unwrap();
+        fs.write(&auth(), id, 0, b"final").await.unwrap();
+        fs.setattr(&auth(), id, chmod).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(denied(fs.write(&auth(), id, 5, b"!").await.map(drop)));
+        assert!(denied(fs.setattr(&auth(), id, chmod).await.map(drop)));
+        assert!(denied(fs.remove(&auth(), mount_id, &fname("report")).await));
+        assert!(denied(
+            fs.rename(
+                &auth(),
+                mount_id,
+                &fname("report"),
+                mount_id,
+                &fname("moved")
+            )
+            .await
+        ));
+        assert!(denied(
+            fs.create(&auth(), mount_id, &fname("report"), chmod)
+                .await
+                .map(drop)
+        ));
+        assert_eq!(std::fs::read(dir.join("report")).unwrap(), b"final");
+
+        // Directories are not sealed
+        let (sub, _) = fs
+            .mkdir(&auth(), mount_id, &fname("sub"), &chmod)
+            .await
+            .unwrap();
+        fs.setattr(&auth(), sub, chmod).await.unwrap();
+        fs.remove(&auth(), mount_id, &fname("sub")).await.unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_readdir_refresh_attrs() {
+        let dir = scratch_dir("readdir_refresh");
+        std::fs::write(dir.join("file"), b"abc").unwrap();
+        let size_listed = |listing: ReadDirResult| listing.entries[0].attr.size;
 
-        if let Ok(meta) = path.symlink_metadata() {
-            if meta.is_dir() {
-                tokio::fs::remove_dir(&path)
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let server = ServerConfig {
+            readdir_refresh_attrs: true,
+            ..ServerConfig::default()
+        };
+        let (refreshing, refreshing_id) = mirror(&dir, server).await;
+        assert_eq!(
+            size_listed(fs.readdir(&auth(), mount_id, 0, 10).await.unwrap()),
+            3
+        );
+        assert_eq!(
+            size_listed(
+                refreshing
+                    .readdir(&auth(), refreshing_id, 0, 10)
                     .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-            } else {
-                tokio::fs::remove_file(&path)
+                    .unwrap()
+            ),
+            3
+        );
+
+        // Growing a file leaves its directory unchanged
+        std::fs::write(dir.join("file"), b"abcdef").unwrap();
+        assert_eq!(
+            size_listed(fs.readdir(&auth(), mount_id, 0, 10).await.unwrap()),
+            3
+        );
+        assert_eq!(
+            size_listed(
+                refreshing
+                    .readdir(&auth(), refreshing_id, 0, 10)
                     .await
-                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-            }
+                    .unwrap()
+            ),
+            6
+        );
 
-            let filesym = fsmap
-                .intern
-                .intern(OsStr::from_bytes(filename).to_os_string())
-                .unwrap();
-            let mut sympath = ent.name.clone();
-            sympath.push(filesym);
-            if let Some(fileid) = fsmap.path_to_id.get(&sympath).copied() {
-                // update the fileid -> path
-                // and the path -> fileid mappings for the deleted file
-                fsmap.id_to_path.remove(&fileid);
-                fsmap.path_to_id.remove(&sympath);
-                // we need to update the children listing for the directories
-                if let Ok(dirent_mut) = fsmap.find_entry_mut(dirid) {
-                    if let Some(ref mut fromch) = dirent_mut.children {
-                        fromch.remove(&fileid);
-                    }
-                }
-            }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-            let _ = fsmap.refresh_entry(dirid).await;
-        } else {
-            return Err(nfsstat3::NFS3ERR_NOENT);
-        }
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_no_atime_reads() {
+        let dir = scratch_dir("no_atime");
+        let path = dir.join("file");
+        std::fs::write(&path, b"data").unwrap();
+        // Older than the modification time, so even relatime would update it
+        let old = std::time::SystemTime::now() - Duration::from_secs(3 * 24 * 3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_times(std::fs::FileTimes::new().set_accessed(old))
+            .unwrap();
+        let atime = || std::fs::metadata(&path).unwrap().accessed().unwrap();
+        let before = atime();
 
-        Ok(())
+        let server = ServerConfig {
+            no_atime: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        assert_eq!(fs.read(&auth(), id, 0, 16).await.unwrap().0, b"data");
+        assert_eq!(atime(), before);
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
-    async fn rename(
-        &self,
-        _auth: &AuthContext,
-        from_dirid: fileid3,
-        from_filename: &filename3,
-        to_dirid: fileid3,
-        to_filename: &filename3,
-    ) -> Result<(), nfsstat3> {
-        if self.read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
+    #[tokio::test]
+    async fn test_block_size_is_advertised() {
+        let dir = scratch_dir("block_size");
+        std::fs::write(dir.join("file"), vec![1; 10000]).unwrap();
+
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let used = fs.getattr(&auth(), id).await.unwrap().used;
+        assert_eq!(
+            fs.fsinfo(&auth(), mount_id).await.unwrap().wtmult,
+            1024 * 1024
+        );
+
+        for block_size in [4096, 512 * 1024] {
+            let server = ServerConfig {
+                block_size: Some(block_size),
+                ..ServerConfig::default()
+            };
+            let (fs, mount_id) = mirror(&dir, server).await;
+            let info = fs.fsinfo(&auth(), mount_id).await.unwrap();
+            assert_eq!((info.rtmult, info.wtmult), (block_size, block_size));
+            assert!(info.rtpref >= block_size && info.rtpref % block_size == 0);
+            assert!(info.wtpref >= block_size && info.wtpref % block_size == 0);
+            // The space used is not rounded to the advertised blocks
+            let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+            assert_eq!(fs.getattr(&auth(), id).await.unwrap().used, used);
         }
 
-        let mut fsmap = self.fsmap.lock().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        let from_dirent = fsmap.find_entry(from_dirid)?;
-        let (from_dir_path, from_read_only) = match fsmap.sym_to_real_path(&from_dirent.name).await
-        {
-            Some(path) => path,
-            None => {
-                // This is a mount point, cannot rename from here
-                return Err(nfsstat3::NFS3ERR_ACCES);
-            }
+    #[tokio::test]
+    async fn test_readdir_dot_entries() {
+        let dir = scratch_dir("dot_entries");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/a"), b"").unwrap();
+        std::fs::write(dir.join("sub/b"), b"").unwrap();
+        let names = |result: &ReadDirResult| -> Vec<(String, fileid3)> {
+            result
+                .entries
+                .iter()
+                .map(|e| (String::from_utf8_lossy(&e.name).into_owned(), e.fileid))
+                .collect()
         };
 
-        let to_dirent = fsmap.find_entry(to_dirid)?;
-        let (to_dir_path, to_read_only) = match fsmap.sym_to_real_path(&to_dirent.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point, cannot rename to here
-                return Err(nfsstat3::NFS3ERR_ACCES);
+        for snapshot in [false, true] {
+            let server = ServerConfig {
+                readdir_dot_entries: true,
+                snapshot,
+                ..ServerConfig::default()
+            };
+            let (fs, mount_id) = mirror(&dir, server).await;
+            if snapshot {
+                fs.take_snapshot().await;
             }
-        };
+            let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+            let a = fs.lookup(&auth(), sub, &fname("a")).await.unwrap();
+            let b = fs.lookup(&auth(), sub, &fname("b")).await.unwrap();
 
-        if from_read_only || to_read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
+            // The root is its own parent
+            let root = fs.readdir(&auth(), 0, 0, 16).await.unwrap();
+            assert_eq!(
+                names(&root),
+                [(".".into(), 0), ("..".into(), 0), ("test".into(), mount_id)]
+            );
+            let listed = fs.readdir(&auth(), sub, 0, 16).await.unwrap();
+            let listed = names(&listed);
+            assert_eq!(listed[..2], [(".".into(), sub), ("..".into(), mount_id)]);
+            let files: Vec<_> = listed[2..].iter().map(|(_, id)| *id).collect();
+            assert!(files == [a, b] || files == [b, a], "{:?}", files);
+
+            // The dot entries count against the page size and are listed once
+            let first = fs.readdir(&auth(), sub, 0, 3).await.unwrap();
+            assert_eq!(names(&first), listed[..3]);
+            assert!(!first.end);
+            let rest = fs.readdir(&auth(), sub, listed[2].1, 3).await.unwrap();
+            assert_eq!(names(&rest), listed[3..]);
+            assert!(rest.end);
+            // A page ending on a dot entry continues with the first file
+            let rest = fs.readdir(&auth(), sub, mount_id, 3).await.unwrap();
+            assert_eq!(names(&rest), listed[2..]);
         }
 
-        let mut from_path = from_dir_path;
-        from_path.push(OsStr::from_bytes(from_filename));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        let mut to_path = to_dir_path;
-        // to folder must exist
-        if !exists_no_traverse(&to_path) {
-            return Err(nfsstat3::NFS3ERR_NOENT);
-        }
-        to_path.push(OsStr::from_bytes(to_filename));
+    #[tokio::test]
+    async fn test_readdir_continues_kept_listing_after_dot_entries() {
+        let dir = scratch_dir("dot_entries_kept");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/a"), b"").unwrap();
+        std::fs::write(dir.join("sub/b"), b"").unwrap();
+        let server = ServerConfig {
+            readdir_dot_entries: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
 
-        // src path must exist
-        if !exists_no_traverse(&from_path) {
-            return Err(nfsstat3::NFS3ERR_NOENT);
-        }
-        debug!("Rename {:?} to {:?}", from_path, to_path);
-        tokio::fs::rename(&from_path, &to_path)
+        let first = fs.readdir(&auth(), sub, 0, 3).await.unwrap();
+        assert!(!first.end);
+        let files: Vec<fileid3> = fs
+            .readdir(&auth(), sub, 0, 16)
             .await
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
+            .unwrap()
+            .entries
+            .iter()
+            .skip(2)
+            .map(|e| e.fileid)
+            .collect();
+
+        // The reply was cut after `..`, and the directory changed since
+        std::fs::write(dir.join("sub/c"), b"").unwrap();
+        let rest = fs.readdir(&auth(), sub, mount_id, 16).await.unwrap();
+        let ids: Vec<fileid3> = rest.entries.iter().map(|e| e.fileid).collect();
+        assert_eq!(ids, files);
+        assert!(rest.end);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_removing_one_hard_link_keeps_the_others() {
+        let dir = scratch_dir("hard_links");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), b"shared").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+        let file = fs.lookup(&auth(), mount_id, &fname("a")).await.unwrap();
+        fs.link(&auth(), file, mount_id, &fname("b")).await.unwrap();
+        fs.link(&auth(), file, sub, &fname("c")).await.unwrap();
+        assert_eq!(fs.lookup(&auth(), sub, &fname("c")).await.unwrap(), file);
+        assert!(fs.fsmap.lock().await.check_consistency().is_empty());
+
+        // Removing the name the entry was created under hands it another one
+        fs.remove(&auth(), mount_id, &fname("a")).await.unwrap();
+        assert_eq!(
+            fs.lookup(&auth(), mount_id, &fname("b")).await.unwrap(),
+            file
+        );
+        assert_eq!(fs.getattr(&auth(), file).await.unwrap().nlink, 2);
+        assert_eq!(fs.read(&auth(), file, 0, 16).await.unwrap().0, b"shared");
+        assert!(fs.fsmap.lock().await.check_consistency().is_empty());
+
+        fs.remove(&auth(), mount_id, &fname("b")).await.unwrap();
+        assert_eq!(fs.lookup(&auth(), sub, &fname("c")).await.unwrap(), file);
+        assert_eq!(fs.read(&auth(), file, 0, 16).await.unwrap().0, b"shared");
+        let root = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        assert!(root.entries.iter().all(|e| e.fileid != file));
+
+        fs.remove(&auth(), sub, &fname("c")).await.unwrap();
+        assert!(matches!(
+            fs.getattr(&auth(), file).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        assert!(fs.fsmap.lock().await.check_consistency().is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_renaming_a_second_hard_link_keeps_the_first() {
+        let dir = scratch_dir("rename_hard_link");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), b"shared").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+        let file = fs.lookup(&auth(), mount_id, &fname("a")).await.unwrap();
+        fs.link(&auth(), file, mount_id, &fname("b")).await.unwrap();
 
-        let oldsym = fsmap
-            .intern
-            .intern(OsStr::from_bytes(from_filename).to_os_string())
+        fs.rename(&auth(), mount_id, &fname("b"), sub, &fname("c"))
+            .await
             .unwrap();
-        let newsym = fsmap
-            .intern
-            .intern(OsStr::from_bytes(to_filename).to_os_string())
+        assert_eq!(
+            fs.lookup(&auth(), mount_id, &fname("a")).await.unwrap(),
+            file
+        );
+        assert_eq!(fs.lookup(&auth(), sub, &fname("c")).await.unwrap(), file);
+        assert!(matches!(
+            fs.lookup(&auth(), mount_id, &fname("b")).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+        let root = fs.readdir(&auth(), mount_id, 0, 16).await.unwrap();
+        assert!(root.entries.iter().any(|e| e.fileid == file));
+        assert!(fs.fsmap.lock().await.check_consistency().is_empty());
+
+        // Renaming one link over another of the same file leaves both
+        fs.rename(&auth(), sub, &fname("c"), mount_id, &fname("a"))
+            .await
             .unwrap();
+        assert_eq!(fs.lookup(&auth(), sub, &fname("c")).await.unwrap(), file);
+        assert_eq!(
+            fs.lookup(&auth(), mount_id, &fname("a")).await.unwrap(),
+            file
+        );
+        assert!(fs.fsmap.lock().await.check_consistency().is_empty());
 
-        let mut from_sympath = from_dirent.name.clone();
-        from_sympath.push(oldsym);
-        let mut to_sympath = to_dirent.name.clone();
-        to_sympath.push(newsym);
-        if let Some(fileid) = fsmap.path_to_id.get(&from_sympath).copied() {
-            // update the fileid -> path
-            // and the path -> fileid mappings for the new file
-            fsmap.id_to_path.get_mut(&fileid).unwrap().name = to_sympath.clone();
-            fsmap.path_to_id.remove(&from_sympath);
-            fsmap.path_to_id.insert(to_sympath, fileid);
-            if to_dirid != from_dirid {
-                // moving across directories.
-                // we need to update the children listing for the directories
-                if let Ok(from_dirent_mut) = fsmap.find_entry_mut(from_dirid) {
-                    if let Some(ref mut fromch) = from_dirent_mut.children {
-                        fromch.remove(&fileid);
-                    }
-                }
-                if let Ok(to_dirent_mut) = fsmap.find_entry_mut(to_dirid) {
-                    if let Some(ref mut toch) = to_dirent_mut.children {
-                        toch.insert(fileid);
-                    }
-                }
-            }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_rename_directory_updates_parent_links() {
+        let dir = scratch_dir("rename_parent");
+        std::fs::create_dir_all(dir.join("a/sub")).unwrap();
+        std::fs::create_dir(dir.join("b")).unwrap();
+        std::fs::write(dir.join("a/sub/file"), b"data").unwrap();
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let a = fs.lookup(&auth(), mount_id, &fname("a")).await.unwrap();
+        let b = fs.lookup(&auth(), mount_id, &fname("b")).await.unwrap();
+        let sub = fs.lookup(&auth(), a, &fname("sub")).await.unwrap();
+        let file = fs.lookup(&auth(), sub, &fname("file")).await.unwrap();
+        {
+            let fsmap = fs.fsmap.lock().await;
+            assert_eq!(fsmap.parent_of(0), Some(0));
+            assert_eq!(fsmap.parent_of(mount_id), Some(0));
+            assert_eq!(fsmap.parent_of(sub), Some(a));
+            assert_eq!(fsmap.parent_of(file), Some(sub));
         }
-        let _ = fsmap.refresh_entry(from_dirid).await;
-        if to_dirid != from_dirid {
-            let _ = fsmap.refresh_entry(to_dirid).await;
+
+        fs.rename(&auth(), a, &fname("sub"), b, &fname("moved"))
+            .await
+            .unwrap();
+        {
+            let fsmap = fs.fsmap.lock().await;
+            assert_eq!(fsmap.parent_of(sub), Some(b));
+            // Entries below the moved directory keep their parent and follow it
+            assert_eq!(fsmap.parent_of(file), Some(sub));
+            let name = &fsmap.id_to_path[&file].name;
+            assert_eq!(
+                fsmap.sym_to_path(name).await,
+                Path::new("test/b/moved/file")
+            );
         }
+        assert_eq!(fs.lookup(&auth(), b, &fname("moved")).await.unwrap(), sub);
+        assert_eq!(fs.lookup(&auth(), sub, &fname("file")).await.unwrap(), file);
+        assert_eq!(fs.read(&auth(), file, 0, 16).await.unwrap().0, b"data");
 
-        Ok(())
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
-    async fn mkdir(
-        &self,
-        _auth: &AuthContext,
-        dirid: fileid3,
-        dirname: &filename3,
-        _attrs: &sattr3,
-    ) -> Result<(fileid3, fattr3), nfsstat3> {
-        self.create_fs_object(dirid, dirname, &CreateFSObject::Directory)
-            .await
-    }
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_allocate_and_deallocate() {
+        let dir = scratch_dir("allocate");
+        let path = dir.join("file");
+        std::fs::write(&path, vec![1; 64 * 1024]).unwrap();
+        // Not every file system under the temp directory supports fallocate
+        let probe = dir.join("probe");
+        let probe_file = std::fs::File::create(&probe).unwrap();
+        if fallocate(&probe_file, 0, 8192, false).is_err()
+            || fallocate(&probe_file, 0, 4096, true).is_err()
+        {
+            let _ = std::fs::remove_dir_all(&dir);
+            return;
+        }
 
-    async fn symlink(
-        &self,
-        _auth: &AuthContext,
-        dirid: fileid3,
-        linkname: &filename3,
-        symlink: &nfspath3,
-        attr: &sattr3,
-    ) -> Result<(fileid3, fattr3), nfsstat3> {
-        self.create_fs_object(
-            dirid,
-            linkname,
-            &CreateFSObject::Symlink((*attr, symlink.clone())),
-        )
-        .await
-    }
+        let (fs, mount_id) = mirror(&dir, ServerConfig::default()).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let attr = fs.allocate(&auth(), id, 0, 1 << 20).await.unwrap();
+        assert_eq!(attr.size, 1 << 20);
+        assert!(attr.used >= 1 << 20);
+        assert!(matches!(
+            fs.allocate(&auth(), id, 0, 0).await,
+            Err(nfsstat3::NFS3ERR_INVAL)
+        ));
 
-    async fn readlink(&self, _auth: &AuthContext, id: fileid3) -> Result<nfspath3, nfsstat3> {
-        let fsmap = self.fsmap.lock().await;
-        let ent = fsmap.find_entry(id)?;
+        let attr = fs.deallocate(&auth(), id, 4096, 8192).await.unwrap();
+        assert_eq!(attr.size, 1 << 20);
+        let (data, _) = fs.read(&auth(), id, 0, 16384).await.unwrap();
+        assert!(data[..4096].iter().all(|&b| b == 1));
+        assert!(data[4096..12288].iter().all(|&b| b == 0));
+        assert!(data[12288..].iter().all(|&b| b == 1));
 
-        // Get the real file system path
-        let (path, _read_only) = match fsmap.sym_to_real_path(&ent.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point or root, cannot readlink
-                return Err(nfsstat3::NFS3ERR_BADTYPE);
-            }
+        let server = ServerConfig {
+            read_only: true,
+            ..ServerConfig::default()
         };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        assert!(matches!(
+            fs.allocate(&auth(), id, 0, 4096).await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(matches!(
+            fs.deallocate(&auth(), id, 0, 4096).await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
 
-        drop(fsmap);
-        if path.is_symlink() {
-            if let Ok(target) = path.read_link() {
-                Ok(target.as_os_str().as_bytes().into())
-            } else {
-                Err(nfsstat3::NFS3ERR_IO)
-            }
-        } else {
-            Err(nfsstat3::NFS3ERR_BADTYPE)
-        }
+        let _ = std::fs::remove_dir_all(&dir);
     }
 
-    async fn mknod(
-        &self,
-        _auth: &AuthContext,
-        dirid: fileid3,
-        filename: &filename3,
-        ftype: ftype3,
-        attr: &sattr3,
-        spec: Option<&specdata3>,
-    ) -> Result<(fileid3, fattr3), nfsstat3> {
-        // For mirrorfs, we'll create regular files for special file types
-        // since creating actual device files requires elevated privileges
-        match ftype {
-            ftype3::NF3CHR | ftype3::NF3BLK => {
-                // Create a regular file to represent the device
-                // In a real implementation, you would use spec.specdata1 (major) and spec.specdata2 (minor)
-                if let Some(_device_spec) = spec {
-                    // Could log or store device major/minor info here
-                }
-                self.create_fs_object(dirid, filename, &CreateFSObject::File(*attr))
+    #[tokio::test]
+    async fn test_read_only_server_refuses_every_write_op() {
+        let dir = scratch_dir("ro_matrix");
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        let attrs = sattr3 {
+            mode: set_mode3::mode(0o600),
+            uid: set_uid3::Void,
+            gid: set_gid3::Void,
+            size: set_size3::Void,
+            atime: set_atime::DONT_CHANGE,
+            mtime: set_mtime::DONT_CHANGE,
+        };
+        let listing = || {
+            let mut names: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            names.sort();
+            names
+        };
+        let before = listing();
+
+        // Read-only from the configuration, and switched to read-only later
+        let server = ServerConfig {
+            read_only: true,
+            ..ServerConfig::default()
+        };
+        let (configured, _) = mirror(&dir, server).await;
+        let (switched, _) = mirror(&dir, ServerConfig::default()).await;
+        switched.read_only_switch.set(true);
+        for fs in [configured, switched] {
+            let mount_id = fs.lookup(&auth(), 0, &fname("test")).await.unwrap();
+            let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+            let sub = fs.lookup(&auth(), mount_id, &fname("sub")).await.unwrap();
+            let results = [
+                (
+                    "create",
+                    fs.create(&auth(), mount_id, &fname("new"), attrs)
+                        .await
+                        .map(drop),
+                ),
+                (
+                    "create_exclusive",
+                    fs.create_exclusive(&auth(), mount_id, &fname("new"))
+                        .await
+                        .map(drop),
+                ),
+                ("write", fs.write(&auth(), id, 0, b"x").await.map(drop)),
+                ("setattr", fs.setattr(&auth(), id, attrs).await.map(drop)),
+                ("remove", fs.remove(&auth(), mount_id, &fname("file")).await),
+                (
+                    "rename",
+                    fs.rename(&auth(), mount_id, &fname("file"), sub, &fname("moved"))
+                        .await,
+                ),
+                (
+                    "mkdir",
+                    fs.mkdir(&auth(), mount_id, &fname("dir"), &attrs)
+                        .await
+                        .map(drop),
+                ),
+                (
+                    "symlink",
+                    fs.symlink(
+                        &auth(),
+                        mount_id,
+                        &fname("link"),
+                        &"file".as_bytes().into(),
+                        &attrs,
+                    )
                     .await
-            }
-            ftype3::NF3SOCK | ftype3::NF3FIFO => {
-                // FIFOs can be created with mkfifo, but for simplicity create regular files
-                self.create_fs_object(dirid, filename, &CreateFSObject::File(*attr))
+                    .map(drop),
+                ),
+                ("link", fs.link(&auth(), id, mount_id, &fname("hard")).await),
+                (
+                    "mknod",
+                    fs.mknod(
+                        &auth(),
+                        mount_id,
+                        &fname("fifo"),
+                        ftype3::NF3FIFO,
+                        &attrs,
+                        None,
+                    )
                     .await
+                    .map(drop),
+                ),
+            ];
+            for (op, result) in results {
+                assert!(
+                    matches!(result, Err(nfsstat3::NFS3ERR_ROFS)),
+                    "{} returned {:?}",
+                    op,
+                    result
+                );
             }
-            _ => Err(nfsstat3::NFS3ERR_BADTYPE),
-        }
-    }
-
-    async fn link(
-        &self,
-        _auth: &AuthContext,
-        fileid: fileid3,
-        linkdirid: fileid3,
-        linkname: &filename3,
-    ) -> Result<(), nfsstat3> {
-        if self.read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
         }
+        assert_eq!(listing(), before);
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"data");
 
-        let mut fsmap = self.fsmap.lock().await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        // Get the file path
-        let file_entry = fsmap.find_entry(fileid)?;
-        let (file_path, _file_read_only) = match fsmap.sym_to_real_path(&file_entry.name).await {
-            Some(path) => path,
-            None => {
-                // This is a mount point or root, cannot link
-                return Err(nfsstat3::NFS3ERR_ACCES);
-            }
+    #[tokio::test]
+    async fn test_grace_period_defers_writes() {
+        let dir = scratch_dir("grace");
+        std::fs::write(dir.join("file"), b"data").unwrap();
+        let server = ServerConfig {
+            grace_period_secs: 1,
+            ..ServerConfig::default()
         };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
 
-        // Get the link directory path
-        let linkdir_entry = fsmap.find_entry(linkdirid)?;
-        let (link_dir_path, link_read_only) =
-            match fsmap.sym_to_real_path(&linkdir_entry.name).await {
-                Some(path) => path,
-                None => {
-                    // This is a mount point, cannot create link here
-                    return Err(nfsstat3::NFS3ERR_ACCES);
-                }
-            };
-
-        if link_read_only {
-            return Err(nfsstat3::NFS3ERR_ROFS);
-        }
-
-        let mut link_path = link_dir_path;
-        link_path.push(OsStr::from_bytes(linkname));
+        assert!(matches!(
+            fs.write(&auth(), id, 0, b"new!").await,
+            Err(nfsstat3::NFS3ERR_JUKEBOX)
+        ));
+        assert!(matches!(
+            fs.remove(&auth(), mount_id, &fname("file")).await,
+            Err(nfsstat3::NFS3ERR_JUKEBOX)
+        ));
+        // Reads are served during the grace period
+        assert_eq!(fs.read(&auth(), id, 0, 16).await.unwrap().0, b"data");
 
-        // Create the hard link
-        tokio::fs::hard_link(&file_path, &link_path)
-            .await
-            .map_err(|e| {
-                debug!("Failed to create hard link: {:?}", e);
-                match e.kind() {
-                    std::io::ErrorKind::PermissionDenied => nfsstat3::NFS3ERR_ACCES,
-                    std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
-                    std::io::ErrorKind::AlreadyExists => nfsstat3::NFS3ERR_EXIST,
-                    _ => nfsstat3::NFS3ERR_IO,
-                }
-            })?;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        fs.write(&auth(), id, 0, b"new!").await.unwrap();
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"new!");
 
-        // Update the fsmap with the new link
-        let link_sym = fsmap
-            .intern
-            .intern(OsStr::from_bytes(linkname).to_os_string())
-            .unwrap();
-        let mut link_sympath = linkdir_entry.name.clone();
-        link_sympath.push(link_sym);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-        // The link points to the same fileid as the original file
-        fsmap.path_to_id.insert(link_sympath.clone(), fileid);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_ordered_file_io_never_tears_reads() {
+        const SIZE: usize = 8 * 1024 * 1024;
+        let dir = scratch_dir("ordered_io");
+        std::fs::write(dir.join("file"), vec![b'a'; SIZE]).unwrap();
+        let server = ServerConfig {
+            ordered_file_io: true,
+            ..ServerConfig::default()
+        };
+        let (fs, mount_id) = mirror(&dir, server).await;
+        let id = fs.lookup(&auth(), mount_id, &fname("file")).await.unwrap();
+        let fs = Arc::new(fs);
 
-        // Update the directory's children if needed
-        if let Ok(linkdir_entry_mut) = fsmap.find_entry_mut(linkdirid) {
-            if let Some(ref mut children) = linkdir_entry_mut.children {
-                children.insert(fileid);
-            }
+        // Each write replaces the whole file with a single byte value
+        let writer = {
+            let fs = Arc::clone(&fs);
+            tokio::spawn(async move {
+                for fill in b'b'..=b'e' {
+                    fs.write(&auth(), id, 0, &vec![fill; SIZE]).await.unwrap();
+                }
+            })
+        };
+        while !writer.is_finished() {
+            let (data, _) = fs.read(&auth(), id, 0, SIZE as u32).await.unwrap();
+            assert_eq!(data.len(), SIZE);
+            assert!(
+                data.iter().all(|&b| b == data[0]),
+                "read saw part of a write"
+            );
         }
+        writer.await.unwrap();
+        assert!(
+            std::fs::read(dir.join("file"))
+                .unwrap()
+                .iter()
+                .all(|&b| b == b'e')
+        );
 
-        Ok(())
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
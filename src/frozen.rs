@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use zerofs_nfsserve::nfs::*;
+
+use crate::dirlist::ListedEntry;
+
+/// A file or directory of a frozen tree, as presented to clients
+#[derive(Debug)]
+pub struct FrozenEntry {
+    /// Attributes with the presentation overrides applied
+    pub attr: fattr3,
+    /// Backing file read directly, for regular files outside archives
+    pub source: Option<FrozenSource>,
+    /// Mount the entry belongs to, for the access counters
+    pub mount: Option<usize>,
+    /// Directory holding the entry (the root holds itself)
+    pub parent: fileid3,
+    /// Entries of a directory in readdir order, with presented attributes
+    pub listing: Option<Arc<Vec<ListedEntry>>>,
+}
+
+/// Where a frozen regular file is read from
+#[derive(Debug)]
+pub struct FrozenSource {
+    pub path: PathBuf,
+    pub use_mmap: bool,
+}
+
+/// Immutable copy of a snapshot, served without locking the file map
+///
+/// A frozen map never changes, so GETATTR, READ and READDIR can answer from
+/// a copy of it taken once. The copy doubles the memory the snapshot takes;
+/// anything not in it, like archive members, is served from the map.
+#[derive(Debug, Default)]
+pub struct FrozenView {
+    entries: HashMap<fileid3, FrozenEntry>,
+}
+
+impl FrozenView {
+    pub fn insert(&mut self, id: fileid3, entry: FrozenEntry) {
+        self.entries.insert(id, entry);
+    }
+
+    pub fn get(&self, id: fileid3) -> Option<&FrozenEntry> {
+        self.entries.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -1,32 +1,71 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs::Metadata;
+use std::future::Future;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use intaglio::Symbol;
 use intaglio::osstr::SymbolTable;
+use serde::Serialize;
 use tokio::fs;
-use tracing::debug;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{debug, warn};
 
 use zerofs_nfsserve::fs_util::*;
 use zerofs_nfsserve::nfs::*;
 
+use crate::archive::{self, Member};
+use crate::config::{IdMap, MountConfig, UnreadableEntries};
+use crate::fileids::FileIdStore;
+use crate::fnv;
+use crate::instrument;
+use crate::metrics::{MountAccess, MountStats};
+use crate::names;
+use crate::ratelimit::{LogLimiter, suppressed_note};
+use crate::retry::RetryPolicy;
+
+/// How long a listing of a date view is served before the mount is scanned again
+const DATE_VIEW_REFRESH: Duration = Duration::from_secs(60);
+
+/// How long repeats of a backing store error are counted instead of logged
+const LOG_REPEAT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Fileids below this are reserved for mount points, files get higher ones
+const MOUNT_FILEIDS: fileid3 = 1 << 16;
+
 #[derive(Debug, Clone)]
 pub struct FSEntry {
     pub name: Vec<Symbol>,
+    /// Fileid of the directory holding the entry (the root holds itself)
+    pub parent: fileid3,
     pub fsmeta: fattr3,
     /// metadata when building the children list
     pub children_meta: fattr3,
     pub children: Option<BTreeSet<fileid3>>,
+    /// When `fsmeta` was last read from the backing store
+    pub refreshed: Option<Instant>,
+    /// Resolved backing path, kept with `cache_real_paths` until the entry moves or is relisted
+    pub real_path: OnceLock<RealPath>,
+}
+
+/// Backing path of an entry as resolved by `sym_to_real_path`
+#[derive(Debug, Clone)]
+pub struct RealPath {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// Index of the mount, to count accesses without resolving it again
+    pub mount: usize,
 }
 
 /// File system mapping structure
 #[derive(Debug)]
 pub struct FSMap {
     /// Mount configurations
-    pub mounts: Vec<(String, PathBuf, bool)>, // (target_path, source_path, read_only)
+    pub mounts: Vec<MountConfig>,
     /// Next file ID counter
     pub next_fileid: AtomicU64,
     /// Symbol table for interned strings
@@ -35,6 +74,273 @@ pub struct FSMap {
     pub id_to_path: HashMap<fileid3, FSEntry>,
     /// Mapping from path symbols to file ID
     pub path_to_id: HashMap<Vec<Symbol>, fileid3>,
+    /// Maximum time to wait on a backing store stat (None waits forever)
+    pub stat_timeout: Option<Duration>,
+    /// Serve the cached view only, never refreshing from the backing store
+    pub frozen: bool,
+    /// Retry policy for transient stat errors
+    pub retry: RetryPolicy,
+    /// Serve cached attributes refreshed within this long without a new stat
+    pub attr_cache: Option<Duration>,
+    /// Keep the backing path of each entry once resolved instead of resolving it per request
+    pub cache_real_paths: bool,
+    /// When each mount was last accessed, indexed like `mounts`
+    pub mount_access: Arc<MountAccess>,
+    /// Stop listing a directory after this many entries (unlimited if None)
+    pub max_dir_entries: Option<usize>,
+    /// Most directory levels below an entry walked when it is moved or dropped, or below a date view's source (unlimited if None)
+    pub max_path_depth: Option<usize>,
+    /// Whether entries that cannot be stat'ed are left out of listings or listed anyway
+    pub unreadable_entries: UnreadableEntries,
+    /// Backing path of each file listed in a date view
+    pub date_view_files: HashMap<Vec<Symbol>, PathBuf>,
+    /// Paths that hard links made through the server bind to a fileid, besides the name of its entry
+    pub hard_links: HashMap<fileid3, Vec<Vec<Symbol>>>,
+    /// Virtual status files listed at mount roots
+    pub status_files: HashSet<Vec<Symbol>>,
+    /// Tar member of each file and symlink listed in an archive mount
+    pub archive_members: HashMap<Vec<Symbol>, Member>,
+    /// Fileids persisted across restarts (disabled if None)
+    pub fileid_store: Option<FileIdStore>,
+    /// Limits repeated warnings while a backing store keeps failing
+    pub log_limit: LogLimiter,
+    /// Fail directory listings after this many entries were read
+    #[cfg(test)]
+    fail_listing_after: Option<usize>,
+}
+
+/// Convert file metadata to NFS attributes, keeping nanosecond timestamps
+pub fn fattr3_from_metadata(fid: fileid3, meta: &Metadata) -> fattr3 {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let mut attr = metadata_to_fattr3(fid, meta);
+    // Only files, directories and symlinks are told apart by the conversion
+    let file_type = meta.file_type();
+    if file_type.is_fifo() {
+        attr.ftype = ftype3::NF3FIFO;
+    } else if file_type.is_socket() {
+        attr.ftype = ftype3::NF3SOCK;
+    } else if file_type.is_char_device() {
+        attr.ftype = ftype3::NF3CHR;
+    } else if file_type.is_block_device() {
+        attr.ftype = ftype3::NF3BLK;
+    }
+    attr.atime = nfstime3 {
+        seconds: meta.atime() as u32,
+        nseconds: meta.atime_nsec() as u32,
+    };
+    attr.mtime = nfstime3 {
+        seconds: meta.mtime() as u32,
+        nseconds: meta.mtime_nsec() as u32,
+    };
+    attr.ctime = nfstime3 {
+        seconds: meta.ctime() as u32,
+        nseconds: meta.ctime_nsec() as u32,
+    };
+    attr
+}
+
+/// Convert the metadata of the backing file at `path` to NFS attributes
+///
+/// A symlink's size is reported as the length of its target, as NFS clients
+/// expect; not every file system stores that in `st_size`, so it is read
+/// from the link itself. `path` is only used for symlinks.
+pub async fn backing_attr(fid: fileid3, meta: &Metadata, path: Option<&Path>) -> fattr3 {
+    let mut attr = fattr3_from_metadata(fid, meta);
+    if let (true, Some(path)) = (meta.file_type().is_symlink(), path) {
+        if let Ok(target) = fs::read_link(path).await {
+            attr.size = target.as_os_str().len() as u64;
+        }
+    }
+    attr
+}
+
+fn nfstime3_eq(lhs: &nfstime3, rhs: &nfstime3) -> bool {
+    lhs.seconds == rhs.seconds && lhs.nseconds == rhs.nseconds
+}
+
+/// Check whether two attribute sets differ in a way that requires a reload
+///
+/// Timestamps are compared at nanosecond precision so that two modifications
+/// within the same second are still detected.
+pub fn attrs_differ(lhs: &fattr3, rhs: &fattr3) -> bool {
+    lhs.ftype as u32 != rhs.ftype as u32
+        || lhs.size != rhs.size
+        || lhs.mode != rhs.mode
+        || lhs.uid != rhs.uid
+        || lhs.gid != rhs.gid
+        || lhs.nlink != rhs.nlink
+        || !nfstime3_eq(&lhs.mtime, &rhs.mtime)
+        || !nfstime3_eq(&lhs.ctime, &rhs.ctime)
+}
+
+/// Check whether the content of a file or directory changed: its type, size or mtime
+///
+/// A change to only its permissions, owner, link count or ctime leaves a
+/// directory's listing valid.
+pub fn content_differs(lhs: &fattr3, rhs: &fattr3) -> bool {
+    lhs.ftype as u32 != rhs.ftype as u32
+        || lhs.size != rhs.size
+        || !nfstime3_eq(&lhs.mtime, &rhs.mtime)
+}
+
+/// Run a backing store IO operation, bounded by `timeout` if set
+///
+/// A stale network mount can hang stat calls indefinitely; a timeout turns that
+/// into `NFS3ERR_JUKEBOX` so the client retries instead of wedging the server.
+pub async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    op: impl Future<Output = std::io::Result<T>>,
+) -> Result<T, nfsstat3> {
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, op)
+            .await
+            .map_err(|_| nfsstat3::NFS3ERR_JUKEBOX)?,
+        None => op.await,
+    };
+    result.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => nfsstat3::NFS3ERR_NOENT,
+        _ => nfsstat3::NFS3ERR_IO,
+    })
+}
+
+/// Stat `path` like `FSMap::stat`, without holding the map's lock during the stat
+///
+/// A stale network mount can hang a stat for the whole `stat_timeout`; with
+/// the lock released meanwhile, only the requests that need that path wait.
+pub async fn stat_unlocked(fsmap: &Mutex<FSMap>, path: &Path) -> Result<Metadata, nfsstat3> {
+    stat_unlocked_with(fsmap, path, fs::symlink_metadata).await
+}
+
+async fn stat_unlocked_with<F, Fut>(
+    fsmap: &Mutex<FSMap>,
+    path: &Path,
+    stat: F,
+) -> Result<Metadata, nfsstat3>
+where
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = std::io::Result<Metadata>>,
+{
+    let (retry, timeout) = {
+        let fsmap = fsmap.lock().await;
+        (fsmap.retry, fsmap.stat_timeout)
+    };
+    instrument::count_stat();
+    let result = with_timeout(timeout, retry.run(|| stat(path.to_path_buf()))).await;
+    fsmap.lock().await.note_stat_result(path, &result);
+    result
+}
+
+/// Refresh `id` like `FSMap::refresh_entry`, stat'ing it without holding the lock
+///
+/// Returns the lock, taken again to apply the stat, with the result.
+pub async fn refresh_unlocked(
+    fsmap: &Mutex<FSMap>,
+    id: fileid3,
+) -> (MutexGuard<'_, FSMap>, Result<RefreshResult, nfsstat3>) {
+    let path = fsmap.lock().await.refresh_stat_path(id).await;
+    let taken = match path {
+        Some(path) => {
+            let result = stat_unlocked(fsmap, &path).await;
+            Some((path, result))
+        }
+        None => None,
+    };
+    let mut guard = fsmap.lock().await;
+    let result = guard.refresh_entry_with(id, taken).await;
+    (guard, result)
+}
+
+/// List `id` like `FSMap::refresh_dir_list`, scanning a date view without holding the lock
+///
+/// A date view stats its whole mount tree, which must not stall every other
+/// request. Returns the lock, taken again to apply the listing, with the result.
+pub async fn refresh_dir_list_unlocked(
+    fsmap: &Mutex<FSMap>,
+    id: fileid3,
+) -> (MutexGuard<'_, FSMap>, Result<(), nfsstat3>) {
+    let scan = fsmap.lock().await.date_view_scan(id);
+    let scanned = match scan {
+        Some(scan) => Some(scan.run().await),
+        None => None,
+    };
+    let mut guard = fsmap.lock().await;
+    let result = guard.refresh_dir_list_with(id, scanned).await;
+    (guard, result)
+}
+
+/// Walk of a mount source for its date view, run without the map's lock
+struct DateViewScan {
+    source: PathBuf,
+    retry: RetryPolicy,
+    stat_timeout: Option<Duration>,
+    max_dir_entries: Option<usize>,
+    max_path_depth: Option<usize>,
+}
+
+/// Files found by a `DateViewScan`
+struct DateViewListing {
+    /// Attributes of the source, given to the date directories
+    source_meta: Metadata,
+    /// Backing path and attributes of each file, by date and then by name in the view
+    groups: BTreeMap<String, BTreeMap<OsString, (PathBuf, Metadata)>>,
+    /// Entries past `max_dir_entries` or `max_path_depth` were left out
+    truncated: bool,
+}
+
+impl DateViewScan {
+    /// Stat every regular file below the source, at most `max_path_depth` levels deep
+    async fn run(self) -> Result<DateViewListing, nfsstat3> {
+        instrument::count_stat();
+        let stat = self.retry.run(|| fs::symlink_metadata(&self.source));
+        let source_meta = with_timeout(self.stat_timeout, stat).await?;
+
+        let mut groups: BTreeMap<String, BTreeMap<OsString, (PathBuf, Metadata)>> = BTreeMap::new();
+        let mut truncated = false;
+        let mut pending = vec![(self.source.clone(), 0)];
+        while let Some((dir, depth)) = pending.pop() {
+            instrument::count_read_dir();
+            let Ok(mut listing) = fs::read_dir(&dir).await else {
+                debug!("Skipping unreadable {:?} in date view", dir);
+                continue;
+            };
+            let mut count = 0;
+            while let Ok(Some(child)) = listing.next_entry().await {
+                if self.max_dir_entries.is_some_and(|max| count >= max) {
+                    truncated = true;
+                    break;
+                }
+                count += 1;
+                instrument::count_stat();
+                let Ok(meta) = with_timeout(self.stat_timeout, child.metadata()).await else {
+                    continue;
+                };
+                if meta.is_dir() {
+                    match self.max_path_depth.is_some_and(|max| depth >= max) {
+                        true => truncated = true,
+                        false => pending.push((child.path(), depth + 1)),
+                    }
+                } else if meta.is_file() {
+                    let path = child.path();
+                    let relative = path.strip_prefix(&self.source).unwrap_or(&path);
+                    let mut name = relative.as_os_str().as_bytes().to_vec();
+                    for byte in name.iter_mut().filter(|byte| **byte == b'/') {
+                        *byte = b'_';
+                    }
+                    groups
+                        .entry(date_name(&meta))
+                        .or_default()
+                        .entry(OsStr::from_bytes(&name).to_os_string())
+                        .or_insert((path, meta));
+                }
+            }
+        }
+        Ok(DateViewListing {
+            source_meta,
+            groups,
+            truncated,
+        })
+    }
 }
 
 pub enum RefreshResult {
@@ -43,10 +349,46 @@ pub enum RefreshResult {
     /// The fileid needs to be reloaded. mtime has been updated, caches
     /// need to be evicted.
     Reload,
+    /// Only the attributes changed, e.g. by a chmod; the content and a
+    /// directory's listing are still valid.
+    MetaOnly,
     /// Nothing has changed
     Noop,
 }
 
+/// Content of a mount's status file
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    #[serde(flatten)]
+    stats: MountStats,
+    /// Entries below the mount root in the map
+    entries: usize,
+}
+
+/// Snapshot of the file map written by a dump
+#[derive(Debug, Serialize)]
+pub struct FSMapDump {
+    /// Activity of each mount
+    pub mounts: Vec<MountStats>,
+    pub entries: Vec<DumpedEntry>,
+    /// Paths in `path_to_id` whose fileid has no entry
+    pub unmapped_paths: Vec<(PathBuf, fileid3)>,
+}
+
+/// One cached entry as written by a dump
+#[derive(Debug, Serialize)]
+pub struct DumpedEntry {
+    pub fileid: fileid3,
+    pub path: PathBuf,
+    /// Fileid that `path_to_id` maps this entry's path to, normally `fileid`
+    pub path_id: Option<fileid3>,
+    pub ftype: String,
+    pub size: u64,
+    pub mtime: u32,
+    /// Children ids, if the directory has been listed
+    pub children: Option<Vec<fileid3>>,
+}
+
 impl FSMap {
     /// Create a new FSMap with root directory only
     pub fn new_with_root(root_dir: PathBuf) -> FSMap {
@@ -56,6 +398,23 @@ impl FSMap {
             intern: SymbolTable::new(),
             id_to_path: HashMap::new(),
             path_to_id: HashMap::new(),
+            stat_timeout: None,
+            frozen: false,
+            retry: RetryPolicy::default(),
+            attr_cache: None,
+            cache_real_paths: false,
+            mount_access: Arc::new(MountAccess::default()),
+            max_dir_entries: None,
+            max_path_depth: None,
+            unreadable_entries: UnreadableEntries::default(),
+            date_view_files: HashMap::new(),
+            hard_links: HashMap::new(),
+            status_files: HashSet::new(),
+            archive_members: HashMap::new(),
+            fileid_store: None,
+            log_limit: LogLimiter::new(LOG_REPEAT_WINDOW),
+            #[cfg(test)]
+            fail_listing_after: None,
         };
 
         // Create root entry with actual root directory metadata
@@ -66,9 +425,12 @@ impl FSMap {
 
         let root_entry = FSEntry {
             name: Vec::new(),
-            fsmeta: metadata_to_fattr3(0, &root_metadata),
-            children_meta: metadata_to_fattr3(0, &root_metadata),
+            parent: 0,
+            fsmeta: fattr3_from_metadata(0, &root_metadata),
+            children_meta: fattr3_from_metadata(0, &root_metadata),
             children: Some(BTreeSet::new()),
+            refreshed: None,
+            real_path: OnceLock::new(),
         };
 
         fsmap.id_to_path.insert(0, root_entry);
@@ -78,13 +440,31 @@ impl FSMap {
     }
 
     /// Create a new FSMap with mount points
-    pub fn new_with_mounts(root_dir: PathBuf, mounts: Vec<(String, PathBuf, bool)>) -> FSMap {
+    pub fn new_with_mounts(root_dir: PathBuf, mounts: Vec<MountConfig>) -> FSMap {
+        let mount_access = MountAccess::new(mounts.iter().map(|m| m.target.clone()).collect());
         let mut fsmap = FSMap {
             mounts,
-            next_fileid: AtomicU64::new(1),
+            next_fileid: AtomicU64::new(MOUNT_FILEIDS),
             intern: SymbolTable::new(),
             id_to_path: HashMap::new(),
             path_to_id: HashMap::new(),
+            stat_timeout: None,
+            frozen: false,
+            retry: RetryPolicy::default(),
+            attr_cache: None,
+            cache_real_paths: false,
+            mount_access: Arc::new(mount_access),
+            max_dir_entries: None,
+            max_path_depth: None,
+            unreadable_entries: UnreadableEntries::default(),
+            date_view_files: HashMap::new(),
+            hard_links: HashMap::new(),
+            status_files: HashSet::new(),
+            archive_members: HashMap::new(),
+            fileid_store: None,
+            log_limit: LogLimiter::new(LOG_REPEAT_WINDOW),
+            #[cfg(test)]
+            fail_listing_after: None,
         };
 
         // Create root entry with actual root directory metadata
@@ -95,40 +475,56 @@ impl FSMap {
 
         let root_entry = FSEntry {
             name: Vec::new(),
-            fsmeta: metadata_to_fattr3(0, &root_metadata),
-            children_meta: metadata_to_fattr3(0, &root_metadata),
+            parent: 0,
+            fsmeta: fattr3_from_metadata(0, &root_metadata),
+            children_meta: fattr3_from_metadata(0, &root_metadata),
             children: Some(BTreeSet::new()),
+            refreshed: None,
+            real_path: OnceLock::new(),
         };
 
         fsmap.id_to_path.insert(0, root_entry);
         fsmap.path_to_id.insert(Vec::new(), 0);
 
         // Initialize mount points as root children
-        for (target_path, source_path, _read_only) in &fsmap.mounts {
-            let target_sym = fsmap
+        for mount in &fsmap.mounts {
+            let Ok(target_sym) = fsmap
                 .intern
-                .intern(OsStr::new(target_path.trim_start_matches('/')).to_os_string())
-                .unwrap();
+                .intern(OsStr::new(mount.target_name()).to_os_string())
+            else {
+                warn!("Symbol table full, not serving mount {:?}", mount.target);
+                continue;
+            };
 
+            let fileid =
+                mount_fileid(mount.target_name(), &fsmap.id_to_path).unwrap_or_else(|| {
+                    warn!("No reserved fileid left for mount {:?}", mount.target);
+                    fsmap.allocate_fileid()
+                });
+            let mut source_meta = fattr3_from_metadata(
+                fileid,
+                &mount.source.metadata().unwrap_or_else(|_| {
+                    // Create default metadata if source doesn't exist
+                    std::fs::metadata(".").unwrap()
+                }),
+            );
+            if mount.archive {
+                // The archive file is presented as the directory of its members
+                source_meta.ftype = ftype3::NF3DIR;
+                source_meta.mode |= (source_meta.mode & 0o444) >> 2;
+            }
+
+            // Children are listed from the source directory on first access
             let mount_entry = FSEntry {
                 name: vec![target_sym],
-                fsmeta: metadata_to_fattr3(
-                    1,
-                    &source_path.metadata().unwrap_or_else(|_| {
-                        // Create default metadata if source doesn't exist
-                        std::fs::metadata(".").unwrap()
-                    }),
-                ),
-                children_meta: metadata_to_fattr3(
-                    1,
-                    &source_path
-                        .metadata()
-                        .unwrap_or_else(|_| std::fs::metadata(".").unwrap()),
-                ),
+                parent: 0,
+                fsmeta: source_meta,
+                children_meta: source_meta,
                 children: None,
+                refreshed: None,
+                real_path: OnceLock::new(),
             };
 
-            let fileid = fsmap.next_fileid.fetch_add(1, Ordering::SeqCst) as fileid3;
             fsmap.id_to_path.insert(fileid, mount_entry);
             fsmap.path_to_id.insert(vec![target_sym], fileid);
 
@@ -143,39 +539,407 @@ impl FSMap {
         fsmap
     }
 
+    /// Use `store` to give files the fileids they had before a restart
+    ///
+    /// New fileids are allocated above every stored one, so a path seen for
+    /// the first time never takes the id a known path will claim later.
+    pub fn attach_fileid_store(&mut self, store: FileIdStore) {
+        if let Some(max) = store.max_id() {
+            self.next_fileid.fetch_max(max + 1, Ordering::Relaxed);
+        }
+        self.fileid_store = Some(store);
+    }
+
+    /// Reserve room for `entries` more files and directories
+    ///
+    /// Called before a large tree is walked so the maps and the intern table
+    /// are allocated once instead of being rehashed as they grow.
+    pub fn reserve(&mut self, entries: usize) {
+        self.id_to_path.reserve(entries);
+        self.path_to_id.reserve(entries);
+        self.intern.reserve(entries);
+    }
+
+    /// Rebuild the intern table from the names still in use
+    ///
+    /// Interned names are never released, so over a churning tree the table
+    /// keeps every name it has ever seen. It is rebuilt once the names no
+    /// longer in use outnumber the live ones. Every symbol changes, so none
+    /// may be held across this call. Returns the number of names dropped.
+    pub fn compact_intern(&mut self) -> Result<usize, nfsstat3> {
+        let live: HashSet<Symbol> = self
+            .path_to_id
+            .keys()
+            .chain(self.id_to_path.values().map(|entry| &entry.name))
+            .chain(self.date_view_files.keys())
+            .chain(self.archive_members.keys())
+            .chain(self.status_files.iter())
+            .flatten()
+            .copied()
+            .collect();
+        let dead = self.intern.len() - live.len();
+        if dead <= live.len() {
+            return Ok(0);
+        }
+
+        // Nothing is changed until every live name is in the new table
+        let mut intern = SymbolTable::with_capacity(live.len());
+        let mut remap = HashMap::with_capacity(live.len());
+        for old in live {
+            let name = self.intern.get(old).ok_or(nfsstat3::NFS3ERR_SERVERFAULT)?;
+            let new = intern
+                .intern(name.to_os_string())
+                .map_err(|_| nfsstat3::NFS3ERR_SERVERFAULT)?;
+            remap.insert(old, new);
+        }
+        let rename =
+            |name: Vec<Symbol>| -> Vec<Symbol> { name.iter().map(|sym| remap[sym]).collect() };
+        for entry in self.id_to_path.values_mut() {
+            entry.name = rename(std::mem::take(&mut entry.name));
+        }
+        for links in self.hard_links.values_mut() {
+            for link in links.iter_mut() {
+                *link = rename(std::mem::take(link));
+            }
+        }
+        self.path_to_id = self
+            .path_to_id
+            .drain()
+            .map(|(name, id)| (rename(name), id))
+            .collect();
+        self.date_view_files = self
+            .date_view_files
+            .drain()
+            .map(|(name, path)| (rename(name), path))
+            .collect();
+        self.archive_members = self
+            .archive_members
+            .drain()
+            .map(|(name, member)| (rename(name), member))
+            .collect();
+        self.status_files = self.status_files.drain().map(rename).collect();
+        self.intern = intern;
+        Ok(dead)
+    }
+
+    /// Stat a backing path without following symlinks, bounded by `stat_timeout`
+    ///
+    /// The caller holds the map's lock for as long as the stat takes; see
+    /// `stat_unlocked` for stats that may hang.
+    pub async fn stat(&self, path: &Path) -> Result<Metadata, nfsstat3> {
+        instrument::count_stat();
+        let stat = self.retry.run(|| fs::symlink_metadata(path));
+        let result = with_timeout(self.stat_timeout, stat).await;
+        self.note_stat_result(path, &result);
+        result
+    }
+
+    /// Warn about a failed stat of `path`
+    fn note_stat_result(&self, path: &Path, result: &Result<Metadata, nfsstat3>) {
+        let kind = match result {
+            Err(nfsstat3::NFS3ERR_JUKEBOX) => "stat timeout",
+            Err(nfsstat3::NFS3ERR_IO) => "stat error",
+            _ => return,
+        };
+        // A failing source fails every stat below it, so warn once per mount
+        let mount = self
+            .mounts
+            .iter()
+            .position(|mount| path.starts_with(&mount.source))
+            .unwrap_or(usize::MAX);
+        if let Some(suppressed) = self.log_limit.check(kind, mount) {
+            match kind {
+                "stat timeout" => warn!(
+                    "Timed out waiting for stat of {:?}{}",
+                    path,
+                    suppressed_note(suppressed)
+                ),
+                _ => warn!("Failed to stat {:?}{}", path, suppressed_note(suppressed)),
+            }
+        }
+    }
+
+    /// Stat `path`, or take the result of a stat of it made without the lock
+    async fn stat_or_taken(
+        &self,
+        path: &Path,
+        taken: &mut Option<(PathBuf, Result<Metadata, nfsstat3>)>,
+    ) -> Result<Metadata, nfsstat3> {
+        match taken.take() {
+            Some((taken_path, result)) if taken_path == path => result,
+            _ => self.stat(path).await,
+        }
+    }
+
+    /// Backing path that `refresh_entry` would stat for `id`, if any
+    async fn refresh_stat_path(&self, id: fileid3) -> Option<PathBuf> {
+        let entry = self.id_to_path.get(&id)?;
+        if self.frozen || self.in_archive(&entry.name) || self.status_files.contains(&entry.name) {
+            return None;
+        }
+        if let Some((path, _)) = self.real_path_of(id).await {
+            return match (self.attr_cache, entry.refreshed) {
+                (Some(ttl), Some(refreshed)) if refreshed.elapsed() < ttl => None,
+                _ => Some(path),
+            };
+        }
+        let [name] = entry.name[..] else {
+            return None;
+        };
+        let name = self.intern.get(name)?;
+        self.mounts
+            .iter()
+            .find(|mount| name == OsStr::new(mount.target_name()))
+            .map(|mount| mount.source.clone())
+    }
+
+    /// Intern a file name, failing with `NFS3ERR_SERVERFAULT` once the symbol table is full
+    pub fn intern_name(&mut self, name: OsString) -> Result<Symbol, nfsstat3> {
+        self.intern.intern(name).map_err(|_| {
+            if let Some(suppressed) = self.log_limit.check("symbol table full", 0) {
+                warn!(
+                    "Symbol table full, cannot track more file names{}",
+                    suppressed_note(suppressed)
+                );
+            }
+            nfsstat3::NFS3ERR_SERVERFAULT
+        })
+    }
+
+    /// Find the mount that a symbolic path belongs to
+    pub fn mount_for(&self, symlist: &[Symbol]) -> Option<&MountConfig> {
+        self.mount_index(symlist).map(|i| &self.mounts[i])
+    }
+
+    /// Index in `mounts` of the mount that a symbolic path belongs to
+    pub fn mount_index(&self, symlist: &[Symbol]) -> Option<usize> {
+        let mount_name = self.intern.get(*symlist.first()?)?;
+        self.mounts
+            .iter()
+            .position(|mount| mount_name == OsStr::new(mount.target_name()))
+    }
+
+    /// Translate the owner of a file through the id maps of the mount it belongs to
+    pub fn map_ids(&self, mut attr: fattr3) -> fattr3 {
+        let Some(mount) = self
+            .id_to_path
+            .get(&attr.fileid)
+            .and_then(|entry| self.mount_for(&entry.name))
+        else {
+            return attr;
+        };
+        attr.uid = IdMap::to_client(&mount.uid_map, attr.uid);
+        attr.gid = IdMap::to_client(&mount.gid_map, attr.gid);
+        attr
+    }
+
+    /// Apply the display overrides of the mount a file belongs to
+    pub fn display_attr(&self, mut attr: fattr3) -> fattr3 {
+        let Some(entry) = self.id_to_path.get(&attr.fileid) else {
+            return attr;
+        };
+        let Some(mount) = self.mount_for(&entry.name) else {
+            return attr;
+        };
+        if entry.name.len() == 1 || mount.display_recursive {
+            if let Some(mode) = mount.display_mode {
+                attr.mode = mode & 0o7777;
+            }
+            if let Some(uid) = mount.display_uid {
+                attr.uid = uid;
+            }
+            if let Some(gid) = mount.display_gid {
+                attr.gid = gid;
+            }
+        }
+        attr
+    }
+
     /// Get the actual file system path for a given symbolic path
     pub async fn sym_to_real_path(&self, symlist: &[Symbol]) -> Option<(PathBuf, bool)> {
         if symlist.is_empty() {
             return None; // Root path doesn't map to a real file
         }
 
+        let index = self.mount_index(symlist)?;
+        self.mount_access.touch(index);
+
+        // Status files have no backing file at all
+        if self.status_files.contains(symlist) {
+            return None;
+        }
+
+        // The date view only exists in the map; its files resolve to where they
+        // were found, and they are never writable through it
+        if self.in_date_view(symlist) {
+            return self
+                .date_view_files
+                .get(symlist)
+                .map(|path| (path.clone(), true));
+        }
+
         // Check if this is a mount point
         if symlist.len() == 1 {
-            let mount_name = self.intern.get(symlist[0])?;
-            for (target_path, source_path, _read_only) in &self.mounts {
-                if mount_name == OsStr::new(target_path.trim_start_matches('/')) {
-                    return Some((source_path.clone(), *_read_only));
-                }
-            }
+            let mount = &self.mounts[index];
+            return Some((mount.source.clone(), mount.read_only));
         }
 
         // Check if this is under a mount point
         if symlist.len() >= 1 {
-            let mount_name = self.intern.get(symlist[0])?;
-            for (target_path, source_path, _read_only) in &self.mounts {
-                if mount_name == OsStr::new(target_path.trim_start_matches('/')) {
-                    let mut real_path = source_path.clone();
-                    for sym in &symlist[1..] {
-                        real_path.push(self.intern.get(*sym)?);
-                    }
-                    return Some((real_path, *_read_only));
-                }
+            let mount = &self.mounts[index];
+            let mut real_path = mount.source.clone();
+            for sym in &symlist[1..] {
+                real_path.push(self.intern.get(*sym)?);
             }
+            return Some((real_path, mount.read_only));
         }
 
         None
     }
 
+    /// Backing path and read-only flag of the entry `id`, as `sym_to_real_path` gives them
+    ///
+    /// With `cache_real_paths` the result is kept in the entry, so requests
+    /// on the same file skip walking its symbols and the mounts.
+    pub async fn real_path_of(&self, id: fileid3) -> Option<(PathBuf, bool)> {
+        let entry = self.id_to_path.get(&id)?;
+        if let Some(cached) = entry.real_path.get() {
+            self.mount_access.touch(cached.mount);
+            return Some((cached.path.clone(), cached.read_only));
+        }
+        let (path, read_only) = self.sym_to_real_path(&entry.name).await?;
+        if let (true, Some(mount)) = (self.cache_real_paths, self.mount_index(&entry.name)) {
+            let _ = entry.real_path.set(RealPath {
+                path: path.clone(),
+                read_only,
+                mount,
+            });
+        }
+        Some((path, read_only))
+    }
+
+    /// Resolve a directory that objects can be created in or removed from
+    ///
+    /// Mount points resolve to their source directory like any other directory.
+    /// The synthetic root only holds the mount points themselves, so it is not
+    /// a valid parent for namespace operations.
+    pub async fn real_dir_path(&self, symlist: &[Symbol]) -> Result<(PathBuf, bool), nfsstat3> {
+        if self.in_date_view(symlist) {
+            return Err(nfsstat3::NFS3ERR_ROFS);
+        }
+        let is_dir = self
+            .path_to_id
+            .get(symlist)
+            .and_then(|id| self.id_to_path.get(id))
+            .is_none_or(|entry| matches!(entry.fsmeta.ftype, ftype3::NF3DIR));
+        if !is_dir {
+            // e.g. a mount whose source is a single file
+            return Err(nfsstat3::NFS3ERR_NOTDIR);
+        }
+        self.sym_to_real_path(symlist)
+            .await
+            .ok_or(nfsstat3::NFS3ERR_ACCES)
+    }
+
+    /// Check whether a symbolic path lies in a mount of a tar archive
+    pub fn in_archive(&self, symlist: &[Symbol]) -> bool {
+        self.mount_for(symlist).is_some_and(|mount| mount.archive)
+    }
+
+    /// Archive file and tar member of a file or symlink in an archive mount
+    pub fn archive_member(&self, symlist: &[Symbol]) -> Option<(PathBuf, Member)> {
+        let member = self.archive_members.get(symlist)?.clone();
+        Some((self.mount_for(symlist)?.source.clone(), member))
+    }
+
+    /// Check whether a symbolic path is the date view of its mount or lies inside it
+    pub fn in_date_view(&self, symlist: &[Symbol]) -> bool {
+        symlist.len() >= 2
+            && self
+                .mount_for(symlist)
+                .and_then(|mount| mount.date_view.as_deref())
+                .is_some_and(|view| self.intern.get(symlist[1]) == Some(OsStr::new(view)))
+    }
+
+    /// Check whether `filename` names the status file in the directory at `dir`
+    pub fn is_status_name(&self, dir: &[Symbol], filename: &[u8]) -> bool {
+        dir.len() == 1
+            && self
+                .mount_for(dir)
+                .and_then(|mount| mount.status_file.as_deref())
+                .is_some_and(|status| status.as_bytes() == filename)
+    }
+
+    /// Current content of the status file at `symlist`, if it is one
+    pub fn status_content(&self, symlist: &[Symbol]) -> Option<Vec<u8>> {
+        if !self.status_files.contains(symlist) {
+            return None;
+        }
+        let index = self.mount_index(symlist)?;
+        let stats = self.mount_access.snapshot().into_iter().nth(index)?;
+        let entries = self
+            .id_to_path
+            .values()
+            .filter(|entry| entry.name.len() > 1 && entry.name[0] == symlist[0])
+            .count();
+        let mut content = serde_json::to_vec_pretty(&StatusReport { stats, entries }).ok()?;
+        content.push(b'\n');
+        Some(content)
+    }
+
+    /// Add the status file at `fullpath` to the map, or update its attributes
+    ///
+    /// It takes the ownership and times of `base`, the mount root, and the
+    /// size of its content right now.
+    fn create_status_file(&mut self, fullpath: &[Symbol], base: fattr3) -> fileid3 {
+        let existing = self.path_to_id.get(fullpath).copied();
+        let id = match existing {
+            Some(id) if self.status_files.contains(fullpath) => id,
+            _ => {
+                // A real file of the same name that is gone
+                if let Some(id) = existing {
+                    self.delete_entry(id);
+                }
+                self.status_files.insert(fullpath.to_vec());
+                let id = self.allocate_fileid();
+                self.path_to_id.insert(fullpath.to_vec(), id);
+                id
+            }
+        };
+        let size = self.status_content(fullpath).map_or(0, |c| c.len() as u64);
+        let attr = fattr3 {
+            ftype: ftype3::NF3REG,
+            mode: 0o444,
+            nlink: 1,
+            size,
+            used: size,
+            fileid: id,
+            ..base
+        };
+        self.id_to_path.insert(
+            id,
+            FSEntry {
+                name: fullpath.to_vec(),
+                parent: self.parent_id(fullpath),
+                fsmeta: attr,
+                children_meta: attr,
+                children: None,
+                refreshed: Some(Instant::now()),
+                real_path: OnceLock::new(),
+            },
+        );
+        id
+    }
+
+    /// Check whether `filename` names the date view in the directory at `dir`
+    pub fn is_date_view(&self, dir: &[Symbol], filename: &[u8]) -> bool {
+        dir.len() == 1
+            && self
+                .mount_for(dir)
+                .and_then(|mount| mount.date_view.as_deref())
+                .is_some_and(|view| view.as_bytes() == filename)
+    }
+
     pub async fn sym_to_path(&self, symlist: &[Symbol]) -> PathBuf {
         let mut ret = PathBuf::new();
         for i in symlist.iter() {
@@ -192,25 +956,314 @@ impl FSMap {
         }
     }
 
+    /// Push `id` and every cached entry below it onto `ret`
+    ///
+    /// Walks a work queue rather than recursing, visits each entry once even
+    /// if listings wrongly refer to it twice, and stops descending past
+    /// `max_path_depth` levels below `id`.
     fn collect_all_children(&self, id: fileid3, ret: &mut Vec<fileid3>) {
-        ret.push(id);
-        if let Some(entry) = self.id_to_path.get(&id) {
-            if let Some(ref ch) = entry.children {
-                for i in ch.iter() {
-                    self.collect_all_children(*i, ret);
-                }
+        let mut seen = HashSet::new();
+        let mut queue = vec![(id, 0)];
+        let mut cut_off = false;
+        while let Some((next, depth)) = queue.pop() {
+            if !seen.insert(next) {
+                continue;
+            }
+            ret.push(next);
+            let Some(children) = self.id_to_path.get(&next).and_then(|e| e.children.as_ref())
+            else {
+                continue;
+            };
+            if self.max_path_depth.is_some_and(|max| depth >= max) {
+                cut_off |= !children.is_empty();
+                continue;
             }
+            queue.extend(children.iter().map(|&child| (child, depth + 1)));
+        }
+        if cut_off {
+            warn!(
+                "Entries more than {} levels below {:?} were left in the map",
+                self.max_path_depth.unwrap_or_default(),
+                id
+            );
         }
     }
 
     pub fn delete_entry(&mut self, id: fileid3) {
+        // The parent's listing must not keep referring to a dead fileid
+        let parent = self.parent_of(id);
+        if let Some(parent) = parent.and_then(|parent| self.id_to_path.get_mut(&parent)) {
+            if let Some(ref mut children) = parent.children {
+                children.remove(&id);
+            }
+        }
         let mut children = Vec::new();
         self.collect_all_children(id, &mut children);
         for i in children.iter() {
             if let Some(ent) = self.id_to_path.remove(i) {
                 self.path_to_id.remove(&ent.name);
+                self.forget_stored_fileid(&ent.name);
+            }
+            for link in self.hard_links.remove(i).into_iter().flatten() {
+                self.path_to_id.remove(&link);
+            }
+        }
+    }
+
+    /// Describe every way the maps disagree with each other, without changing them
+    ///
+    /// Checks that `id_to_path` and `path_to_id` map each entry to the other,
+    /// that every listed child exists in the directory listing it, and that
+    /// the directory above every entry is in the map and is its parent.
+    pub fn check_consistency(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (&id, entry) in &self.id_to_path {
+            let path = self.display_name(&entry.name);
+            match self.path_to_id.get(&entry.name) {
+                Some(&mapped) if mapped == id => {}
+                Some(&mapped) => problems.push(format!(
+                    "fileid {} ({}) is mapped back to fileid {}",
+                    id, path, mapped
+                )),
+                None => problems.push(format!(
+                    "fileid {} ({}) is missing from path_to_id",
+                    id, path
+                )),
+            }
+            if let Some((_, dir)) = entry.name.split_last() {
+                match self.path_to_id.get(dir) {
+                    Some(&dirid) if dirid == entry.parent => {}
+                    Some(&dirid) => problems.push(format!(
+                        "fileid {} ({}) has parent {} instead of {}",
+                        id, path, entry.parent, dirid
+                    )),
+                    None => problems.push(format!(
+                        "fileid {} ({}) is in a directory missing from the map",
+                        id, path
+                    )),
+                }
+            }
+            for &child in entry.children.iter().flatten() {
+                match self.id_to_path.get(&child) {
+                    Some(_) if self.has_link_in(child, &entry.name) => {}
+                    Some(ch) => problems.push(format!(
+                        "fileid {} ({}) lists fileid {} ({}) of another directory",
+                        id,
+                        path,
+                        child,
+                        self.display_name(&ch.name)
+                    )),
+                    None => problems.push(format!(
+                        "fileid {} ({}) lists missing fileid {}",
+                        id, path, child
+                    )),
+                }
+            }
+        }
+        for (name, &id) in &self.path_to_id {
+            match self.id_to_path.get(&id) {
+                Some(entry) if entry.name == *name => {}
+                Some(_) if self.hard_links.get(&id).is_some_and(|l| l.contains(name)) => {}
+                Some(entry) => problems.push(format!(
+                    "{} maps to fileid {}, which is named {}",
+                    self.display_name(name),
+                    id,
+                    self.display_name(&entry.name)
+                )),
+                None => problems.push(format!(
+                    "{} maps to missing fileid {}",
+                    self.display_name(name),
+                    id
+                )),
+            }
+        }
+        problems.sort();
+        problems
+    }
+
+    /// Printable form of a symbolic path, for messages
+    fn display_name(&self, name: &[Symbol]) -> String {
+        let parts: Vec<_> = name
+            .iter()
+            .map(|&sym| {
+                self.intern
+                    .get(sym)
+                    .map_or("?".into(), |part| part.to_string_lossy())
+            })
+            .collect();
+        format!("/{}", parts.join("/"))
+    }
+
+    /// Fileid of the directory holding `id`, if it is in the map
+    pub fn parent_of(&self, id: fileid3) -> Option<fileid3> {
+        Some(self.id_to_path.get(&id)?.parent)
+    }
+
+    /// Fileid of the directory that holds, or will hold, the entry at `sympath`
+    fn parent_id(&self, sympath: &[Symbol]) -> fileid3 {
+        sympath
+            .split_last()
+            .and_then(|(_, parent)| self.path_to_id.get(parent))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Drop an entry whose path now holds a file of another type
+    ///
+    /// The parent is relisted on its next refresh, so the new file gets a
+    /// fileid of its own straight away instead of after the parent changes.
+    fn replace_entry(&mut self, id: fileid3) {
+        let parent = self.parent_of(id);
+        self.delete_entry(id);
+        if let Some(parent) = parent.and_then(|parent| self.id_to_path.get_mut(&parent)) {
+            parent.children = None;
+        }
+    }
+
+    /// Bind `sympath` to the file `id` as another hard link to it
+    pub fn add_link(&mut self, id: fileid3, sympath: Vec<Symbol>) {
+        self.path_to_id.insert(sympath.clone(), id);
+        if self
+            .id_to_path
+            .get(&id)
+            .is_some_and(|ent| ent.name == sympath)
+        {
+            return;
+        }
+        let links = self.hard_links.entry(id).or_default();
+        if !links.contains(&sympath) {
+            links.push(sympath);
+        }
+    }
+
+    /// Bind the hard link `from` of the file `id` to the path it was renamed to
+    ///
+    /// Returns false if `from` is not one of the file's other links, in which
+    /// case it is the entry's own name and `move_entry` applies.
+    pub fn move_link(&mut self, id: fileid3, from: &[Symbol], to: Vec<Symbol>) -> bool {
+        let Some(link) = self
+            .hard_links
+            .get_mut(&id)
+            .and_then(|links| links.iter_mut().find(|link| *link == from))
+        else {
+            return false;
+        };
+        *link = to.clone();
+        self.path_to_id.remove(from);
+        self.path_to_id.insert(to, id);
+        true
+    }
+
+    /// Check whether the file `id` has a path in the directory at `dir`
+    pub fn has_link_in(&self, id: fileid3, dir: &[Symbol]) -> bool {
+        let in_dir =
+            |path: &Vec<Symbol>| path.split_last().is_some_and(|(_, parent)| parent == dir);
+        self.id_to_path
+            .get(&id)
+            .is_some_and(|ent| in_dir(&ent.name))
+            || self
+                .hard_links
+                .get(&id)
+                .is_some_and(|links| links.iter().any(in_dir))
+    }
+
+    /// Drop the binding of a symbolic path, without the children of its entry
+    ///
+    /// Returns the fileid it was bound to and whether that was the file's
+    /// last path. A file with other hard links keeps its entry; if
+    /// `sympath` was the entry's name, the entry takes the name of another.
+    pub fn remove_path(&mut self, sympath: &[Symbol]) -> Option<(fileid3, bool)> {
+        let fileid = self.path_to_id.remove(sympath)?;
+        self.forget_stored_fileid(sympath);
+        let is_name = self
+            .id_to_path
+            .get(&fileid)
+            .is_some_and(|entry| entry.name == sympath);
+        let Some(links) = self.hard_links.get_mut(&fileid) else {
+            self.id_to_path.remove(&fileid);
+            return Some((fileid, true));
+        };
+        links.retain(|link| link != sympath);
+        let renamed = if is_name { links.pop() } else { None };
+        if links.is_empty() {
+            self.hard_links.remove(&fileid);
+        }
+        if is_name && renamed.is_none() {
+            self.id_to_path.remove(&fileid);
+            return Some((fileid, true));
+        }
+        if let Some(name) = renamed {
+            let parent = self.parent_id(&name);
+            if let (Some(source), Some(store)) =
+                (self.source_path(&name), self.fileid_store.as_mut())
+            {
+                store.insert(&source, fileid);
+            }
+            if let Some(entry) = self.id_to_path.get_mut(&fileid) {
+                entry.name = name;
+                entry.parent = parent;
+                entry.real_path = OnceLock::new();
             }
         }
+        Some((fileid, false))
+    }
+
+    /// Bind an entry to the symbolic path it was renamed to
+    ///
+    /// The cached entries below a moved directory move with it and keep
+    /// their parents; only the moved entry gets a new one.
+    pub fn move_entry(&mut self, fileid: fileid3, to: Vec<Symbol>) {
+        let parent = self.parent_id(&to);
+        let Some(entry) = self.id_to_path.get_mut(&fileid) else {
+            return;
+        };
+        entry.parent = parent;
+        let from = entry.name.clone();
+        let mut moved = Vec::new();
+        self.collect_all_children(fileid, &mut moved);
+        for id in moved {
+            let Some(entry) = self.id_to_path.get_mut(&id) else {
+                continue;
+            };
+            let Some(below) = entry.name.strip_prefix(&from[..]) else {
+                continue;
+            };
+            let mut name = to.clone();
+            name.extend_from_slice(below);
+            entry.real_path = OnceLock::new();
+            let old = std::mem::replace(&mut entry.name, name.clone());
+            self.path_to_id.remove(&old);
+            self.forget_stored_fileid(&old);
+            if let (Some(source), Some(store)) =
+                (self.source_path(&name), self.fileid_store.as_mut())
+            {
+                store.insert(&source, id);
+            }
+            self.path_to_id.insert(name, id);
+        }
+    }
+
+    /// Backing path whose fileid is persisted for a symbolic path
+    ///
+    /// Mount points keep the fileids derived from their target names, and
+    /// files in a date view share the backing path of the real file, so
+    /// neither is persisted.
+    fn source_path(&self, symlist: &[Symbol]) -> Option<PathBuf> {
+        if symlist.len() < 2 || self.in_date_view(symlist) {
+            return None;
+        }
+        let mut path = self.mount_for(symlist)?.source.clone();
+        for sym in &symlist[1..] {
+            path.push(self.intern.get(*sym)?);
+        }
+        Some(path)
+    }
+
+    fn forget_stored_fileid(&mut self, sympath: &[Symbol]) {
+        if let (Some(source), Some(store)) = (self.source_path(sympath), self.fileid_store.as_mut())
+        {
+            store.remove(&source);
+        }
     }
 
     pub fn find_entry(&self, id: fileid3) -> Result<FSEntry, nfsstat3> {
@@ -226,29 +1279,152 @@ impl FSMap {
     }
 
     pub async fn find_child(&self, id: fileid3, filename: &[u8]) -> Result<fileid3, nfsstat3> {
-        let mut name = self
-            .id_to_path
-            .get(&id)
-            .ok_or(nfsstat3::NFS3ERR_NOENT)?
-            .name
-            .clone();
-        name.push(
-            self.intern
-                .check_interned(OsStr::from_bytes(filename))
-                .ok_or(nfsstat3::NFS3ERR_NOENT)?,
-        );
-        Ok(*self.path_to_id.get(&name).ok_or(nfsstat3::NFS3ERR_NOENT)?)
+        let dir = self.id_to_path.get(&id).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        if let Some(sym) = self.intern.check_interned(OsStr::from_bytes(filename)) {
+            let mut name = dir.name.clone();
+            name.push(sym);
+            if let Some(child) = self.path_to_id.get(&name) {
+                return Ok(*child);
+            }
+        }
+        self.find_child_loose(dir, filename)
+    }
+
+    /// Match `filename` against the listed children of `dir` by comparison key
+    ///
+    /// Only applies inside mounts with loose name matching. A name matching
+    /// more than one child is ambiguous and reported as `NFS3ERR_NOENT`.
+    fn find_child_loose(&self, dir: &FSEntry, filename: &[u8]) -> Result<fileid3, nfsstat3> {
+        let mount = self.mount_for(&dir.name).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        if !names::has_loose_matching(mount) {
+            return Err(nfsstat3::NFS3ERR_NOENT);
+        }
+        let key = names::match_key(mount, filename);
+        let mut found = None;
+        for child in dir.children.iter().flatten() {
+            let Some(sym) = self.id_to_path.get(child).and_then(|e| e.name.last()) else {
+                continue;
+            };
+            let Some(child_name) = self.intern.get(*sym) else {
+                continue;
+            };
+            if names::match_key(mount, child_name.as_bytes()) == key {
+                if found.is_some() {
+                    debug!("Ambiguous lookup of {:?}", OsStr::from_bytes(filename));
+                    return Err(nfsstat3::NFS3ERR_NOENT);
+                }
+                found = Some(*child);
+            }
+        }
+        match found {
+            Some(child) => Ok(child),
+            None if mount.short_names && filename.contains(&b'~') => {
+                self.find_short_alias(dir, filename)
+            }
+            None => Err(nfsstat3::NFS3ERR_NOENT),
+        }
+    }
+
+    /// Match `filename` against the 8.3 aliases of the listed children of `dir`, ignoring case
+    fn find_short_alias(&self, dir: &FSEntry, filename: &[u8]) -> Result<fileid3, nfsstat3> {
+        let mut children = Vec::new();
+        let mut child_names = Vec::new();
+        for child in dir.children.iter().flatten() {
+            let Some(sym) = self.id_to_path.get(child).and_then(|e| e.name.last()) else {
+                continue;
+            };
+            if let Some(child_name) = self.intern.get(*sym) {
+                children.push(*child);
+                child_names.push(child_name.as_bytes());
+            }
+        }
+        names::short_aliases(&child_names)
+            .into_iter()
+            .zip(children)
+            .find(|(alias, _)| {
+                alias
+                    .as_ref()
+                    .is_some_and(|alias| alias.as_bytes().eq_ignore_ascii_case(filename))
+            })
+            .map(|(_, child)| child)
+            .ok_or(nfsstat3::NFS3ERR_NOENT)
+    }
+
+    /// Drop cached attributes so the next refresh stats the backing store
+    ///
+    /// Called after the server itself modifies an entry, so the attribute
+    /// cache never hides a change made through this server.
+    pub fn invalidate(&mut self, id: fileid3) {
+        if let Some(entry) = self.id_to_path.get_mut(&id) {
+            entry.refreshed = None;
+        }
+    }
+
+    /// Describe every cached entry, for diagnosing cache inconsistencies
+    ///
+    /// Only reads the map; entries are sorted by fileid so dumps diff cleanly.
+    pub async fn dump(&self) -> FSMapDump {
+        let mut entries = Vec::with_capacity(self.id_to_path.len());
+        for (&fileid, entry) in &self.id_to_path {
+            entries.push(DumpedEntry {
+                fileid,
+                path: self.sym_to_path(&entry.name).await,
+                path_id: self.path_to_id.get(&entry.name).copied(),
+                ftype: format!("{:?}", entry.fsmeta.ftype),
+                size: entry.fsmeta.size,
+                mtime: entry.fsmeta.mtime.seconds,
+                children: entry
+                    .children
+                    .as_ref()
+                    .map(|children| children.iter().copied().collect()),
+            });
+        }
+        entries.sort_by_key(|entry| entry.fileid);
+
+        let mut unmapped_paths = Vec::new();
+        for (sympath, &fileid) in &self.path_to_id {
+            if !self.id_to_path.contains_key(&fileid) {
+                unmapped_paths.push((self.sym_to_path(sympath).await, fileid));
+            }
+        }
+        unmapped_paths.sort();
+
+        FSMapDump {
+            mounts: self.mount_access.snapshot(),
+            entries,
+            unmapped_paths,
+        }
     }
 
     pub async fn refresh_entry(&mut self, id: fileid3) -> Result<RefreshResult, nfsstat3> {
+        self.refresh_entry_with(id, None).await
+    }
+
+    /// Refresh `id`, using `taken`, a stat made without the lock, if it is of the path to stat
+    async fn refresh_entry_with(
+        &mut self,
+        id: fileid3,
+        mut taken: Option<(PathBuf, Result<Metadata, nfsstat3>)>,
+    ) -> Result<RefreshResult, nfsstat3> {
         let entry = self
             .id_to_path
             .get(&id)
             .ok_or(nfsstat3::NFS3ERR_NOENT)?
             .clone();
+        // An archive is read once, when its mount is first listed
+        if self.frozen || self.in_archive(&entry.name) {
+            return Ok(RefreshResult::Noop);
+        }
+
+        // Its content, and so its size, changes without anything on disk changing
+        if self.status_files.contains(&entry.name) {
+            let base = self.find_entry(entry.parent)?.fsmeta;
+            self.create_status_file(&entry.name, base);
+            return Ok(RefreshResult::MetaOnly);
+        }
 
         // Get the real file system path
-        let (real_path, _read_only) = match self.sym_to_real_path(&entry.name).await {
+        let (real_path, _read_only) = match self.real_path_of(id).await {
             Some(path) => path,
             None => {
                 // Root entry or mount point, handle differently
@@ -258,32 +1434,39 @@ impl FSMap {
                 } else {
                     // Mount point - check if source exists
                     let mounts = self.mounts.clone();
-                    for (target_path, source_path, _) in &mounts {
+                    for mount in &mounts {
+                        let source_path = &mount.source;
                         if entry.name.len() == 1 {
                             let mount_name = self
                                 .intern
                                 .get(entry.name[0])
                                 .ok_or(nfsstat3::NFS3ERR_NOENT)?;
-                            if mount_name == OsStr::new(target_path.trim_start_matches('/')) {
-                                if !source_path.exists() {
-                                    self.delete_entry(id);
+                            if mount_name == OsStr::new(mount.target_name()) {
+                                let meta = match self.stat_or_taken(source_path, &mut taken).await {
+                                    Ok(meta) => meta,
+                                    Err(nfsstat3::NFS3ERR_NOENT) => {
+                                        self.delete_entry(id);
+                                        debug!(
+                                            "Deleting mount point {:?}: {:?}. Ent: {:?}",
+                                            id, source_path, entry
+                                        );
+                                        return Ok(RefreshResult::Delete);
+                                    }
+                                    Err(e) => return Err(e),
+                                };
+                                let meta = fattr3_from_metadata(id, &meta);
+                                if attrs_differ(&meta, &entry.fsmeta) {
+                                    if let Some(ent) = self.id_to_path.get_mut(&id) {
+                                        ent.fsmeta = meta;
+                                    }
+                                    if !content_differs(&meta, &entry.fsmeta) {
+                                        return Ok(RefreshResult::MetaOnly);
+                                    }
                                     debug!(
-                                        "Deleting mount point {:?}: {:?}. Ent: {:?}",
+                                        "Reloading mount point {:?}: {:?}. Ent: {:?}",
                                         id, source_path, entry
                                     );
-                                    return Ok(RefreshResult::Delete);
-                                }
-                                let meta = fs::symlink_metadata(source_path)
-                                    .await
-                                    .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-                                let meta = metadata_to_fattr3(id, &meta);
-                                if fattr3_differ(&meta, &entry.fsmeta) {
-                                    self.id_to_path.get_mut(&id).unwrap().fsmeta = meta;
-                                    debug!(
-                                        "Reloading mount point {:?}: {:?}. Ent: {:?}",
-                                        id, source_path, entry
-                                    );
-                                    return Ok(RefreshResult::Reload);
+                                    return Ok(RefreshResult::Reload);
                                 }
                                 return Ok(RefreshResult::Noop);
                             }
@@ -294,20 +1477,29 @@ impl FSMap {
             }
         };
 
-        if !exists_no_traverse(&real_path) {
-            self.delete_entry(id);
-            debug!(
-                "Deleting entry A {:?}: {:?}. Ent: {:?}",
-                id, real_path, entry
-            );
-            return Ok(RefreshResult::Delete);
+        if let (Some(ttl), Some(refreshed)) = (self.attr_cache, entry.refreshed) {
+            if refreshed.elapsed() < ttl {
+                return Ok(RefreshResult::Noop);
+            }
         }
 
-        let meta = fs::symlink_metadata(&real_path)
-            .await
-            .map_err(|_| nfsstat3::NFS3ERR_IO)?;
-        let meta = metadata_to_fattr3(id, &meta);
-        if !fattr3_differ(&meta, &entry.fsmeta) {
+        let meta = match self.stat_or_taken(&real_path, &mut taken).await {
+            Ok(meta) => meta,
+            Err(nfsstat3::NFS3ERR_NOENT) => {
+                self.delete_entry(id);
+                debug!(
+                    "Deleting entry A {:?}: {:?}. Ent: {:?}",
+                    id, real_path, entry
+                );
+                return Ok(RefreshResult::Delete);
+            }
+            Err(e) => return Err(e),
+        };
+        let meta = backing_attr(id, &meta, Some(&real_path)).await;
+        if let Some(ent) = self.id_to_path.get_mut(&id) {
+            ent.refreshed = Some(Instant::now());
+        }
+        if !attrs_differ(&meta, &entry.fsmeta) {
             return Ok(RefreshResult::Noop);
         }
         // If we get here we have modifications
@@ -323,7 +1515,7 @@ impl FSMap {
                 "File Type Mismatch META {:?} : {:?} vs {:?}",
                 id, entry.fsmeta, meta
             );
-            self.delete_entry(id);
+            self.replace_entry(id);
             debug!(
                 "Deleting entry B {:?}: {:?}. Ent: {:?}",
                 id, real_path, entry
@@ -332,7 +1524,13 @@ impl FSMap {
         }
         // inplace modification.
         // update metadata
-        self.id_to_path.get_mut(&id).unwrap().fsmeta = meta;
+        if let Some(ent) = self.id_to_path.get_mut(&id) {
+            ent.fsmeta = meta;
+        }
+        if !content_differs(&meta, &entry.fsmeta) {
+            debug!("Attributes of {:?} changed: {:?}", id, real_path);
+            return Ok(RefreshResult::MetaOnly);
+        }
         debug!(
             "Reloading entry {:?}: {:?}. Ent: {:?}",
             id, real_path, entry
@@ -341,13 +1539,64 @@ impl FSMap {
     }
 
     pub async fn refresh_dir_list(&mut self, id: fileid3) -> Result<(), nfsstat3> {
+        self.refresh_dir_list_with(id, None).await
+    }
+
+    /// Scan needed to relist `id`, if it is a date view whose listing is stale
+    fn date_view_scan(&self, id: fileid3) -> Option<DateViewScan> {
+        let entry = self.id_to_path.get(&id)?;
+        if self.frozen || !self.is_date_view_root(entry) || date_view_fresh(entry) {
+            return None;
+        }
+        self.scan_for_view(entry).ok()
+    }
+
+    /// Scan of the mount source behind the date view `view`
+    fn scan_for_view(&self, view: &FSEntry) -> Result<DateViewScan, nfsstat3> {
+        let mount = self.mount_for(&view.name).ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        Ok(DateViewScan {
+            source: mount.source.clone(),
+            retry: self.retry,
+            stat_timeout: self.stat_timeout,
+            max_dir_entries: self.max_dir_entries,
+            max_path_depth: self.max_path_depth,
+        })
+    }
+
+    /// Whether `entry` is the root of a date view
+    fn is_date_view_root(&self, entry: &FSEntry) -> bool {
+        entry.name.len() == 2 && self.in_date_view(&entry.name)
+    }
+
+    /// `refresh_dir_list`, with a date view listed from `scanned` if it was scanned already
+    async fn refresh_dir_list_with(
+        &mut self,
+        id: fileid3,
+        scanned: Option<Result<DateViewListing, nfsstat3>>,
+    ) -> Result<(), nfsstat3> {
         let entry = self
             .id_to_path
             .get(&id)
             .ok_or(nfsstat3::NFS3ERR_NOENT)?
             .clone();
-        // if there are children and the metadata did not change
-        if entry.children.is_some() && !fattr3_differ(&entry.children_meta, &entry.fsmeta) {
+        if self.frozen {
+            return Ok(());
+        }
+        if self.in_archive(&entry.name) {
+            if entry.name.len() == 1 && entry.children.is_none() {
+                self.list_archive(&entry).await?;
+            }
+            return Ok(());
+        }
+        // The date view has no metadata of its own to tell when it changed
+        let is_date_view = self.is_date_view_root(&entry);
+        if is_date_view {
+            if date_view_fresh(&entry) {
+                return Ok(());
+            }
+        } else if entry.children.is_some() && !content_differs(&entry.children_meta, &entry.fsmeta)
+        {
+            // if there are children and the directory's content did not change
             return Ok(());
         }
         if !matches!(entry.fsmeta.ftype, ftype3::NF3DIR) {
@@ -358,72 +1607,470 @@ impl FSMap {
         let mut new_children: Vec<u64> = Vec::new();
         debug!("Relisting entry {:?}: {:?}. Ent: {:?}", id, cur_path, entry);
 
+        // Everything is read from the backing store and interned before the
+        // map is touched, so a failure part way leaves the old listing intact
+        let mut listed: Vec<(OsString, Metadata)> = Vec::new();
+        let mut unreadable: Vec<(OsString, ftype3)> = Vec::new();
+        let mut view_entry = None;
+        let mut status_entry = None;
+
         // Handle root directory differently - list mount points
         if entry.name.is_empty() {
             // Root directory - list mount points
             let mounts = self.mounts.clone();
-            for (target_path, source_path, _read_only) in &mounts {
-                let target_sym = self
-                    .intern
-                    .intern(OsStr::new(target_path.trim_start_matches('/')).to_os_string())
-                    .unwrap();
-                cur_path.push(target_sym);
-
-                if source_path.exists() {
-                    let meta = fs::symlink_metadata(source_path)
-                        .await
-                        .unwrap_or_else(|_| std::fs::metadata(".").unwrap());
-                    let next_id = self.create_entry(&cur_path, meta).await;
-                    new_children.push(next_id);
+            for mount in &mounts {
+                if mount.archive {
+                    // Its attributes are those of a directory, not of the archive file
+                    let target = self.intern.check_interned(OsStr::new(mount.target_name()));
+                    new_children.extend(target.and_then(|sym| self.path_to_id.get(&[sym][..])));
+                } else if let Ok(meta) = self.stat(&mount.source).await {
+                    listed.push((OsStr::new(mount.target_name()).to_os_string(), meta));
                 }
-                cur_path.pop();
             }
+        } else if is_date_view {
+            new_children = self.list_date_view(&entry, scanned).await?;
         } else {
-            // Regular directory - get real path and list contents
+            // Mount point or regular directory - get real path and list contents
             let (real_path, _read_only) = match self.sym_to_real_path(&entry.name).await {
                 Some(path) => path,
-                None => return Ok(()), // Mount point without real path
+                None => return Ok(()), // Mount no longer configured
             };
 
+            instrument::count_read_dir();
             if let Ok(mut listing) = fs::read_dir(&real_path).await {
                 while let Some(entry) = listing
                     .next_entry()
                     .await
                     .map_err(|_| nfsstat3::NFS3ERR_IO)?
                 {
-                    let sym = self.intern.intern(entry.file_name()).unwrap();
-                    cur_path.push(sym);
-                    let meta = entry.metadata().await.unwrap();
-                    let next_id = self.create_entry(&cur_path, meta).await;
-                    new_children.push(next_id);
-                    cur_path.pop();
+                    let count = listed.len() + unreadable.len();
+                    if self.max_dir_entries.is_some_and(|max| count >= max) {
+                        let mount = self.mount_index(&cur_path).unwrap_or(usize::MAX);
+                        if let Some(suppressed) = self.log_limit.check("truncated listing", mount) {
+                            warn!(
+                                "{:?} has more than {} entries, listing only the first ones{}",
+                                real_path,
+                                count,
+                                suppressed_note(suppressed)
+                            );
+                        }
+                        break;
+                    }
+                    // The date view hides a real entry of the same name
+                    if self.is_date_view(&cur_path, entry.file_name().as_bytes()) {
+                        continue;
+                    }
+                    if self
+                        .mount_for(&cur_path)
+                        .is_some_and(|mount| names::is_hidden(mount, entry.file_name().as_bytes()))
+                    {
+                        continue;
+                    }
+                    #[cfg(test)]
+                    if self.fail_listing_after == Some(listed.len()) {
+                        return Err(nfsstat3::NFS3ERR_IO);
+                    }
+                    instrument::count_stat();
+                    match with_timeout(self.stat_timeout, entry.metadata()).await {
+                        Ok(meta) => listed.push((entry.file_name(), meta)),
+                        // Removed since it was listed
+                        Err(nfsstat3::NFS3ERR_NOENT) => {}
+                        // A slow backing store is worth retrying, not hiding
+                        Err(nfsstat3::NFS3ERR_JUKEBOX) => return Err(nfsstat3::NFS3ERR_JUKEBOX),
+                        Err(_) => {
+                            let mount = self.mount_index(&cur_path).unwrap_or(usize::MAX);
+                            if let Some(suppressed) =
+                                self.log_limit.check("unreadable entry", mount)
+                            {
+                                warn!(
+                                    "Cannot stat {:?} in {:?}, {}{}",
+                                    entry.file_name(),
+                                    real_path,
+                                    match self.unreadable_entries {
+                                        UnreadableEntries::Skip => "leaving it out",
+                                        UnreadableEntries::Placeholder => "listing a placeholder",
+                                    },
+                                    suppressed_note(suppressed)
+                                );
+                            }
+                            if self.unreadable_entries == UnreadableEntries::Placeholder {
+                                let ftype = match entry.file_type().await {
+                                    Ok(t) if t.is_dir() => ftype3::NF3DIR,
+                                    Ok(t) if t.is_symlink() => ftype3::NF3LNK,
+                                    _ => ftype3::NF3REG,
+                                };
+                                unreadable.push((entry.file_name(), ftype));
+                            }
+                        }
+                    }
+                }
+            }
+
+            if let Some(view) = self.mount_for(&cur_path).and_then(|m| m.date_view.clone()) {
+                if cur_path.len() == 1 {
+                    let mut view_path = cur_path.clone();
+                    view_path.push(self.intern_name(OsString::from(view))?);
+                    // Only stat the source when the view has no entry yet
+                    let meta = match self.path_to_id.contains_key(&view_path) {
+                        true => None,
+                        false => Some(self.stat(&real_path).await?),
+                    };
+                    view_entry = Some((view_path, meta));
+                }
+            }
+
+            if let Some(status) = self
+                .mount_for(&cur_path)
+                .and_then(|m| m.status_file.clone())
+            {
+                if cur_path.len() == 1 {
+                    // A real file of the same name is listed instead
+                    let shadowed = listed
+                        .iter()
+                        .map(|(name, _)| name)
+                        .chain(unreadable.iter().map(|(name, _)| name))
+                        .any(|name| name.as_bytes() == status.as_bytes());
+                    let mut status_path = cur_path.clone();
+                    status_path.push(self.intern_name(OsString::from(status))?);
+                    status_entry = Some((status_path, shadowed));
                 }
             }
         }
 
-        self.id_to_path
+        let mut symbols = Vec::with_capacity(listed.len());
+        for (name, meta) in listed {
+            symbols.push((self.intern_name(name)?, meta));
+        }
+        let mut placeholders = Vec::with_capacity(unreadable.len());
+        for (name, ftype) in unreadable {
+            placeholders.push((self.intern_name(name)?, ftype));
+        }
+        for (sym, meta) in symbols {
+            cur_path.push(sym);
+            new_children.push(self.create_entry(&cur_path, meta).await);
+            cur_path.pop();
+        }
+        for (sym, ftype) in placeholders {
+            cur_path.push(sym);
+            new_children.push(self.create_placeholder(&cur_path, ftype, entry.fsmeta));
+            cur_path.pop();
+        }
+        if let Some((view_path, meta)) = view_entry {
+            let view_id = match meta {
+                Some(meta) => Some(self.create_entry(&view_path, meta).await),
+                None => self.path_to_id.get(&view_path).copied(),
+            };
+            new_children.extend(view_id);
+        }
+        if let Some((status_path, shadowed)) = status_entry {
+            if shadowed {
+                self.status_files.remove(&status_path);
+            } else {
+                new_children.push(self.create_status_file(&status_path, entry.fsmeta));
+            }
+        }
+
+        let dir = self
+            .id_to_path
             .get_mut(&id)
+            .ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        dir.children = Some(BTreeSet::from_iter(new_children.into_iter()));
+        // Remember which directory metadata this listing corresponds to
+        dir.children_meta = entry.fsmeta;
+        if is_date_view {
+            dir.refreshed = Some(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    /// Add every member of the tar archive of the mount `mount` to the map
+    ///
+    /// The whole tree is listed at once, since the archive is not expected
+    /// to change while it is served. Directories that only appear in member
+    /// paths get the attributes of the mount root.
+    async fn list_archive(&mut self, mount: &FSEntry) -> Result<(), nfsstat3> {
+        let source = self
+            .mount_for(&mount.name)
             .ok_or(nfsstat3::NFS3ERR_NOENT)?
-            .children = Some(BTreeSet::from_iter(new_children.into_iter()));
+            .source
+            .clone();
+        let archive_meta = self.stat(&source).await?;
+        let index = {
+            let source = source.clone();
+            tokio::task::spawn_blocking(move || archive::read_index(&source))
+                .await
+                .map_err(|_| nfsstat3::NFS3ERR_SERVERFAULT)?
+        };
+        let index = index.map_err(|e| {
+            let mount = self.mount_index(&mount.name).unwrap_or(usize::MAX);
+            if let Some(suppressed) = self.log_limit.check("archive", mount) {
+                warn!(
+                    "Failed to read archive {:?}: {}{}",
+                    source,
+                    e,
+                    suppressed_note(suppressed)
+                );
+            }
+            nfsstat3::NFS3ERR_IO
+        })?;
 
+        // Intern every path before the map is touched
+        let mut members: BTreeMap<Vec<Symbol>, Option<Member>> = BTreeMap::new();
+        for member in index {
+            let mut sympath = mount.name.clone();
+            for part in member.path.iter() {
+                sympath.push(self.intern_name(part.to_os_string())?);
+            }
+            for depth in mount.name.len() + 1..sympath.len() {
+                members.entry(sympath[..depth].to_vec()).or_insert(None);
+            }
+            members.insert(sympath, Some(member));
+        }
+
+        let mut ids = Vec::with_capacity(members.len());
+        for (sympath, member) in members {
+            let id = match self.path_to_id.get(&sympath) {
+                Some(&id) => id,
+                None => self.allocate_fileid(),
+            };
+            let fsmeta = match member {
+                Some(ref member) => member.attr(fattr3_from_metadata(id, &archive_meta)),
+                None => fattr3 {
+                    fileid: id,
+                    ..mount.fsmeta
+                },
+            };
+            let is_dir = matches!(fsmeta.ftype, ftype3::NF3DIR);
+            if let (Some(member), false) = (member, is_dir) {
+                self.archive_members.insert(sympath.clone(), member);
+            }
+            self.id_to_path.insert(
+                id,
+                FSEntry {
+                    name: sympath.clone(),
+                    parent: self.parent_id(&sympath),
+                    fsmeta,
+                    children_meta: fsmeta,
+                    children: is_dir.then(BTreeSet::new),
+                    refreshed: None,
+                    real_path: OnceLock::new(),
+                },
+            );
+            self.path_to_id.insert(sympath.clone(), id);
+            ids.push((sympath, id));
+        }
+
+        let mount_id = *self
+            .path_to_id
+            .get(&mount.name)
+            .ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        if let Some(root) = self.id_to_path.get_mut(&mount_id) {
+            root.children = Some(BTreeSet::new());
+            root.children_meta = root.fsmeta;
+        }
+        for (sympath, id) in ids {
+            let parent = match self.path_to_id.get(&sympath[..sympath.len() - 1]) {
+                Some(&parent) => parent,
+                None => mount_id,
+            };
+            if let Some(children) = self
+                .id_to_path
+                .get_mut(&parent)
+                .and_then(|dir| dir.children.as_mut())
+            {
+                children.insert(id);
+            }
+        }
         Ok(())
     }
 
+    /// Group the files of the mount of the date view `view` by modification date
+    ///
+    /// Every regular file below the mount source appears in a directory named
+    /// after the UTC date of its mtime (`YYYY-MM-DD`), under its path relative
+    /// to the source with `/` replaced by `_`. The source is scanned here
+    /// unless `scanned` holds a scan taken without the lock. Entries of an
+    /// earlier scan that no longer appear are dropped. Returns the fileids of
+    /// the date directories.
+    async fn list_date_view(
+        &mut self,
+        view: &FSEntry,
+        scanned: Option<Result<DateViewListing, nfsstat3>>,
+    ) -> Result<Vec<fileid3>, nfsstat3> {
+        let DateViewListing {
+            source_meta,
+            groups,
+            truncated,
+        } = match scanned {
+            Some(scanned) => scanned?,
+            None => self.scan_for_view(view)?.run().await?,
+        };
+        if truncated {
+            let mount = self.mount_index(&view.name).unwrap_or(usize::MAX);
+            if let Some(suppressed) = self.log_limit.check("truncated date view", mount) {
+                warn!(
+                    "Date view {:?} leaves out files past max_dir_entries or max_path_depth{}",
+                    self.sym_to_path(&view.name).await,
+                    suppressed_note(suppressed)
+                );
+            }
+        }
+
+        // Intern every name before the map is touched, so running out of
+        // symbols leaves the previous scan in place
+        let mut interned = Vec::with_capacity(groups.len());
+        for (date, files) in groups {
+            let mut date_path = view.name.clone();
+            date_path.push(self.intern_name(OsString::from(date))?);
+            let mut symbols = Vec::with_capacity(files.len());
+            for (name, file) in files {
+                symbols.push((self.intern_name(name)?, file));
+            }
+            interned.push((date_path, symbols));
+        }
+
+        let mut stale = Vec::new();
+        let view_id = *self
+            .path_to_id
+            .get(&view.name)
+            .ok_or(nfsstat3::NFS3ERR_NOENT)?;
+        self.collect_all_children(view_id, &mut stale);
+        self.date_view_files
+            .retain(|path, _| !path.starts_with(&view.name));
+
+        let mut listed = BTreeSet::new();
+        let mut dates = Vec::new();
+        for (date_path, files) in interned {
+            let date_id = self.create_entry(&date_path, source_meta.clone()).await;
+            let mut children = BTreeSet::new();
+            for (sym, (path, meta)) in files {
+                let mut file_path = date_path.clone();
+                file_path.push(sym);
+                self.date_view_files.insert(file_path.clone(), path);
+                children.insert(self.create_entry(&file_path, meta).await);
+            }
+            listed.extend(children.iter().copied());
+            listed.insert(date_id);
+            if let Some(dir) = self.id_to_path.get_mut(&date_id) {
+                dir.children = Some(children);
+                dir.children_meta = dir.fsmeta;
+            }
+            dates.push(date_id);
+        }
+
+        for id in stale.into_iter().skip(1) {
+            if !listed.contains(&id) {
+                if let Some(ent) = self.id_to_path.remove(&id) {
+                    self.path_to_id.remove(&ent.name);
+                }
+            }
+        }
+        Ok(dates)
+    }
+
+    /// Walk the tree from the root, listing every directory up to `max_depth` levels deep
+    ///
+    /// Directories that fail to list are logged and skipped. Returns the number
+    /// of directories listed.
+    pub async fn walk(&mut self, max_depth: Option<usize>) -> usize {
+        let mut listed = 0;
+        let mut queue = vec![(0, 0)];
+        while let Some((id, depth)) = queue.pop() {
+            let subdirs = match self.list_subdirs(id).await {
+                Ok(subdirs) => subdirs,
+                Err(e) => {
+                    warn!("Failed to list {:?}: {:?}", id, e);
+                    continue;
+                }
+            };
+            listed += 1;
+            if max_depth.is_some_and(|max| depth >= max) {
+                continue;
+            }
+            queue.extend(subdirs.into_iter().map(|child| (child, depth + 1)));
+        }
+        listed
+    }
+
+    /// List a directory and return the fileids of its subdirectories
+    pub async fn list_subdirs(&mut self, id: fileid3) -> Result<Vec<fileid3>, nfsstat3> {
+        self.refresh_dir_list(id).await?;
+        let children = self.id_to_path.get(&id).and_then(|e| e.children.as_ref());
+        Ok(children
+            .into_iter()
+            .flatten()
+            .filter(|child| {
+                self.id_to_path
+                    .get(child)
+                    .is_some_and(|entry| matches!(entry.fsmeta.ftype, ftype3::NF3DIR))
+            })
+            .copied()
+            .collect())
+    }
+
+    /// Populate the whole tree and freeze it, so later refreshes return the cached view
+    pub async fn freeze(&mut self) -> usize {
+        let listed = self.walk(None).await;
+        self.frozen = true;
+        listed
+    }
+
+    /// Allocate a fileid not bound to any entry
+    ///
+    /// Ids come from a counter, but probe past any id that is already in use
+    /// (e.g. one restored or derived by other means) so that handles never
+    /// alias two different paths.
+    pub fn allocate_fileid(&self) -> fileid3 {
+        loop {
+            let id = self.next_fileid.fetch_add(1, Ordering::Relaxed);
+            match self.id_to_path.get(&id) {
+                Some(existing) => warn!(
+                    "fileid {} is already bound to {:?}, probing for another",
+                    id, existing.name
+                ),
+                None => return id,
+            }
+        }
+    }
+
     pub async fn create_entry(&mut self, fullpath: &Vec<Symbol>, meta: Metadata) -> fileid3 {
-        let next_id = if let Some(chid) = self.path_to_id.get(fullpath) {
-            if let Some(chent) = self.id_to_path.get_mut(chid) {
-                chent.fsmeta = metadata_to_fattr3(*chid, &meta);
+        let link_path = match meta.file_type().is_symlink() {
+            true => self.sym_to_real_path(fullpath).await.map(|(path, _)| path),
+            false => None,
+        };
+        // A file replaced by one of another type is a new file
+        if let Some(&chid) = self.path_to_id.get(fullpath) {
+            let ftype = fattr3_from_metadata(chid, &meta).ftype as u32;
+            if self
+                .id_to_path
+                .get(&chid)
+                .is_some_and(|ent| ent.fsmeta.ftype as u32 != ftype)
+            {
+                debug!("Type of {:?} changed, dropping it", chid);
+                self.delete_entry(chid);
+            }
+        }
+        let next_id = if let Some(&chid) = self.path_to_id.get(fullpath) {
+            let attr = backing_attr(chid, &meta, link_path.as_deref()).await;
+            if let Some(chent) = self.id_to_path.get_mut(&chid) {
+                chent.fsmeta = attr;
+                chent.refreshed = Some(Instant::now());
+                chent.real_path = OnceLock::new();
             }
-            *chid
+            chid
         } else {
             // path does not exist
-            let next_id = self.next_fileid.fetch_add(1, Ordering::Relaxed);
-            let metafattr = metadata_to_fattr3(next_id, &meta);
+            let next_id = self.new_fileid(fullpath);
+            let metafattr = backing_attr(next_id, &meta, link_path.as_deref()).await;
             let new_entry = FSEntry {
                 name: fullpath.clone(),
+                parent: self.parent_id(fullpath),
                 fsmeta: metafattr,
                 children_meta: metafattr,
                 children: None,
+                refreshed: Some(Instant::now()),
+                real_path: OnceLock::new(),
             };
             debug!("creating new entry {:?}: {:?}", next_id, meta);
             self.id_to_path.insert(next_id, new_entry);
@@ -432,4 +2079,855 @@ impl FSMap {
         };
         next_id
     }
+
+    /// Add an entry that could be listed but not stat'ed
+    ///
+    /// Everything but its type is filled in from `base`, the attributes of
+    /// its directory, with no permissions. An entry already in the map keeps
+    /// the attributes it was last seen with.
+    pub fn create_placeholder(
+        &mut self,
+        fullpath: &[Symbol],
+        ftype: ftype3,
+        base: fattr3,
+    ) -> fileid3 {
+        if let Some(&chid) = self.path_to_id.get(fullpath) {
+            return chid;
+        }
+        let next_id = self.new_fileid(fullpath);
+        let mut attr = base;
+        attr.ftype = ftype;
+        attr.fileid = next_id;
+        attr.mode = 0;
+        attr.nlink = 1;
+        attr.size = 0;
+        attr.used = 0;
+        let new_entry = FSEntry {
+            name: fullpath.to_vec(),
+            parent: self.parent_id(fullpath),
+            fsmeta: attr,
+            children_meta: attr,
+            children: None,
+            refreshed: Some(Instant::now()),
+            real_path: OnceLock::new(),
+        };
+        debug!("creating placeholder entry {:?}", next_id);
+        self.id_to_path.insert(next_id, new_entry);
+        self.path_to_id.insert(fullpath.to_vec(), next_id);
+        next_id
+    }
+
+    /// Fileid for a new entry at `fullpath`, reusing the persisted one if any
+    fn new_fileid(&mut self, fullpath: &[Symbol]) -> fileid3 {
+        let source = self
+            .fileid_store
+            .is_some()
+            .then(|| self.source_path(fullpath))
+            .flatten();
+        let stored = source
+            .as_ref()
+            .and_then(|source| self.fileid_store.as_ref()?.get(source))
+            .filter(|id| !self.id_to_path.contains_key(id));
+        let next_id = stored.unwrap_or_else(|| self.allocate_fileid());
+        if let (Some(source), Some(store)) = (source, self.fileid_store.as_mut()) {
+            store.insert(&source, next_id);
+        }
+        next_id
+    }
+}
+
+/// Fileid of the mount point with target name `name`
+///
+/// Derived from the name alone, so a mount keeps its fileid across restarts
+/// and when other mounts are added, removed or reordered. A name whose id is
+/// taken gets the next free one in the reserved range, and None once every
+/// id in the range is taken.
+fn mount_fileid(name: &str, taken: &HashMap<fileid3, FSEntry>) -> Option<fileid3> {
+    let start = fnv::hash(name.as_bytes()) % (MOUNT_FILEIDS - 1);
+    (0..MOUNT_FILEIDS - 1)
+        .map(|probe| 1 + (start + probe) % (MOUNT_FILEIDS - 1))
+        .find(|id| !taken.contains_key(id))
+}
+
+/// Whether the listing of the date view root `entry` is recent enough to reuse
+fn date_view_fresh(entry: &FSEntry) -> bool {
+    entry.children.is_some()
+        && entry
+            .refreshed
+            .is_some_and(|at| at.elapsed() < DATE_VIEW_REFRESH)
+}
+
+/// UTC date of a file's modification time, as `YYYY-MM-DD`
+fn date_name(meta: &Metadata) -> String {
+    use std::os::unix::fs::MetadataExt;
+
+    utc_date(meta.mtime())
+}
+
+/// UTC date of a Unix timestamp, as `YYYY-MM-DD`
+pub fn utc_date(secs: i64) -> String {
+    // Days since the epoch to a proleptic Gregorian date
+    let days = secs.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hung_stat_times_out() {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_hung_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // Opening a FIFO for reading blocks until a writer opens it, like a
+        // stat of a stale network mount
+        let fifo = dir.join("fifo");
+        let c_path = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+        // SAFETY: c_path is a valid NUL-terminated string
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let mut fsmap = FSMap::new_with_root(dir.clone());
+        fsmap.stat_timeout = Some(Duration::from_millis(200));
+        let fsmap = Arc::new(Mutex::new(fsmap));
+        let hung = tokio::spawn({
+            let fsmap = Arc::clone(&fsmap);
+            let fifo = fifo.clone();
+            async move {
+                let open =
+                    |path: PathBuf| async move { fs::File::open(path).await?.metadata().await };
+                stat_unlocked_with(&fsmap, &fifo, open).await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!hung.is_finished());
+        // The map stays usable while the backing call hangs
+        assert!(fsmap.try_lock().is_ok());
+        assert!(matches!(
+            hung.await.unwrap(),
+            Err(nfsstat3::NFS3ERR_JUKEBOX)
+        ));
+
+        // Let the abandoned open finish
+        let _ = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&fifo);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_attrs_differ_within_same_second() {
+        let meta = std::fs::metadata(std::env::temp_dir()).unwrap();
+        let before = fattr3_from_metadata(1, &meta);
+        let mut after = before;
+        after.mtime.nseconds = (before.mtime.nseconds + 1) % 1_000_000_000;
+        assert_eq!(before.mtime.seconds, after.mtime.seconds);
+        assert!(attrs_differ(&before, &after));
+        assert!(!attrs_differ(&before, &before));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_detects_sub_second_modification() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_nsec_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        std::fs::write(&file, b"a").unwrap();
+
+        let mut fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let id = fsmap.find_child(mount_id, b"file").await.unwrap();
+
+        // Same second, same size, different nanoseconds
+        let set_mtime = |nsec: i64| {
+            let c_path = std::ffi::CString::new(file.as_os_str().as_bytes()).unwrap();
+            let times = [
+                libc::timespec {
+                    tv_sec: 1_700_000_000,
+                    tv_nsec: nsec,
+                },
+                libc::timespec {
+                    tv_sec: 1_700_000_000,
+                    tv_nsec: nsec,
+                },
+            ];
+            assert_eq!(
+                unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) },
+                0
+            );
+        };
+        set_mtime(100);
+        fsmap.refresh_entry(id).await.unwrap();
+        set_mtime(200);
+        assert!(matches!(
+            fsmap.refresh_entry(id).await.unwrap(),
+            RefreshResult::Reload
+        ));
+        assert_eq!(fsmap.find_entry(id).unwrap().fsmeta.mtime.nseconds, 200);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_stat_maps_missing_to_noent() {
+        let fsmap = FSMap::new_with_root(std::env::temp_dir());
+        let missing = std::env::temp_dir().join("nfs_mirror_definitely_missing");
+        assert!(matches!(
+            fsmap.stat(&missing).await,
+            Err(nfsstat3::NFS3ERR_NOENT)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fileid_collision_probes_next_id() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_collide_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), b"").unwrap();
+        std::fs::write(dir.join("b"), b"").unwrap();
+        let mut fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+        fsmap.refresh_dir_list(0).await.unwrap();
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let a = fsmap.find_child(mount_id, b"a").await.unwrap();
+
+        // Force the counter back onto a bound id, as a colliding id scheme would
+        fsmap.next_fileid.store(a, Ordering::Relaxed);
+        let mut name = fsmap.find_entry(mount_id).unwrap().name;
+        name.push(fsmap.intern.intern(OsString::from("c")).unwrap());
+        let c = fsmap
+            .create_entry(&name, std::fs::metadata(dir.join("b")).unwrap())
+            .await;
+        assert_ne!(c, a);
+        assert_eq!(fsmap.find_child(mount_id, b"a").await.unwrap(), a);
+        assert_eq!(fsmap.path_to_id.get(&name), Some(&c));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dump_lists_entries_with_paths() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_dump_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"abc").unwrap();
+
+        let mut fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let id = fsmap.find_child(mount_id, b"file").await.unwrap();
+
+        let dump = fsmap.dump().await;
+        let file = dump.entries.iter().find(|e| e.fileid == id).unwrap();
+        assert_eq!(file.path, Path::new("test/file"));
+        assert_eq!(file.path_id, Some(id));
+        assert_eq!(file.size, 3);
+        let mount = dump.entries.iter().find(|e| e.fileid == mount_id).unwrap();
+        assert_eq!(mount.children.as_deref(), Some(&[id][..]));
+        assert!(dump.unmapped_paths.is_empty());
+        // Listing the mount accessed it
+        assert!(matches!(
+            dump.mounts[..],
+            [ref mount] if mount.target == "/test" && mount.last_access.is_some()
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_max_dir_entries_truncates_listing() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_max_dir_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..10 {
+            std::fs::write(dir.join(format!("file{}", i)), b"").unwrap();
+        }
+
+        let mut fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+        fsmap.max_dir_entries = Some(4);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let listed = fsmap.find_entry(mount_id).unwrap().children.unwrap();
+        assert_eq!(listed.len(), 4);
+
+        // Relisting the unchanged directory keeps the same view
+        fsmap.find_entry_mut(mount_id).unwrap().children = None;
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        assert_eq!(
+            fsmap.find_entry(mount_id).unwrap().children.unwrap(),
+            listed
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_entries_do_not_fail_listing() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Root can stat anything, so there is nothing to test
+        // SAFETY: geteuid has no preconditions
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!(
+            "nfs_mirror_unreadable_entries_{}",
+            std::process::id()
+        ));
+        let locked = dir.join("locked");
+        std::fs::create_dir_all(locked.join("sub")).unwrap();
+        std::fs::write(locked.join("file"), b"data").unwrap();
+        // Listable but not searchable: names can be read, their attributes cannot
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        for policy in [UnreadableEntries::Skip, UnreadableEntries::Placeholder] {
+            let mut fsmap =
+                FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+            fsmap.unreadable_entries = policy;
+            let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+            let locked_id = fsmap.find_child(mount_id, b"locked").await.unwrap();
+            fsmap.refresh_dir_list(locked_id).await.unwrap();
+            let children = fsmap.find_entry(locked_id).unwrap().children.unwrap();
+            match policy {
+                UnreadableEntries::Skip => assert!(children.is_empty()),
+                UnreadableEntries::Placeholder => {
+                    let mut types: Vec<_> = children
+                        .iter()
+                        .map(|&id| fsmap.find_entry(id).unwrap().fsmeta)
+                        .map(|attr| (attr.ftype as u32, attr.mode))
+                        .collect();
+                    types.sort();
+                    assert_eq!(
+                        types,
+                        vec![(ftype3::NF3REG as u32, 0), (ftype3::NF3DIR as u32, 0)]
+                    );
+                    assert_consistent(&fsmap);
+                }
+            }
+        }
+
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_date_view_groups_files_by_mtime() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_date_view_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), b"a").unwrap();
+        std::fs::write(dir.join("sub/b"), b"bb").unwrap();
+        let set_mtime = |path: &Path, secs: i64| {
+            let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+            let time = libc::timespec {
+                tv_sec: secs,
+                tv_nsec: 0,
+            };
+            let times = [time, time];
+            assert_eq!(
+                unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) },
+                0
+            );
+        };
+        // 2023-11-14 and 2000-02-29
+        set_mtime(&dir.join("a"), 1_700_000_000);
+        set_mtime(&dir.join("sub/b"), 951_782_400);
+
+        let mount = MountConfig {
+            date_view: Some(".by-date".to_string()),
+            ..MountConfig::new(dir.clone(), "/test")
+        };
+        let mut fsmap = FSMap::new_with_mounts(dir.clone(), vec![mount]);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let view = fsmap.find_child(mount_id, b".by-date").await.unwrap();
+        fsmap.refresh_dir_list(view).await.unwrap();
+
+        let day = fsmap.find_child(view, b"2000-02-29").await.unwrap();
+        let b = fsmap.find_child(day, b"sub_b").await.unwrap();
+        let entry = fsmap.find_entry(b).unwrap();
+        assert_eq!(entry.fsmeta.size, 2);
+        assert_eq!(
+            fsmap.sym_to_real_path(&entry.name).await,
+            Some((dir.join("sub/b"), true))
+        );
+        let day = fsmap.find_child(view, b"2023-11-14").await.unwrap();
+        assert!(fsmap.find_child(day, b"a").await.is_ok());
+
+        // The view is read-only and absent from the source
+        let view_name = fsmap.find_entry(view).unwrap().name;
+        assert!(matches!(
+            fsmap.real_dir_path(&view_name).await,
+            Err(nfsstat3::NFS3ERR_ROFS)
+        ));
+        assert!(!dir.join(".by-date").exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_date_view_scan_is_bounded() {
+        let dir = std::env::temp_dir().join(format!(
+            "nfs_mirror_date_view_bounded_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub/deep")).unwrap();
+        std::fs::write(dir.join("a"), b"").unwrap();
+        std::fs::write(dir.join("sub/b"), b"").unwrap();
+        std::fs::write(dir.join("sub/deep/c"), b"").unwrap();
+
+        let mount = MountConfig {
+            date_view: Some(".by-date".to_string()),
+            ..MountConfig::new(dir.clone(), "/test")
+        };
+        let mut fsmap = FSMap::new_with_mounts(dir.clone(), vec![mount]);
+        fsmap.max_path_depth = Some(1);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let view = fsmap.find_child(mount_id, b".by-date").await.unwrap();
+        let fsmap = Mutex::new(fsmap);
+        let listed_files = |fsmap: &FSMap| -> BTreeSet<OsString> {
+            let dates = fsmap.find_entry(view).unwrap().children.unwrap();
+            dates
+                .iter()
+                .flat_map(|&date| fsmap.find_entry(date).unwrap().children.unwrap())
+                .map(|id| {
+                    let name = fsmap.find_entry(id).unwrap().name;
+                    fsmap
+                        .intern
+                        .get(*name.last().unwrap())
+                        .unwrap()
+                        .to_os_string()
+                })
+                .collect()
+        };
+
+        // Directories deeper than max_path_depth are not walked
+        let (mut guard, listed) = refresh_dir_list_unlocked(&fsmap, view).await;
+        listed.unwrap();
+        assert_eq!(
+            listed_files(&guard),
+            BTreeSet::from([OsString::from("a"), OsString::from("sub_b")])
+        );
+
+        // Each directory contributes at most max_dir_entries entries
+        guard.max_path_depth = None;
+        guard.max_dir_entries = Some(1);
+        guard.find_entry_mut(view).unwrap().refreshed = None;
+        drop(guard);
+        let (guard, listed) = refresh_dir_list_unlocked(&fsmap, view).await;
+        listed.unwrap();
+        assert!(listed_files(&guard).len() <= 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reserve_presizes_maps() {
+        let mut fsmap = FSMap::new_with_root(std::env::temp_dir());
+        fsmap.reserve(10_000);
+        assert!(fsmap.id_to_path.capacity() >= 10_000);
+        assert!(fsmap.path_to_id.capacity() >= 10_000);
+        // The root entry is still there
+        assert!(fsmap.find_entry(0).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fileid_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_fileids_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(dir.join("data/a"), b"").unwrap();
+        std::fs::write(dir.join("data/b"), b"").unwrap();
+        let store = dir.join("fileids.json");
+
+        let start = || {
+            let mut fsmap = FSMap::new_with_mounts(
+                dir.clone(),
+                vec![MountConfig::new(dir.join("data"), "/test")],
+            );
+            fsmap.attach_fileid_store(FileIdStore::load(&store).unwrap());
+            tokio::sync::Mutex::new(fsmap)
+        };
+
+        let fsmap = start();
+        let (a, b) = {
+            let mut fsmap = fsmap.lock().await;
+            let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+            (
+                fsmap.find_child(mount_id, b"a").await.unwrap(),
+                fsmap.find_child(mount_id, b"b").await.unwrap(),
+            )
+        };
+        crate::fileids::save(&fsmap).await.unwrap();
+
+        // Different files get listed after the restart
+        std::fs::remove_file(dir.join("data/a")).unwrap();
+        std::fs::write(dir.join("data/c"), b"").unwrap();
+        let fsmap = start();
+        let mut fsmap = fsmap.lock().await;
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        assert_eq!(fsmap.find_child(mount_id, b"b").await.unwrap(), b);
+        let c = fsmap.find_child(mount_id, b"c").await.unwrap();
+        assert_ne!(c, a);
+        assert_ne!(c, b);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Check that `id_to_path`, `path_to_id` and the children lists agree
+    fn assert_consistent(fsmap: &FSMap) {
+        for (&id, entry) in &fsmap.id_to_path {
+            assert_eq!(fsmap.path_to_id.get(&entry.name), Some(&id));
+            for child in entry.children.iter().flatten() {
+                let child = &fsmap.id_to_path[child];
+                assert_eq!(child.name[..child.name.len() - 1], entry.name[..]);
+                assert_eq!(child.parent, id);
+            }
+        }
+        for (name, id) in &fsmap.path_to_id {
+            assert_eq!(&fsmap.id_to_path[id].name, name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_listing_leaves_map_unchanged() {
+        let dir =
+            std::env::temp_dir().join(format!("nfs_mirror_fsmap_fail_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["a", "b", "c"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let mount = MountConfig::new(dir.clone(), "/test");
+
+        for fail_after in 0..4 {
+            let mut fsmap = FSMap::new_with_mounts(dir.clone(), vec![mount.clone()]);
+            let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+            let before = fsmap.dump().await;
+
+            // Change the directory so the next listing differs
+            std::fs::remove_file(dir.join("a")).unwrap();
+            for name in ["d", "e"] {
+                std::fs::write(dir.join(name), b"").unwrap();
+            }
+            fsmap.find_entry_mut(mount_id).unwrap().children_meta.size += 1;
+            fsmap.fail_listing_after = Some(fail_after);
+            assert!(fsmap.refresh_dir_list(mount_id).await.is_err());
+            assert_consistent(&fsmap);
+            let after = fsmap.dump().await;
+            assert_eq!(
+                serde_json::to_value(&after.entries).unwrap(),
+                serde_json::to_value(&before.entries).unwrap(),
+                "map changed by a listing failing after {} entries",
+                fail_after
+            );
+
+            fsmap.fail_listing_after = None;
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+            assert_consistent(&fsmap);
+            assert!(fsmap.find_child(mount_id, b"d").await.is_ok());
+
+            std::fs::write(dir.join("a"), b"").unwrap();
+            for name in ["d", "e"] {
+                std::fs::remove_file(dir.join(name)).unwrap();
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_stat_errors_are_rate_limited() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_ratelimit_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+
+        // A path below a regular file fails to stat with ENOTDIR
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let bad = dir.join("file/child");
+        for _ in 0..100 {
+            assert!(matches!(fsmap.stat(&bad).await, Err(nfsstat3::NFS3ERR_IO)));
+        }
+        // Only the first failure was logged; the next one is still suppressed
+        assert_eq!(fsmap.log_limit.check("stat error", 0), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_mount_fileids_are_stable() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_mount_ids_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file"), b"").unwrap();
+        let mounts: Vec<_> = ["/a", "/b", "/c"]
+            .iter()
+            .map(|target| MountConfig::new(dir.clone(), *target))
+            .collect();
+        let ids = |fsmap: &FSMap| -> Vec<Option<fileid3>> {
+            [b"a", b"b", b"c"]
+                .iter()
+                .map(|name| {
+                    let sym = fsmap.intern.check_interned(OsStr::from_bytes(*name))?;
+                    fsmap.path_to_id.get(&vec![sym]).copied()
+                })
+                .collect()
+        };
+
+        let first = ids(&FSMap::new_with_mounts(dir.clone(), mounts.clone()));
+        assert_eq!(
+            ids(&FSMap::new_with_mounts(dir.clone(), mounts.clone())),
+            first
+        );
+        assert!(
+            first
+                .iter()
+                .flatten()
+                .all(|&id| id > 0 && id < MOUNT_FILEIDS)
+        );
+
+        // Independent of the other mounts and their order
+        let mut reordered = vec![mounts[2].clone(), mounts[0].clone()];
+        let mut fsmap = FSMap::new_with_mounts(dir.clone(), reordered.clone());
+        assert_eq!(ids(&fsmap), [first[0], None, first[2]]);
+        reordered.reverse();
+        assert_eq!(
+            ids(&FSMap::new_with_mounts(dir.clone(), reordered)),
+            [first[0], None, first[2]]
+        );
+
+        // Files are numbered above the mount points
+        let mount_id = fsmap.find_child(0, b"a").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        assert!(fsmap.find_child(mount_id, b"file").await.unwrap() >= MOUNT_FILEIDS);
+
+        // Probing stops once the reserved range is full
+        let root = fsmap.id_to_path[&0].clone();
+        let mut taken = HashMap::new();
+        for id in 1..MOUNT_FILEIDS - 1 {
+            taken.insert(id, root.clone());
+        }
+        assert_eq!(mount_fileid("a", &taken), Some(MOUNT_FILEIDS - 1));
+        taken.insert(MOUNT_FILEIDS - 1, root);
+        assert_eq!(mount_fileid("a", &taken), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_compact_intern_drops_dead_names() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_intern_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("keep")).unwrap();
+        std::fs::write(dir.join("keep/kept"), b"").unwrap();
+        let mut fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        fsmap.refresh_dir_list(mount_id).await.unwrap();
+        let keep = fsmap.find_child(mount_id, b"keep").await.unwrap();
+        fsmap.refresh_dir_list(keep).await.unwrap();
+
+        // Churn through many unique names, listing them before they go
+        for round in 0..10 {
+            let names: Vec<_> = (0..100).map(|i| format!("r{}_{}", round, i)).collect();
+            for name in &names {
+                std::fs::write(dir.join(name), b"").unwrap();
+            }
+            fsmap.find_entry_mut(mount_id).unwrap().children_meta.size += 1;
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+            for name in &names {
+                std::fs::remove_file(dir.join(name)).unwrap();
+            }
+            fsmap.find_entry_mut(mount_id).unwrap().children_meta.size += 1;
+            fsmap.refresh_dir_list(mount_id).await.unwrap();
+        }
+        assert!(fsmap.intern.len() > 1000);
+
+        assert!(fsmap.compact_intern().unwrap() >= 1000);
+        // Only "test", "keep" and "kept" are left
+        assert_eq!(fsmap.intern.len(), 3);
+        assert_consistent(&fsmap);
+        assert_eq!(fsmap.find_child(0, b"test").await.unwrap(), mount_id);
+        assert_eq!(fsmap.find_child(mount_id, b"keep").await.unwrap(), keep);
+        assert!(fsmap.find_child(keep, b"kept").await.is_ok());
+        assert_eq!(
+            fsmap
+                .sym_to_path(&fsmap.find_entry(keep).unwrap().name)
+                .await,
+            PathBuf::from("test/keep")
+        );
+        // Nothing to drop until names die again
+        assert_eq!(fsmap.compact_intern().unwrap(), 0);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_check_consistency_reports_corruption() {
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_fsck_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"").unwrap();
+        std::fs::write(dir.join("other"), b"").unwrap();
+
+        // Each corruption alone is reported, and checking never repairs it
+        let corruptions: [(&str, fn(&mut FSMap, fileid3, fileid3, &[Symbol])); 5] = [
+            ("missing from path_to_id", |fsmap, id, _, _| {
+                let name = fsmap.id_to_path[&id].name.clone();
+                fsmap.path_to_id.remove(&name);
+            }),
+            ("lists missing fileid", |fsmap, id, _, _| {
+                fsmap.id_to_path.remove(&id);
+            }),
+            ("of another directory", |fsmap, _, other, sub| {
+                let sub_id = fsmap.path_to_id[sub];
+                let entry = fsmap.id_to_path.get_mut(&sub_id).unwrap();
+                entry.children.as_mut().unwrap().insert(other);
+            }),
+            ("directory missing from the map", |fsmap, _, _, sub| {
+                fsmap.path_to_id.remove(sub);
+            }),
+            ("has parent", |fsmap, id, other, _| {
+                fsmap.id_to_path.get_mut(&id).unwrap().parent = other;
+            }),
+        ];
+        for (expected, corrupt) in corruptions {
+            let mut fsmap =
+                FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+            fsmap.walk(None).await;
+            assert_eq!(fsmap.check_consistency(), Vec::<String>::new());
+            let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+            let sub_id = fsmap.find_child(mount_id, b"sub").await.unwrap();
+            let file_id = fsmap.find_child(sub_id, b"file").await.unwrap();
+            let other_id = fsmap.find_child(mount_id, b"other").await.unwrap();
+            let sub_name = fsmap.find_entry(sub_id).unwrap().name;
+            corrupt(&mut fsmap, file_id, other_id, &sub_name);
+            let problems = fsmap.check_consistency();
+            assert!(
+                problems.iter().any(|p| p.contains(expected)),
+                "{:?} not in {:?}",
+                expected,
+                problems
+            );
+            assert_eq!(fsmap.check_consistency(), problems);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_delete_entry_handles_deep_trees_and_cycles() {
+        let mut fsmap = FSMap::new_with_root(std::env::temp_dir());
+        let attr = fsmap.find_entry(0).unwrap().fsmeta;
+        // A chain far deeper than recursion on a test thread's stack allows
+        const DEPTH: u64 = 200_000;
+        let chain = |fsmap: &mut FSMap, first: fileid3, cycle: bool| {
+            for id in first..first + DEPTH {
+                let next = match (id + 1 < first + DEPTH, cycle) {
+                    (true, _) => Some(id + 1),
+                    (false, true) => Some(first),
+                    (false, false) => None,
+                };
+                let name = vec![fsmap.intern_name(OsString::from(id.to_string())).unwrap()];
+                fsmap.path_to_id.insert(name.clone(), id);
+                fsmap.id_to_path.insert(
+                    id,
+                    FSEntry {
+                        name,
+                        parent: id.saturating_sub(1),
+                        fsmeta: attr,
+                        children_meta: attr,
+                        children: Some(BTreeSet::from_iter(next)),
+                        refreshed: None,
+                        real_path: OnceLock::new(),
+                    },
+                );
+            }
+        };
+
+        chain(&mut fsmap, 1, false);
+        fsmap.delete_entry(1);
+        assert_eq!(fsmap.id_to_path.len(), 1);
+
+        // A listing that loops back on itself is walked once
+        chain(&mut fsmap, 1, true);
+        fsmap.delete_entry(1);
+        assert_eq!(fsmap.id_to_path.len(), 1);
+
+        // With a limit the walk stops and leaves the deeper entries
+        fsmap.max_path_depth = Some(10);
+        chain(&mut fsmap, 1, false);
+        fsmap.delete_entry(1);
+        assert_eq!(fsmap.id_to_path.len() as u64, 1 + DEPTH - 11);
+        assert!(fsmap.id_to_path.contains_key(&12));
+    }
+
+    #[tokio::test]
+    async fn test_chmod_does_not_relist_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("nfs_mirror_meta_only_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/file"), b"").unwrap();
+
+        let mut fsmap =
+            FSMap::new_with_mounts(dir.clone(), vec![MountConfig::new(dir.clone(), "/test")]);
+        let mount_id = fsmap.find_child(0, b"test").await.unwrap();
+        let sub_id = fsmap.find_child(mount_id, b"sub").await.unwrap();
+        fsmap.refresh_dir_list(sub_id).await.unwrap();
+
+        std::fs::set_permissions(dir.join("sub"), std::fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(matches!(
+            fsmap.refresh_entry(sub_id).await.unwrap(),
+            RefreshResult::MetaOnly
+        ));
+        assert_eq!(fsmap.find_entry(sub_id).unwrap().fsmeta.mode & 0o777, 0o700);
+        // Any relisting would now fail
+        fsmap.fail_listing_after = Some(0);
+        fsmap.refresh_dir_list(sub_id).await.unwrap();
+
+        // A new entry changes the content, which does relist. The mtime is
+        // set explicitly in case the clock did not tick since the listing.
+        std::fs::write(dir.join("sub/new"), b"").unwrap();
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(1000);
+        std::fs::File::open(dir.join("sub"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert!(matches!(
+            fsmap.refresh_entry(sub_id).await.unwrap(),
+            RefreshResult::Reload
+        ));
+        assert!(fsmap.refresh_dir_list(sub_id).await.is_err());
+        fsmap.fail_listing_after = None;
+        fsmap.refresh_dir_list(sub_id).await.unwrap();
+        assert!(fsmap.find_child(sub_id, b"new").await.is_ok());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Wait until no client has been mounted for `idle`
+///
+/// `events` receives `true` when a client mounts and `false` when it unmounts.
+/// The timer only runs while no client is mounted, so this never completes
+/// while any client is connected.
+pub async fn wait_for_idle(mut events: mpsc::Receiver<bool>, idle: Duration) {
+    let mut active: usize = 0;
+    loop {
+        let event = if active == 0 {
+            match tokio::time::timeout(idle, events.recv()).await {
+                Ok(event) => event,
+                Err(_) => return,
+            }
+        } else {
+            events.recv().await
+        };
+
+        match event {
+            Some(true) => active += 1,
+            Some(false) => active = active.saturating_sub(1),
+            // The listener is gone, nothing can connect any more
+            None => return,
+        }
+        debug!("{} mounted clients", active);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_without_clients() {
+        let (_tx, rx) = mpsc::channel(4);
+        let idle = wait_for_idle(rx, Duration::from_millis(20));
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), idle)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_idle_while_mounted() {
+        let (tx, rx) = mpsc::channel(4);
+        let idle = tokio::spawn(wait_for_idle(rx, Duration::from_millis(20)));
+
+        tx.send(true).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!idle.is_finished());
+
+        tx.send(false).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_secs(5), idle)
+                .await
+                .is_ok()
+        );
+    }
+}
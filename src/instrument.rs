@@ -0,0 +1,44 @@
+#[cfg(feature = "instrument")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(feature = "instrument")]
+static READ_DIR_CALLS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "instrument")]
+static STAT_CALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Backing store calls made by the file map, for benchmarks
+///
+/// Only counted when built with the `instrument` feature; without it the
+/// counting hooks are empty and compile away.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallCounts {
+    pub read_dir: u64,
+    pub stat: u64,
+}
+
+/// Count a directory listing opened on the backing store
+#[inline(always)]
+pub fn count_read_dir() {
+    #[cfg(feature = "instrument")]
+    READ_DIR_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a stat of a backing path
+#[inline(always)]
+pub fn count_stat() {
+    #[cfg(feature = "instrument")]
+    STAT_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Calls counted since the last take, resetting the counts
+#[allow(dead_code)]
+pub fn take() -> CallCounts {
+    #[cfg(feature = "instrument")]
+    return CallCounts {
+        read_dir: READ_DIR_CALLS.swap(0, Ordering::Relaxed),
+        stat: STAT_CALLS.swap(0, Ordering::Relaxed),
+    };
+    #[cfg(not(feature = "instrument"))]
+    CallCounts::default()
+}
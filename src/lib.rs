@@ -0,0 +1,26 @@
+pub mod admin;
+pub mod archive;
+pub mod cli;
+pub mod config;
+pub mod daemon;
+pub mod dedup;
+pub mod deferred;
+pub mod dirlist;
+pub mod drc;
+pub mod fileids;
+pub mod fileorder;
+pub mod filesystem;
+pub mod fnv;
+pub mod frozen;
+pub mod fsmap;
+pub mod idle;
+pub mod instrument;
+pub mod metrics;
+pub mod mmap;
+pub mod names;
+pub mod openfiles;
+pub mod ratelimit;
+pub mod readahead;
+pub mod retry;
+pub mod selftest;
+pub mod writeonce;
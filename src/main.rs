@@ -1,17 +1,20 @@
-mod cli;
-mod config;
-mod daemon;
-mod filesystem;
-mod fsmap;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
+use tracing::info;
 use tracing_subscriber::FmtSubscriber;
 
 use zerofs_nfsserve::tcp::{NFSTcp, NFSTcpListener};
 
-use cli::Cli;
-use daemon::{change_working_directory, handle_daemon_mode};
-use filesystem::MirrorFS;
+use nfs_mirror::cli::Cli;
+use nfs_mirror::config::{Config, MountConfig, ServerConfig};
+use nfs_mirror::daemon::{
+    change_working_directory, check_fd_limit, enter_chroot, handle_daemon_mode, install_panic_hook,
+};
+use nfs_mirror::deferred::DeferredFS;
+use nfs_mirror::filesystem::MirrorFS;
+use nfs_mirror::{admin, idle, selftest};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -26,10 +29,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     // Load configuration
-    let config = cli.load_config()?;
+    let mut config = cli.load_config()?;
+    install_panic_hook(config.server.exit_on_panic);
+    if cli.self_test {
+        selftest::prepare(&mut config);
+    }
 
     // Handle daemon mode
-    if config.server.daemon {
+    if config.server.daemon && !cli.fsck {
         handle_daemon_mode(&cli)?;
     }
 
@@ -41,22 +48,127 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Print startup information
     Cli::print_startup_info(&config, &allowed_ips);
+    Cli::log_startup_event(&config, &allowed_ips);
+    check_fd_limit(config.server.max_connections, config.server.raise_fd_limit);
+
+    if cli.fsck {
+        enter_jail(&config.server)?;
+        let fs = build_fs(&config.server, config.mounts)?;
+        let mut fsmap = fs.fsmap.lock().await;
+        let listed = fsmap.walk(None).await;
+        let problems = fsmap.check_consistency();
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        return match problems.len() {
+            0 => {
+                println!(
+                    "File map consistent: {} directories, {} entries",
+                    listed,
+                    fsmap.id_to_path.len()
+                );
+                Ok(())
+            }
+            count => Err(format!("File map has {} inconsistencies", count).into()),
+        };
+    }
 
-    // Create NFS file system - use the first mount's source as root directory
-    let root_dir = if !config.mounts.is_empty() {
-        config.mounts[0].source.canonicalize()?
+    // Bind the NFS TCP socket first, then enter the chroot before the file
+    // system reads any mount source, so the sources resolve inside it
+    let addr = format!("{}:{}", config.server.ip, config.server.port).parse()?;
+    let deferred = DeferredFS::new();
+    let mut listener = NFSTcpListener::bind(addr, deferred.clone()).await?;
+    enter_jail(&config.server)?;
+
+    let self_test_mount = if cli.self_test {
+        Some(selftest::test_mount(&mut config)?)
     } else {
-        return Err("No mount points configured".into());
+        None
     };
+    let Config { server, mounts } = config;
+    let fs = build_fs(&server, mounts)?;
+    if server.snapshot {
+        fs.take_snapshot().await;
+    }
+    fs.spawn_background_tasks();
+    let shutdown = fs.shutdown_handle();
+    let terminated = admin::termination()?;
+    admin::spawn_sigusr1_toggle(Arc::clone(&fs.read_only_switch))?;
+    if let Some(ref path) = server.fsmap_dump_path {
+        admin::spawn_sigusr2_dump(Arc::clone(&fs.fsmap), path.clone())?;
+    }
+    deferred.set(fs);
 
-    let fs = MirrorFS::new_with_mounts(root_dir, config.server.read_only, config.mounts);
+    if let Some(mount) = self_test_mount {
+        let port = listener.get_listen_port();
+        tokio::spawn(async move { listener.handle_forever().await });
+        return match selftest::run(port, mount).await? {
+            selftest::Outcome::Passed => {
+                println!("Self-test passed");
+                Ok(())
+            }
+            selftest::Outcome::Skipped(reason) => {
+                println!("Self-test skipped: {}", reason);
+                Ok(())
+            }
+        };
+    }
 
-    // Start NFS TCP server
-    let addr = format!("{}:{}", config.server.ip, config.server.port).parse()?;
-    let listener = NFSTcpListener::bind(addr, fs).await?;
+    // Start the server, running until it fails, goes idle or is told to stop
+    let idle = match server.exit_when_idle {
+        Some(idle_secs) => {
+            let (mount_tx, mount_rx) = tokio::sync::mpsc::channel(16);
+            listener.set_mount_listener(mount_tx);
+            Some((mount_rx, idle_secs))
+        }
+        None => None,
+    };
+    let idle = async move {
+        match idle {
+            Some((mount_rx, idle_secs)) => {
+                idle::wait_for_idle(mount_rx, Duration::from_secs(idle_secs)).await;
+                info!(
+                    "No clients mounted for {} seconds, shutting down",
+                    idle_secs
+                );
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let served = tokio::select! {
+        result = listener.handle_forever() => result,
+        _ = idle => Ok(()),
+        _ = terminated => Ok(()),
+    };
 
-    // Start the server
-    listener.handle_forever().await?;
+    // Write out persistent state on every exit path
+    shutdown.run().await;
+    served?;
+    Ok(())
+}
 
+/// Enter the configured chroot, if any
+fn enter_jail(server: &ServerConfig) -> Result<(), String> {
+    if let Some(ref jail) = server.chroot {
+        enter_chroot(jail)?;
+        info!("Chrooted into {}", jail.display());
+    }
     Ok(())
 }
+
+/// Create the NFS file system, using the first mount's source as root directory
+fn build_fs(
+    server: &ServerConfig,
+    mounts: Vec<MountConfig>,
+) -> Result<MirrorFS, Box<dyn std::error::Error>> {
+    let Some(first) = mounts.first() else {
+        return Err("No mount points configured".into());
+    };
+    let mut root_dir = first.source.canonicalize()?;
+    // A single-file mount lends its parent directory to the synthetic root
+    if !root_dir.is_dir() {
+        root_dir.pop();
+    }
+    Cli::print_resolved_paths(&root_dir);
+    Ok(MirrorFS::new_with_mounts(root_dir, server, mounts))
+}
@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::info;
+
+/// Operation counters, updated with relaxed atomics off the fsmap lock
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Lookups answered from the cached FSMap
+    pub lookup_hits: AtomicU64,
+    /// Lookups that had to stat the backing store and relist the directory
+    pub lookup_misses: AtomicU64,
+    /// Misses answered with NOENT straight from the stat, without a relist
+    pub lookup_negative: AtomicU64,
+    /// Reads answered from a read-ahead buffer
+    pub read_ahead_hits: AtomicU64,
+    /// Reads and writes currently running or waiting for an IO slot
+    pub io_in_flight: AtomicU64,
+    /// Directories fsynced after a change to their entries
+    pub dir_syncs: AtomicU64,
+    /// When each mount was last accessed and how much data it served
+    pub mount_access: Arc<MountAccess>,
+}
+
+#[derive(Debug, Default)]
+struct MountCounters {
+    /// Last access in unix seconds (0 if never)
+    last: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Result of the last dedup scan, if one finished
+    dedup: Mutex<Option<DedupStats>>,
+}
+
+/// Per-mount activity: last access time and cumulative bytes read and written
+#[derive(Debug, Default)]
+pub struct MountAccess {
+    targets: Vec<String>,
+    counters: Vec<MountCounters>,
+}
+
+/// Activity of one mount at a point in time
+#[derive(Debug, Clone, Serialize)]
+pub struct MountStats {
+    pub target: String,
+    /// Last access in unix seconds, if the mount was ever accessed
+    pub last_access: Option<u64>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Duplicated content found by the last dedup scan, if enabled and finished
+    pub dedup: Option<DedupStats>,
+}
+
+/// Content of one mount as counted by a dedup scan
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct DedupStats {
+    /// Regular files hashed
+    pub files: u64,
+    /// Total size of those files
+    pub bytes: u64,
+    /// Size counting each distinct content once
+    pub unique_bytes: u64,
+}
+
+impl MountAccess {
+    pub fn new(targets: Vec<String>) -> Self {
+        let counters = targets.iter().map(|_| MountCounters::default()).collect();
+        Self { targets, counters }
+    }
+
+    /// Record an access to the mount at `index` now
+    pub fn touch(&self, index: usize) {
+        if let Some(counters) = self.counters.get(index) {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            counters.last.store(now, Ordering::Relaxed);
+        }
+    }
+
+    /// Count bytes read by clients from the mount at `index`
+    pub fn add_read(&self, index: usize, bytes: u64) {
+        if let Some(counters) = self.counters.get(index) {
+            counters.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Count bytes written by clients to the mount at `index`
+    pub fn add_written(&self, index: usize, bytes: u64) {
+        if let Some(counters) = self.counters.get(index) {
+            counters.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the result of a dedup scan of the mount at `index`
+    pub fn set_dedup(&self, index: usize, stats: DedupStats) {
+        if let Some(counters) = self.counters.get(index) {
+            *counters.dedup.lock().unwrap() = Some(stats);
+        }
+    }
+
+    /// Current activity of every mount
+    pub fn snapshot(&self) -> Vec<MountStats> {
+        self.targets
+            .iter()
+            .zip(&self.counters)
+            .map(|(target, counters)| {
+                let last = counters.last.load(Ordering::Relaxed);
+                MountStats {
+                    target: target.clone(),
+                    last_access: (last > 0).then_some(last),
+                    bytes_read: counters.bytes_read.load(Ordering::Relaxed),
+                    bytes_written: counters.bytes_written.load(Ordering::Relaxed),
+                    dedup: *counters.dedup.lock().unwrap(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl DedupStats {
+    /// Share of the bytes that repeat content found elsewhere in the mount
+    pub fn duplicated_percent(&self) -> f64 {
+        match self.bytes {
+            0 => 0.0,
+            bytes => (bytes - self.unique_bytes) as f64 * 100.0 / bytes as f64,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counters that also report the given mount access times
+    pub fn with_mount_access(mount_access: Arc<MountAccess>) -> Self {
+        Self {
+            mount_access,
+            ..Self::default()
+        }
+    }
+
+    /// Increment a counter
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fraction of lookups served from the cache, if any lookups happened
+    pub fn lookup_hit_ratio(&self) -> Option<f64> {
+        let hits = self.lookup_hits.load(Ordering::Relaxed);
+        let total = hits
+            + self.lookup_misses.load(Ordering::Relaxed)
+            + self.lookup_negative.load(Ordering::Relaxed);
+        (total > 0).then(|| hits as f64 / total as f64)
+    }
+
+    /// Log the current counters at info level
+    pub fn log(&self) {
+        info!(
+            "lookup: {} hits, {} misses, {} negative (hit ratio {})",
+            self.lookup_hits.load(Ordering::Relaxed),
+            self.lookup_misses.load(Ordering::Relaxed),
+            self.lookup_negative.load(Ordering::Relaxed),
+            self.lookup_hit_ratio()
+                .map(|r| format!("{:.1}%", r * 100.0))
+                .unwrap_or_else(|| "n/a".to_string())
+        );
+        info!(
+            "read-ahead: {} hits",
+            self.read_ahead_hits.load(Ordering::Relaxed)
+        );
+        info!(
+            "io: {} in flight, {} directory syncs",
+            self.io_in_flight.load(Ordering::Relaxed),
+            self.dir_syncs.load(Ordering::Relaxed)
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        for mount in self.mount_access.snapshot() {
+            match mount.last_access {
+                Some(last) => info!(
+                    "mount {}: last access {}s ago, {} bytes read, {} bytes written",
+                    mount.target,
+                    now.saturating_sub(last),
+                    mount.bytes_read,
+                    mount.bytes_written
+                ),
+                None => info!("mount {}: not accessed", mount.target),
+            }
+            if let Some(dedup) = mount.dedup {
+                info!(
+                    "mount {}: {} files, {} bytes, {} unique ({:.1}% duplicated)",
+                    mount.target,
+                    dedup.files,
+                    dedup.bytes,
+                    dedup.unique_bytes,
+                    dedup.duplicated_percent()
+                );
+            }
+        }
+    }
+
+    /// Spawn a task that logs the counters every `interval`
+    pub fn spawn_logger(self: &Arc<Self>, interval: Duration) {
+        let metrics = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                metrics.log();
+            }
+        });
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use zerofs_nfsserve::nfs::*;
+
+use crate::openfiles::read_open_file;
+use crate::readahead::FileStamp;
+
+/// A read-only shared mapping of a whole file
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+    file: File,
+    /// Device and inode of the mapped file, to notice it being replaced
+    ino: (u64, u64),
+    stamp: FileStamp,
+}
+
+// SAFETY: the mapping is read-only and only unmapped on drop, so it can be
+// read from any thread
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn map(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let meta = file.metadata()?;
+        let len = meta.len() as usize;
+        // SAFETY: a fresh read-only mapping of an open descriptor; len is
+        // checked to be non-zero by the caller
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr,
+            len,
+            ino: (meta.dev(), meta.ino()),
+            stamp: FileStamp {
+                mtime: meta.modified().ok(),
+                size: meta.len(),
+            },
+            file,
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: ptr and len describe a mapping created by `map`
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// Memory mappings of files in mounts with `use_mmap`
+///
+/// A file is mapped on its first read and later reads copy out of the
+/// mapping. A mapping is replaced once the file's mtime, size or inode no
+/// longer match it. At most `capacity` files are mapped; the least recently
+/// used mapping is dropped first.
+///
+/// Only read-only mounts may use this: the length check before each copy
+/// cannot stop another process from truncating the file between the check
+/// and the copy, which raises SIGBUS.
+#[derive(Debug)]
+pub struct MmapCache {
+    capacity: usize,
+    maps: Mutex<HashMap<fileid3, (Arc<Mapping>, Instant)>>,
+}
+
+impl MmapCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Read up to `count` bytes at `offset` from the file at `path` through its mapping
+    ///
+    /// Returns the data and whether EOF was reached.
+    pub fn read(
+        &self,
+        id: fileid3,
+        path: &Path,
+        offset: u64,
+        count: u32,
+    ) -> std::io::Result<(Vec<u8>, bool)> {
+        let meta = path.metadata()?;
+        if meta.len() == 0 {
+            // Empty files cannot be mapped
+            return Ok((Vec::new(), true));
+        }
+        let stamp = FileStamp {
+            mtime: meta.modified().ok(),
+            size: meta.len(),
+        };
+        let mapping = self.mapping(id, path, stamp, (meta.dev(), meta.ino()))?;
+
+        // Touching pages past the end of a file that shrank since it was
+        // mapped raises SIGBUS, so only copy while the file still covers the
+        // mapping and read through the descriptor otherwise
+        if mapping.file.metadata()?.len() < mapping.len as u64 {
+            self.invalidate(id);
+            return read_open_file(&mapping.file, offset, count);
+        }
+        let len = mapping.len as u64;
+        let start = offset.min(len);
+        let end = offset.saturating_add(count as u64).min(len).max(start);
+        // SAFETY: start <= end <= len, so start..end lies within the mapping,
+        // which the file covered when checked above
+        let data = unsafe {
+            std::slice::from_raw_parts(
+                (mapping.ptr as *const u8).add(start as usize),
+                (end - start) as usize,
+            )
+        };
+        Ok((data.to_vec(), offset.saturating_add(count as u64) >= len))
+    }
+
+    /// The mapping of `id` if it still matches the file, otherwise a new one
+    fn mapping(
+        &self,
+        id: fileid3,
+        path: &Path,
+        stamp: FileStamp,
+        ino: (u64, u64),
+    ) -> std::io::Result<Arc<Mapping>> {
+        let mut maps = self.maps.lock().unwrap();
+        if let Some((mapping, used)) = maps.get_mut(&id) {
+            if mapping.stamp == stamp && mapping.ino == ino {
+                *used = Instant::now();
+                return Ok(Arc::clone(mapping));
+            }
+        }
+        let mapping = Arc::new(Mapping::map(path)?);
+        if !maps.contains_key(&id) && maps.len() >= self.capacity {
+            let oldest = maps
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                maps.remove(&oldest);
+            }
+        }
+        maps.insert(id, (Arc::clone(&mapping), Instant::now()));
+        Ok(mapping)
+    }
+
+    /// Drop the mapping of a file that changed or was removed
+    pub fn invalidate(&self, id: fileid3) {
+        self.maps.lock().unwrap().remove(&id);
+    }
+}
@@ -0,0 +1,236 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::{MountConfig, NonUtf8Names, UnicodeForm};
+use crate::fnv;
+
+/// Characters Windows does not allow in file names, besides control characters and `/`
+const WINDOWS_RESERVED: &[char] = &[':', '*', '?', '<', '>', '|', '"', '\\'];
+
+/// Start of the private-use block reserved characters are mapped into, as by Cygwin and WSL
+const PRIVATE_USE_BASE: u32 = 0xF000;
+
+/// Digits of the hash part of a short alias
+const ALIAS_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Check whether a mount matches names by anything looser than exact bytes
+pub fn has_loose_matching(mount: &MountConfig) -> bool {
+    mount.unicode_normalize.is_some()
+        || mount.case_insensitive
+        || mount.non_utf8_names == NonUtf8Names::Latin1
+        || mount.windows_names
+        || mount.short_names
+}
+
+/// Check whether an on-disk name is left out of listings of a mount
+pub fn is_hidden(mount: &MountConfig, name: &[u8]) -> bool {
+    mount.non_utf8_names == NonUtf8Names::Hide && std::str::from_utf8(name).is_err()
+}
+
+/// Name an on-disk entry is listed under in a mount
+pub fn display_name<'a>(mount: &MountConfig, name: &'a [u8]) -> Cow<'a, [u8]> {
+    let shown = match mount.non_utf8_names {
+        NonUtf8Names::Latin1 if std::str::from_utf8(name).is_err() => {
+            Cow::Owned(latin1(name).into_bytes())
+        }
+        _ => Cow::Borrowed(name),
+    };
+    match std::str::from_utf8(&shown) {
+        Ok(text) if mount.windows_names && text.contains(WINDOWS_RESERVED) => Cow::Owned(
+            text.chars()
+                .map(to_private_use)
+                .collect::<String>()
+                .into_bytes(),
+        ),
+        _ => shown,
+    }
+}
+
+/// Map a character Windows does not allow in names into the private-use area
+fn to_private_use(c: char) -> char {
+    match WINDOWS_RESERVED.contains(&c) {
+        true => char::from_u32(PRIVATE_USE_BASE + c as u32).unwrap_or(c),
+        false => c,
+    }
+}
+
+/// Reverse `to_private_use`, leaving every other character alone
+fn from_private_use(c: char) -> char {
+    match char::from_u32((c as u32).wrapping_sub(PRIVATE_USE_BASE)) {
+        Some(reserved) if WINDOWS_RESERVED.contains(&reserved) => reserved,
+        _ => c,
+    }
+}
+
+/// Decode bytes as Latin-1, where every byte is the code point of the same value
+fn latin1(name: &[u8]) -> String {
+    name.iter().map(|&b| char::from(b)).collect()
+}
+
+/// Comparison key for a file name under a mount's name matching options
+///
+/// Two names refer to the same entry if their keys are equal. Keys are only
+/// used for matching; the on-disk bytes are kept for all backing store IO.
+/// Names that are not valid UTF-8 are compared by their Latin-1 decoding
+/// when the mount lists them that way, otherwise as raw bytes, with only
+/// ASCII letters folded when matching case-insensitively. With Windows
+/// names, a reserved character and its private-use form compare equal.
+pub fn match_key(mount: &MountConfig, name: &[u8]) -> Vec<u8> {
+    let decoded;
+    let name = match std::str::from_utf8(name) {
+        Ok(name) => name,
+        Err(_) if mount.non_utf8_names == NonUtf8Names::Latin1 => {
+            decoded = latin1(name);
+            &decoded
+        }
+        Err(_) if mount.case_insensitive => return name.to_ascii_lowercase(),
+        Err(_) => return name.to_vec(),
+    };
+    let unmapped: String;
+    let name = match mount.windows_names {
+        true => {
+            unmapped = name.chars().map(from_private_use).collect();
+            &unmapped
+        }
+        false => name,
+    };
+    let mut name: String = match mount.unicode_normalize {
+        Some(UnicodeForm::Nfc) => name.nfc().collect(),
+        Some(UnicodeForm::Nfd) => name.nfd().collect(),
+        None => name.to_string(),
+    };
+    if mount.case_insensitive {
+        name = name.to_lowercase();
+    }
+    name.into_bytes()
+}
+
+/// Whether `name` is a valid 8.3 name already, ignoring case
+fn is_short(name: &[u8]) -> bool {
+    let (base, ext) = match name.iter().rposition(|&b| b == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &b""[..]),
+    };
+    let valid = |part: &[u8]| part.iter().all(|&b| is_short_char(b) || b == b'~');
+    (1..=8).contains(&base.len()) && ext.len() <= 3 && valid(base) && valid(ext)
+}
+
+/// Characters an alias is made of, besides `~`
+fn is_short_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'-'
+}
+
+/// Uppercase the characters of `part` an alias may keep, dropping the others
+fn short_part(part: &[u8], len: usize) -> String {
+    part.iter()
+        .filter(|&&b| is_short_char(b))
+        .take(len)
+        .map(|&b| char::from(b.to_ascii_uppercase()))
+        .collect()
+}
+
+/// 8.3 short aliases for the names in one directory, `None` for names that are short already
+///
+/// An alias is up to five characters of the name, `~`, two characters of a
+/// hash of the whole name and up to three characters of its extension, e.g.
+/// `LONGF~PD.MAR`, so it only depends on the name itself. Names whose alias
+/// collides with another alias or a short name in the directory take the
+/// next free hash, in order of their bytes, so aliases are stable for as
+/// long as the directory holds the same names.
+pub fn short_aliases(names: &[&[u8]]) -> Vec<Option<String>> {
+    let mut taken: HashSet<String> = names
+        .iter()
+        .filter(|name| is_short(name))
+        .map(|name| String::from_utf8_lossy(name).to_ascii_uppercase())
+        .collect();
+    let mut long: Vec<usize> = (0..names.len()).filter(|&i| !is_short(names[i])).collect();
+    long.sort_by_key(|&i| names[i]);
+
+    let mut aliases = vec![None; names.len()];
+    let slots = ALIAS_DIGITS.len() * ALIAS_DIGITS.len();
+    for i in long {
+        let name = names[i];
+        // A leading dot marks a hidden file, not an extension
+        let (base, ext) = match name.iter().rposition(|&b| b == b'.') {
+            Some(dot) if dot > 0 => (&name[..dot], short_part(&name[dot + 1..], 3)),
+            _ => (name, String::new()),
+        };
+        let mut base = short_part(base, 5);
+        if base.is_empty() {
+            base.push('_');
+        }
+        let start = (fnv::hash(name) % slots as u64) as usize;
+        aliases[i] = (0..slots).find_map(|probe| {
+            let slot = (start + probe) % slots;
+            let digits = [
+                ALIAS_DIGITS[slot / ALIAS_DIGITS.len()],
+                ALIAS_DIGITS[slot % ALIAS_DIGITS.len()],
+            ];
+            let mut alias = format!("{}~{}", base, String::from_utf8_lossy(&digits));
+            if !ext.is_empty() {
+                alias.push('.');
+                alias.push_str(&ext);
+            }
+            taken.insert(alias.clone()).then_some(alias)
+        });
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_aliases() {
+        let names: [&[u8]; 4] = [
+            b"README.TXT",
+            b"Long File Name.markdown",
+            b".bashrc",
+            b"x.y.tar.gz",
+        ];
+        let aliases = short_aliases(&names);
+        assert_eq!(aliases[0], None);
+        for (alias, prefix) in aliases[1..].iter().zip(["LONGF~", "BASHR~", "XYTAR~"]) {
+            let alias = alias.as_deref().unwrap();
+            assert!(alias.starts_with(prefix), "{}", alias);
+            assert!(is_short(alias.as_bytes()), "{}", alias);
+        }
+        assert_eq!(aliases[1].as_deref(), Some("LONGF~PD.MAR"));
+        assert!(aliases[3].as_deref().unwrap().ends_with(".GZ"));
+
+        // The alias of a name does not depend on its neighbours
+        let alone = short_aliases(&[b"Long File Name.markdown"]);
+        assert_eq!(alone[0], aliases[1]);
+    }
+
+    #[test]
+    fn test_short_alias_collisions() {
+        // Same prefix and extension, so aliases differ only where hashes do
+        let names: Vec<Vec<u8>> = (0..2000)
+            .map(|i| format!("collide{:04}.txt", i).into_bytes())
+            .collect();
+        let mut refs: Vec<&[u8]> = names.iter().map(|n| n.as_slice()).collect();
+        let aliases = short_aliases(&refs);
+        let unique: HashSet<_> = aliases.iter().flatten().collect();
+        // Every alias slot for the prefix is used once, the rest get none
+        assert_eq!(unique.len(), ALIAS_DIGITS.len() * ALIAS_DIGITS.len());
+        assert_eq!(aliases.iter().flatten().count(), unique.len());
+
+        // A real short name is never handed out as an alias
+        let taken = aliases[0].clone().unwrap();
+        refs.push(taken.as_bytes());
+        let again = short_aliases(&refs);
+        assert_ne!(again[0].as_deref(), Some(taken.as_str()));
+        assert_eq!(again[refs.len() - 1], None);
+
+        // The same directory gets the same aliases in any order
+        let mut reversed = refs.clone();
+        reversed.reverse();
+        let mut back = short_aliases(&reversed);
+        back.reverse();
+        assert_eq!(back, again);
+    }
+}
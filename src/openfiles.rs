@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use zerofs_nfsserve::nfs::*;
+
+#[derive(Debug)]
+struct OpenFile {
+    file: Arc<File>,
+    used: Instant,
+}
+
+/// Descriptors of files clients recently read or wrote
+///
+/// NFSv3 has no open or close, so a file counts as open while it was used
+/// within `idle`. Holding a descriptor keeps a file's data reachable after it
+/// is deleted on disk, the way a local process keeps reading an unlinked file.
+/// At most `capacity` descriptors are kept; the least recently used is closed
+/// first.
+#[derive(Debug)]
+pub struct OpenFiles {
+    idle: Duration,
+    capacity: usize,
+    files: Mutex<HashMap<fileid3, OpenFile>>,
+}
+
+impl OpenFiles {
+    pub fn new(idle: Duration, capacity: usize) -> Self {
+        Self {
+            idle,
+            capacity,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keep a descriptor of the file at `path` for `id`, or mark the kept one as used
+    ///
+    /// A kept descriptor of a different file, e.g. one that was since replaced
+    /// by a rename, is reopened.
+    pub fn remember(&self, id: fileid3, path: &Path) {
+        let Ok(on_disk) = path.metadata() else {
+            return;
+        };
+        let mut files = self.files.lock().unwrap();
+        files.retain(|_, open| open.used.elapsed() < self.idle);
+        if let Some(open) = files.get_mut(&id) {
+            let same_file = open
+                .file
+                .metadata()
+                .is_ok_and(|meta| (meta.dev(), meta.ino()) == (on_disk.dev(), on_disk.ino()));
+            if same_file {
+                open.used = Instant::now();
+                return;
+            }
+        }
+        let Ok(file) = File::open(path) else {
+            return;
+        };
+        if !files.contains_key(&id) && files.len() >= self.capacity {
+            let oldest = files
+                .iter()
+                .min_by_key(|(_, open)| open.used)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                files.remove(&oldest);
+            }
+        }
+        files.insert(
+            id,
+            OpenFile {
+                file: Arc::new(file),
+                used: Instant::now(),
+            },
+        );
+    }
+
+    /// How long a file counts as open after its last use
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    /// The descriptor kept for `id`, if the file was used recently
+    pub fn get(&self, id: fileid3) -> Option<Arc<File>> {
+        let files = self.files.lock().unwrap();
+        let open = files.get(&id)?;
+        (open.used.elapsed() < self.idle).then(|| Arc::clone(&open.file))
+    }
+
+    /// Close the descriptor kept for `id`
+    pub fn forget(&self, id: fileid3) -> Option<Arc<File>> {
+        self.files.lock().unwrap().remove(&id).map(|open| open.file)
+    }
+}
+
+/// Read up to `count` bytes at `offset` from an open file, returning the data and whether EOF was reached
+pub fn read_open_file(file: &File, offset: u64, count: u32) -> std::io::Result<(Vec<u8>, bool)> {
+    let len = file.metadata()?.len();
+    let start = offset.min(len);
+    let end = offset.saturating_add(count as u64).min(len).max(start);
+    let mut buf = vec![0; (end - start) as usize];
+    file.read_exact_at(&mut buf, start)?;
+    Ok((buf, offset.saturating_add(count as u64) >= len))
+}
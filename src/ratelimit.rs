@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most distinct keys tracked before expired ones are dropped
+const MAX_KEYS: usize = 1024;
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    suppressed: u64,
+}
+
+/// Limits how often a log message is repeated during an error storm
+///
+/// Messages are keyed by a kind and a scope, e.g. a mount index. The first
+/// message for a key is logged, repeats within `window` are only counted,
+/// and the next message logged after the window reports how many were
+/// suppressed.
+#[derive(Debug)]
+pub struct LogLimiter {
+    window: Duration,
+    seen: Mutex<HashMap<(&'static str, usize), Window>>,
+}
+
+impl LogLimiter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a message for `kind` and `scope` should be logged now
+    ///
+    /// Returns the number of messages suppressed since the last one logged,
+    /// or None if this one should be suppressed too.
+    pub fn check(&self, kind: &'static str, scope: usize) -> Option<u64> {
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(window) = seen.get_mut(&(kind, scope)) {
+            if window.started.elapsed() < self.window {
+                window.suppressed += 1;
+                return None;
+            }
+            let suppressed = window.suppressed;
+            *window = Window {
+                started: Instant::now(),
+                suppressed: 0,
+            };
+            return Some(suppressed);
+        }
+        if seen.len() >= MAX_KEYS {
+            seen.retain(|_, window| window.started.elapsed() < self.window);
+        }
+        seen.insert(
+            (kind, scope),
+            Window {
+                started: Instant::now(),
+                suppressed: 0,
+            },
+        );
+        Some(0)
+    }
+}
+
+/// Note appended to a log message that follows suppressed repeats
+pub fn suppressed_note(suppressed: u64) -> String {
+    match suppressed {
+        0 => String::new(),
+        n => format!(" ({} similar messages suppressed)", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_suppressed_within_window() {
+        let limiter = LogLimiter::new(Duration::from_millis(50));
+        let logged = (0..1000)
+            .filter(|_| limiter.check("stat", 0).is_some())
+            .count();
+        assert_eq!(logged, 1);
+        // Other keys have their own window
+        assert_eq!(limiter.check("stat", 1), Some(0));
+        assert_eq!(limiter.check("list", 0), Some(0));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(limiter.check("stat", 0), Some(999));
+        assert_eq!(limiter.check("stat", 0), None);
+        assert_eq!(suppressed_note(0), "");
+        assert_eq!(suppressed_note(3), " (3 similar messages suppressed)");
+    }
+}
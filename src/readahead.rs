@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+use zerofs_nfsserve::nfs::*;
+
+/// Backing file state a read-ahead buffer was filled from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub mtime: Option<SystemTime>,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+struct Buffered {
+    offset: u64,
+    data: Vec<u8>,
+    stamp: FileStamp,
+    used: Instant,
+}
+
+/// Data read past the end of client reads, kept for the sequential reads that follow
+///
+/// Each buffer belongs to one file and is only served while the file's mtime
+/// and size still match the ones it was filled with. Writes through this
+/// server drop the file's buffer explicitly. At most `capacity` files are
+/// buffered; the least recently used buffer is dropped first.
+#[derive(Debug)]
+pub struct ReadAhead {
+    /// Bytes read beyond the end of a client read
+    pub window: u64,
+    capacity: usize,
+    buffers: Mutex<HashMap<fileid3, Buffered>>,
+}
+
+impl ReadAhead {
+    pub fn new(window: u64, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            buffers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serve `count` bytes at `offset` from the buffer of `id`, returning the data and EOF flag
+    pub fn get(
+        &self,
+        id: fileid3,
+        offset: u64,
+        count: u32,
+        stamp: FileStamp,
+    ) -> Option<(Vec<u8>, bool)> {
+        let mut buffers = self.buffers.lock().unwrap();
+        let buffered = buffers.get_mut(&id)?;
+        if buffered.stamp != stamp {
+            buffers.remove(&id);
+            return None;
+        }
+        let end = offset.saturating_add(count as u64).min(stamp.size);
+        let buffered_end = buffered.offset + buffered.data.len() as u64;
+        if offset < buffered.offset || end > buffered_end || offset > end {
+            return None;
+        }
+        buffered.used = Instant::now();
+        let from = (offset - buffered.offset) as usize;
+        let to = (end - buffered.offset) as usize;
+        Some((
+            buffered.data[from..to].to_vec(),
+            offset.saturating_add(count as u64) >= stamp.size,
+        ))
+    }
+
+    /// Keep `data`, read at `offset` from `id` while it had `stamp`
+    pub fn insert(&self, id: fileid3, offset: u64, data: Vec<u8>, stamp: FileStamp) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if !buffers.contains_key(&id) && buffers.len() >= self.capacity {
+            let oldest = buffers
+                .iter()
+                .min_by_key(|(_, buffered)| buffered.used)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                buffers.remove(&oldest);
+            }
+        }
+        buffers.insert(
+            id,
+            Buffered {
+                offset,
+                data,
+                stamp,
+                used: Instant::now(),
+            },
+        );
+    }
+
+    /// Drop the buffer of a file that changed
+    pub fn invalidate(&self, id: fileid3) {
+        self.buffers.lock().unwrap().remove(&id);
+    }
+}
@@ -0,0 +1,105 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use tracing::debug;
+
+/// Bounded retry with exponential backoff for transient backing store errors
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt (0 disables retrying)
+    pub retries: u32,
+    /// Delay before the first retry, doubled for each further retry
+    pub backoff: Duration,
+}
+
+/// Check whether an IO error is worth retrying
+///
+/// Only errors that describe a temporary condition qualify; errors such as
+/// ENOENT or EACCES describe the actual state of the file and fail fast.
+pub fn is_retryable(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::ENOTCONN)),
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, backoff: Duration) -> Self {
+        Self { retries, backoff }
+    }
+
+    /// Run `op`, retrying it while it fails with a retryable error
+    pub async fn run<T, F, Fut>(&self, mut op: F) -> io::Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let mut delay = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Err(e) if attempt < self.retries && is_retryable(&e) => {
+                    attempt += 1;
+                    debug!("retrying after {:?} (attempt {})", e, attempt);
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn test_retries_transient_errors() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(1));
+        let calls = AtomicU32::new(0);
+        let result = policy
+            .run(|| async {
+                if calls.fetch_add(1, Ordering::Relaxed) < 2 {
+                    Err(io::Error::from_raw_os_error(libc::EAGAIN))
+                } else {
+                    Ok(42)
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_retries() {
+        let policy = RetryPolicy::new(2, Duration::from_millis(1));
+        let calls = AtomicU32::new(0);
+        let result: io::Result<()> = policy
+            .run(|| async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err(io::Error::from_raw_os_error(libc::EINTR))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_fail_fast() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(1));
+        for errno in [libc::ENOENT, libc::EACCES] {
+            let calls = AtomicU32::new(0);
+            let result: io::Result<()> = policy
+                .run(|| async {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Err(io::Error::from_raw_os_error(errno))
+                })
+                .await;
+            assert!(result.is_err());
+            assert_eq!(calls.load(Ordering::Relaxed), 1);
+        }
+    }
+}
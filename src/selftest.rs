@@ -0,0 +1,174 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::{Config, MountConfig};
+
+/// Result of a self-test run that did not fail
+#[derive(Debug)]
+pub enum Outcome {
+    /// The export was mounted and every check passed
+    Passed,
+    /// The checks could not run in this environment
+    Skipped(String),
+}
+
+/// Adjust a configuration so the self-test server listens on an ephemeral local port
+pub fn prepare(config: &mut Config) {
+    config.server.ip = "127.0.0.1".parse().unwrap();
+    config.server.port = 0;
+    config.server.mount_port = None;
+    config.server.daemon = false;
+}
+
+/// Add the mount exercised by the self-test to `config` and return it
+///
+/// It serves a fresh temporary directory, so the checks never write into a
+/// configured mount source. Called once inside any chroot, so the directory
+/// is created where the server will look for it.
+pub fn test_mount(config: &mut Config) -> Result<MountConfig, String> {
+    let source =
+        std::env::temp_dir().join(format!("nfs_mirror_self_test_src_{}", std::process::id()));
+    std::fs::create_dir_all(&source)
+        .map_err(|e| format!("Failed to create {}: {}", source.display(), e))?;
+    let mount = MountConfig {
+        read_only: config.server.read_only || config.server.snapshot,
+        ..MountConfig::new(source, "/.nfs_mirror_self_test")
+    };
+    config.mounts.push(mount.clone());
+    Ok(mount)
+}
+
+/// Mount `mount` from the server on `port`, exercise it and unmount it
+///
+/// Runs the blocking mount and file operations off the async runtime, since
+/// they are served by this same process.
+pub async fn run(port: u16, mount: MountConfig) -> Result<Outcome, String> {
+    tokio::task::spawn_blocking(move || run_blocking(port, &mount))
+        .await
+        .map_err(|e| format!("Self-test task failed: {}", e))?
+}
+
+fn run_blocking(port: u16, mount: &MountConfig) -> Result<Outcome, String> {
+    // SAFETY: geteuid has no preconditions
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(Outcome::Skipped("mounting NFS requires root".to_string()));
+    }
+
+    let mountpoint = std::env::temp_dir().join(format!("nfs_mirror_self_test_{}", port));
+    std::fs::create_dir_all(&mountpoint)
+        .map_err(|e| format!("Failed to create {}: {}", mountpoint.display(), e))?;
+
+    let result = match mount_export(port, &mount.target, &mountpoint) {
+        Ok(()) => {
+            let checks = check_export(&mountpoint, mount.read_only);
+            let unmounted = run_command(Command::new("umount").arg(&mountpoint));
+            checks.and(unmounted).map(|_| Outcome::Passed)
+        }
+        Err(e) if lacks_privileges(&e) => Ok(Outcome::Skipped(format!("cannot mount: {}", e))),
+        Err(e) => Err(e),
+    };
+    let _ = std::fs::remove_dir(&mountpoint);
+    let _ = std::fs::remove_dir_all(&mount.source);
+    result
+}
+
+/// Check whether a mount failure is EPERM or EACCES, i.e. comes from the
+/// environment rather than the server
+fn lacks_privileges(error: &str) -> bool {
+    [libc::EPERM, libc::EACCES]
+        .into_iter()
+        .any(|errno| error.contains(&strerror(errno)))
+}
+
+/// Message for `errno`, as the mount helpers print it
+fn strerror(errno: i32) -> String {
+    let message = std::io::Error::from_raw_os_error(errno).to_string();
+    match message.rfind(" (os error") {
+        Some(end) => message[..end].to_string(),
+        None => message,
+    }
+}
+
+fn mount_export(port: u16, target: &str, mountpoint: &Path) -> Result<(), String> {
+    let options = if cfg!(target_os = "macos") {
+        format!("resvport,nolocks,vers=3,tcp,port={0},mountport={0}", port)
+    } else {
+        format!("nolock,vers=3,tcp,port={0},mountport={0},soft", port)
+    };
+    run_command(
+        Command::new("mount")
+            .args(["-t", "nfs", "-o", &options])
+            .arg(format!("127.0.0.1:{}", target))
+            .arg(mountpoint),
+    )
+}
+
+/// List the mounted export and, unless it is read-only, write, read back and remove a file
+fn check_export(mountpoint: &Path, read_only: bool) -> Result<(), String> {
+    let list = |dir: &Path| -> Result<Vec<PathBuf>, String> {
+        std::fs::read_dir(dir)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+            .map_err(|e| format!("Failed to list {}: {}", dir.display(), e))
+    };
+    list(mountpoint)?;
+    if read_only {
+        return Ok(());
+    }
+
+    let probe = mountpoint.join(format!(".nfs_mirror_self_test_{}", std::process::id()));
+    let data = b"nfs_mirror self-test";
+    std::fs::write(&probe, data).map_err(|e| format!("Failed to write: {}", e))?;
+    let read = std::fs::read(&probe).map_err(|e| format!("Failed to read back: {}", e))?;
+    let listed = list(mountpoint)?.contains(&probe);
+    std::fs::remove_file(&probe).map_err(|e| format!("Failed to remove: {}", e))?;
+
+    if read != data {
+        return Err("Data read back does not match what was written".to_string());
+    }
+    if !listed {
+        return Err("Written file is missing from the directory listing".to_string());
+    }
+    Ok(())
+}
+
+fn run_command(command: &mut Command) -> Result<(), String> {
+    let output = command
+        .output()
+        .map_err(|e| format!("{:?} not found: {}", command.get_program(), e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} failed: {}",
+            command.get_program(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_eperm_and_eacces_skip() {
+        assert!(lacks_privileges(&format!(
+            "\"mount\" failed: mount.nfs: {}",
+            strerror(libc::EPERM)
+        )));
+        assert!(lacks_privileges(&format!(
+            "\"mount\" failed: mount.nfs: {}",
+            strerror(libc::EACCES)
+        )));
+        assert!(!lacks_privileges(&format!(
+            "\"mount\" failed: mount.nfs: {}",
+            strerror(libc::ENOENT)
+        )));
+        assert!(!lacks_privileges(
+            "\"mount\" failed: mount.nfs: mounting 127.0.0.1:/test failed, reason given by server: No such file or directory"
+        ));
+        assert!(!lacks_privileges(
+            "\"mount\" not found: No such file or directory (os error 2)"
+        ));
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use zerofs_nfsserve::nfs::*;
+
+/// Files still being written when files are write-once
+///
+/// NFSv3 has no close, so a file created through the server counts as
+/// being written until it goes `idle` without a write.
+/// After that it is sealed for good. Files the server did not create are
+/// sealed from the start, and a restart seals every file.
+#[derive(Debug)]
+pub struct WriteOnce {
+    idle: Duration,
+    writing: Mutex<HashMap<fileid3, Instant>>,
+}
+
+impl WriteOnce {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            writing: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start tracking a file just created through the server
+    pub fn created(&self, id: fileid3) {
+        let mut writing = self.writing.lock().unwrap();
+        writing.retain(|_, used| used.elapsed() < self.idle);
+        writing.insert(id, Instant::now());
+    }
+
+    /// Whether `id` may still be modified, counting this as a use if so
+    pub fn touch(&self, id: fileid3) -> bool {
+        let mut writing = self.writing.lock().unwrap();
+        match writing.get_mut(&id) {
+            Some(used) if used.elapsed() < self.idle => {
+                *used = Instant::now();
+                true
+            }
+            Some(_) => {
+                writing.remove(&id);
+                false
+            }
+            None => false,
+        }
+    }
+}